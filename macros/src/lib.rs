@@ -0,0 +1,384 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+/*!
+# frequenz-microgrid-formula-engine-macros
+
+The `formula!` macro for `frequenz-microgrid-formula-engine`, re-exported by
+it with the `macros` feature.
+*/
+
+use std::iter::Peekable;
+
+use pest::{
+    iterators::{Pair, Pairs},
+    Parser,
+};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, LitStr};
+
+use parser::{FormulaParser, Rule};
+use syntax::{
+    check_arity, unescape, Associativity, Precedence, MAX_RANGE_LEN, NOT_BINDING_POWER,
+    UNARY_MINUS_BINDING_POWER,
+};
+
+// A proc-macro crate can't export the parser, so it lives in a private module.
+mod parser {
+    use pest_derive::Parser;
+
+    /// The engine's parser, generated from the same grammar.
+    #[derive(Parser)]
+    #[grammar = "../src/grammar.pest"]
+    pub struct FormulaParser;
+}
+
+/// The engine's rules of the syntax beyond the grammar, from the same file.
+#[path = "../../src/syntax.rs"]
+mod syntax;
+
+/// Parse a formula at compile time into an `Expr`, so that malformed formulas
+/// are compile errors.
+///
+/// The number of arguments of built-in functions is checked too, so that
+/// `formula!("MIN(#0)")` doesn't compile. Checks that need an engine's
+/// options or custom functions, like unknown time-of-use windows, happen in
+/// `FormulaEngine::try_from_expr`. Custom operators aren't supported.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::{formula, EngineOptions, Expr, FormulaEngine};
+/// use std::collections::HashMap;
+///
+/// let expr: Expr<f64> = formula!("MAX(#0, #1) + 3");
+/// let fe = FormulaEngine::try_from_expr(expr, EngineOptions::default()).unwrap();
+/// let result = fe.calculate(HashMap::from([(0, Some(1.0)), (1, Some(2.0))]));
+/// assert_eq!(result.unwrap(), Some(5.0));
+/// ```
+///
+/// ```rust,compile_fail
+/// use frequenz_microgrid_formula_engine::{formula, Expr};
+///
+/// // MIN expects at least 2 arguments, got 1
+/// let expr: Expr<f64> = formula!("MIN(#0)");
+/// ```
+#[proc_macro]
+pub fn formula(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    let expanded = FormulaParser::parse(Rule::formula, &literal.value())
+        .map_err(|err| err.to_string())
+        .and_then(|pairs| expr(&mut pairs.peekable(), 0));
+    match expanded {
+        Ok(tokens) => tokens.into(),
+        Err(message) => syn::Error::new(literal.span(), message)
+            .to_compile_error()
+            .into(),
+    }
+}
+
+/// The path of the engine crate, which the expanded code refers to.
+fn krate() -> TokenStream {
+    quote!(::frequenz_microgrid_formula_engine)
+}
+
+/// Expand the operators binding their left operand at least as tightly as
+/// `min_power`, like the engine's parser.
+fn expr(pairs: &mut Peekable<Pairs<Rule>>, min_power: u8) -> Result<TokenStream, String> {
+    let krate = krate();
+    let pair = pairs.next().ok_or("Expected an expression")?;
+    let mut lhs = match pair.as_rule() {
+        Rule::unary_minus => {
+            let operand = expr(pairs, UNARY_MINUS_BINDING_POWER)?;
+            quote!(#krate::Expr::UnaryMinus(::std::boxed::Box::new(#operand)))
+        }
+        Rule::not => {
+            let operand = expr(pairs, NOT_BINDING_POWER)?;
+            quote!(#krate::Expr::Not(::std::boxed::Box::new(#operand)))
+        }
+        _ => primary(pair)?,
+    };
+
+    while let Some(op) = pairs.peek() {
+        let (op, precedence, associativity) = match op.as_rule() {
+            Rule::EOI => {
+                pairs.next();
+                continue;
+            }
+            Rule::custom_op => {
+                return Err(format!(
+                    "Custom operator {} can't be used in formula!",
+                    op.as_str()
+                ))
+            }
+            rule => operator(rule),
+        };
+        let (left_power, right_power) = precedence.binding_powers(associativity);
+        if left_power < min_power {
+            break;
+        }
+        pairs.next();
+        let rhs = expr(pairs, right_power)?;
+        let op = format_ident!("{}", op);
+        lhs = quote!(#krate::Expr::Op {
+            lhs: ::std::boxed::Box::new(#lhs),
+            op: #krate::Op::#op,
+            rhs: ::std::boxed::Box::new(#rhs),
+        });
+    }
+    Ok(lhs)
+}
+
+/// The `Op` variant of an operator and its precedence, which must match
+/// `Op::precedence` in the engine.
+fn operator(rule: Rule) -> (&'static str, Precedence, Associativity) {
+    let (op, precedence) = match rule {
+        Rule::or => ("Or", Precedence::Or),
+        Rule::and => ("And", Precedence::And),
+        Rule::eq => ("Eq", Precedence::Comparison),
+        Rule::ne => ("Ne", Precedence::Comparison),
+        Rule::lt => ("Lt", Precedence::Comparison),
+        Rule::le => ("Le", Precedence::Comparison),
+        Rule::gt => ("Gt", Precedence::Comparison),
+        Rule::ge => ("Ge", Precedence::Comparison),
+        Rule::add => ("Add", Precedence::Additive),
+        Rule::sub => ("Sub", Precedence::Additive),
+        Rule::mul => ("Mul", Precedence::Multiplicative),
+        Rule::div => ("Div", Precedence::Multiplicative),
+        Rule::modulo => ("Mod", Precedence::Multiplicative),
+        Rule::pow => return ("Pow", Precedence::Power, Associativity::Right),
+        rule => unreachable!("formula! expected operator, found {:?}", rule),
+    };
+    (op, precedence, Associativity::Left)
+}
+
+/// The `Function` variant of a built-in function.
+fn function(rule: Rule) -> Option<&'static str> {
+    Some(match rule {
+        Rule::coalesce => "Coalesce",
+        Rule::min => "Min",
+        Rule::max => "Max",
+        Rule::min_strict => "MinStrict",
+        Rule::max_strict => "MaxStrict",
+        Rule::pos => "Pos",
+        Rule::neg => "Neg",
+        Rule::hypot => "Hypot",
+        Rule::sin => "Sin",
+        Rule::cos => "Cos",
+        Rule::tan => "Tan",
+        Rule::atan2 => "Atan2",
+        Rule::lerp => "Lerp",
+        Rule::curve => "Curve",
+        Rule::poly => "Poly",
+        Rule::kw => "Kw",
+        Rule::mw => "Mw",
+        Rule::kwh => "Kwh",
+        Rule::power => "Pow",
+        Rule::if_else => "If",
+        Rule::case => "Case",
+        Rule::round => "Round",
+        Rule::quantize => "Quantize",
+        Rule::floor => "Floor",
+        Rule::ceil => "Ceil",
+        Rule::sum => "Sum",
+        Rule::product => "Product",
+        Rule::nullif => "NullIf",
+        Rule::is_none => "IsNone",
+        Rule::is_some => "IsSome",
+        Rule::count_some => "CountSome",
+        _ => return None,
+    })
+}
+
+/// The `TemporalFunction` variant of a temporal function.
+fn temporal(rule: Rule) -> Option<&'static str> {
+    Some(match rule {
+        Rule::rolling_avg => "RollingAvg",
+        Rule::rolling_min => "RollingMin",
+        Rule::rolling_max => "RollingMax",
+        Rule::deriv => "Deriv",
+        Rule::integrate => "Integrate",
+        Rule::lag => "Lag",
+        Rule::delta => "Delta",
+        Rule::delta_wrap => "DeltaWrap",
+        Rule::ramp_limit => "RampLimit",
+        Rule::tw_avg => "TwAvg",
+        _ => return None,
+    })
+}
+
+fn primary(primary: Pair<Rule>) -> Result<TokenStream, String> {
+    let krate = krate();
+    let value =
+        |text: String| quote!(#krate::Expr::Value(::core::str::FromStr::from_str(#text).ok()));
+    Ok(match primary.as_rule() {
+        Rule::expr => expr(&mut primary.into_inner().peekable(), 0)?,
+        Rule::num => value(primary.as_str().replace("_", "")),
+        Rule::duration => value(duration_seconds(primary.as_str())?),
+        Rule::constant => value(
+            match primary.as_str() {
+                "PI" => std::f64::consts::PI,
+                "E" => std::f64::consts::E,
+                "SQRT2" => std::f64::consts::SQRT_2,
+                _ => 3f64.sqrt(),
+            }
+            .to_string(),
+        ),
+        Rule::component => component(primary),
+        Rule::named => {
+            let name = primary.as_str().trim_start_matches('$');
+            quote!(#krate::Expr::Named(::std::string::String::from(#name)))
+        }
+        Rule::component_id => {
+            let id = string_literal(primary);
+            quote!(#krate::Expr::Named(::std::string::String::from(#id)))
+        }
+        Rule::reference => {
+            let name = primary.as_str().trim_start_matches('@');
+            quote!(#krate::Expr::Reference(::std::string::String::from(#name)))
+        }
+        Rule::tou => {
+            let name = string_literal(primary);
+            quote!(#krate::Expr::TimeOfUse(::std::string::String::from(#name)))
+        }
+        Rule::let_in => {
+            let mut inner = primary.into_inner();
+            let name = inner.next().map(|x| x.as_str()).unwrap_or_default();
+            let mut next = || match inner.next() {
+                Some(pair) => expr(&mut Pairs::single(pair).peekable(), 0),
+                None => Err("Expected an expression".to_string()),
+            };
+            let (value, body) = (next()?, next()?);
+            quote!(#krate::Expr::Let {
+                name: ::std::string::String::from(#name),
+                value: ::std::boxed::Box::new(#value),
+                body: ::std::boxed::Box::new(#body),
+            })
+        }
+        Rule::variable => {
+            let name = primary.as_str();
+            quote!(#krate::Expr::Variable(::std::string::String::from(#name)))
+        }
+        Rule::custom => {
+            let mut inner = primary.into_inner();
+            let name = inner
+                .next()
+                .map(|x| x.as_str().trim_end_matches('('))
+                .unwrap_or_default();
+            let args = args(inner)?;
+            quote!(#krate::Expr::Custom {
+                name: ::std::string::String::from(#name),
+                args: ::std::vec![#(#args),*],
+            })
+        }
+        Rule::now => quote!(#krate::Expr::Time(#krate::TimeFunction::Now)),
+        Rule::hour => quote!(#krate::Expr::Time(#krate::TimeFunction::Hour)),
+        Rule::dayofweek => {
+            quote!(#krate::Expr::Time(#krate::TimeFunction::DayOfWeek))
+        }
+        rule => match (function(rule), temporal(rule)) {
+            (Some(function), _) => {
+                let function = format_ident!("{}", function);
+                let args = checked_args(primary)?;
+                quote!(#krate::Expr::Function {
+                    function: #krate::Function::#function,
+                    args: ::std::vec![#(#args),*],
+                })
+            }
+            (_, Some(function)) => {
+                let function = format_ident!("{}", function);
+                let args = checked_args(primary)?;
+                quote!(#krate::Expr::Temporal {
+                    function: #krate::TemporalFunction::#function,
+                    args: ::std::vec![#(#args),*],
+                })
+            }
+            (None, None) => {
+                return Err(format!(
+                    "{} can't be used in formula!",
+                    primary.as_str().trim()
+                ))
+            }
+        },
+    })
+}
+
+/// Expand the arguments of a built-in or temporal function, checking their
+/// number like the engine.
+fn checked_args(function: Pair<Rule>) -> Result<Vec<TokenStream>, String> {
+    let call = function.as_str().trim();
+    let name = call[..call.find('(').unwrap_or(call.len())].to_ascii_uppercase();
+    let inner = function.into_inner();
+    let wildcard = inner.clone().any(|arg| arg.as_rule() == Rule::wildcard);
+    let args = args(inner)?;
+    check_arity(&name, args.len(), wildcard)?;
+    Ok(args)
+}
+
+/// Expand the arguments of a function, including placeholder ranges.
+fn args(pairs: Pairs<Rule>) -> Result<Vec<TokenStream>, String> {
+    let krate = krate();
+    let mut args = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::component_range => {
+                let range = pair.as_str().trim().to_string();
+                let ids: Vec<Option<u64>> = pair
+                    .into_inner()
+                    .map(|component| component.as_str().replace("#", "").parse().ok())
+                    .collect();
+                match ids[..] {
+                    [Some(first), Some(last)] if first.abs_diff(last) >= MAX_RANGE_LEN => {
+                        return Err(format!(
+                            "Placeholder range {} has more than {} placeholders",
+                            range, MAX_RANGE_LEN
+                        ))
+                    }
+                    [Some(first), Some(last)] if first <= last => {
+                        args.extend((first..=last).map(|id| quote!(#krate::Expr::Component(#id))))
+                    }
+                    [Some(first), Some(last)] => args.extend(
+                        (last..=first)
+                            .rev()
+                            .map(|id| quote!(#krate::Expr::Component(#id))),
+                    ),
+                    _ => args.push(quote!(#krate::Expr::Value(::core::option::Option::None))),
+                }
+            }
+            Rule::wildcard => args.push(quote!(#krate::Expr::Wildcard)),
+            _ => args.push(expr(&mut Pairs::single(pair).peekable(), 0)?),
+        }
+    }
+    Ok(args)
+}
+
+fn component(pair: Pair<Rule>) -> TokenStream {
+    let krate = krate();
+    match pair.as_str().replace("#", "").parse::<u64>() {
+        Ok(id) => quote!(#krate::Expr::Component(#id)),
+        Err(_) => quote!(#krate::Expr::Value(::core::option::Option::None)),
+    }
+}
+
+/// The number of seconds of a duration, like the engine's parser.
+fn duration_seconds(duration: &str) -> Result<String, String> {
+    let number = duration.trim_end_matches(char::is_alphabetic);
+    let digits = number.replace("_", "");
+    let value = digits.parse::<f64>().map_err(|err| err.to_string())?;
+    let seconds = match &duration[number.len()..] {
+        "ms" => value / 1000.0,
+        "s" => return Ok(digits),
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        "d" => value * 86400.0,
+        unit => return Err(format!("Unknown duration unit: {}", unit)),
+    };
+    Ok(format!("{}", seconds))
+}
+
+/// Get the contents of the string literal in a rule.
+fn string_literal(pair: Pair<Rule>) -> String {
+    pair.into_inner()
+        .flat_map(|string| string.into_inner())
+        .map(|inner| unescape(inner.as_str()))
+        .collect()
+}
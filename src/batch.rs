@@ -0,0 +1,404 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{collections::HashMap, ops::Neg};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{from_bool, Bound, Expr, Function, Inputs},
+    formula_engine::FormulaEngine,
+    functions::FunctionRegistry,
+    options::EngineOptions,
+    value::FormulaValue,
+};
+
+/// The results of calculating an expression for several rows of values.
+type Results<T> = Vec<Result<Option<T>, FormulaError>>;
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Calculate the results of the formula for several rows of component
+    /// values, e.g. to backfill a derived metric over historical data.
+    ///
+    /// The formula is evaluated node by node for all rows at once, instead of
+    /// once per row. The results are those [`FormulaEngine::calculate`] gives
+    /// for each row, including its errors: like it, IF and CASE only
+    /// evaluate the branches the rows select.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 * 2").unwrap();
+    /// let results = fe.calculate_batch(&[
+    ///     HashMap::from([(0, Some(1.0))]),
+    ///     HashMap::from([(0, None)]),
+    /// ]);
+    /// assert_eq!(results[0].as_ref().unwrap(), &Some(2.0));
+    /// assert_eq!(results[1].as_ref().unwrap(), &None);
+    /// ```
+    pub fn calculate_batch(&self, rows: &[HashMap<u64, Option<T>>]) -> Results<T> {
+        let rows: Vec<&dyn Inputs<T>> = rows.iter().map(|row| row as &dyn Inputs<T>).collect();
+        self.calculate_rows(&rows)
+    }
+
+    /// Calculate the result column of the formula from columns of component
+    /// values of equal length, e.g. as stored for historical data.
+    ///
+    /// Like [`FormulaEngine::calculate_batch`], the formula is evaluated
+    /// operator by operator over whole columns. The first error of any row
+    /// is returned. Without any columns, the result column is empty.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+    /// let columns = HashMap::from([
+    ///     (0, &[Some(1.0), None][..]),
+    ///     (1, &[Some(2.0), Some(3.0)][..]),
+    /// ]);
+    /// assert_eq!(fe.calculate_columns(&columns).unwrap(), [Some(3.0), None]);
+    /// ```
+    pub fn calculate_columns(
+        &self,
+        columns: &HashMap<u64, &[Option<T>]>,
+    ) -> Result<Vec<Option<T>>, FormulaError> {
+        let (columns, len) = sorted_columns(columns)?;
+        let rows: Vec<_> = (0..len)
+            .map(|row| ColumnRow {
+                columns: &columns,
+                row,
+            })
+            .collect();
+        let rows: Vec<&dyn Inputs<T>> = rows.iter().map(|row| row as &dyn Inputs<T>).collect();
+        self.calculate_rows(&rows).into_iter().collect()
+    }
+
+    /// Calculate the results of the formula for each of the given rows of
+    /// inputs.
+    fn calculate_rows(&self, rows: &[&dyn Inputs<T>]) -> Results<T> {
+        if !self.options.strict() {
+            return self
+                .expr()
+                .calculate_rows(rows, &self.options, &self.functions);
+        }
+
+        // Only the rows with values for all placeholders are calculated.
+        let mut results = Vec::with_capacity(rows.len());
+        let mut complete = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            match self.check_values(row) {
+                Ok(()) => {
+                    complete.push(i);
+                    results.push(Ok(None));
+                }
+                Err(err) => results.push(Err(err)),
+            }
+        }
+        let subset: Vec<_> = complete.iter().map(|&i| rows[i]).collect();
+        scatter(
+            &complete,
+            self.expr()
+                .calculate_rows(&subset, &self.options, &self.functions),
+            &mut results,
+        );
+        results
+    }
+}
+
+impl<T: FormulaValue + Float> Expr<T> {
+    /// Calculate the expression for each of the given rows of inputs.
+    fn calculate_rows(
+        &self,
+        rows: &[&dyn Inputs<T>],
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Results<T> {
+        let calculate = |expr: &Expr<T>| expr.calculate_rows(rows, options, functions);
+        match self {
+            Expr::Value(value) => rows.iter().map(|_| Ok(*value)).collect(),
+            Expr::UnaryMinus(expr) => map_rows(calculate(expr), |x| Ok(x.map(Neg::neg))),
+            Expr::Not(expr) => {
+                map_rows(
+                    calculate(expr),
+                    |x| Ok(x.map(|x| from_bool(x == T::zero()))),
+                )
+            }
+            Expr::Op { lhs, op, rhs } => zip_rows(calculate(lhs), calculate(rhs))
+                .map(|operands| {
+                    let (lhs, rhs) = operands?;
+                    self.apply_op(op, lhs, rhs, options)
+                })
+                .collect(),
+            Expr::Function {
+                function: function @ (Function::If | Function::Case),
+                args,
+            } => {
+                let selected = Expr::select_rows(function, args, rows, options, functions);
+                Expr::calculate_selected(args, selected, rows, options, functions)
+            }
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => {
+                let selected = Expr::select_rows(function, args, rows, options, functions);
+                Expr::calculate_selected(branches, selected, rows, options, functions)
+            }
+            Expr::Function { function, args } => {
+                Expr::calculate_args_rows(args, rows, options, functions)
+                    .into_iter()
+                    .map(|args| Ok(function.apply(&args?, options)))
+                    .collect()
+            }
+            Expr::CustomOp { symbol, lhs, rhs } => match functions.operator(symbol) {
+                Some(op) => zip_rows(calculate(lhs), calculate(rhs))
+                    .map(|operands| {
+                        let (lhs, rhs) = operands?;
+                        Ok((op.function)(lhs, rhs))
+                    })
+                    .collect(),
+                None => self.calculate_each(rows, options, functions),
+            },
+            Expr::Custom { name, args } => match functions.get(name) {
+                Some(function) => Expr::calculate_args_rows(args, rows, options, functions)
+                    .into_iter()
+                    .map(|args| Ok(function(&args?)))
+                    .collect(),
+                None => self.calculate_each(rows, options, functions),
+            },
+            Expr::Let { name, value, body } => {
+                let mut results = Vec::with_capacity(rows.len());
+                let mut bound = Vec::new();
+                let mut indices = Vec::new();
+                for (i, (row, value)) in rows.iter().zip(calculate(value)).enumerate() {
+                    match value {
+                        Ok(value) => {
+                            bound.push(Bound {
+                                inputs: *row,
+                                name,
+                                value,
+                            });
+                            indices.push(i);
+                            results.push(Ok(None));
+                        }
+                        Err(err) => results.push(Err(err)),
+                    }
+                }
+                let bound: Vec<_> = bound.iter().map(|row| row as &dyn Inputs<T>).collect();
+                scatter(
+                    &indices,
+                    body.calculate_rows(&bound, options, functions),
+                    &mut results,
+                );
+                results
+            }
+            // Placeholders and other leaves are looked up for each row, and
+            // temporal functions fail for each row.
+            Expr::Component(_)
+            | Expr::Wildcard
+            | Expr::Named(_)
+            | Expr::Variable(_)
+            | Expr::Reference(_)
+            | Expr::TimeOfUse(_)
+            | Expr::Time(_)
+            | Expr::Temporal { .. } => self.calculate_each(rows, options, functions),
+        }
+    }
+
+    /// Calculate the expression for each row separately.
+    fn calculate_each(
+        &self,
+        rows: &[&dyn Inputs<T>],
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Results<T> {
+        rows.iter()
+            .map(|row| self.calculate(row, options, functions))
+            .collect()
+    }
+
+    /// Calculate the arguments of a function for each row, expanding `#*` to
+    /// all values given in the row.
+    fn calculate_args_rows(
+        args: &[Expr<T>],
+        rows: &[&dyn Inputs<T>],
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Vec<Result<Vec<Option<T>>, FormulaError>> {
+        let mut results: Vec<_> = rows
+            .iter()
+            .map(|_| Ok(Vec::with_capacity(args.len())))
+            .collect();
+        for arg in args {
+            let values: Vec<_> = match arg {
+                Expr::Wildcard => rows
+                    .iter()
+                    .map(|row| Expr::wildcard_values(row, options))
+                    .collect(),
+                arg => map_rows(arg.calculate_rows(rows, options, functions), |value| {
+                    Ok(vec![value])
+                }),
+            };
+            for (result, values) in results.iter_mut().zip(values) {
+                // The first error of a row is kept, like when calculating it
+                // on its own.
+                if let Ok(args) = result {
+                    match values {
+                        Ok(values) => args.extend(values),
+                        Err(err) => *result = Err(err),
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    /// Get the index of the argument a selecting function evaluates to for
+    /// each row, evaluating only the conditions needed for IF and CASE.
+    fn select_rows(
+        function: &Function,
+        args: &[Expr<T>],
+        rows: &[&dyn Inputs<T>],
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Vec<Result<Option<usize>, FormulaError>> {
+        if !matches!(function, Function::If | Function::Case) {
+            return Expr::calculate_args_rows(args, rows, options, functions)
+                .into_iter()
+                .map(|args| Ok(function.select(&args?)))
+                .collect();
+        }
+
+        // Like `Function::select_branch`, with the rows whose branch isn't
+        // known yet evaluating the next condition.
+        let mut selected: Vec<_> = rows.iter().map(|_| Ok(None)).collect();
+        let mut pending: Vec<usize> = (0..rows.len()).collect();
+        let mut i = 0;
+        while Function::is_condition(i, args.len()) && !pending.is_empty() {
+            let subset: Vec<_> = pending.iter().map(|&row| rows[row]).collect();
+            let conditions = args[i].calculate_rows(&subset, options, functions);
+            let mut next = Vec::new();
+            for (row, condition) in pending.into_iter().zip(conditions) {
+                match condition {
+                    Ok(Some(c)) if c != T::zero() => selected[row] = Ok(Some(i + 1)),
+                    Ok(Some(_)) => next.push(row),
+                    Ok(None) => selected[row] = Ok(None),
+                    Err(err) => selected[row] = Err(err),
+                }
+            }
+            pending = next;
+            i += 2;
+        }
+        for row in pending {
+            selected[row] = Ok((i < args.len()).then_some(i));
+        }
+        selected
+    }
+
+    /// Calculate the branch each row selects, for the rows that select one.
+    fn calculate_selected(
+        branches: &[Expr<T>],
+        selected: Vec<Result<Option<usize>, FormulaError>>,
+        rows: &[&dyn Inputs<T>],
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Results<T> {
+        let mut by_branch = vec![Vec::new(); branches.len()];
+        let results = selected
+            .into_iter()
+            .enumerate()
+            .map(|(row, selected)| {
+                if let Ok(Some(branch)) = selected {
+                    by_branch[branch].push(row);
+                }
+                selected.map(|_| None)
+            })
+            .collect();
+        let mut results: Results<T> = results;
+        for (branch, indices) in branches.iter().zip(by_branch) {
+            if indices.is_empty() {
+                continue;
+            }
+            let subset: Vec<_> = indices.iter().map(|&row| rows[row]).collect();
+            scatter(
+                &indices,
+                branch.calculate_rows(&subset, options, functions),
+                &mut results,
+            );
+        }
+        results
+    }
+}
+
+/// Columns of component values with their component IDs.
+pub(crate) type Columns<'a, T> = Vec<(u64, &'a [Option<T>])>;
+
+/// Get the columns sorted by component ID, and their common length.
+pub(crate) fn sorted_columns<'a, T>(
+    columns: &HashMap<u64, &'a [Option<T>]>,
+) -> Result<(Columns<'a, T>, usize), FormulaError> {
+    let mut columns: Vec<_> = columns.iter().map(|(id, column)| (*id, *column)).collect();
+    columns.sort_by_key(|(id, _)| *id);
+    let len = columns.first().map_or(0, |(_, column)| column.len());
+    if columns.iter().any(|(_, column)| column.len() != len) {
+        return Err(FormulaError(
+            "All columns must have the same length".to_string(),
+        ));
+    }
+    Ok((columns, len))
+}
+
+/// A row of columns of component values, sorted by component ID.
+struct ColumnRow<'a, T> {
+    columns: &'a [(u64, &'a [Option<T>])],
+    row: usize,
+}
+
+impl<T: Copy> Inputs<T> for ColumnRow<'_, T> {
+    fn component(&self, id: u64) -> Option<Option<T>> {
+        self.columns
+            .binary_search_by_key(&id, |(id, _)| *id)
+            .ok()
+            .map(|i| self.columns[i].1[self.row])
+    }
+
+    fn named(&self, _name: &str) -> Option<Option<T>> {
+        None
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        Some(
+            self.columns
+                .iter()
+                .map(|(_, column)| column[self.row])
+                .collect(),
+        )
+    }
+}
+
+/// Map the values of the rows that don't have errors.
+fn map_rows<T, U>(
+    rows: Results<T>,
+    f: impl Fn(Option<T>) -> Result<U, FormulaError>,
+) -> Vec<Result<U, FormulaError>> {
+    rows.into_iter().map(|row| row.and_then(&f)).collect()
+}
+
+/// Pair the values of two operands for each row, keeping the error of the
+/// first operand if both have one.
+fn zip_rows<T>(
+    lhs: Results<T>,
+    rhs: Results<T>,
+) -> impl Iterator<Item = Result<(Option<T>, Option<T>), FormulaError>> {
+    lhs.into_iter().zip(rhs).map(|(lhs, rhs)| Ok((lhs?, rhs?)))
+}
+
+/// Replace the results at the given indices by the given values.
+fn scatter<R>(indices: &[usize], values: Vec<R>, results: &mut [R]) {
+    for (&i, value) in indices.iter().zip(values) {
+        results[i] = value;
+    }
+}
@@ -0,0 +1,268 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! A small HTTP/JSON service exposing formula evaluation.
+//!
+//! Usage: `serve [ADDRESS]` (default `127.0.0.1:8080`).
+//!
+//! Endpoints:
+//! - `GET /formulas`: list the registered formulas as `{"name": "formula"}`.
+//! - `PUT /formulas/{name}`: register a formula, body `{"formula": "#0 + #1"}`.
+//! - `DELETE /formulas/{name}`: remove a formula.
+//! - `POST /formulas/{name}/calculate`: evaluate a formula, body
+//!   `{"0": 1.0, "1": null}`, response `{"result": 1.0}`.
+//! - `POST /formulas/{name}/values`: update the latest values of components
+//!   of a formula's streaming engine, body `{"0": 1.0}`, response
+//!   `{"result": 1.0}` with the result for the latest values.
+//! - `GET /formulas/{name}/stream`: a WebSocket sending the result of the
+//!   streaming engine as `{"result": 1.0}`, first the current one and then
+//!   whenever it changes. It is closed when the formula is removed or
+//!   registered again, and pinged while the result doesn't change, to notice
+//!   closed connections.
+//!
+//! Results that JSON numbers can't represent are the strings `"NaN"`,
+//! `"Infinity"` and `"-Infinity"`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use frequenz_microgrid_formula_engine::{FormulaEngine, FormulaError, StreamingFormulaEngine};
+use serde_json::{json, Map, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+/// How long a WebSocket waits for a new result before it is pinged, so that
+/// the subscriber of a closed connection stops without waiting for a change.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Formula {
+    formula: String,
+    engine: FormulaEngine<f64>,
+    streaming: StreamingFormulaEngine<f64>,
+    /// The channels of the WebSockets streaming the results.
+    subscribers: Arc<Mutex<Vec<Sender<String>>>>,
+}
+
+fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let server = match Server::http(&address) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Failed to listen on {}: {}", address, err);
+            std::process::exit(1);
+        }
+    };
+    eprintln!("Listening on {}", address);
+
+    let mut formulas = HashMap::new();
+    for mut request in server.incoming_requests() {
+        if let Some(name) = stream_name(&request) {
+            match formulas.get(&name) {
+                Some(formula) => subscribe(formula, request),
+                None => respond(request, not_found(&name)),
+            }
+            continue;
+        }
+        let mut body = String::new();
+        let response = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => handle(&mut formulas, &request, &body),
+            Err(err) => (400, json!({ "error": err.to_string() })),
+        };
+        respond(request, response);
+    }
+}
+
+fn segments(request: &Request) -> Vec<&str> {
+    request
+        .url()
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Get the name of the formula of a request to the WebSocket endpoint,
+/// which is handled separately as upgrading consumes the request.
+fn stream_name(request: &Request) -> Option<String> {
+    match (request.method(), segments(request).as_slice()) {
+        (Method::Get, ["formulas", name, "stream"]) => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+fn handle(formulas: &mut HashMap<String, Formula>, request: &Request, body: &str) -> (u16, Value) {
+    match (request.method(), segments(request).as_slice()) {
+        (Method::Get, ["formulas"]) => (
+            200,
+            formulas
+                .iter()
+                .map(|(name, f)| (name.clone(), Value::from(f.formula.clone())))
+                .collect::<Map<String, Value>>()
+                .into(),
+        ),
+        (Method::Put, ["formulas", name]) => match register(body) {
+            Ok(formula) => {
+                formulas.insert(name.to_string(), formula);
+                (200, json!({ "name": name }))
+            }
+            Err(err) => (400, json!({ "error": err.to_string() })),
+        },
+        (Method::Delete, ["formulas", name]) => match formulas.remove(*name) {
+            Some(_) => (200, json!({ "name": name })),
+            None => not_found(name),
+        },
+        (Method::Post, ["formulas", name, "calculate"]) => match formulas.get(*name) {
+            Some(formula) => match calculate(&formula.engine, body) {
+                Ok(result) => (200, json!({ "result": number(result) })),
+                Err(err) => (400, json!({ "error": err.to_string() })),
+            },
+            None => not_found(name),
+        },
+        (Method::Post, ["formulas", name, "values"]) => match formulas.get_mut(*name) {
+            Some(formula) => match update(&mut formula.streaming, body) {
+                Ok(()) => (200, result(&formula.streaming.current())),
+                Err(err) => (400, json!({ "error": err.to_string() })),
+            },
+            None => not_found(name),
+        },
+        _ => (404, json!({ "error": "Unknown endpoint" })),
+    }
+}
+
+fn register(body: &str) -> Result<Formula, FormulaError> {
+    let formula = serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| v.get("formula").and_then(Value::as_str).map(str::to_string))
+        .ok_or(FormulaError(
+            "Expected a JSON object with a \"formula\" string".to_string(),
+        ))?;
+    let engine = FormulaEngine::try_new(&formula)?;
+    let mut streaming = engine.streaming();
+    let subscribers = Arc::new(Mutex::new(Vec::<Sender<String>>::new()));
+    let senders = subscribers.clone();
+    streaming.on_change(move |current| {
+        let message = result(current).to_string();
+        if let Ok(mut senders) = senders.lock() {
+            senders.retain(|sender| sender.send(message.clone()).is_ok());
+        }
+    });
+    Ok(Formula {
+        formula,
+        engine,
+        streaming,
+        subscribers,
+    })
+}
+
+fn calculate(engine: &FormulaEngine<f64>, body: &str) -> Result<Option<f64>, FormulaError> {
+    engine.calculate(values(body)?)
+}
+
+fn values(body: &str) -> Result<HashMap<u64, Option<f64>>, FormulaError> {
+    serde_json::from_str::<HashMap<String, Option<f64>>>(body)
+        .map_err(|e| FormulaError(format!("Invalid component values: {}", e)))?
+        .into_iter()
+        .map(|(id, value)| {
+            id.parse()
+                .map(|id| (id, value))
+                .map_err(|_| FormulaError(format!("Invalid component id: {}", id)))
+        })
+        .collect()
+}
+
+/// Update the streaming engine with the values of a request, in the order
+/// of the component IDs.
+fn update(streaming: &mut StreamingFormulaEngine<f64>, body: &str) -> Result<(), FormulaError> {
+    let mut values: Vec<_> = values(body)?.into_iter().collect();
+    values.sort_by_key(|(id, _)| *id);
+    for (id, value) in values {
+        // Errors for components without values yet are part of the result.
+        streaming.update(id, value).ok();
+    }
+    Ok(())
+}
+
+/// Upgrade a request to a WebSocket streaming the results of a formula.
+fn subscribe(formula: &Formula, request: Request) {
+    let key = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+        .map(|header| derive_accept_key(header.value.as_bytes()));
+    let Some(key) = key else {
+        let error = json!({ "error": "Expected a WebSocket upgrade request" });
+        return respond(request, (400, error));
+    };
+    let header = Header::from_bytes("Sec-WebSocket-Accept", key).expect("accept key is valid");
+    let stream = request.upgrade("websocket", Response::empty(101).with_header(header));
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    sender
+        .send(result(&formula.streaming.current()).to_string())
+        .ok();
+    if let Ok(mut subscribers) = formula.subscribers.lock() {
+        subscribers.push(sender);
+    }
+    std::thread::spawn(move || {
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+        loop {
+            let message = match receiver.recv_timeout(PING_INTERVAL) {
+                Ok(message) => Message::Text(message),
+                Err(RecvTimeoutError::Timeout) => Message::Ping(Vec::new()),
+                // The formula was removed or replaced.
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // Sending fails once the connection is closed, which a ping
+            // notices without waiting for the result to change.
+            if socket.send(message).is_err() {
+                return;
+            }
+        }
+        socket.close(None).ok();
+        socket.flush().ok();
+    });
+}
+
+fn result(result: &Result<Option<f64>, FormulaError>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": number(*result) }),
+        Err(err) => json!({ "error": err.to_string() }),
+    }
+}
+
+/// Encode a result as JSON, with `NaN` and infinities as strings, as JSON
+/// numbers can't represent them.
+fn number(value: Option<f64>) -> Value {
+    match value {
+        Some(value) if value.is_nan() => "NaN".into(),
+        Some(value) if value == f64::INFINITY => "Infinity".into(),
+        Some(value) if value == f64::NEG_INFINITY => "-Infinity".into(),
+        value => json!(value),
+    }
+}
+
+fn not_found(name: &str) -> (u16, Value) {
+    (
+        404,
+        json!({ "error": format!("Unknown formula: {}", name) }),
+    )
+}
+
+fn respond(request: Request, (status, body): (u16, Value)) {
+    let header =
+        Header::from_bytes("Content-Type", "application/json").expect("static header is valid");
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(err) = request.respond(response) {
+        eprintln!("Failed to send response: {}", err);
+    }
+}
@@ -0,0 +1,186 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{collections::HashMap, convert::Infallible};
+
+use num_traits::Float;
+
+use crate::{
+    expression::{Expr, Function},
+    formula_engine::FormulaEngine,
+    functions::FunctionRegistry,
+    options::EngineOptions,
+    value::FormulaValue,
+};
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Create a new FormulaEngine with the given components replaced by
+    /// constant values, e.g. rated powers, and the parts of the formula that
+    /// only depend on constants calculated in advance.
+    ///
+    /// The bound components aren't in the new engine's
+    /// [`components`](FormulaEngine::components) anymore. A `#*` placeholder
+    /// still only stands for the values given when calculating.
+    ///
+    /// Arguments of COALESCE after a constant that isn't `None`, and the
+    /// branches of IF and CASE after a constant condition, are removed with
+    /// the components only they use, as they can't affect the result. Errors
+    /// of the removed COALESCE arguments aren't reported anymore.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("MIN(#0, #1 * 1000)").unwrap();
+    /// let bound = fe.bind(HashMap::from([(1, 5.0)]));
+    /// assert_eq!(bound.to_string(), "MIN(#0, 5000)");
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, #1, #2)").unwrap();
+    /// let bound = fe.bind(HashMap::from([(1, 0.0)]));
+    /// assert_eq!(bound.to_string(), "COALESCE(#0, 0)");
+    /// ```
+    pub fn bind(&self, values: HashMap<u64, T>) -> FormulaEngine<T> {
+        self.with_expr(self.expr().bind(&values, &self.options, &self.functions))
+    }
+}
+
+impl<T: FormulaValue + Float> Expr<T> {
+    /// Replace the given components by their values, and fold the constant
+    /// parts of the expression.
+    fn bind(
+        &self,
+        values: &HashMap<u64, T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Expr<T> {
+        match self {
+            Expr::Component(id) => match values.get(id) {
+                Some(value) => Expr::Value(Some(*value)),
+                None => self.clone(),
+            },
+            // A constant variable is inlined, so that the body can be folded,
+            // and one the body doesn't use anymore is removed.
+            Expr::Let { name, value, body } => match value.bind(values, options, functions) {
+                constant @ Expr::Value(_) => body
+                    .inline(name, &constant)
+                    .bind(values, options, functions),
+                value => match body.bind(values, options, functions) {
+                    body if !body.uses_variable(name) => body,
+                    body => Expr::Let {
+                        name: name.clone(),
+                        value: Box::new(value),
+                        body: Box::new(body),
+                    },
+                },
+            },
+            expr => expr
+                .try_map_children(|child| {
+                    Ok::<_, Infallible>(child.bind(values, options, functions))
+                })
+                .unwrap_or_else(|never| match never {})
+                .prune_branches()
+                .fold_constant(options, functions),
+        }
+    }
+
+    /// Remove the arguments of COALESCE, IF and CASE that can't affect the
+    /// result, because of a constant argument before them.
+    fn prune_branches(self) -> Expr<T> {
+        let (function, pruned) = match self {
+            Expr::Function {
+                function: Function::Coalesce,
+                args,
+            } => {
+                let mut pruned = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg {
+                        Expr::Value(None) => {}
+                        Expr::Value(Some(_)) => {
+                            pruned.push(arg);
+                            break;
+                        }
+                        arg => pruned.push(arg),
+                    }
+                }
+                (Function::Coalesce, pruned)
+            }
+            Expr::Function {
+                function: function @ (Function::If | Function::Case),
+                args,
+            } => {
+                let mut pruned = Vec::with_capacity(args.len());
+                let mut args = args.into_iter();
+                while let Some(arg) = args.next() {
+                    // The default is the argument without a branch after it.
+                    let Some(branch) = args.next() else {
+                        pruned.push(arg);
+                        break;
+                    };
+                    match arg {
+                        Expr::Value(Some(c)) if c != T::zero() => {
+                            pruned.push(branch);
+                            break;
+                        }
+                        Expr::Value(Some(_)) => {}
+                        // Reaching a `None` condition makes the result `None`.
+                        Expr::Value(None) => break,
+                        condition => pruned.extend([condition, branch]),
+                    }
+                }
+                (function, pruned)
+            }
+            expr => return expr,
+        };
+        match <[_; 1]>::try_from(pruned) {
+            Ok([Expr::Wildcard]) => Expr::Function {
+                function,
+                args: vec![Expr::Wildcard],
+            },
+            Ok([arg]) => arg,
+            Err(args) if args.is_empty() => Expr::Value(None),
+            Err(args) => Expr::Function { function, args },
+        }
+    }
+
+    /// Whether the expression uses the `LET` variable of the given name.
+    fn uses_variable(&self, name: &str) -> bool {
+        match self {
+            Expr::Variable(variable) => variable == name,
+            expr => expr
+                .children()
+                .into_iter()
+                .any(|child| child.uses_variable(name)),
+        }
+    }
+
+    /// Replace the expression by its value if all its operands are values,
+    /// unless its value may change between calculations, like that of a
+    /// custom function.
+    pub(crate) fn fold_constant(
+        self,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Expr<T> {
+        let pure = matches!(
+            self,
+            Expr::UnaryMinus(_)
+                | Expr::Not(_)
+                | Expr::Op { .. }
+                | Expr::Function { .. }
+                | Expr::Let { .. }
+                | Expr::Select { .. }
+        );
+        if !pure
+            || !self
+                .children()
+                .iter()
+                .all(|child| matches!(child, Expr::Value(_)))
+        {
+            return self;
+        }
+        match self.calculate(&HashMap::<u64, Option<T>>::new(), options, functions) {
+            Ok(value) => Expr::Value(value),
+            Err(_) => self,
+        }
+    }
+}
@@ -0,0 +1,165 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Constructors and operators for building expressions in Rust code.
+//!
+//! ```rust
+//! use frequenz_microgrid_formula_engine::{Expr, FormulaEngine, EngineOptions};
+//! use std::collections::HashMap;
+//!
+//! let expr = Expr::max([Expr::component(0), Expr::component(1)]) + Expr::value(3.0);
+//! let fe = FormulaEngine::try_from_expr(expr, EngineOptions::default()).unwrap();
+//! let result = fe.calculate(HashMap::from([(0, Some(1.0)), (1, Some(2.0))]));
+//! assert_eq!(result.unwrap(), Some(5.0));
+//! ```
+
+use std::ops::{Add, Div, Mul, Neg, Not, Rem, Sub};
+
+use crate::expression::{Expr, Function, Op};
+
+impl<T> Expr<T> {
+    /// A constant value.
+    pub fn value(value: T) -> Self {
+        Expr::Value(Some(value))
+    }
+
+    /// A missing value.
+    pub fn none() -> Self {
+        Expr::Value(None)
+    }
+
+    /// The `#id` placeholder.
+    pub fn component(id: u64) -> Self {
+        Expr::Component(id)
+    }
+
+    /// The `$name` placeholder, or the `#"id"` placeholder with an opaque
+    /// component ID.
+    pub fn named(name: impl Into<String>) -> Self {
+        Expr::Named(name.into())
+    }
+
+    /// `COALESCE(args...)`.
+    pub fn coalesce(args: impl IntoIterator<Item = Expr<T>>) -> Self {
+        Expr::call(Function::Coalesce, args)
+    }
+
+    /// `MIN(args...)`.
+    pub fn min(args: impl IntoIterator<Item = Expr<T>>) -> Self {
+        Expr::call(Function::Min, args)
+    }
+
+    /// `MAX(args...)`.
+    pub fn max(args: impl IntoIterator<Item = Expr<T>>) -> Self {
+        Expr::call(Function::Max, args)
+    }
+
+    /// `SUM(args...)`.
+    pub fn sum(args: impl IntoIterator<Item = Expr<T>>) -> Self {
+        Expr::call(Function::Sum, args)
+    }
+
+    /// `PRODUCT(args...)`.
+    pub fn product(args: impl IntoIterator<Item = Expr<T>>) -> Self {
+        Expr::call(Function::Product, args)
+    }
+
+    /// `IF(condition, then, otherwise)`.
+    pub fn if_else(condition: Expr<T>, then: Expr<T>, otherwise: Expr<T>) -> Self {
+        Expr::call(Function::If, [condition, then, otherwise])
+    }
+
+    /// `self ^ exponent`.
+    pub fn pow(self, exponent: Expr<T>) -> Self {
+        Expr::op(self, Op::Pow, exponent)
+    }
+
+    /// `self == rhs`.
+    pub fn equals(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Eq, rhs)
+    }
+
+    /// `self != rhs`.
+    pub fn not_equals(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Ne, rhs)
+    }
+
+    /// `self < rhs`.
+    pub fn lt(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Lt, rhs)
+    }
+
+    /// `self <= rhs`.
+    pub fn le(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Le, rhs)
+    }
+
+    /// `self > rhs`.
+    pub fn gt(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Gt, rhs)
+    }
+
+    /// `self >= rhs`.
+    pub fn ge(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Ge, rhs)
+    }
+
+    /// `self AND rhs`.
+    pub fn and(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::And, rhs)
+    }
+
+    /// `self OR rhs`.
+    pub fn or(self, rhs: Expr<T>) -> Self {
+        Expr::op(self, Op::Or, rhs)
+    }
+
+    pub(crate) fn op(lhs: Expr<T>, op: Op, rhs: Expr<T>) -> Expr<T> {
+        Expr::Op {
+            lhs: Box::new(lhs),
+            op,
+            rhs: Box::new(rhs),
+        }
+    }
+
+    pub(crate) fn call(function: Function, args: impl IntoIterator<Item = Expr<T>>) -> Expr<T> {
+        Expr::Function {
+            function,
+            args: args.into_iter().collect(),
+        }
+    }
+}
+
+macro_rules! impl_binary_op {
+    ($trait:ident, $method:ident, $op:expr) => {
+        impl<T> $trait for Expr<T> {
+            type Output = Expr<T>;
+
+            fn $method(self, rhs: Expr<T>) -> Expr<T> {
+                Expr::op(self, $op, rhs)
+            }
+        }
+    };
+}
+
+impl_binary_op!(Add, add, Op::Add);
+impl_binary_op!(Sub, sub, Op::Sub);
+impl_binary_op!(Mul, mul, Op::Mul);
+impl_binary_op!(Div, div, Op::Div);
+impl_binary_op!(Rem, rem, Op::Mod);
+
+impl<T> Neg for Expr<T> {
+    type Output = Expr<T>;
+
+    fn neg(self) -> Expr<T> {
+        Expr::UnaryMinus(Box::new(self))
+    }
+}
+
+impl<T> Not for Expr<T> {
+    type Output = Expr<T>;
+
+    fn not(self) -> Expr<T> {
+        Expr::Not(Box::new(self))
+    }
+}
@@ -0,0 +1,92 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! A front-end for formulas over component categories, as written for the
+//! Frequenz SDK, e.g. `battery_power + pv_power`.
+
+use std::collections::HashMap;
+
+use pest::Parser;
+
+use crate::{
+    error::FormulaError,
+    expression::{Expr, Function},
+    formula_engine::FormulaEngine,
+    functions::FunctionRegistry,
+    options::EngineOptions,
+    parser::{FormulaParser, Rule},
+    value::FormulaValue,
+};
+
+impl<T: FormulaValue> FormulaEngine<T> {
+    /// Create a new FormulaEngine from a formula over component categories,
+    /// e.g. `battery_power + pv_power - #7`, evaluating it with the given
+    /// options.
+    ///
+    /// Each category is a term of the formula that stands for the sum of the
+    /// values of the components it is mapped to, like `SUM(#1, #2)`, which is
+    /// `None` only if all of them are. A category without components is `0`.
+    /// `LET` variables shadow categories of the same name.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::{EngineOptions, FormulaEngine};
+    /// use std::collections::HashMap;
+    ///
+    /// let categories = HashMap::from([
+    ///     ("battery_power".to_string(), vec![1, 2]),
+    ///     ("pv_power".to_string(), vec![3]),
+    /// ]);
+    /// let fe = FormulaEngine::<f64>::try_new_with_categories(
+    ///     "battery_power + pv_power",
+    ///     &categories,
+    ///     EngineOptions::default(),
+    /// )
+    /// .unwrap();
+    /// assert_eq!(fe.to_string(), "SUM(#1, #2) + #3");
+    /// ```
+    pub fn try_new_with_categories(
+        s: &str,
+        categories: &HashMap<String, Vec<u64>>,
+        options: EngineOptions,
+    ) -> Result<Self, FormulaError> {
+        let pairs = FormulaParser::parse(Rule::formula, s)?;
+        let expr = Expr::parse(pairs, &FunctionRegistry::default())?;
+        Self::try_from_expr(expr.lower_categories(categories, &mut Vec::new())?, options)
+    }
+}
+
+impl<T: FormulaValue> Expr<T> {
+    /// Replace the categories in the expression by the sums of their
+    /// components, with the given `LET` variables in scope.
+    fn lower_categories<'a>(
+        &'a self,
+        categories: &HashMap<String, Vec<u64>>,
+        scope: &mut Vec<&'a str>,
+    ) -> Result<Expr<T>, FormulaError> {
+        match self {
+            Expr::Variable(name) if !scope.contains(&name.as_str()) => {
+                match categories.get(name).map(Vec::as_slice) {
+                    Some([]) => Ok(Expr::Value(Some(T::zero()))),
+                    Some([id]) => Ok(Expr::Component(*id)),
+                    Some(ids) => Ok(Expr::Function {
+                        function: Function::Sum,
+                        args: ids.iter().copied().map(Expr::Component).collect(),
+                    }),
+                    None => Err(FormulaError(format!("Unknown category: {}", name))),
+                }
+            }
+            Expr::Let { name, value, body } => {
+                let value = value.lower_categories(categories, scope)?;
+                scope.push(name);
+                let body = body.lower_categories(categories, scope);
+                scope.pop();
+                Ok(Expr::Let {
+                    name: name.clone(),
+                    value: Box::new(value),
+                    body: Box::new(body?),
+                })
+            }
+            expr => expr.try_map_children(|child| child.lower_categories(categories, scope)),
+        }
+    }
+}
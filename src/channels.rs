@@ -0,0 +1,94 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::future::Future;
+
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::{broadcast, watch};
+
+use num_traits::Float;
+
+use crate::{error::FormulaError, formula_engine::FormulaEngine, value::FormulaValue};
+
+/// The result of a formula, as published to channels.
+type FormulaResult<T> = Result<Option<T>, FormulaError>;
+
+impl<T: FormulaValue + Float + Send + Sync + 'static> FormulaEngine<T> {
+    /// Get a task publishing the result of the formula to a watch channel
+    /// whenever the value of a component changes, to spawn on a runtime.
+    ///
+    /// The task starts with the current values of the receivers, and ends
+    /// when all of them or the sender are closed.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use tokio::sync::watch;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+    /// let (meter, meter_rx) = watch::channel(Some(1.0));
+    /// let (_battery, battery_rx) = watch::channel(Some(2.0));
+    /// let (sender, mut results) = watch::channel(Ok(None));
+    /// let task = fe.publish_watch([(0, meter_rx), (1, battery_rx)], sender);
+    /// # let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    /// # runtime.block_on(async move {
+    /// tokio::spawn(task);
+    /// results.changed().await.unwrap();
+    /// assert_eq!(results.borrow_and_update().as_ref().unwrap(), &Some(3.0));
+    /// meter.send(Some(4.0)).unwrap();
+    /// results.changed().await.unwrap();
+    /// assert_eq!(results.borrow_and_update().as_ref().unwrap(), &Some(6.0));
+    /// # });
+    /// ```
+    pub fn publish_watch(
+        &self,
+        receivers: impl IntoIterator<Item = (u64, watch::Receiver<Option<T>>)>,
+        sender: watch::Sender<FormulaResult<T>>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        self.publish(receivers, move |result| sender.send(result).is_ok())
+    }
+
+    /// Get a task publishing the result of the formula to a broadcast
+    /// channel whenever the value of a component changes, like
+    /// [`FormulaEngine::publish_watch`].
+    pub fn publish_broadcast(
+        &self,
+        receivers: impl IntoIterator<Item = (u64, watch::Receiver<Option<T>>)>,
+        sender: broadcast::Sender<FormulaResult<T>>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        self.publish(receivers, move |result| sender.send(result).is_ok())
+    }
+
+    /// Get a task sending the results, until `send` returns false.
+    fn publish(
+        &self,
+        receivers: impl IntoIterator<Item = (u64, watch::Receiver<Option<T>>)>,
+        mut send: impl FnMut(FormulaResult<T>) -> bool + Send + 'static,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let mut results = self.stream_components(
+            receivers
+                .into_iter()
+                .map(|(id, receiver)| (id, values(receiver)))
+                .collect::<Vec<_>>(),
+        );
+        async move {
+            while let Some(result) = results.next().await {
+                if !send(result) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Get the current value of a receiver and the values it changes to.
+fn values<T: Copy + Send + Sync>(
+    receiver: watch::Receiver<Option<T>>,
+) -> impl Stream<Item = Option<T>> + Send {
+    stream::unfold((receiver, true), |(mut receiver, first)| async move {
+        if !first {
+            receiver.changed().await.ok()?;
+        }
+        let value = *receiver.borrow_and_update();
+        Some((value, (receiver, false)))
+    })
+}
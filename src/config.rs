@@ -0,0 +1,210 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Loading of formula registries from TOML and YAML files.
+//!
+//! A file has a `formulas` table of the formulas by name, each with its
+//! formula and options:
+//!
+//! | Key            | Value                                           | Default |
+//! |----------------|-------------------------------------------------|---------|
+//! | `formula`      | The formula, e.g. `"#0 + #1"`                   |         |
+//! | `none_as_zero` | See [`EngineOptions::with_none_as_zero`]        | `false` |
+//! | `strict`       | See [`EngineOptions::with_strict`]              | `false` |
+//! | `max_age`      | The maximum age of component values in seconds, see [`FormulaRegistry::streaming`] | none |
+//!
+//! For example:
+//!
+//! ```toml
+//! [formulas.grid_power]
+//! formula = "#0 + #1"
+//! none_as_zero = true
+//! max_age = 10
+//!
+//! [formulas.consumption]
+//! formula = "@grid_power - #2"
+//! ```
+//!
+//! Formulas can contain parameters like `${GRID_METER}`, which are replaced
+//! by their values from the environment, or from a map with the `_with_params`
+//! loaders, before the formulas are parsed. Parameters without values are an
+//! error.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    display::is_identifier, error::FormulaError, formula_engine::FormulaEngine,
+    formula_registry::FormulaRegistry, options::EngineOptions, value::FormulaValue,
+};
+
+/// The contents of a formula file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    formulas: BTreeMap<String, FormulaConfig>,
+}
+
+/// A formula of a formula file, with its options.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FormulaConfig {
+    formula: String,
+    #[serde(default)]
+    none_as_zero: bool,
+    #[serde(default)]
+    strict: bool,
+    max_age: Option<f64>,
+}
+
+impl<T: FormulaValue> FormulaRegistry<T> {
+    /// Load formulas from a TOML or YAML file, by its extension `.toml`,
+    /// `.yaml` or `.yml`, as described in the [`config`](crate::config)
+    /// module, with parameters from the environment.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FormulaError> {
+        Self::load_with(path.as_ref(), &env_param)
+    }
+
+    /// Load formulas from a TOML or YAML file, with parameters from a map.
+    pub fn load_with_params(
+        path: impl AsRef<Path>,
+        params: &HashMap<String, String>,
+    ) -> Result<Self, FormulaError> {
+        Self::load_with(path.as_ref(), &|name| params.get(name).cloned())
+    }
+
+    /// Load formulas from a TOML document, with parameters from the
+    /// environment.
+    pub fn from_toml(source: &str) -> Result<Self, FormulaError> {
+        Self::from_toml_with(source, &env_param)
+    }
+
+    /// Load formulas from a TOML document, with parameters from a map.
+    pub fn from_toml_with_params(
+        source: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<Self, FormulaError> {
+        Self::from_toml_with(source, &|name| params.get(name).cloned())
+    }
+
+    /// Load formulas from a YAML document, with parameters from the
+    /// environment.
+    pub fn from_yaml(source: &str) -> Result<Self, FormulaError> {
+        Self::from_yaml_with(source, &env_param)
+    }
+
+    /// Load formulas from a YAML document, with parameters from a map.
+    pub fn from_yaml_with_params(
+        source: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<Self, FormulaError> {
+        Self::from_yaml_with(source, &|name| params.get(name).cloned())
+    }
+
+    fn load_with(
+        path: &Path,
+        param: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, FormulaError> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| FormulaError(format!("Failed to read {}: {}", path.display(), err)))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml_with(&source, param),
+            Some("yaml" | "yml") => Self::from_yaml_with(&source, param),
+            _ => Err(FormulaError(format!(
+                "Unknown formula file format: {}",
+                path.display()
+            ))),
+        }
+    }
+
+    fn from_toml_with(
+        source: &str,
+        param: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, FormulaError> {
+        let config = toml::from_str(source)
+            .map_err(|err| FormulaError(format!("Invalid formula file: {}", err)))?;
+        Self::from_config(config, param)
+    }
+
+    fn from_yaml_with(
+        source: &str,
+        param: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, FormulaError> {
+        let config = serde_yaml::from_str(source)
+            .map_err(|err| FormulaError(format!("Invalid formula file: {}", err)))?;
+        Self::from_config(config, param)
+    }
+
+    /// Register the formulas of a formula file, with the values of their
+    /// parameters.
+    fn from_config(
+        config: Config,
+        param: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, FormulaError> {
+        let mut registry = Self::new();
+        for (name, formula) in config.formulas {
+            let options = EngineOptions::default()
+                .with_none_as_zero(formula.none_as_zero)
+                .with_strict(formula.strict);
+            let engine = substitute(&formula.formula, param)
+                .and_then(|source| FormulaEngine::try_new_with_options(&source, options))
+                .map_err(|err| FormulaError(format!("Invalid formula {}: {}", name, err)))?;
+            registry.register(name.clone(), engine)?;
+            if let Some(max_age) = formula.max_age {
+                let max_age = Duration::try_from_secs_f64(max_age).map_err(|_| {
+                    FormulaError(format!("Invalid max_age of {}: {}", name, max_age))
+                })?;
+                registry.set_max_age(&name, max_age)?;
+            }
+        }
+        Ok(registry)
+    }
+}
+
+/// Get the value of a parameter from the environment.
+fn env_param(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Replace the `${NAME}` parameters of a formula by their values.
+///
+/// The error lists all parameters without values.
+fn substitute(
+    formula: &str,
+    param: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, FormulaError> {
+    let mut substituted = String::with_capacity(formula.len());
+    let mut missing = Vec::new();
+    let mut rest = formula;
+    while let Some(start) = rest.find("${") {
+        substituted.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(FormulaError(format!(
+                "Unterminated parameter: {}",
+                &rest[start..]
+            )));
+        };
+        let name = &rest[start + 2..start + end];
+        if !is_identifier(name) {
+            return Err(FormulaError(format!("Invalid parameter name: {}", name)));
+        }
+        match param(name) {
+            Some(value) => substituted.push_str(&value),
+            None => missing.push(format!("${{{}}}", name)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    if !missing.is_empty() {
+        return Err(FormulaError(format!(
+            "Missing values for parameters {}",
+            missing.join(", ")
+        )));
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
@@ -0,0 +1,347 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::fmt::{Display, Formatter, Result};
+
+use crate::{
+    expression::{Expr, Function, Op},
+    formula_engine::FormulaEngine,
+    syntax::{NOT_BINDING_POWER, UNARY_MINUS_BINDING_POWER},
+    value::{is_infinite, is_nan, FormulaValue},
+};
+
+/// The binding powers of expressions that never need parentheses.
+const ATOM: (u8, u8) = (u8::MAX, u8::MAX);
+
+/// How [`Expr::pretty`] lays out formulas.
+#[derive(Debug, Clone)]
+pub struct PrettyOptions {
+    indent: usize,
+    spaces: bool,
+    max_width: usize,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        Self {
+            indent: 4,
+            spaces: true,
+            max_width: 80,
+        }
+    }
+}
+
+impl PrettyOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of spaces the arguments of a function call that is
+    /// split over several lines are indented by. Defaults to 4.
+    pub fn with_indent(mut self, indent: usize) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    /// Set whether to put spaces around operators and after commas. Defaults
+    /// to `true`.
+    pub fn with_spaces(mut self, spaces: bool) -> Self {
+        self.spaces = spaces;
+        self
+    }
+
+    /// Set the width that lines are kept within where possible, by putting
+    /// the arguments of function calls on lines of their own. Defaults to 80.
+    pub fn with_max_width(mut self, max_width: usize) -> Self {
+        self.max_width = max_width;
+        self
+    }
+}
+
+/// Formulas are rendered on one line, with the fewest parentheses that keep
+/// them parsing to the same expression.
+impl<T: FormulaValue> Display for Expr<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let mut out = String::new();
+        self.layout(&PrettyOptions::default(), None, &mut out);
+        f.write_str(&out)
+    }
+}
+
+impl<T: FormulaValue> Display for FormulaEngine<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.expr().fmt(f)
+    }
+}
+
+impl<T: FormulaValue> FormulaEngine<T> {
+    /// Render the formula like [`Expr::pretty`].
+    pub fn pretty(&self, options: &PrettyOptions) -> String {
+        self.expr().pretty(options)
+    }
+}
+
+impl<T: FormulaValue> Expr<T> {
+    /// Render the formula like [`Display`], but split function calls that
+    /// don't fit the line over several lines, one argument per line.
+    pub fn pretty(&self, options: &PrettyOptions) -> String {
+        let mut out = String::new();
+        self.layout(options, Some(0), &mut out);
+        out
+    }
+
+    /// Append the formula to `out`. If there is a depth of indentation,
+    /// function calls that don't fit the line are split over several lines,
+    /// with their arguments one level deeper.
+    fn layout(&self, options: &PrettyOptions, depth: Option<usize>, out: &mut String) {
+        let Some(depth) = depth else {
+            return self.layout_parts(options, None, out);
+        };
+        let mut flat = String::new();
+        self.layout_parts(options, None, &mut flat);
+        let column = out.chars().rev().take_while(|&c| c != '\n').count();
+        if column + flat.chars().count() <= options.max_width {
+            out.push_str(&flat);
+        } else {
+            self.layout_parts(options, Some(depth), out);
+        }
+    }
+
+    /// Append the parts of the formula, laying out its operands with
+    /// [`Expr::layout`].
+    fn layout_parts(&self, options: &PrettyOptions, depth: Option<usize>, out: &mut String) {
+        let operator = |symbol: &str| {
+            if options.spaces {
+                format!(" {} ", symbol)
+            } else {
+                symbol.to_string()
+            }
+        };
+        match self {
+            Expr::Value(value) => out.push_str(&literal(*value)),
+            Expr::UnaryMinus(operand) => {
+                out.push('-');
+                operand.layout_operand(options, depth, UNARY_MINUS_BINDING_POWER, out);
+            }
+            Expr::Not(operand) => {
+                out.push_str("NOT ");
+                operand.layout_operand(options, depth, NOT_BINDING_POWER, out);
+            }
+            Expr::Op { lhs, op, rhs } => {
+                let (left_power, right_power) = self.binding_powers();
+                let parenthesize = lhs.binding_powers().1 <= left_power;
+                lhs.layout_parenthesized(options, depth, parenthesize, out);
+                // Word operators always need spaces to be told apart from
+                // their operands.
+                match op {
+                    Op::And | Op::Or => out.push_str(&format!(" {} ", op.symbol())),
+                    _ => out.push_str(&operator(op.symbol())),
+                }
+                rhs.layout_operand(options, depth, right_power, out);
+            }
+            // The precedence of custom operators isn't known without the
+            // registry, so all their compound operands are parenthesized.
+            // They always need spaces, so that they don't run into unary
+            // minus.
+            Expr::CustomOp { symbol, lhs, rhs } => {
+                lhs.layout_parenthesized(options, depth, lhs.binding_powers() != ATOM, out);
+                out.push_str(&format!(" {} ", symbol));
+                rhs.layout_parenthesized(options, depth, rhs.binding_powers() != ATOM, out);
+            }
+            Expr::Function { function, args } => {
+                layout_call(options, depth, function.name(), args, out)
+            }
+            Expr::Custom { name, args } => layout_call(options, depth, name, args, out),
+            Expr::Temporal { function, args } => {
+                layout_call(options, depth, function.name(), args, out)
+            }
+            Expr::Component(id) => out.push_str(&format!("#{}", id)),
+            Expr::Wildcard => out.push_str("#*"),
+            Expr::Named(name) => out.push_str(&named_placeholder(name)),
+            Expr::Let { name, value, body } => {
+                out.push_str(&format!("LET {}{}", name, operator("=")));
+                value.layout(options, depth, out);
+                out.push_str(" IN ");
+                body.layout(options, depth, out);
+            }
+            Expr::Variable(name) => out.push_str(name),
+            Expr::Reference(name) => out.push_str(&format!("@{}", name)),
+            Expr::TimeOfUse(name) => out.push_str(&format!("TOU({})", string_literal(name))),
+            Expr::Time(function) => out.push_str(&format!("{}()", function.name())),
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => select_case(function, args, branches).layout_parts(options, depth, out),
+        }
+    }
+
+    /// Get how tightly the expression binds at its left and right edges,
+    /// like the binding powers of operators when parsing.
+    fn binding_powers(&self) -> (u8, u8) {
+        match self {
+            Expr::Op { op, .. } => {
+                let (precedence, associativity) = op.precedence();
+                precedence.binding_powers(associativity)
+            }
+            Expr::Value(Some(value)) if literal(Some(*value)).starts_with('-') => {
+                (u8::MAX, UNARY_MINUS_BINDING_POWER)
+            }
+            Expr::UnaryMinus(_) => (u8::MAX, UNARY_MINUS_BINDING_POWER),
+            Expr::Not(_) => (u8::MAX, NOT_BINDING_POWER),
+            // A LET body extends as far as possible to the right.
+            Expr::Let { .. } => (u8::MAX, 0),
+            Expr::CustomOp { .. } => (0, 0),
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => select_case(function, args, branches).binding_powers(),
+            _ => ATOM,
+        }
+    }
+
+    /// Append the expression as an operand parsed with the given minimum
+    /// binding power, i.e. the right operand of an operator.
+    fn layout_operand(
+        &self,
+        options: &PrettyOptions,
+        depth: Option<usize>,
+        min_power: u8,
+        out: &mut String,
+    ) {
+        let (left, right) = self.binding_powers();
+        let parenthesize = left < min_power || right < min_power;
+        self.layout_parenthesized(options, depth, parenthesize, out);
+    }
+
+    fn layout_parenthesized(
+        &self,
+        options: &PrettyOptions,
+        depth: Option<usize>,
+        parenthesize: bool,
+        out: &mut String,
+    ) {
+        if parenthesize {
+            out.push('(');
+            self.layout(options, depth, out);
+            out.push(')');
+        } else {
+            self.layout(options, depth, out);
+        }
+    }
+}
+
+/// Get the literal of a value, using equivalent expressions for those
+/// without one.
+fn literal<T: FormulaValue>(value: Option<T>) -> String {
+    match value {
+        None => "NULLIF(0, 0)".to_string(),
+        Some(value) if is_nan(value) => "(0 / 0)".to_string(),
+        Some(value) if is_infinite(value) && value < T::zero() => "(-1 / 0)".to_string(),
+        Some(value) if is_infinite(value) => "(1 / 0)".to_string(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Append a function call, with one argument per line if `depth` is given.
+fn layout_call<T: FormulaValue>(
+    options: &PrettyOptions,
+    depth: Option<usize>,
+    name: &str,
+    args: &[Expr<T>],
+    out: &mut String,
+) {
+    out.push_str(name);
+    out.push('(');
+    match depth {
+        Some(depth) if !args.is_empty() => {
+            let indent = |depth| " ".repeat(depth * options.indent);
+            for (i, arg) in args.iter().enumerate() {
+                out.push_str(if i > 0 { ",\n" } else { "\n" });
+                out.push_str(&indent(depth + 1));
+                arg.layout(options, Some(depth + 1), out);
+            }
+            out.push('\n');
+            out.push_str(&indent(depth));
+        }
+        _ => {
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(if options.spaces { ", " } else { "," });
+                }
+                arg.layout(options, depth, out);
+            }
+        }
+    }
+    out.push(')');
+}
+
+/// Write a named placeholder as `$name`, or as `#"id"` if it isn't an
+/// identifier.
+pub(crate) fn named_placeholder(name: &str) -> String {
+    if is_identifier(name) {
+        format!("${}", name)
+    } else {
+        format!("#{}", string_literal(name))
+    }
+}
+
+/// Write a string literal, escaping quotes and backslashes.
+fn string_literal(string: &str) -> String {
+    format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Whether a name can be written as a `$name` placeholder.
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Build the `CASE` equivalent to a [`Expr::Select`], which has no syntax of
+/// its own.
+pub(crate) fn select_case<T: FormulaValue>(
+    function: &Function,
+    args: &[Expr<T>],
+    branches: &[Expr<T>],
+) -> Expr<T> {
+    let none = Expr::none();
+    let is_some = |arg: &Expr<T>| Expr::call(Function::IsSome, [arg.clone()]);
+    let mut case = Vec::new();
+    match function {
+        Function::Coalesce => {
+            for (arg, branch) in args.iter().zip(branches) {
+                case.extend([is_some(arg), branch.clone()]);
+            }
+            case.push(none);
+        }
+        Function::If | Function::Case => {
+            for (i, arg) in args.iter().enumerate() {
+                let is_condition = i % 2 == 0 && i + 1 < args.len();
+                case.push(if is_condition { arg } else { &branches[i] }.clone());
+            }
+        }
+        // The last of several equal extremes is selected.
+        Function::Min | Function::Max | Function::MinStrict | Function::MaxStrict => {
+            let extreme = Expr::call(function.clone(), args.to_vec());
+            for (arg, branch) in args.iter().zip(branches).rev() {
+                let selected = Expr::op(arg.clone(), Op::Eq, extreme.clone());
+                case.extend([Expr::op(is_some(arg), Op::And, selected), branch.clone()]);
+            }
+            case.push(none);
+        }
+        Function::NullIf => {
+            case.extend([
+                is_some(&Expr::call(Function::NullIf, args.to_vec())),
+                branches[0].clone(),
+                none,
+            ]);
+        }
+        _ => return none,
+    }
+    Expr::call(Function::Case, case)
+}
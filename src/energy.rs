@@ -0,0 +1,452 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    formula_engine::FormulaEngine,
+    options::EngineOptions,
+    streaming::StreamingFormulaEngine,
+    temporal::{trapezoid, StateSnapshot},
+    value::{FormulaValue, Sample},
+};
+
+const SECONDS_PER_HOUR: i64 = 60 * 60;
+const SECONDS_PER_DAY: i64 = 24 * SECONDS_PER_HOUR;
+
+/// A callback for the intervals completed by an [`EnergyAccumulator`].
+pub type IntervalCallback<T> = dyn FnMut(&EnergyInterval<T>) + Send;
+
+/// When an [`EnergyAccumulator`] starts a new interval.
+///
+/// Boundaries are in UTC, or in the local time of the engine's timezone
+/// with the `chrono-tz` feature, see
+/// [`EngineOptions::with_timezone`](crate::EngineOptions).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetBoundary {
+    /// At the start of every hour.
+    Hourly,
+    /// At midnight.
+    Daily,
+    /// At midnight on the given day of every month, from 1 to 31, or on the
+    /// last day of months without that day.
+    BillingPeriod(u32),
+}
+
+impl ResetBoundary {
+    /// Get the start of the interval `time` is in.
+    fn start(self, time: SystemTime, options: &EngineOptions) -> SystemTime {
+        let local = local_seconds(time, options);
+        match self {
+            // Going back from `time` keeps hours apart when the clock is
+            // turned back.
+            ResetBoundary::Hourly => {
+                let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let into_hour = local.rem_euclid(SECONDS_PER_HOUR) as u64;
+                UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs().saturating_sub(into_hour))
+            }
+            ResetBoundary::Daily => {
+                from_local_seconds(local.div_euclid(SECONDS_PER_DAY) * SECONDS_PER_DAY, options)
+            }
+            ResetBoundary::BillingPeriod(day) => {
+                let (year, month, today) = civil_from_days(local.div_euclid(SECONDS_PER_DAY));
+                let (year, month) = if today >= billing_day(year, month, day) {
+                    (year, month)
+                } else {
+                    previous_month(year, month)
+                };
+                let days = days_from_civil(year, month, billing_day(year, month, day));
+                from_local_seconds(days * SECONDS_PER_DAY, options)
+            }
+        }
+    }
+
+    /// Get the end of the interval starting at `start`.
+    fn end(self, start: SystemTime, options: &EngineOptions) -> SystemTime {
+        let days = local_seconds(start, options).div_euclid(SECONDS_PER_DAY);
+        match self {
+            ResetBoundary::Hourly => start + Duration::from_secs(SECONDS_PER_HOUR as u64),
+            ResetBoundary::Daily => from_local_seconds((days + 1) * SECONDS_PER_DAY, options),
+            ResetBoundary::BillingPeriod(day) => {
+                let (year, month, _) = civil_from_days(days);
+                let (year, month) = next_month(year, month);
+                let days = days_from_civil(year, month, billing_day(year, month, day));
+                from_local_seconds(days * SECONDS_PER_DAY, options)
+            }
+        }
+    }
+}
+
+/// The energy of a completed interval of an [`EnergyAccumulator`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnergyInterval<T> {
+    /// When the interval started.
+    pub start: SystemTime,
+    /// When the interval ended, and the next one started.
+    pub end: SystemTime,
+    /// The integral of the results of the formula over the interval, in
+    /// units of the results times hours, e.g. Wh for a formula in W.
+    pub energy: T,
+}
+
+/// The state of an [`EnergyAccumulator`], from
+/// [`EnergyAccumulator::state_snapshot`], e.g. to continue the current
+/// interval after restarting the process.
+///
+/// With the `serde` feature, snapshots can be serialized, e.g. to a file.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccumulatorSnapshot<T> {
+    /// The start of the current interval.
+    pub start: Option<SystemTime>,
+    /// The energy of the current interval up to the first sample.
+    pub energy: T,
+    /// The last two results with values, oldest first.
+    pub samples: Vec<(SystemTime, T)>,
+    /// The state of the temporal functions of the formula.
+    pub state: StateSnapshot<T>,
+}
+
+/// A formula, e.g. of the power of a meter, integrated over time into the
+/// energies of intervals between reset boundaries.
+///
+/// The results of the formula are integrated like with `INTEGRATE`, along
+/// the line between the results with values. Results that are `None` or
+/// errors, e.g. before all components have values, are bridged by the
+/// line. The first interval starts at the boundary before the first result
+/// with a value, and intervals end at the line's value at their boundary.
+///
+/// Results at the same time replace each other, so that updating several
+/// components at once takes a single sample. An interval is therefore only
+/// completed once a result after its end is followed by a later one.
+/// Callbacks registered with [`on_interval`](Self::on_interval), e.g. to
+/// persist the energies, are called with every completed interval.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::{FormulaEngine, ResetBoundary};
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+/// let mut accumulator = fe.energy_accumulator(ResetBoundary::Hourly).unwrap();
+/// let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+///
+/// accumulator.update_at(0, Some(500.0), at(0));
+/// accumulator.update_at(1, Some(500.0), at(0));
+/// accumulator.update_at(1, Some(1500.0), at(1800));
+/// assert_eq!(accumulator.current().unwrap().energy, 750.0);
+///
+/// assert!(accumulator.update_at(0, Some(2500.0), at(5400)).is_empty());
+/// let intervals = accumulator.update_at(0, Some(2500.0), at(7200));
+/// assert_eq!(intervals.len(), 1);
+/// assert_eq!((intervals[0].start, intervals[0].end), (at(0), at(3600)));
+/// assert_eq!(intervals[0].energy, 2000.0);
+/// ```
+pub struct EnergyAccumulator<T> {
+    streaming: StreamingFormulaEngine<T>,
+    boundary: ResetBoundary,
+    /// The start and end of the current interval.
+    interval: Option<(SystemTime, SystemTime)>,
+    /// The energy of the current interval up to `from`.
+    energy: T,
+    /// The result the energy is integrated up to.
+    from: Option<(SystemTime, T)>,
+    /// The last result with a value, replaced by results at the same time.
+    last: Option<(SystemTime, T)>,
+    callbacks: Vec<Box<IntervalCallback<T>>>,
+}
+
+impl<T: Debug> Debug for EnergyAccumulator<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnergyAccumulator")
+            .field("streaming", &self.streaming)
+            .field("boundary", &self.boundary)
+            .field("interval", &self.interval)
+            .field("energy", &self.energy)
+            .field("from", &self.from)
+            .field("last", &self.last)
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Create an [`EnergyAccumulator`] integrating the results of the
+    /// formula into intervals between `boundary`s.
+    pub fn energy_accumulator(
+        &self,
+        boundary: ResetBoundary,
+    ) -> Result<EnergyAccumulator<T>, FormulaError> {
+        if let ResetBoundary::BillingPeriod(day) = boundary {
+            if !(1..=31).contains(&day) {
+                return Err(FormulaError(format!(
+                    "The billing period must start on a day from 1 to 31, not {}",
+                    day
+                )));
+            }
+        }
+        Ok(EnergyAccumulator {
+            streaming: self.streaming(),
+            boundary,
+            interval: None,
+            energy: T::zero(),
+            from: None,
+            last: None,
+            callbacks: Vec::new(),
+        })
+    }
+}
+
+impl<T: FormulaValue + Float> EnergyAccumulator<T> {
+    /// Get the engine of the formula.
+    pub fn engine(&self) -> &FormulaEngine<T> {
+        self.streaming.engine()
+    }
+
+    /// Get the current interval, with its energy up to the last result with
+    /// a value, or `None` before the first one.
+    pub fn current(&self) -> Option<EnergyInterval<T>> {
+        let (start, end) = self.interval?;
+        let pending = match (self.from, self.last) {
+            (Some(from), Some(last)) if last.0 < end => trapezoid(from, last),
+            (Some(from), Some(last)) => trapezoid(from, (end, interpolate(from, last, end)?)),
+            _ => None,
+        };
+        Some(EnergyInterval {
+            start,
+            end,
+            energy: pending.map_or(self.energy, |area| self.energy + area),
+        })
+    }
+
+    /// Register a callback to call with every completed interval, e.g. to
+    /// persist its energy.
+    pub fn on_interval(&mut self, callback: impl FnMut(&EnergyInterval<T>) + Send + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Set the value of a component received at `timestamp`, and get the
+    /// intervals this completes, oldest first.
+    ///
+    /// Results from before the last result with a value are ignored.
+    pub fn update_at(
+        &mut self,
+        id: u64,
+        value: Option<T>,
+        timestamp: SystemTime,
+    ) -> Vec<EnergyInterval<T>> {
+        match self.streaming.update_at(id, value, timestamp) {
+            Ok(Some(value)) => self.record(timestamp, value),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Set the value of a component from a sample, and get the intervals
+    /// this completes, like [`update_at`](Self::update_at) at the time the
+    /// value was measured.
+    pub fn update_sample(&mut self, id: u64, sample: Sample<T>) -> Vec<EnergyInterval<T>> {
+        self.update_at(id, sample.value, sample.timestamp)
+    }
+
+    /// Get a snapshot of the current interval and the formula's state, to
+    /// continue with by [`restore`](Self::restore).
+    pub fn state_snapshot(&self) -> AccumulatorSnapshot<T> {
+        AccumulatorSnapshot {
+            start: self.interval.map(|(start, _)| start),
+            energy: self.energy,
+            samples: self.from.into_iter().chain(self.last).collect(),
+            state: self.streaming.state_snapshot(),
+        }
+    }
+
+    /// Continue with the interval and the formula's state of a snapshot,
+    /// e.g. one taken before restarting the process.
+    pub fn restore(&mut self, snapshot: &AccumulatorSnapshot<T>) -> Result<(), FormulaError> {
+        let (from, last) = match snapshot.samples.as_slice() {
+            [] => (None, None),
+            [last] => (None, Some(*last)),
+            [from, last] if from.0 < last.0 => (Some(*from), Some(*last)),
+            _ => {
+                return Err(FormulaError(
+                    "Energy accumulator snapshots have up to two samples, oldest first".to_string(),
+                ))
+            }
+        };
+        self.streaming.restore(&snapshot.state)?;
+        let options = &self.streaming.engine().options;
+        self.interval = snapshot
+            .start
+            .map(|start| (start, self.boundary.end(start, options)));
+        self.energy = snapshot.energy;
+        self.from = from;
+        self.last = last;
+        Ok(())
+    }
+
+    /// Add a result with a value, and get the intervals this completes.
+    fn record(&mut self, timestamp: SystemTime, value: T) -> Vec<EnergyInterval<T>> {
+        let mut completed = Vec::new();
+        match self.last {
+            Some((time, _)) if timestamp < time => return completed,
+            Some((time, _)) if timestamp == time => {}
+            Some(last) => {
+                // The last result is followed, so the line up to it is final.
+                if let Some(from) = self.from {
+                    self.integrate(from, last, &mut completed);
+                }
+                self.from = Some(last);
+            }
+            // A restored interval without results continues if it hasn't
+            // ended yet.
+            None if self.interval.is_some_and(|(_, end)| timestamp < end) => {}
+            None => {
+                let options = &self.streaming.engine().options;
+                let start = self.boundary.start(timestamp, options);
+                self.interval = Some((start, self.boundary.end(start, options)));
+            }
+        }
+        self.last = Some((timestamp, value));
+        completed
+    }
+
+    /// Add the energy from one result to a later one, completing the
+    /// intervals ending before the later one.
+    fn integrate(
+        &mut self,
+        mut from: (SystemTime, T),
+        to: (SystemTime, T),
+        completed: &mut Vec<EnergyInterval<T>>,
+    ) {
+        let Some((mut start, mut end)) = self.interval else {
+            return;
+        };
+        while to.0 >= end {
+            let Some(value) = interpolate(from, to, end) else {
+                break;
+            };
+            if let Some(area) = trapezoid(from, (end, value)) {
+                self.energy = self.energy + area;
+            }
+            let interval = EnergyInterval {
+                start,
+                end,
+                energy: self.energy,
+            };
+            for callback in &mut self.callbacks {
+                callback(&interval);
+            }
+            completed.push(interval);
+            (start, end) = (
+                end,
+                self.boundary.end(end, &self.streaming.engine().options),
+            );
+            self.energy = T::zero();
+            from = (start, value);
+        }
+        self.interval = Some((start, end));
+        if let Some(area) = trapezoid(from, to) {
+            self.energy = self.energy + area;
+        }
+    }
+}
+
+/// Get the value at `time` on the line from one result to a later one.
+fn interpolate<T: FormulaValue + Float>(
+    (start, from): (SystemTime, T),
+    (end, to): (SystemTime, T),
+    time: SystemTime,
+) -> Option<T> {
+    let elapsed = time.duration_since(start).ok()?.as_secs_f64();
+    let total = end.duration_since(start).ok()?.as_secs_f64();
+    Some(from + (to - from) * T::from_f64(elapsed / total)?)
+}
+
+/// Get the seconds since the Unix epoch of the wall clock at `time`.
+fn local_seconds(time: SystemTime, options: &EngineOptions) -> i64 {
+    #[cfg(feature = "chrono-tz")]
+    if let Some(timezone) = options.timezone() {
+        let time = chrono::DateTime::<chrono::Utc>::from(time).with_timezone(timezone);
+        return time.naive_local().and_utc().timestamp();
+    }
+    #[cfg(not(feature = "chrono-tz"))]
+    let _ = options;
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Get the first time the wall clock shows `seconds` since the Unix epoch,
+/// or an hour later if the clock skips it.
+fn from_local_seconds(seconds: i64, options: &EngineOptions) -> SystemTime {
+    #[cfg(feature = "chrono-tz")]
+    if let Some(timezone) = options.timezone() {
+        use chrono::TimeZone;
+
+        let local = chrono::DateTime::from_timestamp(seconds, 0).map(|time| time.naive_utc());
+        let time = local.and_then(|local| {
+            timezone.from_local_datetime(&local).earliest().or_else(|| {
+                let later = local + chrono::TimeDelta::hours(1);
+                timezone.from_local_datetime(&later).earliest()
+            })
+        });
+        if let Some(time) = time {
+            return time.into();
+        }
+    }
+    #[cfg(not(feature = "chrono-tz"))]
+    let _ = options;
+    UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+/// Get the day a billing period starting on `day` starts on in a month.
+fn billing_day(year: i64, month: u32, day: u32) -> u32 {
+    let (next_year, next_month) = next_month(year, month);
+    let days_in_month = days_from_civil(next_year, next_month, 1) - days_from_civil(year, month, 1);
+    day.min(days_in_month as u32)
+}
+
+fn previous_month(year: i64, month: u32) -> (i64, u32) {
+    match month {
+        1 => (year - 1, 12),
+        _ => (year, month - 1),
+    }
+}
+
+fn next_month(year: i64, month: u32) -> (i64, u32) {
+    match month {
+        12 => (year + 1, 1),
+        _ => (year, month + 1),
+    }
+}
+
+/// Get the days since 1970-01-01 of a date in the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = (month + 9) % 12;
+    let day_of_year = (153 * month as i64 + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Get the year, month and day of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
@@ -2,177 +2,1626 @@
 // Copyright © 2024 Frequenz Energy-as-a-Service GmbH
 
 use crate::{
+    display::named_placeholder,
     error::FormulaError,
-    parser::{Rule, PRATT_PARSER},
+    functions::FunctionRegistry,
+    options::{DivisionByZero, EngineOptions},
+    parser::Rule,
+    syntax::{
+        check_arity, unescape, Associativity, Precedence, MAX_RANGE_LEN, NOT_BINDING_POWER,
+        UNARY_MINUS_BINDING_POWER,
+    },
+    value::{FormulaValue, Sample},
 };
-use pest::iterators::Pairs;
-use std::ops::{Add, Div, Mul, Sub};
+use num_traits::{Float, FromPrimitive};
+use pest::iterators::{Pair, Pairs};
 use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
+    hash::{Hash, Hasher},
 };
-use std::{ops::Neg, str::FromStr};
+use std::{convert::Infallible, iter::Peekable, ops::Neg, str::FromStr, time::UNIX_EPOCH};
+
+/// The values of the placeholders a formula is evaluated with.
+pub trait Inputs<T> {
+    /// Get the value of the `#id` placeholder, or `None` if it isn't given.
+    fn component(&self, id: u64) -> Option<Option<T>>;
+
+    /// Get the value of the `$name` placeholder, or `None` if it isn't given.
+    fn named(&self, name: &str) -> Option<Option<T>>;
+
+    /// Get all given values, ordered by their placeholders, or `None` if they
+    /// can't be listed.
+    fn all(&self) -> Option<Vec<Option<T>>>;
+
+    /// Get the value of a `LET` variable, or `None` if it isn't bound.
+    fn variable(&self, _name: &str) -> Option<Option<T>> {
+        None
+    }
+
+    /// Get the result of the referenced formula, or `None` if it isn't known.
+    fn reference(&self, _name: &str) -> Option<Option<T>> {
+        None
+    }
+}
+
+impl<T, I: Inputs<T> + ?Sized> Inputs<T> for &I {
+    fn component(&self, id: u64) -> Option<Option<T>> {
+        (**self).component(id)
+    }
+
+    fn named(&self, name: &str) -> Option<Option<T>> {
+        (**self).named(name)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        (**self).all()
+    }
+
+    fn variable(&self, name: &str) -> Option<Option<T>> {
+        (**self).variable(name)
+    }
+
+    fn reference(&self, name: &str) -> Option<Option<T>> {
+        (**self).reference(name)
+    }
+}
+
+/// Inputs with the value of a `LET` variable bound in addition.
+pub(crate) struct Bound<'a, T> {
+    pub(crate) inputs: &'a dyn Inputs<T>,
+    pub(crate) name: &'a str,
+    pub(crate) value: Option<T>,
+}
+
+impl<T: Copy> Inputs<T> for Bound<'_, T> {
+    fn component(&self, id: u64) -> Option<Option<T>> {
+        self.inputs.component(id)
+    }
+
+    fn named(&self, name: &str) -> Option<Option<T>> {
+        self.inputs.named(name)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        self.inputs.all()
+    }
+
+    fn variable(&self, name: &str) -> Option<Option<T>> {
+        if name == self.name {
+            Some(self.value)
+        } else {
+            self.inputs.variable(name)
+        }
+    }
+
+    fn reference(&self, name: &str) -> Option<Option<T>> {
+        self.inputs.reference(name)
+    }
+}
+
+/// A source of component values to calculate formulas with, e.g. a cache
+/// of the latest telemetry, so that they don't need to be copied into a
+/// `HashMap` first.
+///
+/// It is implemented for `HashMap`s from component IDs to values, owned or
+/// borrowed, for slices of values indexed by component ID, and for closures.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::FormulaEngine;
+///
+/// let fe = FormulaEngine::<f64>::try_new("#1 + #2").unwrap();
+/// let result = fe.calculate(|id: u64| Some(Some(id as f64 * 10.0)));
+/// assert_eq!(result.unwrap(), Some(30.0));
+/// ```
+pub trait ValueProvider<T> {
+    /// Get the value of the component with the given ID, or `None` if it
+    /// isn't known.
+    fn get(&self, id: u64) -> Option<Option<T>>;
+
+    /// Get the values of all known components, ordered by ID, for `#*`, or
+    /// `None` if they can't be listed.
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        None
+    }
+}
+
+impl<T: Copy> ValueProvider<T> for HashMap<u64, Option<T>> {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        HashMap::get(self, &id).copied()
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        let mut values: Vec<_> = self.iter().collect();
+        values.sort_by_key(|(id, _)| **id);
+        Some(values.into_iter().map(|(_, value)| *value).collect())
+    }
+}
+
+impl<T: Copy> ValueProvider<T> for &HashMap<u64, Option<T>> {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        ValueProvider::get(*self, id)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        ValueProvider::all(*self)
+    }
+}
+
+impl<T: Copy> ValueProvider<T> for HashMap<u64, Sample<T>> {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        HashMap::get(self, &id).map(|sample| sample.value)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        let mut samples: Vec<_> = self.iter().collect();
+        samples.sort_by_key(|(id, _)| **id);
+        Some(
+            samples
+                .into_iter()
+                .map(|(_, sample)| sample.value)
+                .collect(),
+        )
+    }
+}
+
+impl<T: Copy> ValueProvider<T> for &HashMap<u64, Sample<T>> {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        ValueProvider::get(*self, id)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        ValueProvider::all(*self)
+    }
+}
+
+impl<T: Copy> ValueProvider<T> for &[Option<T>] {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        usize::try_from(id)
+            .ok()
+            .and_then(|id| <[Option<T>]>::get(self, id))
+            .copied()
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        Some(self.to_vec())
+    }
+}
+
+impl<T, F: Fn(u64) -> Option<Option<T>>> ValueProvider<T> for F {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        self(id)
+    }
+}
+
+/// Component values sorted by ID, without duplicates.
+pub(crate) struct SortedValues<T>(Vec<(u64, Option<T>)>);
+
+impl<T> FromIterator<(u64, Option<T>)> for SortedValues<T> {
+    /// Collect the values, keeping the last value of each component, as
+    /// collecting into a `HashMap` does.
+    fn from_iter<I: IntoIterator<Item = (u64, Option<T>)>>(iter: I) -> Self {
+        let mut values: Vec<_> = iter.into_iter().collect();
+        values.reverse();
+        values.sort_by_key(|(id, _)| *id);
+        values.dedup_by_key(|(id, _)| *id);
+        Self(values)
+    }
+}
+
+impl<T: Copy> ValueProvider<T> for SortedValues<T> {
+    fn get(&self, id: u64) -> Option<Option<T>> {
+        self.0
+            .binary_search_by_key(&id, |(id, _)| *id)
+            .ok()
+            .map(|i| self.0[i].1)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        Some(self.0.iter().map(|(_, value)| *value).collect())
+    }
+}
+
+/// The values of a [`ValueProvider`] as inputs.
+pub(crate) struct Provided<P>(pub(crate) P);
+
+impl<T: Copy, P: ValueProvider<T>> Inputs<T> for Provided<P> {
+    fn component(&self, id: u64) -> Option<Option<T>> {
+        self.0.get(id)
+    }
+
+    fn named(&self, _name: &str) -> Option<Option<T>> {
+        None
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        self.0.all()
+    }
+}
+
+impl<T: Copy> Inputs<T> for HashMap<u64, Option<T>> {
+    fn component(&self, id: u64) -> Option<Option<T>> {
+        self.get(&id).copied()
+    }
+
+    fn named(&self, _name: &str) -> Option<Option<T>> {
+        None
+    }
 
-#[derive(Debug)]
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        ValueProvider::all(self)
+    }
+}
+
+impl<T: Copy> Inputs<T> for HashMap<String, Option<T>> {
+    fn component(&self, _id: u64) -> Option<Option<T>> {
+        None
+    }
+
+    fn named(&self, name: &str) -> Option<Option<T>> {
+        self.get(name).copied()
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        let mut values: Vec<_> = self.iter().collect();
+        values.sort_by_key(|(name, _)| *name);
+        Some(values.into_iter().map(|(_, value)| *value).collect())
+    }
+}
+
+/// A parsed formula, as a tree of operators and function calls over
+/// placeholders and values.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Expr<T> {
+    /// A number or constant, or `None` for a missing value.
     Value(Option<T>),
     UnaryMinus(Box<Expr<T>>),
+    /// `1` if the operand is zero, else `0`.
+    Not(Box<Expr<T>>),
+    /// A built-in infix operator.
     Op {
         lhs: Box<Expr<T>>,
         op: Op,
         rhs: Box<Expr<T>>,
     },
+    /// A call of a built-in function.
     Function {
         function: Function,
         args: Vec<Expr<T>>,
     },
-    Component(usize),
+    /// An operator from the [`FunctionRegistry`].
+    CustomOp {
+        symbol: String,
+        lhs: Box<Expr<T>>,
+        rhs: Box<Expr<T>>,
+    },
+    /// A call of a function from the [`FunctionRegistry`].
+    Custom {
+        name: String,
+        args: Vec<Expr<T>>,
+    },
+    /// A call of a function of the samples of its first argument over time.
+    Temporal {
+        function: TemporalFunction,
+        args: Vec<Expr<T>>,
+    },
+    /// The `#id` placeholder.
+    Component(u64),
+    /// The `#*` placeholder in a variadic function, standing for all given
+    /// values.
+    Wildcard,
+    /// A named placeholder, e.g. `$grid_meter`, or one with an opaque
+    /// component ID, e.g. `#"mtr-7f3a"`.
+    Named(String),
+    /// `LET name = value IN body`, evaluating `value` once for all uses of
+    /// `name` in `body`.
+    Let {
+        name: String,
+        value: Box<Expr<T>>,
+        body: Box<Expr<T>>,
+    },
+    /// A variable bound by an enclosing `LET`.
+    Variable(String),
+    /// A reference to the result of another formula of a
+    /// [`FormulaRegistry`](crate::FormulaRegistry), e.g. `@grid_power`.
+    Reference(String),
+    /// `1` if the current time is in the named time-of-use window, else `0`.
+    TimeOfUse(String),
+    /// A function of the current time, e.g. `NOW()`.
+    Time(TimeFunction),
+    /// The branch at the index of the argument that `function` selects from
+    /// `args`, used for the piecewise derivatives of MIN, MAX and COALESCE.
+    Select {
+        function: Function,
+        args: Vec<Expr<T>>,
+        branches: Vec<Expr<T>>,
+    },
 }
 
-impl<T: FromStr + Debug> TryFrom<Pairs<'_, Rule>> for Expr<T>
-where
-    <T as FromStr>::Err: Debug,
-{
+/// Expressions are equal if they have the same structure. Values are equal
+/// if they are equal numbers or both NaN, so that every expression is equal
+/// to itself.
+impl<T: FormulaValue> PartialEq for Expr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let same_node = match (self, other) {
+            // NaN is the only value that isn't ordered with itself.
+            (Expr::Value(Some(a)), Expr::Value(Some(b))) => {
+                a == b || (a.partial_cmp(a).is_none() && b.partial_cmp(b).is_none())
+            }
+            (Expr::Value(a), Expr::Value(b)) => a.is_none() && b.is_none(),
+            (Expr::Op { op: a, .. }, Expr::Op { op: b, .. }) => a == b,
+            (Expr::Function { function: a, .. }, Expr::Function { function: b, .. }) => a == b,
+            (Expr::CustomOp { symbol: a, .. }, Expr::CustomOp { symbol: b, .. })
+            | (Expr::Custom { name: a, .. }, Expr::Custom { name: b, .. })
+            | (Expr::Named(a), Expr::Named(b))
+            | (Expr::Let { name: a, .. }, Expr::Let { name: b, .. })
+            | (Expr::Variable(a), Expr::Variable(b))
+            | (Expr::Reference(a), Expr::Reference(b))
+            | (Expr::TimeOfUse(a), Expr::TimeOfUse(b)) => a == b,
+            (Expr::Component(a), Expr::Component(b)) => a == b,
+            (Expr::Time(a), Expr::Time(b)) => a == b,
+            (Expr::Temporal { function: a, .. }, Expr::Temporal { function: b, .. }) => a == b,
+            (
+                Expr::Select {
+                    function: a,
+                    args: a_args,
+                    ..
+                },
+                Expr::Select {
+                    function: b,
+                    args: b_args,
+                    ..
+                },
+            ) => a == b && a_args.len() == b_args.len(),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        };
+        let (children, other_children) = (self.children(), other.children());
+        same_node
+            && children.len() == other_children.len()
+            && children.iter().zip(other_children).all(|(a, b)| *a == b)
+    }
+}
+
+impl<T: FormulaValue> Eq for Expr<T> {}
+
+impl<T: FormulaValue + Float> Hash for Expr<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            // Equal values must hash the same, including `0` and `-0`.
+            Expr::Value(Some(value)) if value.is_nan() => T::nan().integer_decode().hash(state),
+            Expr::Value(Some(value)) if value.is_zero() => T::zero().integer_decode().hash(state),
+            Expr::Value(value) => value.map(|value| value.integer_decode()).hash(state),
+            Expr::Op { op, .. } => op.hash(state),
+            Expr::Function { function, .. } => function.hash(state),
+            Expr::CustomOp { symbol: name, .. }
+            | Expr::Custom { name, .. }
+            | Expr::Named(name)
+            | Expr::Let { name, .. }
+            | Expr::Variable(name)
+            | Expr::Reference(name)
+            | Expr::TimeOfUse(name) => name.hash(state),
+            Expr::Component(id) => id.hash(state),
+            Expr::Time(function) => function.hash(state),
+            Expr::Temporal { function, .. } => function.hash(state),
+            Expr::Select { function, args, .. } => (function, args.len()).hash(state),
+            Expr::UnaryMinus(_) | Expr::Not(_) | Expr::Wildcard => {}
+        }
+        for child in self.children() {
+            child.hash(state);
+        }
+    }
+}
+
+impl<T: FromStr> TryFrom<Pairs<'_, Rule>> for Expr<T> {
     type Error = FormulaError;
 
     fn try_from(value: Pairs<Rule>) -> Result<Self, Self::Error> {
-        Ok(PRATT_PARSER
-            .map_primary(|primary| match primary.as_rule() {
-                Rule::expr => {
-                    Expr::try_from(primary.into_inner()).unwrap_or_else(|_| Expr::Value(None))
-                }
-                Rule::num => Expr::Value(primary.as_str().parse().ok()),
-                Rule::component => primary
-                    .as_str()
-                    .replace("#", "")
-                    .parse()
-                    .map(Expr::Component)
-                    .unwrap_or_else(|_| Expr::Value(None)),
-                Rule::coalesce => Expr::Function {
-                    function: Function::Coalesce,
-                    args: primary
-                        .into_inner()
-                        .map(|x| {
-                            Expr::try_from(Pairs::single(x)).unwrap_or_else(|_| Expr::Value(None))
-                        })
-                        .collect(),
+        Expr::parse(value, &FunctionRegistry::default())
+    }
+}
+
+impl<T: FromStr> Expr<T> {
+    /// Parse an expression, resolving custom operators from the registry.
+    pub(crate) fn parse(
+        pairs: Pairs<Rule>,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Self, FormulaError> {
+        Expr::parse_operators(&mut pairs.peekable(), 0, functions)
+    }
+
+    /// Parse the operators binding their left operand at least as tightly as
+    /// `min_power`, Pratt style.
+    fn parse_operators(
+        pairs: &mut Peekable<Pairs<Rule>>,
+        min_power: u8,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Self, FormulaError> {
+        let pair = pairs
+            .next()
+            .ok_or(FormulaError("Expected an expression".to_string()))?;
+        let mut lhs = match pair.as_rule() {
+            Rule::unary_minus => Expr::UnaryMinus(Box::new(Expr::parse_operators(
+                pairs,
+                UNARY_MINUS_BINDING_POWER,
+                functions,
+            )?)),
+            Rule::not => Expr::Not(Box::new(Expr::parse_operators(
+                pairs,
+                NOT_BINDING_POWER,
+                functions,
+            )?)),
+            _ => Expr::parse_primary(pair, functions)?,
+        };
+
+        while let Some(op) = pairs.peek() {
+            let (precedence, associativity) = match op.as_rule() {
+                Rule::EOI => {
+                    pairs.next();
+                    continue;
+                }
+                Rule::custom_op => functions
+                    .operator(op.as_str())
+                    .map(|op| (op.precedence, op.associativity))
+                    .ok_or_else(|| FormulaError(format!("Unknown operator: {}", op.as_str())))?,
+                rule => Op::from_rule(rule).precedence(),
+            };
+            let (left_power, right_power) = precedence.binding_powers(associativity);
+            if left_power < min_power {
+                break;
+            }
+            let op = pairs.next().expect("peeked operator");
+            let rhs = Box::new(Expr::parse_operators(pairs, right_power, functions)?);
+            lhs = match op.as_rule() {
+                Rule::custom_op => Expr::CustomOp {
+                    symbol: op.as_str().to_string(),
+                    lhs: Box::new(lhs),
+                    rhs,
                 },
-                Rule::min => Expr::Function {
-                    function: Function::Min,
-                    args: primary
-                        .into_inner()
-                        .map(|x| {
-                            Expr::try_from(Pairs::single(x)).unwrap_or_else(|_| Expr::Value(None))
-                        })
-                        .collect(),
+                rule => Expr::Op {
+                    lhs: Box::new(lhs),
+                    op: Op::from_rule(rule),
+                    rhs,
                 },
-                Rule::max => Expr::Function {
-                    function: Function::Max,
-                    args: primary
-                        .into_inner()
-                        .map(|x| {
-                            Expr::try_from(Pairs::single(x)).unwrap_or_else(|_| Expr::Value(None))
-                        })
-                        .collect(),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_primary(
+        primary: Pair<Rule>,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Self, FormulaError> {
+        let parse = |pair| Expr::parse(Pairs::single(pair), functions);
+        Ok(match primary.as_rule() {
+            Rule::expr => Expr::parse(primary.into_inner(), functions)?,
+            Rule::num => Expr::Value(primary.as_str().replace("_", "").parse().ok()),
+            Rule::duration => Expr::Value(duration_seconds(primary.as_str()).parse().ok()),
+            Rule::constant => Expr::Value(constant(primary.as_str()).to_string().parse().ok()),
+            Rule::component => primary
+                .as_str()
+                .replace("#", "")
+                .parse()
+                .map(Expr::Component)
+                .unwrap_or_else(|_| Expr::Value(None)),
+            Rule::named => Expr::Named(primary.as_str().trim_start_matches('$').to_string()),
+            Rule::component_id => Expr::Named(string_literal(primary)),
+            Rule::reference => {
+                Expr::Reference(primary.as_str().trim_start_matches('@').to_string())
+            }
+            Rule::tou => Expr::TimeOfUse(string_literal(primary)),
+            Rule::let_in => {
+                let mut inner = primary.into_inner();
+                let name = inner.next().map(|x| x.as_str().to_string());
+                let mut next = || inner.next().map(parse).unwrap_or(Ok(Expr::Value(None)));
+                Expr::Let {
+                    name: name.unwrap_or_default(),
+                    value: Box::new(next()?),
+                    body: Box::new(next()?),
+                }
+            }
+            Rule::variable => Expr::Variable(primary.as_str().to_string()),
+            Rule::custom => {
+                let mut inner = primary.into_inner();
+                let name = inner
+                    .next()
+                    .map(|x| x.as_str().trim_end_matches('(').to_string());
+                Expr::Custom {
+                    name: name.unwrap_or_default(),
+                    args: Expr::parse_args(inner, functions)?,
+                }
+            }
+            Rule::now => Expr::Time(TimeFunction::Now),
+            Rule::hour => Expr::Time(TimeFunction::Hour),
+            Rule::dayofweek => Expr::Time(TimeFunction::DayOfWeek),
+            rule => match (Function::from_rule(rule), TemporalFunction::from_rule(rule)) {
+                (Some(function), _) => Expr::Function {
+                    function,
+                    args: Expr::parse_args(primary.into_inner(), functions)?,
                 },
-                rule => unreachable!("Expr::parse expected atom, found {:?}", rule),
-            })
-            .map_infix(|lhs, op, rhs| Expr::Op {
-                lhs: Box::new(lhs),
-                op: match op.as_rule() {
-                    Rule::add => Op::Add,
-                    Rule::sub => Op::Sub,
-                    Rule::mul => Op::Mul,
-                    Rule::div => Op::Div,
-                    rule => unreachable!("Expr::parse expected operator, found {:?}", rule),
+                (_, Some(function)) => Expr::Temporal {
+                    function,
+                    args: Expr::parse_args(primary.into_inner(), functions)?,
                 },
-                rhs: Box::new(rhs),
-            })
-            .map_prefix(|op, rhs| match op.as_rule() {
-                Rule::unary_minus => Expr::UnaryMinus(Box::new(rhs)),
-                _ => unreachable!(),
-            })
-            .map_postfix(|lhs, op| match op.as_rule() {
-                Rule::EOI => lhs,
-                _ => unreachable!(),
-            })
-            .parse(value))
+                _ => unreachable!("Expr::parse expected atom, found {:?}", rule),
+            },
+        })
+    }
+
+    /// Parse the arguments of a function, expanding placeholder ranges.
+    fn parse_args(
+        pairs: Pairs<Rule>,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Vec<Self>, FormulaError> {
+        let mut args = Vec::new();
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::component_range => args.extend(component_range(pair)?),
+                Rule::wildcard => args.push(Expr::Wildcard),
+                _ => args.push(Expr::parse(Pairs::single(pair), functions)?),
+            }
+        }
+        Ok(args)
     }
 }
 
-impl<
-        T: Copy
-            + Neg<Output = T>
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Div<Output = T>
-            + PartialOrd,
-    > Expr<T>
-{
-    pub fn calculate(&self, values: &HashMap<usize, Option<T>>) -> Result<Option<T>, FormulaError> {
+impl<T: FormulaValue + Float> Expr<T> {
+    pub(crate) fn calculate(
+        &self,
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Option<T>, FormulaError> {
         Ok(match self {
             Expr::Value(value) => *value,
-            Expr::UnaryMinus(expr) => expr.calculate(values)?.map(Neg::neg),
-            Expr::Op { lhs, op, rhs } => op.apply(lhs.calculate(values)?, rhs.calculate(values)?),
+            Expr::UnaryMinus(expr) => expr.calculate(values, options, functions)?.map(Neg::neg),
+            Expr::Not(expr) => expr
+                .calculate(values, options, functions)?
+                .map(|x| from_bool(x == T::zero())),
+            Expr::Op { lhs, op, rhs } => self.apply_op(
+                op,
+                lhs.calculate(values, options, functions)?,
+                rhs.calculate(values, options, functions)?,
+                options,
+            )?,
+            // IF and CASE only evaluate the selected branch.
+            Expr::Function {
+                function: function @ (Function::If | Function::Case),
+                args,
+            } => match Expr::select(function, args, values, options, functions)? {
+                Some(i) => args[i].calculate(values, options, functions)?,
+                None => None,
+            },
             Expr::Function { function, args } => function.apply(
-                &args
-                    .iter()
-                    .map(|expr| expr.calculate(values))
-                    .collect::<Result<Vec<Option<T>>, FormulaError>>()?,
+                &Expr::calculate_args(args, values, options, functions)?,
+                options,
             ),
-            Expr::Component(i) => values
-                .get(i)
-                .copied()
-                .ok_or(FormulaError("Placeholder out of bounds".to_string()))?,
+            Expr::CustomOp { symbol, lhs, rhs } => match functions.operator(symbol) {
+                Some(op) => (op.function)(
+                    lhs.calculate(values, options, functions)?,
+                    rhs.calculate(values, options, functions)?,
+                ),
+                None => return Err(FormulaError(format!("Unknown operator: {}", symbol))),
+            },
+            Expr::Custom { name, args } => match functions.get(name) {
+                Some(function) => {
+                    function(&Expr::calculate_args(args, values, options, functions)?)
+                }
+                None => return Err(FormulaError(format!("Unknown function: {}", name))),
+            },
+            Expr::Component(i) => options.placeholder_value(
+                values
+                    .component(*i)
+                    .ok_or(FormulaError("Placeholder out of bounds".to_string()))?,
+            ),
+            Expr::Named(name) => {
+                options.placeholder_value(values.named(name).ok_or_else(|| {
+                    FormulaError(format!("Missing value for {}", named_placeholder(name)))
+                })?)
+            }
+            Expr::Wildcard => {
+                return Err(FormulaError(
+                    "#* is only allowed in variadic functions".to_string(),
+                ))
+            }
+            Expr::Let { name, value, body } => {
+                let value = value.calculate(values, options, functions)?;
+                body.calculate(
+                    &Bound {
+                        inputs: values,
+                        name,
+                        value,
+                    },
+                    options,
+                    functions,
+                )?
+            }
+            Expr::Variable(name) => values
+                .variable(name)
+                .ok_or_else(|| FormulaError(format!("Unknown variable: {}", name)))?,
+            Expr::Reference(name) => values
+                .reference(name)
+                .ok_or_else(|| FormulaError(format!("Unknown formula: {}", name)))?,
+            Expr::TimeOfUse(name) => Some(from_bool(options.in_tou_window(name))),
+            Expr::Time(function) => function.apply(options),
+            Expr::Temporal { function, .. } => return Err(function.stateless_error()),
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => match Expr::select(function, args, values, options, functions)? {
+                Some(i) => branches[i].calculate(values, options, functions)?,
+                None => None,
+            },
         })
     }
 
-    pub fn components(&self) -> HashSet<usize> {
+    /// Apply the operator of this operation to the values of its operands.
+    pub(crate) fn apply_op(
+        &self,
+        op: &Op,
+        lhs: Option<T>,
+        rhs: Option<T>,
+        options: &EngineOptions,
+    ) -> Result<Option<T>, FormulaError> {
+        if matches!(op, Op::Div | Op::Mod) && rhs == Some(T::zero()) {
+            match options.division_by_zero() {
+                DivisionByZero::Infinity => {}
+                DivisionByZero::None => return Ok(None),
+                DivisionByZero::Error => {
+                    return Err(FormulaError(format!("Division by zero: {}", self)))
+                }
+            }
+        }
+        Ok(op.apply(lhs, rhs))
+    }
+
+    /// Get the index of the argument a selecting function evaluates to,
+    /// evaluating only the conditions needed for IF and CASE.
+    pub(crate) fn select(
+        function: &Function,
+        args: &[Expr<T>],
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Option<usize>, FormulaError> {
+        match function {
+            Function::If | Function::Case => Function::select_branch(args.len(), |i| {
+                args[i].calculate(values, options, functions)
+            }),
+            _ => Ok(function.select(&Expr::calculate_args(args, values, options, functions)?)),
+        }
+    }
+
+    /// Get the indices of the arguments the result of a selecting function
+    /// depends on: the argument it selects, or all arguments if none is
+    /// selected. For IF and CASE, these are the conditions evaluated, up to
+    /// the first `None` one, and the branch they select.
+    pub(crate) fn used_args(
+        function: &Function,
+        args: &[Expr<T>],
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Vec<usize>, FormulaError> {
+        if matches!(function, Function::If | Function::Case) {
+            let mut used = Vec::new();
+            let selected = Function::select_branch(args.len(), |i| {
+                used.push(i);
+                args[i].calculate(values, options, functions)
+            })?;
+            used.extend(selected);
+            return Ok(used);
+        }
+        Ok(
+            match Expr::select(function, args, values, options, functions)? {
+                Some(i) => vec![i],
+                None => (0..args.len()).collect(),
+            },
+        )
+    }
+
+    /// Calculate the arguments of a function, expanding `#*` to all given
+    /// values.
+    fn calculate_args(
+        args: &[Expr<T>],
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Vec<Option<T>>, FormulaError> {
+        let mut results = Vec::with_capacity(args.len());
+        for arg in args {
+            match arg {
+                Expr::Wildcard => results.extend(Expr::wildcard_values(values, options)?),
+                arg => results.push(arg.calculate(values, options, functions)?),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Get all given values, for `#*`.
+    pub(crate) fn wildcard_values(
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+    ) -> Result<Vec<Option<T>>, FormulaError> {
+        let all: Vec<_> = values
+            .all()
+            .ok_or_else(|| FormulaError("The values for #* can't be listed".to_string()))?
+            .into_iter()
+            .map(|value| options.placeholder_value(value))
+            .collect();
+        if options.strict() && all.iter().any(Option::is_none) {
+            return Err(FormulaError("Missing values for #*".to_string()));
+        }
+        Ok(all)
+    }
+}
+
+impl<T: FormulaValue> Expr<T> {
+    /// Check that the expression can be evaluated with the given options.
+    pub(crate) fn validate(
+        &self,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<(), FormulaError> {
+        self.validate_in(options, functions, &mut Vec::new())
+    }
+
+    /// Like [`Expr::validate`], with the given `LET` variables in scope.
+    fn validate_in<'a>(
+        &'a self,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+        scope: &mut Vec<&'a str>,
+    ) -> Result<(), FormulaError> {
+        match self {
+            Expr::Value(_)
+            | Expr::Component(_)
+            | Expr::Wildcard
+            | Expr::Named(_)
+            | Expr::Reference(_)
+            | Expr::Time(_) => Ok(()),
+            Expr::UnaryMinus(expr) | Expr::Not(expr) => expr.validate_in(options, functions, scope),
+            Expr::Op { lhs, rhs, .. } | Expr::CustomOp { lhs, rhs, .. } => {
+                lhs.validate_in(options, functions, scope)?;
+                rhs.validate_in(options, functions, scope)
+            }
+            Expr::Function { function, args } => {
+                function.validate_arity(args)?;
+                args.iter()
+                    .try_for_each(|arg| arg.validate_in(options, functions, scope))
+            }
+            Expr::Temporal { function, args } => {
+                function.validate_arity(args)?;
+                args.iter()
+                    .try_for_each(|arg| arg.validate_in(options, functions, scope))
+            }
+            Expr::Custom { name, args } => {
+                if functions.get(name).is_none() {
+                    return Err(FormulaError(format!("Unknown function: {}", name)));
+                }
+                args.iter()
+                    .try_for_each(|arg| arg.validate_in(options, functions, scope))
+            }
+            Expr::Let { name, value, body } => {
+                value.validate_in(options, functions, scope)?;
+                scope.push(name);
+                let result = body.validate_in(options, functions, scope);
+                scope.pop();
+                result
+            }
+            Expr::Variable(name) => {
+                if scope.contains(&name.as_str()) {
+                    Ok(())
+                } else {
+                    Err(FormulaError(format!("Unknown variable: {}", name)))
+                }
+            }
+            Expr::TimeOfUse(name) => {
+                if options.has_tou_window(name) {
+                    Ok(())
+                } else {
+                    Err(FormulaError(format!(
+                        "Unknown time-of-use window: {}",
+                        name
+                    )))
+                }
+            }
+            Expr::Select { args, branches, .. } => args
+                .iter()
+                .chain(branches)
+                .try_for_each(|arg| arg.validate_in(options, functions, scope)),
+        }
+    }
+
+    pub fn components(&self) -> HashSet<u64> {
         match self {
-            Expr::Value(_) => HashSet::new(),
-            Expr::UnaryMinus(expr) => expr.components(),
-            Expr::Op { lhs, rhs, .. } => {
+            Expr::Value(_)
+            | Expr::Wildcard
+            | Expr::Named(_)
+            | Expr::Variable(_)
+            | Expr::Reference(_)
+            | Expr::TimeOfUse(_)
+            | Expr::Time(_) => HashSet::new(),
+            Expr::UnaryMinus(expr) | Expr::Not(expr) => expr.components(),
+            Expr::Op { lhs, rhs, .. }
+            | Expr::CustomOp { lhs, rhs, .. }
+            | Expr::Let {
+                value: lhs,
+                body: rhs,
+                ..
+            } => {
                 let mut components = lhs.components();
                 components.extend(rhs.components());
                 components
             }
-            Expr::Function { args, .. } => args
+            Expr::Function { args, .. }
+            | Expr::Custom { args, .. }
+            | Expr::Temporal { args, .. } => args
                 .iter()
                 .map(Expr::components)
                 .fold(HashSet::new(), |acc, x| acc.union(&x).copied().collect()),
             Expr::Component(i) => HashSet::from([*i]),
+            Expr::Select { args, branches, .. } => args
+                .iter()
+                .chain(branches)
+                .map(Expr::components)
+                .fold(HashSet::new(), |acc, x| acc.union(&x).copied().collect()),
+        }
+    }
+
+    pub fn names(&self) -> HashSet<String> {
+        match self {
+            Expr::Value(_)
+            | Expr::Component(_)
+            | Expr::Wildcard
+            | Expr::Variable(_)
+            | Expr::Reference(_)
+            | Expr::TimeOfUse(_)
+            | Expr::Time(_) => HashSet::new(),
+            Expr::UnaryMinus(expr) | Expr::Not(expr) => expr.names(),
+            Expr::Op { lhs, rhs, .. }
+            | Expr::CustomOp { lhs, rhs, .. }
+            | Expr::Let {
+                value: lhs,
+                body: rhs,
+                ..
+            } => {
+                let mut names = lhs.names();
+                names.extend(rhs.names());
+                names
+            }
+            Expr::Function { args, .. }
+            | Expr::Custom { args, .. }
+            | Expr::Temporal { args, .. } => args.iter().flat_map(Expr::names).collect(),
+            Expr::Named(name) => HashSet::from([name.clone()]),
+            Expr::Select { args, branches, .. } => {
+                args.iter().chain(branches).flat_map(Expr::names).collect()
+            }
+        }
+    }
+
+    /// Get the names of the formulas the expression references as `@name`.
+    pub fn references(&self) -> HashSet<String> {
+        match self {
+            Expr::Reference(name) => HashSet::from([name.clone()]),
+            expr => expr
+                .children()
+                .into_iter()
+                .flat_map(Expr::references)
+                .collect(),
         }
     }
 }
 
-#[derive(Debug)]
+impl<T: FormulaValue> Expr<T> {
+    /// Get the partial derivative of the expression with respect to the given
+    /// component.
+    pub fn derivative(&self, component: u64) -> Result<Expr<T>, FormulaError> {
+        Ok(self
+            .nonzero_derivative(component)?
+            .unwrap_or(Expr::Value(Some(T::zero()))))
+    }
+
+    /// Like [`Expr::derivative`], but returns `None` if the derivative is
+    /// identically zero, so that zero terms can be left out.
+    fn nonzero_derivative(&self, component: u64) -> Result<Option<Expr<T>>, FormulaError> {
+        Ok(match self {
+            // Logical negation is piecewise constant.
+            Expr::Value(_) | Expr::Named(_) | Expr::TimeOfUse(_) | Expr::Time(_) | Expr::Not(_) => {
+                None
+            }
+            Expr::Component(i) => (*i == component).then_some(Expr::Value(Some(T::one()))),
+            Expr::Wildcard => {
+                return Err(FormulaError(
+                    "Cannot differentiate formulas using #*".to_string(),
+                ))
+            }
+            // Validation ensures variables only occur in the bodies of `LET`s,
+            // which are inlined before being differentiated.
+            Expr::Variable(_) => None,
+            Expr::Let { name, value, body } => {
+                body.inline(name, value).nonzero_derivative(component)?
+            }
+            Expr::Reference(name) => {
+                return Err(FormulaError(format!(
+                    "Derivative of formula reference @{} is not supported",
+                    name
+                )))
+            }
+            Expr::UnaryMinus(expr) => expr
+                .nonzero_derivative(component)?
+                .map(|d| Expr::UnaryMinus(Box::new(d))),
+            Expr::Op { lhs, op, rhs } => {
+                let (dlhs, drhs) = (
+                    lhs.nonzero_derivative(component)?,
+                    rhs.nonzero_derivative(component)?,
+                );
+                Expr::op_derivative(lhs, op, rhs, dlhs, drhs)?
+            }
+            Expr::Function { function, args } => {
+                Expr::function_derivative(function, args, component)?
+            }
+            Expr::CustomOp { symbol, lhs, rhs } => {
+                if lhs.nonzero_derivative(component)?.is_some()
+                    || rhs.nonzero_derivative(component)?.is_some()
+                {
+                    return Err(FormulaError(format!(
+                        "Derivative of custom operator {} is not supported",
+                        symbol
+                    )));
+                }
+                None
+            }
+            Expr::Custom { name, args } => {
+                for arg in args {
+                    if arg.nonzero_derivative(component)?.is_some() {
+                        return Err(FormulaError(format!(
+                            "Derivative of custom function {} is not supported",
+                            name
+                        )));
+                    }
+                }
+                None
+            }
+            Expr::Temporal { function, args } => {
+                for arg in args {
+                    if arg.nonzero_derivative(component)?.is_some() {
+                        return Err(FormulaError(format!(
+                            "Derivative of {} is not supported",
+                            function.name()
+                        )));
+                    }
+                }
+                None
+            }
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => Expr::select_derivative(function, args, branches, component)?,
+        })
+    }
+
+    /// Replace the given `LET` variable with its value.
+    pub(crate) fn inline(&self, name: &str, value: &Expr<T>) -> Expr<T> {
+        let inline = |expr: &Expr<T>| expr.inline(name, value);
+        let inline_all = |exprs: &[Expr<T>]| exprs.iter().map(inline).collect();
+        match self {
+            Expr::Variable(variable) if variable == name => value.clone(),
+            Expr::UnaryMinus(expr) => Expr::UnaryMinus(Box::new(inline(expr))),
+            Expr::Not(expr) => Expr::Not(Box::new(inline(expr))),
+            Expr::Op { lhs, op, rhs } => Expr::op(inline(lhs), op.clone(), inline(rhs)),
+            Expr::Function { function, args } => Expr::Function {
+                function: function.clone(),
+                args: inline_all(args),
+            },
+            Expr::CustomOp { symbol, lhs, rhs } => Expr::CustomOp {
+                symbol: symbol.clone(),
+                lhs: Box::new(inline(lhs)),
+                rhs: Box::new(inline(rhs)),
+            },
+            Expr::Custom { name, args } => Expr::Custom {
+                name: name.clone(),
+                args: inline_all(args),
+            },
+            Expr::Temporal { function, args } => Expr::Temporal {
+                function: function.clone(),
+                args: inline_all(args),
+            },
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => Expr::Select {
+                function: function.clone(),
+                args: inline_all(args),
+                branches: inline_all(branches),
+            },
+            Expr::Let {
+                name: inner,
+                value: inner_value,
+                body,
+            } => Expr::Let {
+                name: inner.clone(),
+                value: Box::new(inline(inner_value)),
+                // An inner `LET` of the same name shadows the variable.
+                body: Box::new(if inner == name {
+                    *body.clone()
+                } else {
+                    inline(body)
+                }),
+            },
+            expr => expr.clone(),
+        }
+    }
+
+    /// Replace the divisors of `/` and `%` by `NULLIF(divisor, 0)`, so that
+    /// dividing by zero evaluates to `None`, as with
+    /// [`DivisionByZero::None`].
+    pub(crate) fn nullif_divisors(&self) -> Expr<T> {
+        match self {
+            Expr::Op {
+                lhs,
+                op: op @ (Op::Div | Op::Mod),
+                rhs,
+            } => Expr::op(
+                lhs.nullif_divisors(),
+                op.clone(),
+                Expr::Function {
+                    function: Function::NullIf,
+                    args: vec![rhs.nullif_divisors(), Expr::Value(Some(T::zero()))],
+                },
+            ),
+            expr => expr
+                .try_map_children(|child| Ok::<_, Infallible>(child.nullif_divisors()))
+                .unwrap_or_else(|never| match never {}),
+        }
+    }
+
+    fn op_derivative(
+        lhs: &Expr<T>,
+        op: &Op,
+        rhs: &Expr<T>,
+        dlhs: Option<Expr<T>>,
+        drhs: Option<Expr<T>>,
+    ) -> Result<Option<Expr<T>>, FormulaError> {
+        Ok(match op {
+            Op::Add | Op::Sub => match (dlhs, drhs) {
+                (Some(dlhs), Some(drhs)) => Some(Expr::op(dlhs, op.clone(), drhs)),
+                (dlhs, None) => dlhs,
+                (None, Some(drhs)) => Some(match op {
+                    Op::Sub => Expr::UnaryMinus(Box::new(drhs)),
+                    _ => drhs,
+                }),
+            },
+            // (ab)' = a'b + ab'
+            Op::Mul => {
+                let dlhs = dlhs.map(|d| Expr::times(d, rhs.clone()));
+                let drhs = drhs.map(|d| Expr::times(lhs.clone(), d));
+                match (dlhs, drhs) {
+                    (Some(dlhs), Some(drhs)) => Some(Expr::op(dlhs, Op::Add, drhs)),
+                    (dlhs, drhs) => dlhs.or(drhs),
+                }
+            }
+            // (a/b)' = (a'b - ab') / b²
+            Op::Div => match (dlhs, drhs) {
+                (None, None) => None,
+                (Some(dlhs), None) => Some(Expr::op(dlhs, Op::Div, rhs.clone())),
+                (dlhs, Some(drhs)) => {
+                    let drhs = Expr::times(lhs.clone(), drhs);
+                    let numerator = match dlhs {
+                        Some(dlhs) => Expr::op(Expr::times(dlhs, rhs.clone()), Op::Sub, drhs),
+                        None => Expr::UnaryMinus(Box::new(drhs)),
+                    };
+                    Some(Expr::op(
+                        numerator,
+                        Op::Div,
+                        Expr::times(rhs.clone(), rhs.clone()),
+                    ))
+                }
+            },
+            // Comparisons and logical operators are piecewise constant.
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::And | Op::Or => None,
+            // (a % c)' = a' for a divisor c that doesn't depend on the
+            // component, except at the discontinuities.
+            Op::Mod => match (dlhs, drhs) {
+                (dlhs, None) => dlhs,
+                (_, Some(_)) => {
+                    return Err(FormulaError(
+                        "Derivative with respect to a divisor of % is not supported".to_string(),
+                    ))
+                }
+            },
+            // (a^c)' = c·a^(c-1)·a' for an exponent c that doesn't depend on
+            // the component.
+            Op::Pow => match (dlhs, drhs) {
+                (None, None) => None,
+                (Some(dlhs), None) => {
+                    let exponent = match rhs {
+                        Expr::Value(value) => Expr::Value(value.map(|c| c - T::one())),
+                        rhs => Expr::op(rhs.clone(), Op::Sub, Expr::Value(Some(T::one()))),
+                    };
+                    Some(Expr::times(
+                        Expr::times(rhs.clone(), Expr::op(lhs.clone(), Op::Pow, exponent)),
+                        dlhs,
+                    ))
+                }
+                (_, Some(_)) => {
+                    return Err(FormulaError(
+                        "Derivative with respect to an exponent is not supported".to_string(),
+                    ))
+                }
+            },
+        })
+    }
+
+    fn function_derivative(
+        function: &Function,
+        args: &[Expr<T>],
+        component: u64,
+    ) -> Result<Option<Expr<T>>, FormulaError> {
+        match function {
+            Function::Coalesce
+            | Function::Min
+            | Function::Max
+            | Function::MinStrict
+            | Function::MaxStrict => Expr::select_derivative(function, args, args, component),
+            // The value compared against is never selected.
+            Function::NullIf => Expr::select_derivative(
+                function,
+                args,
+                &[args[0].clone(), Expr::Value(None)],
+                component,
+            ),
+            // Conditions are never selected, so their derivatives are irrelevant.
+            Function::If | Function::Case => Expr::select_derivative(
+                function,
+                args,
+                &args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, arg)| {
+                        if Function::is_condition(i, args.len()) {
+                            Expr::Value(None)
+                        } else {
+                            arg.clone()
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+                component,
+            ),
+            // POS(x) = MAX_STRICT(x, 0) and NEG(x) = MIN_STRICT(x, 0).
+            Function::Pos | Function::Neg => {
+                let args = [args[0].clone(), Expr::Value(Some(T::zero()))];
+                let function = match function {
+                    Function::Pos => Function::MaxStrict,
+                    _ => Function::MinStrict,
+                };
+                Expr::select_derivative(&function, &args, &args, component)
+            }
+            // HYPOT(a, b)' = (aa' + bb') / HYPOT(a, b)
+            Function::Hypot => {
+                let mut terms = Vec::new();
+                for arg in args {
+                    if let Some(derivative) = arg.nonzero_derivative(component)? {
+                        terms.push(Expr::times(arg.clone(), derivative));
+                    }
+                }
+                Ok(terms
+                    .into_iter()
+                    .reduce(|acc, term| Expr::op(acc, Op::Add, term))
+                    .map(|numerator| {
+                        Expr::op(
+                            numerator,
+                            Op::Div,
+                            Expr::Function {
+                                function: Function::Hypot,
+                                args: args.to_vec(),
+                            },
+                        )
+                    }))
+            }
+            Function::Sin | Function::Cos | Function::Tan => {
+                let Some(derivative) = args[0].nonzero_derivative(component)? else {
+                    return Ok(None);
+                };
+                let call = |function| Expr::Function {
+                    function,
+                    args: args.to_vec(),
+                };
+                Ok(Some(match function {
+                    // SIN(u)' = COS(u)u'
+                    Function::Sin => Expr::times(call(Function::Cos), derivative),
+                    // COS(u)' = -SIN(u)u'
+                    Function::Cos => {
+                        Expr::UnaryMinus(Box::new(Expr::times(call(Function::Sin), derivative)))
+                    }
+                    // TAN(u)' = u' / COS(u)²
+                    _ => Expr::op(
+                        derivative,
+                        Op::Div,
+                        Expr::times(call(Function::Cos), call(Function::Cos)),
+                    ),
+                }))
+            }
+            // ATAN2(y, x)' = (xy' - yx') / (x² + y²)
+            Function::Atan2 => {
+                let (y, x) = (&args[0], &args[1]);
+                let numerator = match (
+                    y.nonzero_derivative(component)?,
+                    x.nonzero_derivative(component)?,
+                ) {
+                    (None, None) => return Ok(None),
+                    (Some(dy), None) => Expr::times(x.clone(), dy),
+                    (None, Some(dx)) => Expr::UnaryMinus(Box::new(Expr::times(y.clone(), dx))),
+                    (Some(dy), Some(dx)) => Expr::op(
+                        Expr::times(x.clone(), dy),
+                        Op::Sub,
+                        Expr::times(y.clone(), dx),
+                    ),
+                };
+                Ok(Some(Expr::op(
+                    numerator,
+                    Op::Div,
+                    Expr::op(
+                        Expr::times(x.clone(), x.clone()),
+                        Op::Add,
+                        Expr::times(y.clone(), y.clone()),
+                    ),
+                )))
+            }
+            // LERP(a, b, t) = a + (b - a)t
+            Function::Lerp => Expr::op(
+                args[0].clone(),
+                Op::Add,
+                Expr::op(
+                    Expr::op(args[1].clone(), Op::Sub, args[0].clone()),
+                    Op::Mul,
+                    args[2].clone(),
+                ),
+            )
+            .nonzero_derivative(component),
+            Function::Kw | Function::Mw | Function::Kwh => Ok(args[0]
+                .nonzero_derivative(component)?
+                .map(|derivative| Expr::times(Expr::Value(function.unit_scale()), derivative))),
+            // POLY(x, c0, c1, c2, ...)' =
+            //     x' POLY(x, c1, 2c2, ...) + POLY(x, c0', c1', c2', ...)
+            Function::Poly => {
+                let (x, coefficients) = (&args[0], &args[1..]);
+                let mut terms = Vec::new();
+                if let Some(dx) = x.nonzero_derivative(component)? {
+                    if coefficients.len() > 1 {
+                        let mut args = vec![x.clone()];
+                        for (i, c) in coefficients.iter().enumerate().skip(1) {
+                            args.push(Expr::times(Expr::Value(T::from_usize(i)), c.clone()));
+                        }
+                        terms.push(Expr::times(
+                            dx,
+                            Expr::Function {
+                                function: Function::Poly,
+                                args,
+                            },
+                        ));
+                    }
+                }
+                let dcoefficients = coefficients
+                    .iter()
+                    .map(|c| c.nonzero_derivative(component))
+                    .collect::<Result<Vec<_>, FormulaError>>()?;
+                if dcoefficients.iter().any(Option::is_some) {
+                    let mut args = vec![x.clone()];
+                    args.extend(
+                        dcoefficients
+                            .into_iter()
+                            .map(|dc| dc.unwrap_or(Expr::Value(Some(T::zero())))),
+                    );
+                    terms.push(Expr::Function {
+                        function: Function::Poly,
+                        args,
+                    });
+                }
+                Ok(terms
+                    .into_iter()
+                    .reduce(|acc, term| Expr::op(acc, Op::Add, term)))
+            }
+            Function::Curve => {
+                for arg in args {
+                    if arg.nonzero_derivative(component)?.is_some() {
+                        return Err(FormulaError(
+                            "Derivative of CURVE is not supported".to_string(),
+                        ));
+                    }
+                }
+                Ok(None)
+            }
+            Function::Pow => {
+                let (dlhs, drhs) = (
+                    args[0].nonzero_derivative(component)?,
+                    args[1].nonzero_derivative(component)?,
+                );
+                Expr::op_derivative(&args[0], &Op::Pow, &args[1], dlhs, drhs)
+            }
+            // QUANTIZE(x, s) is piecewise constant in x and ROUND(x / s)s' in s.
+            Function::Quantize => Ok(args[1].nonzero_derivative(component)?.map(|derivative| {
+                Expr::times(
+                    Expr::Function {
+                        function: Function::Round,
+                        args: vec![Expr::op(args[0].clone(), Op::Div, args[1].clone())],
+                    },
+                    derivative,
+                )
+            })),
+            // Rounding and availability checks are piecewise constant.
+            Function::Round
+            | Function::Floor
+            | Function::Ceil
+            | Function::IsNone
+            | Function::IsSome
+            | Function::CountSome => Ok(None),
+            // Arguments are differentiated as if they were all available.
+            Function::Sum => {
+                let derivatives = args
+                    .iter()
+                    .map(|arg| arg.nonzero_derivative(component))
+                    .filter_map(Result::transpose)
+                    .collect::<Result<Vec<_>, FormulaError>>()?;
+                Ok((!derivatives.is_empty()).then_some(Expr::Function {
+                    function: Function::Sum,
+                    args: derivatives,
+                }))
+            }
+            // (abc)' = a'bc + ab'c + abc'
+            Function::Product => {
+                let mut terms = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    if let Some(derivative) = arg.nonzero_derivative(component)? {
+                        let mut factors = args.to_vec();
+                        factors[i] = derivative;
+                        terms.push(Expr::Function {
+                            function: Function::Product,
+                            args: factors,
+                        });
+                    }
+                }
+                Ok(terms
+                    .into_iter()
+                    .reduce(|acc, term| Expr::op(acc, Op::Add, term)))
+            }
+        }
+    }
+
+    /// The derivative of a function that selects one of its arguments is
+    /// piecewise: it is the derivative of whichever argument is selected.
+    fn select_derivative(
+        function: &Function,
+        args: &[Expr<T>],
+        branches: &[Expr<T>],
+        component: u64,
+    ) -> Result<Option<Expr<T>>, FormulaError> {
+        let branches = branches
+            .iter()
+            .map(|branch| branch.nonzero_derivative(component))
+            .collect::<Result<Vec<Option<Expr<T>>>, FormulaError>>()?;
+        if branches.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        Ok(Some(Expr::Select {
+            function: function.clone(),
+            args: args.to_vec(),
+            branches: branches
+                .into_iter()
+                .map(|branch| branch.unwrap_or(Expr::Value(Some(T::zero()))))
+                .collect(),
+        }))
+    }
+
+    /// Multiply two expressions, leaving out factors that are one.
+    fn times(lhs: Expr<T>, rhs: Expr<T>) -> Expr<T> {
+        match (lhs, rhs) {
+            (Expr::Value(Some(one)), expr) | (expr, Expr::Value(Some(one))) if one == T::one() => {
+                expr
+            }
+            (lhs, rhs) => Expr::op(lhs, Op::Mul, rhs),
+        }
+    }
+}
+
+/// Get the contents of the string literal in a rule.
+fn string_literal(pair: Pair<Rule>) -> String {
+    pair.into_inner()
+        .flat_map(|string| string.into_inner())
+        .map(|inner| unescape(inner.as_str()))
+        .collect()
+}
+
+/// Expand a `#first..#last` range to its placeholders, in order from `first`
+/// to `last`, which may also count down.
+fn component_range<T>(pair: Pair<Rule>) -> Result<Vec<Expr<T>>, FormulaError> {
+    let range = pair.as_str().trim().to_string();
+    let ids: Vec<Option<u64>> = pair
+        .into_inner()
+        .map(|component| component.as_str().replace("#", "").parse().ok())
+        .collect();
+    match ids[..] {
+        [Some(first), Some(last)] if first.abs_diff(last) >= MAX_RANGE_LEN => {
+            Err(FormulaError(format!(
+                "Placeholder range {} has more than {} placeholders",
+                range, MAX_RANGE_LEN
+            )))
+        }
+        [Some(first), Some(last)] if first <= last => {
+            Ok((first..=last).map(Expr::Component).collect())
+        }
+        [Some(first), Some(last)] => Ok((last..=first).rev().map(Expr::Component).collect()),
+        _ => Ok(vec![Expr::Value(None)]),
+    }
+}
+
+/// The number of seconds of a duration, e.g. `90` for `1.5m`, as written in
+/// formulas.
+fn duration_seconds(duration: &str) -> String {
+    let digits = duration
+        .trim_end_matches(char::is_alphabetic)
+        .replace("_", "");
+    let unit = &duration[duration.trim_end_matches(char::is_alphabetic).len()..];
+    let value = digits.parse::<f64>().unwrap_or(f64::NAN);
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "s" => return digits,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        "d" => value * 86400.0,
+        unit => unreachable!("Expr::parse expected duration unit, found {:?}", unit),
+    };
+    format!("{}", seconds)
+}
+
+/// The value of a named constant. Its `Display` output round-trips, so it can
+/// be passed through `T::from_str`.
+fn constant(name: &str) -> f64 {
+    use std::f64::consts;
+    match name {
+        "PI" => consts::PI,
+        "E" => consts::E,
+        "SQRT2" => consts::SQRT_2,
+        "SQRT3" => 3f64.sqrt(),
+        name => unreachable!("Expr::parse expected constant, found {:?}", name),
+    }
+}
+
+/// Convert a condition to `1` if it holds, else `0`.
+pub(crate) fn from_bool<T: FormulaValue>(condition: bool) -> T {
+    if condition {
+        T::one()
+    } else {
+        T::zero()
+    }
+}
+
+/// The built-in infix operators.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum Op {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
 }
 
 impl Op {
-    pub fn apply<
-        T: Copy
-            + Neg<Output = T>
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Div<Output = T>,
-    >(
-        &self,
-        lhs: Option<T>,
-        rhs: Option<T>,
-    ) -> Option<T> {
+    fn from_rule(rule: Rule) -> Op {
+        match rule {
+            Rule::add => Op::Add,
+            Rule::sub => Op::Sub,
+            Rule::mul => Op::Mul,
+            Rule::div => Op::Div,
+            Rule::modulo => Op::Mod,
+            Rule::pow => Op::Pow,
+            Rule::eq => Op::Eq,
+            Rule::ne => Op::Ne,
+            Rule::lt => Op::Lt,
+            Rule::le => Op::Le,
+            Rule::gt => Op::Gt,
+            Rule::ge => Op::Ge,
+            Rule::and => Op::And,
+            Rule::or => Op::Or,
+            rule => unreachable!("Expr::parse expected operator, found {:?}", rule),
+        }
+    }
+
+    pub(crate) fn precedence(&self) -> (Precedence, Associativity) {
+        match self {
+            Op::Or => (Precedence::Or, Associativity::Left),
+            Op::And => (Precedence::And, Associativity::Left),
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                (Precedence::Comparison, Associativity::Left)
+            }
+            Op::Add | Op::Sub => (Precedence::Additive, Associativity::Left),
+            Op::Mul | Op::Div | Op::Mod => (Precedence::Multiplicative, Associativity::Left),
+            Op::Pow => (Precedence::Power, Associativity::Right),
+        }
+    }
+
+    /// The operator as written in formulas.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Mod => "%",
+            Op::Pow => "^",
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::And => "AND",
+            Op::Or => "OR",
+        }
+    }
+
+    /// Get the operator written as the given symbol, as returned by
+    /// [`Op::symbol`].
+    pub fn from_symbol(symbol: &str) -> Option<Op> {
+        Some(match symbol {
+            "+" => Op::Add,
+            "-" => Op::Sub,
+            "*" => Op::Mul,
+            "/" => Op::Div,
+            "%" => Op::Mod,
+            "^" => Op::Pow,
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "AND" => Op::And,
+            "OR" => Op::Or,
+            _ => return None,
+        })
+    }
+
+    pub fn apply<T: FormulaValue + Float>(&self, lhs: Option<T>, rhs: Option<T>) -> Option<T> {
+        // Logical operators use three-valued logic: a `None` operand only
+        // makes the result `None` if the other operand doesn't decide it.
+        let truth = |x: Option<T>| x.map(|x| x != T::zero());
+        match (self, truth(lhs), truth(rhs)) {
+            (Op::And, Some(false), _) | (Op::And, _, Some(false)) => return Some(T::zero()),
+            (Op::Or, Some(true), _) | (Op::Or, _, Some(true)) => return Some(T::one()),
+            _ => {}
+        }
+
         if let (Some(lhs), Some(rhs)) = (lhs, rhs) {
             Some(match self {
                 Op::Add => lhs + rhs,
                 Op::Sub => lhs - rhs,
                 Op::Mul => lhs * rhs,
                 Op::Div => lhs / rhs,
+                Op::Mod => lhs % rhs,
+                Op::Pow => lhs.powf(rhs),
+                Op::Eq => from_bool(lhs == rhs),
+                Op::Ne => from_bool(lhs != rhs),
+                Op::Lt => from_bool(lhs < rhs),
+                Op::Le => from_bool(lhs <= rhs),
+                Op::Gt => from_bool(lhs > rhs),
+                Op::Ge => from_bool(lhs >= rhs),
+                Op::And => from_bool(lhs != T::zero() && rhs != T::zero()),
+                Op::Or => from_bool(lhs != T::zero() || rhs != T::zero()),
             })
         } else {
             None
@@ -180,40 +1629,513 @@ impl Op {
     }
 }
 
-#[derive(Debug)]
+/// The built-in functions, other than those of the current time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum Function {
     Coalesce,
     Min,
     Max,
+    MinStrict,
+    MaxStrict,
+    Pos,
+    Neg,
+    Hypot,
+    Sin,
+    Cos,
+    Tan,
+    Atan2,
+    Lerp,
+    Curve,
+    Poly,
+    Kw,
+    Mw,
+    Kwh,
+    Pow,
+    If,
+    Case,
+    Round,
+    Quantize,
+    Floor,
+    Ceil,
+    Sum,
+    Product,
+    NullIf,
+    IsNone,
+    IsSome,
+    CountSome,
 }
 
 impl Function {
-    pub fn apply<T: Copy + PartialOrd>(&self, values: &[Option<T>]) -> Option<T> {
+    fn from_rule(rule: Rule) -> Option<Function> {
+        Some(match rule {
+            Rule::coalesce => Function::Coalesce,
+            Rule::min => Function::Min,
+            Rule::max => Function::Max,
+            Rule::min_strict => Function::MinStrict,
+            Rule::max_strict => Function::MaxStrict,
+            Rule::pos => Function::Pos,
+            Rule::neg => Function::Neg,
+            Rule::hypot => Function::Hypot,
+            Rule::sin => Function::Sin,
+            Rule::cos => Function::Cos,
+            Rule::tan => Function::Tan,
+            Rule::atan2 => Function::Atan2,
+            Rule::lerp => Function::Lerp,
+            Rule::curve => Function::Curve,
+            Rule::poly => Function::Poly,
+            Rule::kw => Function::Kw,
+            Rule::mw => Function::Mw,
+            Rule::kwh => Function::Kwh,
+            Rule::power => Function::Pow,
+            Rule::if_else => Function::If,
+            Rule::case => Function::Case,
+            Rule::round => Function::Round,
+            Rule::quantize => Function::Quantize,
+            Rule::floor => Function::Floor,
+            Rule::ceil => Function::Ceil,
+            Rule::sum => Function::Sum,
+            Rule::product => Function::Product,
+            Rule::nullif => Function::NullIf,
+            Rule::is_none => Function::IsNone,
+            Rule::is_some => Function::IsSome,
+            Rule::count_some => Function::CountSome,
+            _ => return None,
+        })
+    }
+
+    /// The upper-case name of the function, as written in formulas.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Coalesce => "COALESCE",
+            Function::Min => "MIN",
+            Function::Max => "MAX",
+            Function::MinStrict => "MIN_STRICT",
+            Function::MaxStrict => "MAX_STRICT",
+            Function::Pos => "POS",
+            Function::Neg => "NEG",
+            Function::Hypot => "HYPOT",
+            Function::Sin => "SIN",
+            Function::Cos => "COS",
+            Function::Tan => "TAN",
+            Function::Atan2 => "ATAN2",
+            Function::Lerp => "LERP",
+            Function::Curve => "CURVE",
+            Function::Poly => "POLY",
+            Function::Kw => "KW",
+            Function::Mw => "MW",
+            Function::Kwh => "KWH",
+            Function::Pow => "POW",
+            Function::If => "IF",
+            Function::Case => "CASE",
+            Function::Round => "ROUND",
+            Function::Quantize => "QUANTIZE",
+            Function::Floor => "FLOOR",
+            Function::Ceil => "CEIL",
+            Function::Sum => "SUM",
+            Function::Product => "PRODUCT",
+            Function::NullIf => "NULLIF",
+            Function::IsNone => "IS_NONE",
+            Function::IsSome => "IS_SOME",
+            Function::CountSome => "COUNT_SOME",
+        }
+    }
+
+    /// Get the function with the given upper-case name, as returned by
+    /// [`Function::name`].
+    pub fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "COALESCE" => Function::Coalesce,
+            "MIN" => Function::Min,
+            "MAX" => Function::Max,
+            "MIN_STRICT" => Function::MinStrict,
+            "MAX_STRICT" => Function::MaxStrict,
+            "POS" => Function::Pos,
+            "NEG" => Function::Neg,
+            "HYPOT" => Function::Hypot,
+            "SIN" => Function::Sin,
+            "COS" => Function::Cos,
+            "TAN" => Function::Tan,
+            "ATAN2" => Function::Atan2,
+            "LERP" => Function::Lerp,
+            "CURVE" => Function::Curve,
+            "POLY" => Function::Poly,
+            "KW" => Function::Kw,
+            "MW" => Function::Mw,
+            "KWH" => Function::Kwh,
+            "POW" => Function::Pow,
+            "IF" => Function::If,
+            "CASE" => Function::Case,
+            "ROUND" => Function::Round,
+            "QUANTIZE" => Function::Quantize,
+            "FLOOR" => Function::Floor,
+            "CEIL" => Function::Ceil,
+            "SUM" => Function::Sum,
+            "PRODUCT" => Function::Product,
+            "NULLIF" => Function::NullIf,
+            "IS_NONE" => Function::IsNone,
+            "IS_SOME" => Function::IsSome,
+            "COUNT_SOME" => Function::CountSome,
+            _ => return None,
+        })
+    }
+
+    /// Check that the function is called with a valid number of arguments.
+    ///
+    /// A `#*` placeholder can stand for any number of arguments.
+    fn validate_arity<T>(&self, args: &[Expr<T>]) -> Result<(), FormulaError> {
+        let wildcard = args.iter().any(|arg| matches!(arg, Expr::Wildcard));
+        check_arity(self.name(), args.len(), wildcard).map_err(FormulaError)
+    }
+
+    pub fn apply<T: FormulaValue + Float>(
+        &self,
+        values: &[Option<T>],
+        options: &EngineOptions,
+    ) -> Option<T> {
+        let round = |x| options.rounding_mode().round(x);
         match self {
-            Function::Coalesce => values
+            Function::Coalesce
+            | Function::Min
+            | Function::Max
+            | Function::MinStrict
+            | Function::MaxStrict
+            | Function::If
+            | Function::Case
+            | Function::NullIf => self.select(values).and_then(|i| values[i]),
+            Function::Pow => Op::Pow.apply(values[0], values[1]),
+            Function::Pos => values[0].map(|x| x.max(T::zero())),
+            Function::Neg => values[0].map(|x| x.min(T::zero())),
+            Function::Hypot => values
                 .iter()
-                .copied()
-                .find(Option::is_some)
-                .unwrap_or_default(),
-            // Option::min defines None as the smallest value, so we need to handle this case separately
-            Function::Min => values.iter().copied().fold(None, |acc, x| match (acc, x) {
-                (Some(acc), Some(x)) => match acc.partial_cmp(&x) {
-                    Some(std::cmp::Ordering::Less) => Some(acc),
-                    _ => Some(x),
+                .try_fold(T::zero(), |acc, x| x.map(|x| acc.hypot(x))),
+            Function::Sin => values[0].map(T::sin),
+            Function::Cos => values[0].map(T::cos),
+            Function::Tan => values[0].map(T::tan),
+            Function::Atan2 => Some(values[0]?.atan2(values[1]?)),
+            Function::Lerp => {
+                let (a, b, t) = (values[0]?, values[1]?, values[2]?);
+                Some(a + (b - a) * t)
+            }
+            Function::Kw | Function::Mw | Function::Kwh => Some(values[0]? * self.unit_scale()?),
+            // Horner's scheme: c0 + x(c1 + x(c2 + ...))
+            Function::Poly => {
+                let x = values[0]?;
+                values[1..]
+                    .iter()
+                    .rev()
+                    .try_fold(T::zero(), |acc, c| c.map(|c| acc * x + c))
+            }
+            Function::Curve => Self::curve(
+                values[0]?,
+                &values[1..]
+                    .chunks(2)
+                    .map(|point| Some((point[0]?, point[1]?)))
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            Function::Round => match values {
+                [x] => x.map(round),
+                // Round to the given number of decimal digits.
+                [x, digits] => match (x, digits) {
+                    (Some(x), Some(digits)) => {
+                        let scale = T::from_u8(10)?.powf(digits.round());
+                        Some(round(*x * scale) / scale)
+                    }
+                    _ => None,
                 },
-                (Some(acc), None) => Some(acc),
-                (None, Some(x)) => Some(x),
-                (None, None) => None,
-            }),
-            Function::Max => values.iter().copied().fold(None, |acc, x| match (acc, x) {
-                (Some(acc), Some(x)) => match acc.partial_cmp(&x) {
-                    Some(std::cmp::Ordering::Greater) => Some(acc),
-                    _ => Some(x),
+                _ => None,
+            },
+            // Round to the nearest multiple of a positive step.
+            Function::Quantize => {
+                let (x, step) = (values[0]?, values[1]?);
+                (step > T::zero()).then(|| round(x / step) * step)
+            }
+            Function::Floor => values[0].map(T::floor),
+            Function::Ceil => values[0].map(T::ceil),
+            Function::Sum => values.iter().flatten().copied().reduce(|acc, x| acc + x),
+            Function::Product => values
+                .iter()
+                .try_fold(T::one(), |acc, x| x.map(|x| acc * x)),
+            Function::IsNone => Some(from_bool(values[0].is_none())),
+            Function::IsSome => Some(from_bool(values[0].is_some())),
+            Function::CountSome => T::from_usize(values.iter().flatten().count()),
+        }
+    }
+
+    /// Whether the function evaluates to one of its arguments, as chosen by
+    /// [`Function::select`].
+    pub(crate) fn is_selecting(&self) -> bool {
+        matches!(
+            self,
+            Function::Coalesce
+                | Function::Min
+                | Function::Max
+                | Function::MinStrict
+                | Function::MaxStrict
+                | Function::If
+                | Function::Case
+                | Function::NullIf
+        )
+    }
+
+    /// Get the index of the argument a selecting function like MIN evaluates
+    /// to, or `None` if all arguments are `None`.
+    ///
+    /// For IF and CASE, values after the selected branch's condition are
+    /// not needed.
+    pub(crate) fn select<T: FormulaValue>(&self, values: &[Option<T>]) -> Option<usize> {
+        match self {
+            Function::Coalesce => values.iter().position(Option::is_some),
+            Function::If | Function::Case => {
+                Self::select_branch(values.len(), |i| Ok::<_, Infallible>(values[i]))
+                    .unwrap_or_else(|never| match never {})
+            }
+            Function::Min => Self::select_by(values, std::cmp::Ordering::Less),
+            Function::Max => Self::select_by(values, std::cmp::Ordering::Greater),
+            // The strict variants are `None` if any argument is.
+            Function::MinStrict if values.iter().all(Option::is_some) => {
+                Self::select_by(values, std::cmp::Ordering::Less)
+            }
+            Function::MaxStrict if values.iter().all(Option::is_some) => {
+                Self::select_by(values, std::cmp::Ordering::Greater)
+            }
+            Function::NullIf => match (values[0], values[1]) {
+                (Some(a), Some(b)) if a == b => None,
+                (a, _) => a.map(|_| 0),
+            },
+            // The other functions don't evaluate to one of their arguments.
+            _ => None,
+        }
+    }
+
+    /// Get the factor a unit-scaling function converts to base units with,
+    /// e.g. from kW to W.
+    fn unit_scale<T: FormulaValue>(&self) -> Option<T> {
+        match self {
+            Function::Kw | Function::Kwh => T::from_f64(1e3),
+            Function::Mw => T::from_f64(1e6),
+            _ => None,
+        }
+    }
+
+    /// Interpolate linearly between the points of a curve, given in
+    /// ascending order of their x coordinates, clamping to the end points.
+    fn curve<T: FormulaValue>(x: T, points: &[(T, T)]) -> Option<T> {
+        if points.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+            return None;
+        }
+        let (first, last) = (points.first()?, points.last()?);
+        if x <= first.0 {
+            return Some(first.1);
+        }
+        if x >= last.0 {
+            return Some(last.1);
+        }
+        points.windows(2).find_map(|pair| {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            (x <= x1).then(|| y0 + (y1 - y0) * (x - x0) / (x1 - x0))
+        })
+    }
+
+    /// Select the branch of the first condition that is true, or the trailing
+    /// default if there is one. A `None` condition makes the result `None`.
+    ///
+    /// Arguments alternate between conditions and their branches, as in
+    /// `CASE(cond1, val1, cond2, val2, default)`.
+    pub(crate) fn select_branch<T: FormulaValue, E>(
+        len: usize,
+        mut condition: impl FnMut(usize) -> Result<Option<T>, E>,
+    ) -> Result<Option<usize>, E> {
+        let mut i = 0;
+        while Self::is_condition(i, len) {
+            match condition(i)? {
+                Some(c) if c != T::zero() => return Ok(Some(i + 1)),
+                Some(_) => i += 2,
+                None => return Ok(None),
+            }
+        }
+        Ok((i < len).then_some(i))
+    }
+
+    /// Whether the argument at index `i` of an IF or CASE is a condition.
+    pub(crate) fn is_condition(i: usize, len: usize) -> bool {
+        i.is_multiple_of(2) && i + 1 < len
+    }
+
+    /// Select the index of the value that compares as `keep` against all
+    /// others, preferring later values on ties.
+    // Option::min defines None as the smallest value, so we need to handle this case separately
+    fn select_by<T: Copy + PartialOrd>(
+        values: &[Option<T>],
+        keep: std::cmp::Ordering,
+    ) -> Option<usize> {
+        values
+            .iter()
+            .enumerate()
+            .fold(None, |acc: Option<(usize, T)>, (i, x)| match (acc, x) {
+                (Some((j, acc)), Some(x)) => match acc.partial_cmp(x) {
+                    Some(ordering) if ordering == keep => Some((j, acc)),
+                    _ => Some((i, *x)),
                 },
                 (Some(acc), None) => Some(acc),
-                (None, Some(x)) => Some(x),
+                (None, Some(x)) => Some((i, *x)),
                 (None, None) => None,
+            })
+            .map(|(i, _)| i)
+    }
+}
+
+/// Functions of the current time, as given by the engine's clock.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TimeFunction {
+    /// Seconds since the Unix epoch.
+    Now,
+    /// Hour of the day, from 0 to 23.
+    Hour,
+    /// Day of the week, from 1 (Monday) to 7 (Sunday).
+    DayOfWeek,
+}
+
+impl TimeFunction {
+    /// The upper-case name of the function, as written in formulas.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimeFunction::Now => "NOW",
+            TimeFunction::Hour => "HOUR",
+            TimeFunction::DayOfWeek => "DAYOFWEEK",
+        }
+    }
+
+    /// Get the function with the given upper-case name, as returned by
+    /// [`TimeFunction::name`].
+    pub fn from_name(name: &str) -> Option<TimeFunction> {
+        Some(match name {
+            "NOW" => TimeFunction::Now,
+            "HOUR" => TimeFunction::Hour,
+            "DAYOFWEEK" => TimeFunction::DayOfWeek,
+            _ => return None,
+        })
+    }
+
+    pub fn apply<T: FromPrimitive>(&self, options: &EngineOptions) -> Option<T> {
+        match self {
+            TimeFunction::Now => T::from_f64(match options.now().duration_since(UNIX_EPOCH) {
+                Ok(duration) => duration.as_secs_f64(),
+                Err(err) => -err.duration().as_secs_f64(),
             }),
+            TimeFunction::Hour => T::from_u32(options.local_time().seconds_of_day / 3600),
+            TimeFunction::DayOfWeek => T::from_u8(options.local_time().weekday as u8 + 1),
+        }
+    }
+}
+
+/// Functions of the samples of their first argument over time.
+///
+/// They are calculated by stateful engines, like
+/// [`StreamingFormulaEngine`](crate::StreamingFormulaEngine), which take a
+/// sample of the argument whenever they calculate the formula. Samples
+/// without a value are left out. Calculating them with
+/// [`FormulaEngine::calculate`](crate::FormulaEngine::calculate) is an error.
+///
+/// Windows are given in seconds, which can be written as durations, e.g.
+/// `30s`, `5m` or `1h`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum TemporalFunction {
+    /// The mean of the samples in a window, e.g. `ROLLING_AVG(#0, 30s)`.
+    RollingAvg,
+    /// The minimum of the samples in a window.
+    RollingMin,
+    /// The maximum of the samples in a window.
+    RollingMax,
+    /// The change per second from the previous sample, e.g. `DERIV(#0)`.
+    Deriv,
+    /// The integral over time in hours, e.g. the energy in Wh of a power in
+    /// W, with the trapezoidal rule. It restarts from zero while the
+    /// optional second argument is true, e.g. `INTEGRATE(#0, HOUR() == 0)`.
+    Integrate,
+    /// The value of the sample the optional second argument, by default 1,
+    /// samples before the last one, e.g. `LAG(#0, 2)`.
+    Lag,
+    /// The change of the argument from its previous sample, e.g. the energy
+    /// since the last sample of a meter's energy register, `DELTA(#0)`.
+    Delta,
+    /// The change of the first argument from its previous sample, for
+    /// counters that wrap around to zero at the second argument, e.g.
+    /// `DELTA_WRAP(#0, 65536)` for a 16-bit register.
+    DeltaWrap,
+    /// The first argument, limited to change by at most the second argument
+    /// per second from the previous result, e.g. `RAMP_LIMIT(#0, 100)`.
+    RampLimit,
+    /// The average of the first argument over the window of the second
+    /// argument in seconds, weighted by how long each sample held, e.g.
+    /// `TW_AVG(#0, 15m)`.
+    TwAvg,
+}
+
+impl TemporalFunction {
+    fn from_rule(rule: Rule) -> Option<TemporalFunction> {
+        Some(match rule {
+            Rule::rolling_avg => TemporalFunction::RollingAvg,
+            Rule::rolling_min => TemporalFunction::RollingMin,
+            Rule::rolling_max => TemporalFunction::RollingMax,
+            Rule::deriv => TemporalFunction::Deriv,
+            Rule::integrate => TemporalFunction::Integrate,
+            Rule::lag => TemporalFunction::Lag,
+            Rule::delta => TemporalFunction::Delta,
+            Rule::delta_wrap => TemporalFunction::DeltaWrap,
+            Rule::ramp_limit => TemporalFunction::RampLimit,
+            Rule::tw_avg => TemporalFunction::TwAvg,
+            _ => return None,
+        })
+    }
+
+    /// The upper-case name of the function, as written in formulas.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TemporalFunction::RollingAvg => "ROLLING_AVG",
+            TemporalFunction::RollingMin => "ROLLING_MIN",
+            TemporalFunction::RollingMax => "ROLLING_MAX",
+            TemporalFunction::Deriv => "DERIV",
+            TemporalFunction::Integrate => "INTEGRATE",
+            TemporalFunction::Lag => "LAG",
+            TemporalFunction::Delta => "DELTA",
+            TemporalFunction::DeltaWrap => "DELTA_WRAP",
+            TemporalFunction::RampLimit => "RAMP_LIMIT",
+            TemporalFunction::TwAvg => "TW_AVG",
         }
     }
+
+    /// Get the function with the given upper-case name, as returned by
+    /// [`TemporalFunction::name`].
+    pub fn from_name(name: &str) -> Option<TemporalFunction> {
+        Some(match name {
+            "ROLLING_AVG" => TemporalFunction::RollingAvg,
+            "ROLLING_MIN" => TemporalFunction::RollingMin,
+            "ROLLING_MAX" => TemporalFunction::RollingMax,
+            "DERIV" => TemporalFunction::Deriv,
+            "INTEGRATE" => TemporalFunction::Integrate,
+            "LAG" => TemporalFunction::Lag,
+            "DELTA" => TemporalFunction::Delta,
+            "DELTA_WRAP" => TemporalFunction::DeltaWrap,
+            "RAMP_LIMIT" => TemporalFunction::RampLimit,
+            "TW_AVG" => TemporalFunction::TwAvg,
+            _ => return None,
+        })
+    }
+
+    /// Check that the function is called with a valid number of arguments.
+    fn validate_arity<T>(&self, args: &[Expr<T>]) -> Result<(), FormulaError> {
+        check_arity(self.name(), args.len(), false).map_err(FormulaError)
+    }
+
+    /// The error of calculating the function without a stateful engine.
+    pub(crate) fn stateless_error(&self) -> FormulaError {
+        FormulaError(format!(
+            "{} can only be calculated by stateful engines, e.g. FormulaEngine::streaming",
+            self.name()
+        ))
+    }
 }
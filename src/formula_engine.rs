@@ -1,61 +1,307 @@
 // License: MIT
 // Copyright © 2024 Frequenz Energy-as-a-Service GmbH
 
-use std::collections::{HashMap, HashSet};
-use std::fmt::Debug;
 use std::{
-    ops::{Add, Div, Mul, Neg, Sub},
-    str::FromStr,
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
 };
 
-use pest::{iterators::Pairs, Parser};
+use num_traits::Float;
+use pest::Parser;
 
 use crate::{
+    display::named_placeholder,
     error::FormulaError,
-    expression::Expr,
+    expression::{Expr, Inputs, Provided, SortedValues, ValueProvider},
+    functions::FunctionRegistry,
+    memo::ResultCache,
+    options::EngineOptions,
     parser::{FormulaParser, Rule},
+    value::FormulaValue,
+    vm::Program,
 };
 
 /// FormulaEngine holds the parsed expression and can calculate the result
 /// based on the provided component values.
-#[derive(Debug)]
+///
+/// Engines are equal if their expressions are; their options and custom
+/// functions aren't compared.
+///
+/// Engines over `f32` and `f64` are `Send` and `Sync`, as custom functions
+/// and clocks must be, so one engine can be shared by several threads.
+#[derive(Debug, Clone)]
 pub struct FormulaEngine<T> {
     expr: Expr<T>,
-    components: HashSet<usize>,
+    components: HashSet<u64>,
+    names: HashSet<String>,
+    references: HashSet<String>,
+    program: Program<T>,
+    cache: Option<ResultCache<T>>,
+    pub(crate) options: EngineOptions,
+    pub(crate) functions: FunctionRegistry<T>,
 }
 
-impl<
-        'a,
-        T: FromStr
-            + Copy
-            + Neg<Output = T>
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Div<Output = T>
-            + PartialOrd,
-    > FormulaEngine<T>
-where
-    Expr<T>: TryFrom<Pairs<'a, Rule>>,
-    <T as FromStr>::Err: Debug,
-    FormulaError: From<<Expr<T> as TryFrom<Pairs<'a, Rule>>>::Error>,
-{
+impl<T: FormulaValue> PartialEq for FormulaEngine<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr
+    }
+}
+
+impl<T: FormulaValue> Eq for FormulaEngine<T> {}
+
+impl<T: FormulaValue + Float> Hash for FormulaEngine<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expr.hash(state);
+    }
+}
+
+/// A [`FormulaEngine`] over `f32` values.
+///
+/// Being an alias, it has all constructors of [`FormulaEngine`] without
+/// naming the value type:
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::{EngineOptions, Formula32};
+///
+/// let fe = Formula32::try_new_with_options("#0 + #1", EngineOptions::default()).unwrap();
+/// assert_eq!(fe.calculate_slice(&[Some(1.0), Some(2.5)]).unwrap(), Some(3.5));
+/// ```
+pub type Formula32 = FormulaEngine<f32>;
+
+/// A [`FormulaEngine`] over `f64` values, with all of its constructors, like
+/// `Formula64::try_new`.
+pub type Formula64 = FormulaEngine<f64>;
+
+impl<T: FormulaValue> FormulaEngine<T> {
     /// Create a new FormulaEngine from a formula string.
-    pub fn try_new(s: &'a str) -> Result<Self, FormulaError> {
+    pub fn try_new(s: &str) -> Result<Self, FormulaError> {
+        Self::try_new_with_options(s, EngineOptions::default())
+    }
+
+    /// Create a new FormulaEngine from a formula string, evaluating it with
+    /// the given options.
+    pub fn try_new_with_options(s: &str, options: EngineOptions) -> Result<Self, FormulaError> {
+        Self::try_new_with_functions(s, options, FunctionRegistry::default())
+    }
+
+    /// Create a new FormulaEngine from a formula string that can call the
+    /// given custom functions, evaluating it with the given options.
+    pub fn try_new_with_functions(
+        s: &str,
+        options: EngineOptions,
+        functions: FunctionRegistry<T>,
+    ) -> Result<Self, FormulaError> {
+        functions.validate()?;
         let pairs = FormulaParser::parse(Rule::formula, s)?;
-        let expr = Expr::try_from(pairs)?;
+        let expr = Expr::parse(pairs, &functions)?;
+        Self::try_from_parts(expr, options, functions)
+    }
+
+    /// Create a new FormulaEngine from an expression built in code, evaluating
+    /// it with the given options.
+    pub fn try_from_expr(expr: Expr<T>, options: EngineOptions) -> Result<Self, FormulaError> {
+        Self::try_from_parts(expr, options, FunctionRegistry::default())
+    }
+
+    fn try_from_parts(
+        expr: Expr<T>,
+        options: EngineOptions,
+        functions: FunctionRegistry<T>,
+    ) -> Result<Self, FormulaError> {
+        expr.validate(&options, &functions)?;
         let components = expr.components();
+        let names = expr.names();
+        let references = expr.references();
+        let program = Program::compile(&expr, &options);
+        let cache = options.memoize().then(|| ResultCache::new(&expr)).flatten();
 
-        Ok(Self { expr, components })
+        Ok(Self {
+            expr,
+            components,
+            names,
+            references,
+            program,
+            cache,
+            options,
+            functions,
+        })
+    }
+
+    /// Get the parsed expression of the formula.
+    pub fn expr(&self) -> &Expr<T> {
+        &self.expr
     }
 
     /// Get the components of the formula.
-    pub fn components(&self) -> &HashSet<usize> {
+    ///
+    /// Components only covered by a `#*` placeholder aren't included.
+    pub fn components(&self) -> &HashSet<u64> {
         &self.components
     }
 
-    /// Calculate the result of the formula based on the provided component values.
-    pub fn calculate(&self, values: HashMap<usize, Option<T>>) -> Result<Option<T>, FormulaError> {
-        self.expr.calculate(&values)
+    /// Get the names of the formula's `$name` placeholders and the IDs of its
+    /// `#"id"` placeholders.
+    pub fn names(&self) -> &HashSet<String> {
+        &self.names
+    }
+
+    /// Get the names of the formulas the formula references as `@name`.
+    pub fn references(&self) -> &HashSet<String> {
+        &self.references
+    }
+
+    /// Calculate the result of the formula based on the provided component
+    /// values, e.g. a `HashMap` from component IDs to values.
+    pub fn calculate(&self, values: impl ValueProvider<T>) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate_inputs(&Provided(values))
+    }
+
+    /// Calculate the result of the formula based on the provided pairs of
+    /// component IDs and values, without building a `HashMap` of them.
+    ///
+    /// If a component is given several times, its last value is used.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + #2").unwrap();
+    /// let result = fe.calculate_iter([(2, Some(2.0)), (0, Some(1.0))]);
+    /// assert_eq!(result.unwrap(), Some(3.0));
+    /// ```
+    pub fn calculate_iter(
+        &self,
+        values: impl IntoIterator<Item = (u64, Option<T>)>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate(values.into_iter().collect::<SortedValues<T>>())
+    }
+
+    /// Calculate the result of the formula based on the provided component
+    /// values, indexed by component ID.
+    ///
+    /// This avoids building a `HashMap` for each calculation if component IDs
+    /// are small. Like all values given, those of components the formula
+    /// doesn't use are included in `#*`.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + #2").unwrap();
+    /// let result = fe.calculate_slice(&[Some(1.0), None, Some(2.0)]);
+    /// assert_eq!(result.unwrap(), Some(3.0));
+    /// ```
+    pub fn calculate_slice(&self, values: &[Option<T>]) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate(values)
+    }
+
+    pub(crate) fn calculate_inputs(
+        &self,
+        values: &impl Inputs<T>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        if self.options.strict() {
+            self.check_values(values)?;
+        }
+        let calculate = || self.program.run(values, &self.options, &self.functions);
+        match &self.cache {
+            Some(cache) => cache.get_or_calculate(values, calculate),
+            None => calculate(),
+        }
+    }
+
+    /// Check that all placeholders of the formula have values that aren't
+    /// `None`, listing those that don't otherwise.
+    pub(crate) fn check_values(&self, values: &impl Inputs<T>) -> Result<(), FormulaError>
+    where
+        T: Float,
+    {
+        let is_missing = |value: Option<Option<T>>| {
+            value
+                .and_then(|value| self.options.placeholder_value(value))
+                .is_none()
+        };
+        let mut components: Vec<_> = self
+            .components
+            .iter()
+            .filter(|id| is_missing(values.component(**id)))
+            .collect();
+        components.sort();
+        let mut names: Vec<_> = self
+            .names
+            .iter()
+            .filter(|name| is_missing(values.named(name)))
+            .collect();
+        names.sort();
+
+        let missing: Vec<_> = components
+            .into_iter()
+            .map(|id| format!("#{}", id))
+            .chain(names.into_iter().map(|name| named_placeholder(name)))
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(FormulaError(format!(
+                "Missing values for {}",
+                missing.join(", ")
+            )))
+        }
+    }
+
+    /// Calculate the result of the formula based on the provided values for
+    /// its `$name` placeholders, given without the `$`, and its `#"id"`
+    /// placeholders, given by ID.
+    pub fn calculate_named(
+        &self,
+        values: HashMap<String, Option<T>>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate_inputs(&values)
+    }
+
+    /// Create a new FormulaEngine for the partial derivative of the formula
+    /// with respect to the given component.
+    ///
+    /// The derivatives of MIN, MAX and COALESCE are piecewise: they are the
+    /// derivative of whichever argument the function selects.
+    pub fn derivative(&self, component: u64) -> Result<Self, FormulaError> {
+        Ok(self.with_expr(self.expr.derivative(component)?))
+    }
+
+    /// Create a new FormulaEngine for an expression derived from the
+    /// formula's, with the same options and custom functions.
+    pub(crate) fn with_expr(&self, expr: Expr<T>) -> Self {
+        let components = expr.components();
+        let names = expr.names();
+        let references = expr.references();
+        let program = Program::compile(&expr, &self.options);
+        let cache = self
+            .options
+            .memoize()
+            .then(|| ResultCache::new(&expr))
+            .flatten();
+
+        Self {
+            expr,
+            components,
+            names,
+            references,
+            program,
+            cache,
+            options: self.options.clone(),
+            functions: self.functions.clone(),
+        }
     }
 }
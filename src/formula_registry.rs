@@ -0,0 +1,294 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use num_traits::Float;
+
+use crate::{
+    display::is_identifier,
+    error::FormulaError,
+    expression::{Inputs, Provided, ValueProvider},
+    formula_engine::FormulaEngine,
+    streaming::StreamingFormulaEngine,
+    value::FormulaValue,
+};
+
+/// Formulas registered under unique names, e.g. the formulas of a site.
+///
+/// Formulas can use the results of other formulas of the registry as
+/// `@name`, e.g. `@grid_power - @pv_power`. They can reference formulas that
+/// are registered later, but not themselves, directly or indirectly.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::{FormulaEngine, FormulaRegistry};
+/// use std::collections::HashMap;
+///
+/// let mut formulas = FormulaRegistry::new();
+/// formulas
+///     .register("grid_power", FormulaEngine::<f64>::try_new("#0 + #1").unwrap())
+///     .unwrap();
+/// let result = formulas.calculate("grid_power", HashMap::from([(0, Some(1.0)), (1, Some(2.0))]));
+/// assert_eq!(result.unwrap(), Some(3.0));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FormulaRegistry<T> {
+    formulas: HashMap<String, FormulaEngine<T>>,
+    /// The maximum ages of the component values of the formulas' streaming
+    /// engines.
+    max_ages: HashMap<String, Duration>,
+}
+
+impl<T> Default for FormulaRegistry<T> {
+    fn default() -> Self {
+        Self {
+            formulas: HashMap::new(),
+            max_ages: HashMap::new(),
+        }
+    }
+}
+
+impl<T: FormulaValue> FormulaRegistry<T> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a formula under a name, which must be unique.
+    ///
+    /// Names consist of ASCII letters, digits and underscores, and don't
+    /// start with a digit, like `grid_power`.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        formula: FormulaEngine<T>,
+    ) -> Result<(), FormulaError> {
+        let name = name.into();
+        if !is_identifier(&name) {
+            return Err(FormulaError(format!("Invalid formula name: {}", name)));
+        }
+        if self.formulas.contains_key(&name) {
+            return Err(FormulaError(format!(
+                "Formula already registered: {}",
+                name
+            )));
+        }
+        if let Some(path) = self.reference_path(&formula, &name, &mut HashSet::new()) {
+            return Err(FormulaError(format!(
+                "Circular formula reference: @{} -> @{}",
+                name,
+                path.join(" -> @")
+            )));
+        }
+        self.formulas.insert(name, formula);
+        Ok(())
+    }
+
+    /// Remove a formula, returning it if it was registered.
+    pub fn remove(&mut self, name: &str) -> Option<FormulaEngine<T>> {
+        self.max_ages.remove(name);
+        self.formulas.remove(name)
+    }
+
+    /// Set the maximum age of the component values of the streaming engine
+    /// of a formula, from [`streaming`](Self::streaming).
+    pub fn set_max_age(&mut self, name: &str, max_age: Duration) -> Result<(), FormulaError> {
+        self.get(name)?;
+        self.max_ages.insert(name.to_string(), max_age);
+        Ok(())
+    }
+
+    /// Get the maximum age of the component values of a formula, if set.
+    pub fn max_age(&self, name: &str) -> Option<Duration> {
+        self.max_ages.get(name).copied()
+    }
+
+    /// Create a [`StreamingFormulaEngine`] for the formula registered under a
+    /// name, with its maximum age.
+    pub fn streaming(&self, name: &str) -> Result<StreamingFormulaEngine<T>, FormulaError>
+    where
+        T: Float,
+    {
+        let streaming = self.get(name)?.streaming();
+        Ok(match self.max_age(name) {
+            Some(max_age) => streaming.with_max_age(max_age),
+            None => streaming,
+        })
+    }
+
+    /// Get the formula registered under a name.
+    pub fn get(&self, name: &str) -> Result<&FormulaEngine<T>, FormulaError> {
+        self.formulas
+            .get(name)
+            .ok_or_else(|| FormulaError(format!("Unknown formula: {}", name)))
+    }
+
+    /// Whether a formula is registered under a name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.formulas.contains_key(name)
+    }
+
+    /// Get the names of the registered formulas, in arbitrary order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.formulas.keys().map(String::as_str)
+    }
+
+    /// Get the number of registered formulas.
+    pub fn len(&self) -> usize {
+        self.formulas.len()
+    }
+
+    /// Whether no formulas are registered.
+    pub fn is_empty(&self) -> bool {
+        self.formulas.is_empty()
+    }
+
+    /// Calculate the result of the formula registered under a name, based on
+    /// the component values of a [`ValueProvider`], e.g. a `HashMap`,
+    /// together with the formulas it references.
+    pub fn calculate(
+        &self,
+        name: &str,
+        values: impl ValueProvider<T>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate_into(name, &Provided(values), &mut HashMap::new())
+    }
+
+    /// Calculate the result of the formula registered under a name, based on
+    /// the values of its `$name` and `#"id"` placeholders, like
+    /// [`FormulaEngine::calculate_named`], together with the formulas it
+    /// references.
+    pub fn calculate_named(
+        &self,
+        name: &str,
+        values: HashMap<String, Option<T>>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate_into(name, &values, &mut HashMap::new())
+    }
+
+    /// Calculate the results of all registered formulas, based on the
+    /// component values of a [`ValueProvider`].
+    ///
+    /// Each formula is calculated once, after the formulas it references.
+    pub fn calculate_all(
+        &self,
+        values: impl ValueProvider<T>,
+    ) -> Result<HashMap<String, Option<T>>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate_all_inputs(&Provided(values))
+    }
+
+    /// Calculate the results of all registered formulas, based on the values
+    /// of their `$name` and `#"id"` placeholders.
+    pub fn calculate_all_named(
+        &self,
+        values: HashMap<String, Option<T>>,
+    ) -> Result<HashMap<String, Option<T>>, FormulaError>
+    where
+        T: Float,
+    {
+        self.calculate_all_inputs(&values)
+    }
+
+    fn calculate_all_inputs(
+        &self,
+        values: &dyn Inputs<T>,
+    ) -> Result<HashMap<String, Option<T>>, FormulaError>
+    where
+        T: Float,
+    {
+        let mut results = HashMap::with_capacity(self.formulas.len());
+        for name in self.formulas.keys() {
+            self.calculate_into(name, values, &mut results)?;
+        }
+        Ok(results)
+    }
+
+    /// Calculate the result of a formula, after the formulas it references,
+    /// unless it already is in `results`.
+    fn calculate_into(
+        &self,
+        name: &str,
+        values: &dyn Inputs<T>,
+        results: &mut HashMap<String, Option<T>>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        if let Some(result) = results.get(name) {
+            return Ok(*result);
+        }
+        let formula = self.get(name)?;
+        for reference in formula.references() {
+            self.calculate_into(reference, values, results)?;
+        }
+        let result = formula.calculate_inputs(&WithResults {
+            inputs: values,
+            results,
+        })?;
+        results.insert(name.to_string(), result);
+        Ok(result)
+    }
+
+    /// Find a chain of references from a formula to the formula of the given
+    /// name, skipping the formulas already visited.
+    fn reference_path(
+        &self,
+        formula: &FormulaEngine<T>,
+        name: &str,
+        visited: &mut HashSet<String>,
+    ) -> Option<Vec<String>> {
+        let mut references: Vec<_> = formula.references().iter().collect();
+        references.sort();
+        for reference in references {
+            if reference == name {
+                return Some(vec![reference.clone()]);
+            }
+            if !visited.insert(reference.clone()) {
+                continue;
+            }
+            if let Some(referenced) = self.formulas.get(reference) {
+                if let Some(mut path) = self.reference_path(referenced, name, visited) {
+                    path.insert(0, reference.clone());
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Component values with the results of the formulas calculated so far.
+struct WithResults<'a, T> {
+    inputs: &'a dyn Inputs<T>,
+    results: &'a HashMap<String, Option<T>>,
+}
+
+impl<T: Copy> Inputs<T> for WithResults<'_, T> {
+    fn component(&self, id: u64) -> Option<Option<T>> {
+        self.inputs.component(id)
+    }
+
+    fn named(&self, name: &str) -> Option<Option<T>> {
+        self.inputs.named(name)
+    }
+
+    fn all(&self) -> Option<Vec<Option<T>>> {
+        self.inputs.all()
+    }
+
+    fn reference(&self, name: &str) -> Option<Option<T>> {
+        self.results.get(name).copied()
+    }
+}
@@ -0,0 +1,156 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use pest::Parser;
+
+use crate::{
+    error::FormulaError,
+    parser::{FormulaParser, Rule},
+    syntax::{Associativity, Precedence},
+};
+
+/// The implementation of a custom function, taking the values of its
+/// arguments.
+pub type CustomFunction<T> = dyn Fn(&[Option<T>]) -> Option<T> + Send + Sync;
+
+/// The implementation of a custom infix operator, taking the values of its
+/// operands.
+pub type CustomOperator<T> = dyn Fn(Option<T>, Option<T>) -> Option<T> + Send + Sync;
+
+/// A registered custom infix operator.
+pub(crate) struct Operator<T> {
+    pub(crate) precedence: Precedence,
+    pub(crate) associativity: Associativity,
+    pub(crate) function: Arc<CustomOperator<T>>,
+}
+
+impl<T> Clone for Operator<T> {
+    fn clone(&self) -> Self {
+        Self {
+            precedence: self.precedence,
+            associativity: self.associativity,
+            function: self.function.clone(),
+        }
+    }
+}
+
+/// Custom functions and infix operators that formulas can use, in addition
+/// to the built-in ones.
+#[derive(Clone)]
+pub struct FunctionRegistry<T> {
+    functions: HashMap<String, Arc<CustomFunction<T>>>,
+    operators: HashMap<String, Operator<T>>,
+}
+
+impl<T> Default for FunctionRegistry<T> {
+    fn default() -> Self {
+        Self {
+            functions: HashMap::new(),
+            operators: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Debug for FunctionRegistry<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FunctionRegistry")
+            .field("functions", &self.functions.keys())
+            .field("operators", &self.operators.keys())
+            .finish()
+    }
+}
+
+impl<T> FunctionRegistry<T> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function that formulas can call as `name(arg, ...)`.
+    ///
+    /// Names are case-sensitive identifiers of letters, digits and `_`,
+    /// not starting with a digit. They can't shadow built-in functions, whose
+    /// names are case-insensitive, or keywords like `NOT`. Engines created
+    /// with a registry holding other names fail to build.
+    ///
+    /// Arguments support the same `#first..#last` ranges and `#*` placeholder
+    /// as the built-in variadic functions.
+    pub fn register(
+        mut self,
+        name: impl Into<String>,
+        function: impl Fn(&[Option<T>]) -> Option<T> + Send + Sync + 'static,
+    ) -> Self {
+        self.functions.insert(name.into(), Arc::new(function));
+        self
+    }
+
+    /// Register an infix operator that formulas can use as `lhs symbol rhs`,
+    /// binding like the built-in operators of the given precedence.
+    ///
+    /// Symbols start with `~`, `?`, `:` or a non-ASCII math symbol like `⊖`,
+    /// optionally followed by more of these or the characters of the built-in
+    /// operators, e.g. `~-` or `?:`. Engines created with a registry holding
+    /// other symbols fail to build.
+    pub fn register_operator(
+        mut self,
+        symbol: impl Into<String>,
+        precedence: Precedence,
+        associativity: Associativity,
+        function: impl Fn(Option<T>, Option<T>) -> Option<T> + Send + Sync + 'static,
+    ) -> Self {
+        self.operators.insert(
+            symbol.into(),
+            Operator {
+                precedence,
+                associativity,
+                function: Arc::new(function),
+            },
+        );
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&CustomFunction<T>> {
+        self.functions.get(name).map(Arc::as_ref)
+    }
+
+    pub(crate) fn operator(&self, symbol: &str) -> Option<&Operator<T>> {
+        self.operators.get(symbol)
+    }
+
+    /// Check that all function names and operator symbols can be used in
+    /// formulas.
+    pub(crate) fn validate(&self) -> Result<(), FormulaError> {
+        for name in self.functions.keys() {
+            let call = format!("{}(", name);
+            let valid = FormulaParser::parse(Rule::custom_name, &call)
+                .is_ok_and(|pairs| pairs.as_str() == call);
+            if !valid {
+                return Err(FormulaError(format!("Invalid function name: {}", name)));
+            }
+            // Built-in functions and keywords take precedence over custom
+            // functions, so a call must parse as the custom function.
+            let call = format!("{}(0)", name);
+            let custom = FormulaParser::parse(Rule::formula, &call)
+                .ok()
+                .and_then(|mut pairs| pairs.next())
+                .and_then(|expr| expr.into_inner().next())
+                .is_some_and(|primary| primary.as_rule() == Rule::custom);
+            if !custom {
+                return Err(FormulaError(format!(
+                    "Function name {} is taken by a built-in function or keyword",
+                    name
+                )));
+            }
+        }
+        for symbol in self.operators.keys() {
+            let valid = FormulaParser::parse(Rule::custom_op, symbol)
+                .is_ok_and(|pairs| pairs.as_str() == symbol);
+            if !valid {
+                return Err(FormulaError(format!("Invalid operator symbol: {}", symbol)));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,525 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    ops::Neg,
+    time::SystemTime,
+};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{from_bool, Expr, Function, Op, TemporalFunction},
+    formula_engine::FormulaEngine,
+    options::DivisionByZero,
+    temporal::{StateSnapshot, TemporalState},
+    value::{same_value, FormulaValue, Sample},
+};
+
+/// The result of a part of a formula.
+type NodeResult<T> = Result<Option<T>, FormulaError>;
+
+/// A formula that keeps the latest value of each component and the results
+/// of all parts of the formula, so that an update of a component only
+/// recalculates the parts that depend on it.
+///
+/// Parts using `#*`, the clock or custom functions are recalculated on
+/// every update. The results are those [`FormulaEngine::calculate`] gives
+/// for the latest values.
+///
+/// [Temporal functions](crate::TemporalFunction) take a sample of their
+/// arguments whenever a component these depend on is updated.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::FormulaEngine;
+///
+/// let fe = FormulaEngine::<f64>::try_new("#0 + MAX(#1, #2)").unwrap();
+/// let mut incremental = fe.incremental();
+/// incremental.update(0, Some(1.0)).ok();
+/// incremental.update(1, Some(2.0)).ok();
+/// assert_eq!(incremental.update(2, Some(5.0)).unwrap(), Some(6.0));
+/// assert_eq!(incremental.update(2, None).unwrap(), Some(3.0));
+/// ```
+#[derive(Debug)]
+pub struct IncrementalEngine<T> {
+    engine: FormulaEngine<T>,
+    values: HashMap<u64, Option<T>>,
+    /// The parts of the formula, each after its operands.
+    nodes: Vec<Node<T>>,
+    /// The nodes of each component's placeholders.
+    readers: HashMap<u64, Vec<usize>>,
+    /// The nodes recalculated on every update.
+    volatile: Vec<usize>,
+    /// Whether each node depends on a volatile node.
+    unstable: Vec<bool>,
+    /// Whether each node is queued for recalculation.
+    queued: Vec<bool>,
+    /// The samples of the temporal functions.
+    states: Vec<TemporalState<T>>,
+    /// The time of the current update.
+    now: SystemTime,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    kind: Kind<T>,
+    operands: Vec<usize>,
+    /// The nodes using the result of this one.
+    dependents: Vec<usize>,
+    result: NodeResult<T>,
+}
+
+/// A part of a formula, with its operands as separate nodes.
+#[derive(Debug)]
+enum Kind<T> {
+    /// A part without operands, calculated like on its own.
+    Leaf(Expr<T>),
+    UnaryMinus,
+    Not,
+    /// An operator, with the error of division by zero.
+    Op(Op, Option<String>),
+    CustomOp(String),
+    /// A function, with the positions of its `#*` arguments as `None`.
+    Function(Function, Vec<Option<usize>>),
+    Custom(String, Vec<Option<usize>>),
+    Let,
+    /// A `LET` variable, with the value as its operand.
+    Variable,
+    Select(Function, usize),
+    /// A temporal function, with the index of its state.
+    Temporal(TemporalFunction, usize),
+}
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Create an [`IncrementalEngine`] for the formula, without any
+    /// component values yet.
+    pub fn incremental(&self) -> IncrementalEngine<T> {
+        let mut incremental = IncrementalEngine {
+            engine: self.clone(),
+            values: HashMap::new(),
+            nodes: Vec::new(),
+            readers: HashMap::new(),
+            volatile: Vec::new(),
+            unstable: Vec::new(),
+            queued: Vec::new(),
+            states: Vec::new(),
+            now: self.options.now(),
+        };
+        incremental.add(self.expr(), &mut Vec::new());
+        incremental.queued = vec![false; incremental.nodes.len()];
+        for i in 0..incremental.nodes.len() {
+            incremental.nodes[i].result = incremental.recalculate(i);
+        }
+        incremental
+    }
+}
+
+impl<T: FormulaValue + Float> IncrementalEngine<T> {
+    /// Get the engine of the formula.
+    pub fn engine(&self) -> &FormulaEngine<T> {
+        &self.engine
+    }
+
+    /// Get the latest values of the components.
+    pub fn values(&self) -> &HashMap<u64, Option<T>> {
+        &self.values
+    }
+
+    /// Set the value of a component, and get the new result of the formula.
+    ///
+    /// Temporal functions take their samples at the time of the clock of the
+    /// engine's options.
+    pub fn update(&mut self, id: u64, value: Option<T>) -> Result<Option<T>, FormulaError> {
+        let now = self.engine.options.now();
+        self.update_at(id, value, now)
+    }
+
+    /// Set the value of a component, and get the new result of the formula,
+    /// with temporal functions taking their samples at `timestamp`.
+    pub fn update_at(
+        &mut self,
+        id: u64,
+        value: Option<T>,
+        timestamp: SystemTime,
+    ) -> Result<Option<T>, FormulaError> {
+        self.now = timestamp;
+        self.values.insert(id, value);
+        let mut queue = BinaryHeap::new();
+        let readers = self.readers.get(&id).into_iter().flatten();
+        for i in readers.chain(&self.volatile) {
+            if !self.queued[*i] {
+                self.queued[*i] = true;
+                queue.push(Reverse(*i));
+            }
+        }
+        // Nodes come after their operands, so that each node is calculated
+        // once, after all its changed operands.
+        while let Some(Reverse(i)) = queue.pop() {
+            self.queued[i] = false;
+            let result = self.recalculate(i);
+            if same_result(&result, &self.nodes[i].result) {
+                continue;
+            }
+            self.nodes[i].result = result;
+            for dependent in &self.nodes[i].dependents {
+                if !self.queued[*dependent] {
+                    self.queued[*dependent] = true;
+                    queue.push(Reverse(*dependent));
+                }
+            }
+        }
+        self.result()
+    }
+
+    /// Set the value of a component from a sample, and get the new result of
+    /// the formula, with temporal functions taking their samples at the
+    /// sample's timestamp.
+    pub fn update_sample(&mut self, id: u64, sample: Sample<T>) -> Result<Option<T>, FormulaError> {
+        self.update_at(id, sample.value, sample.timestamp)
+    }
+
+    /// Forget the samples of the temporal functions, as if they were just
+    /// created. Their results change with the next update.
+    pub fn reset(&mut self) {
+        self.states.fill_with(TemporalState::default);
+    }
+
+    /// Get a snapshot of the samples of the temporal functions, to continue
+    /// with by [`restore`](Self::restore).
+    pub fn state_snapshot(&self) -> StateSnapshot<T> {
+        StateSnapshot {
+            functions: self
+                .temporal_functions()
+                .map(|(function, state)| self.states[state].snapshot(function))
+                .collect(),
+        }
+    }
+
+    /// Continue with the samples of the temporal functions of a snapshot.
+    /// Their results change with the next update.
+    ///
+    /// The snapshot must be of the temporal functions of the same formula.
+    pub fn restore(&mut self, snapshot: &StateSnapshot<T>) -> Result<(), FormulaError> {
+        let functions = self.temporal_functions().map(|(function, _)| function);
+        if !functions.eq(snapshot.functions.iter().map(|state| &state.function)) {
+            return Err(FormulaError(format!(
+                "The state snapshot isn't of the temporal functions of {}",
+                self.engine
+            )));
+        }
+        self.states = snapshot
+            .functions
+            .iter()
+            .map(TemporalState::restore)
+            .collect();
+        Ok(())
+    }
+
+    /// Get the samples of a component kept by the temporal functions of its
+    /// placeholder, e.g. `ROLLING_AVG(#0, 300)`, oldest first, to store them
+    /// and [`seed_history`](Self::seed_history) another engine with them.
+    ///
+    /// The samples are bounded by the maximum history length and age of the
+    /// engine's options.
+    pub fn history(&self, id: u64) -> Vec<(SystemTime, T)> {
+        let samples: BTreeMap<_, _> = self
+            .temporal_functions_of(id)
+            .flat_map(|(_, state)| self.states[state].samples().copied())
+            .collect();
+        samples.into_iter().collect()
+    }
+
+    /// Add samples of a component taken before to the temporal functions of
+    /// its placeholder, e.g. to warm up `ROLLING_AVG(#0, 300)` from stored
+    /// samples after restarting. Their results change with the next update.
+    pub fn seed_history(&mut self, id: u64, samples: &[(SystemTime, T)]) {
+        let states: Vec<_> = self
+            .temporal_functions_of(id)
+            .map(|(function, state)| (function.clone(), state))
+            .collect();
+        for (function, state) in states {
+            self.states[state].seed(&function, samples, &self.engine.options);
+        }
+    }
+
+    /// Get the result of the formula for the latest values.
+    pub fn result(&self) -> Result<Option<T>, FormulaError> {
+        if self.engine.options.strict() {
+            self.engine.check_values(&self.values)?;
+        }
+        self.node_result(self.nodes.len() - 1)
+    }
+
+    /// Get the temporal functions with the indices of their states, in the
+    /// order of the formula.
+    fn temporal_functions(&self) -> impl Iterator<Item = (&TemporalFunction, usize)> {
+        let mut functions: Vec<_> = self
+            .nodes
+            .iter()
+            .filter_map(|node| match &node.kind {
+                Kind::Temporal(function, state) => Some((function, *state)),
+                _ => None,
+            })
+            .collect();
+        functions.sort_by_key(|(_, state)| *state);
+        functions.into_iter()
+    }
+
+    /// Get the temporal functions whose first argument is the placeholder of
+    /// a component, with the indices of their states.
+    fn temporal_functions_of(
+        &self,
+        id: u64,
+    ) -> impl Iterator<Item = (&TemporalFunction, usize)> + '_ {
+        self.nodes.iter().filter_map(move |node| match &node.kind {
+            Kind::Temporal(function, state)
+                if matches!(
+                    self.nodes[node.operands[0]].kind,
+                    Kind::Leaf(Expr::Component(component)) if component == id
+                ) =>
+            {
+                Some((function, *state))
+            }
+            _ => None,
+        })
+    }
+
+    /// Add the nodes of an expression, with the `LET` variables in scope,
+    /// returning the index of its node.
+    fn add<'a>(&mut self, expr: &'a Expr<T>, scope: &mut Vec<(&'a str, usize)>) -> usize {
+        let (kind, operands) = match expr {
+            Expr::UnaryMinus(operand) => (Kind::UnaryMinus, vec![self.add(operand, scope)]),
+            Expr::Not(operand) => (Kind::Not, vec![self.add(operand, scope)]),
+            Expr::Op { lhs, op, rhs } => {
+                let error =
+                    matches!(op, Op::Div | Op::Mod).then(|| format!("Division by zero: {}", expr));
+                let operands = vec![self.add(lhs, scope), self.add(rhs, scope)];
+                (Kind::Op(op.clone(), error), operands)
+            }
+            Expr::CustomOp { symbol, lhs, rhs } => {
+                let operands = vec![self.add(lhs, scope), self.add(rhs, scope)];
+                (Kind::CustomOp(symbol.clone()), operands)
+            }
+            Expr::Function { function, args } => {
+                let args = self.add_args(args, scope);
+                let operands = args.iter().flatten().copied().collect();
+                (Kind::Function(function.clone(), args), operands)
+            }
+            Expr::Custom { name, args } => {
+                let args = self.add_args(args, scope);
+                let operands = args.iter().flatten().copied().collect();
+                (Kind::Custom(name.clone(), args), operands)
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.add(value, scope);
+                scope.push((name, value));
+                let body = self.add(body, scope);
+                scope.pop();
+                (Kind::Let, vec![value, body])
+            }
+            Expr::Variable(name) => match scope.iter().rev().find(|(bound, _)| bound == name) {
+                Some((_, value)) => (Kind::Variable, vec![*value]),
+                None => (Kind::Leaf(expr.clone()), vec![]),
+            },
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => {
+                let operands = args
+                    .iter()
+                    .chain(branches)
+                    .map(|arg| self.add(arg, scope))
+                    .collect();
+                (Kind::Select(function.clone(), args.len()), operands)
+            }
+            Expr::Temporal { function, args } => {
+                let operands = args.iter().map(|arg| self.add(arg, scope)).collect();
+                self.states.push(TemporalState::default());
+                (
+                    Kind::Temporal(function.clone(), self.states.len() - 1),
+                    operands,
+                )
+            }
+            leaf => (Kind::Leaf(leaf.clone()), vec![]),
+        };
+
+        let i = self.nodes.len();
+        let volatile = match &kind {
+            Kind::Leaf(Expr::Component(id)) => {
+                self.readers.entry(*id).or_default().push(i);
+                false
+            }
+            Kind::Leaf(expr) => matches!(expr, Expr::Time(_) | Expr::TimeOfUse(_)),
+            Kind::Function(_, args) => args.iter().any(Option::is_none),
+            Kind::Custom(..) | Kind::CustomOp(_) => true,
+            // Temporal functions take a sample whenever the components of
+            // their arguments are updated, even if the arguments stay the
+            // same, but not on updates of other components.
+            Kind::Temporal(..) => {
+                let unstable = operands.iter().any(|operand| self.unstable[*operand]);
+                if !unstable {
+                    for id in self.components_of(&operands) {
+                        self.readers.entry(id).or_default().push(i);
+                    }
+                }
+                unstable
+            }
+            _ => false,
+        };
+        if volatile {
+            self.volatile.push(i);
+        }
+        let unstable = volatile || operands.iter().any(|operand| self.unstable[*operand]);
+        self.unstable.push(unstable);
+        for operand in &operands {
+            self.nodes[*operand].dependents.push(i);
+        }
+        self.nodes.push(Node {
+            kind,
+            operands,
+            dependents: Vec::new(),
+            result: Ok(None),
+        });
+        i
+    }
+
+    /// Get the components the nodes of a temporal function's arguments
+    /// depend on.
+    fn components_of(&self, operands: &[usize]) -> BTreeSet<u64> {
+        let mut components = BTreeSet::new();
+        let mut seen = HashSet::new();
+        let mut pending = operands.to_vec();
+        while let Some(i) = pending.pop() {
+            if !seen.insert(i) {
+                continue;
+            }
+            match &self.nodes[i].kind {
+                Kind::Leaf(Expr::Component(id)) => {
+                    components.insert(*id);
+                }
+                _ => pending.extend(&self.nodes[i].operands),
+            }
+        }
+        components
+    }
+
+    /// Add the nodes of the arguments of a function, other than `#*`.
+    fn add_args<'a>(
+        &mut self,
+        args: &'a [Expr<T>],
+        scope: &mut Vec<(&'a str, usize)>,
+    ) -> Vec<Option<usize>> {
+        args.iter()
+            .map(|arg| match arg {
+                Expr::Wildcard => None,
+                arg => Some(self.add(arg, scope)),
+            })
+            .collect()
+    }
+
+    /// Get the cached result of a node.
+    fn node_result(&self, i: usize) -> NodeResult<T> {
+        match &self.nodes[i].result {
+            Ok(value) => Ok(*value),
+            Err(err) => Err(FormulaError(err.0.clone())),
+        }
+    }
+
+    /// Calculate the result of a node, taking a sample for temporal
+    /// functions.
+    fn recalculate(&mut self, i: usize) -> NodeResult<T> {
+        let Kind::Temporal(function, state) = &self.nodes[i].kind else {
+            return self.calculate_node(i);
+        };
+        let args = self.nodes[i]
+            .operands
+            .iter()
+            .map(|operand| self.node_result(*operand))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.states[*state].sample(function, self.now, &args, &self.engine.options))
+    }
+
+    /// Calculate the result of a node from the cached results of its
+    /// operands, like [`Expr::calculate`] does.
+    fn calculate_node(&self, i: usize) -> NodeResult<T> {
+        let options = &self.engine.options;
+        let functions = &self.engine.functions;
+        let node = &self.nodes[i];
+        let operand = |i: usize| self.node_result(node.operands[i]);
+        let args = |args: &[Option<usize>]| -> Result<Vec<_>, FormulaError> {
+            let mut results = Vec::with_capacity(args.len());
+            for arg in args {
+                match arg {
+                    Some(arg) => results.push(self.node_result(*arg)?),
+                    None => results.extend(Expr::wildcard_values(&self.values, options)?),
+                }
+            }
+            Ok(results)
+        };
+        Ok(match &node.kind {
+            Kind::Leaf(expr) => expr.calculate(&self.values, options, functions)?,
+            Kind::UnaryMinus => operand(0)?.map(Neg::neg),
+            Kind::Not => operand(0)?.map(|x| from_bool(x == T::zero())),
+            Kind::Op(op, error) => {
+                let (lhs, rhs) = (operand(0)?, operand(1)?);
+                if let (Some(error), Some(rhs)) = (error, rhs) {
+                    if rhs == T::zero() {
+                        match options.division_by_zero() {
+                            DivisionByZero::Infinity => {}
+                            DivisionByZero::None => return Ok(None),
+                            DivisionByZero::Error => return Err(FormulaError(error.clone())),
+                        }
+                    }
+                }
+                op.apply(lhs, rhs)
+            }
+            Kind::CustomOp(symbol) => match functions.operator(symbol) {
+                Some(op) => (op.function)(operand(0)?, operand(1)?),
+                None => return Err(FormulaError(format!("Unknown operator: {}", symbol))),
+            },
+            // IF and CASE only use the selected branch.
+            Kind::Function(Function::If | Function::Case, _) => {
+                match Function::select_branch(node.operands.len(), operand)? {
+                    Some(i) => operand(i)?,
+                    None => None,
+                }
+            }
+            Kind::Function(function, function_args) => {
+                function.apply(&args(function_args)?, options)
+            }
+            Kind::Custom(name, function_args) => match functions.get(name) {
+                Some(function) => function(&args(function_args)?),
+                None => return Err(FormulaError(format!("Unknown function: {}", name))),
+            },
+            Kind::Let => {
+                operand(0)?;
+                operand(1)?
+            }
+            Kind::Variable => operand(0)?,
+            Kind::Select(function, count) => {
+                let selected = match function {
+                    Function::If | Function::Case => Function::select_branch(*count, operand)?,
+                    _ => function.select(&(0..*count).map(operand).collect::<Result<Vec<_>, _>>()?),
+                };
+                match selected {
+                    Some(i) => operand(count + i)?,
+                    None => None,
+                }
+            }
+            Kind::Temporal(..) => unreachable!("temporal functions are sampled by recalculate"),
+        })
+    }
+}
+
+/// Whether two results are the same.
+pub(crate) fn same_result<T: FormulaValue + Float>(a: &NodeResult<T>, b: &NodeResult<T>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => same_value(*a, *b),
+        (Err(a), Err(b)) => a.0 == b.0,
+        _ => false,
+    }
+}
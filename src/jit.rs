@@ -0,0 +1,598 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Formulas compiled to native code with Cranelift.
+
+use std::{fmt::Debug, marker::PhantomData};
+
+use cranelift_codegen::{
+    ir::{condcodes::FloatCC, types, AbiParam, Block, InstBuilder, MemFlags, Type, Value},
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{Expr, Function, Op, Provided, ValueProvider},
+    formula_engine::FormulaEngine,
+    options::DivisionByZero,
+    value::FormulaValue,
+};
+
+/// The value types formulas can be compiled to native code for, i.e. `f32`
+/// and `f64`.
+pub trait JitValue: FormulaValue + Float {
+    /// The Cranelift type of the values.
+    #[doc(hidden)]
+    const TYPE: Type;
+
+    /// Emit a constant value.
+    #[doc(hidden)]
+    fn constant(builder: &mut FunctionBuilder, value: Self) -> Value;
+}
+
+impl JitValue for f64 {
+    const TYPE: Type = types::F64;
+
+    fn constant(builder: &mut FunctionBuilder, value: Self) -> Value {
+        builder.ins().f64const(value)
+    }
+}
+
+impl JitValue for f32 {
+    const TYPE: Type = types::F32;
+
+    fn constant(builder: &mut FunctionBuilder, value: Self) -> Value {
+        builder.ins().f32const(value)
+    }
+}
+
+/// The compiled function, taking the values of the formula's components and
+/// whether they are present, and writing the result. It returns `1` if the
+/// result is present, `0` if it is `None` and `2` if the formula needs to
+/// be calculated by the engine instead.
+type NativeFunction<T> = unsafe extern "C" fn(*const T, *const u8, *mut T) -> u8;
+
+/// The status the compiled function returns to fall back to the engine.
+const FALL_BACK: i64 = 2;
+
+/// The number of components whose values are gathered without allocating.
+const INLINE_COMPONENTS: usize = 32;
+
+/// A [`FormulaEngine`] with its formula compiled to native code, for the
+/// formulas calculated at the highest rates.
+///
+/// Formulas of `+`, `-`, `*`, `/`, comparisons, AND, OR and NOT, with
+/// COALESCE, MIN, MAX, MIN_STRICT, MAX_STRICT, SUM, COUNT_SOME, NULLIF,
+/// IS_NONE, IS_SOME, IF, CASE and LET, over components and values are
+/// compiled. Other formulas, and calculations whose results need the engine,
+/// like errors, are calculated by the engine, so the results are always
+/// those of [`FormulaEngine::calculate`].
+pub struct JitFormula<T> {
+    engine: FormulaEngine<T>,
+    native: Option<NativeCode<T>>,
+}
+
+/// The compiled function, with the module owning its code.
+struct NativeCode<T> {
+    module: Option<JITModule>,
+    function: NativeFunction<T>,
+    /// The IDs of the formula's components, in the order the compiled
+    /// function takes their values.
+    components: Vec<u64>,
+}
+
+// SAFETY: The module is only used to free the code's memory when dropped,
+// and the compiled function only reads its arguments and writes its result,
+// so it can be called from several threads at once.
+unsafe impl<T: Send> Send for NativeCode<T> {}
+unsafe impl<T: Sync> Sync for NativeCode<T> {}
+
+impl<T> Drop for NativeCode<T> {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: The function can't be called after its code is dropped.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+impl<T: Debug> Debug for JitFormula<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JitFormula")
+            .field("engine", &self.engine)
+            .field("native", &self.native.is_some())
+            .finish()
+    }
+}
+
+impl<T: JitValue> FormulaEngine<T> {
+    /// Compile the formula to native code for the host machine, falling back
+    /// to the engine for formulas that can't be compiled.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("MAX(#0 - #1, 0)").unwrap();
+    /// let jit = fe.jit().unwrap();
+    /// assert!(jit.is_native());
+    /// let result = jit.calculate(HashMap::from([(0, Some(5.0)), (1, Some(2.0))]));
+    /// assert_eq!(result.unwrap(), Some(3.0));
+    /// ```
+    pub fn jit(&self) -> Result<JitFormula<T>, FormulaError> {
+        let native = if self.expr().is_jit() {
+            Some(NativeCode::compile(self)?)
+        } else {
+            None
+        };
+        Ok(JitFormula {
+            engine: self.clone(),
+            native,
+        })
+    }
+}
+
+impl<T: JitValue> JitFormula<T> {
+    /// Get the engine the formula was compiled from.
+    pub fn engine(&self) -> &FormulaEngine<T> {
+        &self.engine
+    }
+
+    /// Whether the formula was compiled to native code, rather than being
+    /// calculated by the engine.
+    pub fn is_native(&self) -> bool {
+        self.native.is_some()
+    }
+
+    /// Calculate the result of the formula based on the provided component
+    /// values, like [`FormulaEngine::calculate`].
+    pub fn calculate(&self, values: impl ValueProvider<T>) -> Result<Option<T>, FormulaError> {
+        let inputs = Provided(values);
+        let Some(native) = &self.native else {
+            return self.engine.calculate_inputs(&inputs);
+        };
+        let options = &self.engine.options;
+        if options.strict() {
+            self.engine.check_values(&inputs)?;
+        }
+
+        let count = native.components.len();
+        let (mut inline_values, mut inline_present) =
+            ([T::zero(); INLINE_COMPONENTS], [0; INLINE_COMPONENTS]);
+        let (mut heap_values, mut heap_present);
+        let (values, present) = if count <= INLINE_COMPONENTS {
+            (&mut inline_values[..count], &mut inline_present[..count])
+        } else {
+            (heap_values, heap_present) = (vec![T::zero(); count], vec![0; count]);
+            (&mut heap_values[..], &mut heap_present[..])
+        };
+        for (i, id) in native.components.iter().enumerate() {
+            // Let the engine report components that aren't given, unless the
+            // formula doesn't need them.
+            let Some(value) = inputs.0.get(*id) else {
+                return self.engine.calculate_inputs(&inputs);
+            };
+            if let Some(value) = options.placeholder_value(value) {
+                (values[i], present[i]) = (value, 1);
+            }
+        }
+
+        let mut result = T::zero();
+        // SAFETY: The function reads one value and presence flag per
+        // component and writes one result.
+        match unsafe { (native.function)(values.as_ptr(), present.as_ptr(), &mut result) } {
+            0 => Ok(None),
+            1 => Ok(Some(result)),
+            _ => self.engine.calculate_inputs(&inputs),
+        }
+    }
+}
+
+impl<T: JitValue> NativeCode<T> {
+    /// Compile the engine's formula, which must be supported.
+    fn compile(engine: &FormulaEngine<T>) -> Result<Self, FormulaError> {
+        let error =
+            |err: &dyn std::fmt::Display| FormulaError(format!("JIT compilation failed: {}", err));
+
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|err| error(&err))?;
+        let isa = cranelift_native::builder()
+            .map_err(|err| error(&err))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| error(&err))?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let mut components: Vec<_> = engine.components().iter().copied().collect();
+        components.sort();
+
+        let mut context = module.make_context();
+        let pointer = module.target_config().pointer_type();
+        context.func.signature.params = vec![AbiParam::new(pointer); 3];
+        context.func.signature.returns = vec![AbiParam::new(types::I8)];
+        let mut function_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut function_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+
+        let mut translator = Translator {
+            builder,
+            components: &components,
+            values: params[0],
+            present: params[1],
+            scope: Vec::new(),
+            fall_back: None,
+            division_by_zero: engine.options.division_by_zero(),
+            value_type: PhantomData,
+        };
+        let (value, present) = translator.translate(engine.expr());
+        let builder = &mut translator.builder;
+        builder
+            .ins()
+            .store(MemFlags::trusted(), value, params[2], 0);
+        builder.ins().return_(&[present]);
+        if let Some(block) = translator.fall_back {
+            builder.switch_to_block(block);
+            let status = builder.ins().iconst(types::I8, FALL_BACK);
+            builder.ins().return_(&[status]);
+        }
+        builder.seal_all_blocks();
+        translator.builder.finalize();
+
+        let id = module
+            .declare_function("formula", Linkage::Export, &context.func.signature)
+            .map_err(|err| error(&err))?;
+        module
+            .define_function(id, &mut context)
+            .map_err(|err| error(&err))?;
+        module.clear_context(&mut context);
+        module.finalize_definitions().map_err(|err| error(&err))?;
+        let code = module.get_finalized_function(id);
+
+        Ok(Self {
+            module: Some(module),
+            // SAFETY: The code was compiled with the signature of the type.
+            function: unsafe { std::mem::transmute::<*const u8, NativeFunction<T>>(code) },
+            components,
+        })
+    }
+}
+
+impl<T> Expr<T> {
+    /// Whether the expression can be compiled to native code.
+    fn is_jit(&self) -> bool {
+        match self {
+            Expr::Value(_) | Expr::Component(_) | Expr::Variable(_) => true,
+            Expr::UnaryMinus(operand) | Expr::Not(operand) => operand.is_jit(),
+            Expr::Op { lhs, op, rhs } => {
+                !matches!(op, Op::Mod | Op::Pow) && lhs.is_jit() && rhs.is_jit()
+            }
+            Expr::Function { function, args } => {
+                matches!(
+                    function,
+                    Function::Coalesce
+                        | Function::Min
+                        | Function::Max
+                        | Function::MinStrict
+                        | Function::MaxStrict
+                        | Function::If
+                        | Function::Case
+                        | Function::Sum
+                        | Function::CountSome
+                        | Function::NullIf
+                        | Function::IsNone
+                        | Function::IsSome
+                ) && !args.is_empty()
+                    && args.iter().all(Expr::is_jit)
+            }
+            Expr::Let { value, body, .. } => value.is_jit() && body.is_jit(),
+            _ => false,
+        }
+    }
+}
+
+/// Translates expressions to Cranelift IR, as pairs of a value and a flag
+/// of whether it is present.
+struct Translator<'a, 'b, T> {
+    builder: FunctionBuilder<'b>,
+    components: &'a [u64],
+    /// The pointers to the values of the components and their flags.
+    values: Value,
+    present: Value,
+    /// The `LET` variables in scope.
+    scope: Vec<(&'a str, (Value, Value))>,
+    /// The block returning the status to fall back to the engine, if needed.
+    fall_back: Option<Block>,
+    division_by_zero: DivisionByZero,
+    value_type: PhantomData<T>,
+}
+
+impl<'a, T: JitValue> Translator<'a, '_, T> {
+    fn translate(&mut self, expr: &'a Expr<T>) -> (Value, Value) {
+        match expr {
+            Expr::Value(Some(value)) => (self.constant(*value), self.flag(true)),
+            Expr::Value(None) => (self.constant(T::zero()), self.flag(false)),
+            Expr::Component(id) => {
+                let index = self
+                    .components
+                    .binary_search(id)
+                    .unwrap_or_else(|_| unreachable!("#{} is a component", id));
+                let size = T::TYPE.bytes() as usize;
+                let flags = MemFlags::trusted();
+                let value =
+                    self.builder
+                        .ins()
+                        .load(T::TYPE, flags, self.values, offset(index * size));
+                let present =
+                    self.builder
+                        .ins()
+                        .load(types::I8, flags, self.present, offset(index));
+                (value, present)
+            }
+            Expr::Variable(name) => self
+                .scope
+                .iter()
+                .rev()
+                .find(|(bound, _)| bound == name)
+                .map(|(_, value)| *value)
+                .unwrap_or_else(|| unreachable!("{} is bound", name)),
+            Expr::UnaryMinus(operand) => {
+                let (value, present) = self.translate(operand);
+                (self.builder.ins().fneg(value), present)
+            }
+            Expr::Not(operand) => {
+                let (value, present) = self.translate(operand);
+                let zero = self.constant(T::zero());
+                let is_zero = self.builder.ins().fcmp(FloatCC::Equal, value, zero);
+                (self.number(is_zero), present)
+            }
+            Expr::Op { lhs, op, rhs } => {
+                let lhs = self.translate(lhs);
+                let rhs = self.translate(rhs);
+                self.translate_op(op, lhs, rhs)
+            }
+            Expr::Function {
+                function: Function::If | Function::Case,
+                args,
+            } => self.translate_branches(args),
+            Expr::Function { function, args } => {
+                let args: Vec<_> = args.iter().map(|arg| self.translate(arg)).collect();
+                self.translate_function(function, &args)
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.translate(value);
+                self.scope.push((name, value));
+                let result = self.translate(body);
+                self.scope.pop();
+                result
+            }
+            expr => unreachable!("{} isn't compiled", expr),
+        }
+    }
+
+    fn translate_op(
+        &mut self,
+        op: &Op,
+        (lhs, lhs_present): (Value, Value),
+        (rhs, rhs_present): (Value, Value),
+    ) -> (Value, Value) {
+        let both_present = self.builder.ins().band(lhs_present, rhs_present);
+        let cc = match op {
+            Op::Eq => Some(FloatCC::Equal),
+            Op::Ne => Some(FloatCC::NotEqual),
+            Op::Lt => Some(FloatCC::LessThan),
+            Op::Le => Some(FloatCC::LessThanOrEqual),
+            Op::Gt => Some(FloatCC::GreaterThan),
+            Op::Ge => Some(FloatCC::GreaterThanOrEqual),
+            _ => None,
+        };
+        if let Some(cc) = cc {
+            let holds = self.builder.ins().fcmp(cc, lhs, rhs);
+            return (self.number(holds), both_present);
+        }
+
+        let zero = self.constant(T::zero());
+        match op {
+            Op::Add => (self.builder.ins().fadd(lhs, rhs), both_present),
+            Op::Sub => (self.builder.ins().fsub(lhs, rhs), both_present),
+            Op::Mul => (self.builder.ins().fmul(lhs, rhs), both_present),
+            Op::Div => {
+                let value = self.builder.ins().fdiv(lhs, rhs);
+                let is_zero = self.builder.ins().fcmp(FloatCC::Equal, rhs, zero);
+                let zero_divisor = self.builder.ins().band(rhs_present, is_zero);
+                match self.division_by_zero {
+                    DivisionByZero::Infinity => (value, both_present),
+                    DivisionByZero::None => {
+                        let nonzero = self.not(zero_divisor);
+                        (value, self.builder.ins().band(both_present, nonzero))
+                    }
+                    DivisionByZero::Error => {
+                        let fall_back = self.fall_back_block();
+                        let next = self.builder.create_block();
+                        self.builder
+                            .ins()
+                            .brif(zero_divisor, fall_back, &[], next, &[]);
+                        self.builder.switch_to_block(next);
+                        (value, both_present)
+                    }
+                }
+            }
+            // Three-valued logic: an operand that decides the result makes it
+            // present.
+            Op::And | Op::Or => {
+                let (cc, decided) = match op {
+                    Op::And => (FloatCC::Equal, T::zero()),
+                    _ => (FloatCC::NotEqual, T::one()),
+                };
+                let lhs_decides = self.builder.ins().fcmp(cc, lhs, zero);
+                let lhs_decides = self.builder.ins().band(lhs_present, lhs_decides);
+                let rhs_decides = self.builder.ins().fcmp(cc, rhs, zero);
+                let rhs_decides = self.builder.ins().band(rhs_present, rhs_decides);
+                let decides = self.builder.ins().bor(lhs_decides, rhs_decides);
+                let (decided, other) = (self.constant(decided), self.constant(T::one() - decided));
+                (
+                    self.builder.ins().select(decides, decided, other),
+                    self.builder.ins().bor(decides, both_present),
+                )
+            }
+            op => unreachable!("{} isn't compiled", op.symbol()),
+        }
+    }
+
+    fn translate_function(
+        &mut self,
+        function: &Function,
+        args: &[(Value, Value)],
+    ) -> (Value, Value) {
+        let (first, rest) = args
+            .split_first()
+            .unwrap_or_else(|| unreachable!("functions have arguments"));
+        match function {
+            // The first argument that isn't `None` wins.
+            Function::Coalesce => rest
+                .iter()
+                .fold(*first, |(acc, acc_present), (x, present)| {
+                    (
+                        self.builder.ins().select(acc_present, acc, *x),
+                        self.builder.ins().bor(acc_present, *present),
+                    )
+                }),
+            Function::Min | Function::Max | Function::MinStrict | Function::MaxStrict => {
+                let cc = match function {
+                    Function::Min | Function::MinStrict => FloatCC::LessThan,
+                    _ => FloatCC::GreaterThan,
+                };
+                // Keep the value so far unless the next one is present and it
+                // doesn't compare as kept against it, like on ties.
+                let (value, any_present) =
+                    rest.iter()
+                        .fold(*first, |(acc, acc_present), (x, present)| {
+                            let kept = self.builder.ins().fcmp(cc, acc, *x);
+                            let kept = self.builder.ins().band(acc_present, kept);
+                            let missing = self.not(*present);
+                            let keep = self.builder.ins().bor(missing, kept);
+                            (
+                                self.builder.ins().select(keep, acc, *x),
+                                self.builder.ins().bor(acc_present, *present),
+                            )
+                        });
+                if matches!(function, Function::MinStrict | Function::MaxStrict) {
+                    let all_present = rest.iter().fold(first.1, |acc, (_, present)| {
+                        self.builder.ins().band(acc, *present)
+                    });
+                    (value, all_present)
+                } else {
+                    (value, any_present)
+                }
+            }
+            // Adding `-0` leaves all values unchanged.
+            Function::Sum => {
+                let negative_zero = self.constant(-T::zero());
+                let none = self.flag(false);
+                args.iter()
+                    .fold((negative_zero, none), |(acc, acc_present), (x, present)| {
+                        let x = self.builder.ins().select(*present, *x, negative_zero);
+                        (
+                            self.builder.ins().fadd(acc, x),
+                            self.builder.ins().bor(acc_present, *present),
+                        )
+                    })
+            }
+            Function::CountSome => {
+                let (zero, one) = (self.constant(T::zero()), self.constant(T::one()));
+                let count = args.iter().fold(zero, |acc, (_, present)| {
+                    let x = self.builder.ins().select(*present, one, zero);
+                    self.builder.ins().fadd(acc, x)
+                });
+                (count, self.flag(true))
+            }
+            Function::NullIf => {
+                let ((a, a_present), (b, b_present)) = (args[0], args[1]);
+                let equal = self.builder.ins().fcmp(FloatCC::Equal, a, b);
+                let equal = self.builder.ins().band(b_present, equal);
+                let unequal = self.not(equal);
+                (a, self.builder.ins().band(a_present, unequal))
+            }
+            Function::IsNone => {
+                let missing = self.not(first.1);
+                (self.number(missing), self.flag(true))
+            }
+            Function::IsSome => (self.number(first.1), self.flag(true)),
+            function => unreachable!("{} isn't compiled", function.name()),
+        }
+    }
+
+    /// Translate the conditions of an IF or CASE to branches to the code of
+    /// the argument they select, so that only the conditions needed and the
+    /// selected argument are calculated.
+    fn translate_branches(&mut self, args: &'a [Expr<T>]) -> (Value, Value) {
+        let merge = self.builder.create_block();
+        self.builder.append_block_param(merge, T::TYPE);
+        self.builder.append_block_param(merge, types::I8);
+        let none = [self.constant(T::zero()), self.flag(false)];
+
+        let mut i = 0;
+        while Function::is_condition(i, args.len()) {
+            let (condition, present) = self.translate(&args[i]);
+            let (check, branch, next) = (
+                self.builder.create_block(),
+                self.builder.create_block(),
+                self.builder.create_block(),
+            );
+            self.builder.ins().brif(present, check, &[], merge, &none);
+            self.builder.switch_to_block(check);
+            let zero = self.constant(T::zero());
+            let holds = self.builder.ins().fcmp(FloatCC::NotEqual, condition, zero);
+            self.builder.ins().brif(holds, branch, &[], next, &[]);
+            self.builder.switch_to_block(branch);
+            let (value, present) = self.translate(&args[i + 1]);
+            self.builder.ins().jump(merge, &[value, present]);
+            self.builder.switch_to_block(next);
+            i += 2;
+        }
+        let (value, present) = match args.get(i) {
+            Some(default) => self.translate(default),
+            None => (none[0], none[1]),
+        };
+        self.builder.ins().jump(merge, &[value, present]);
+        self.builder.switch_to_block(merge);
+        let params = self.builder.block_params(merge);
+        (params[0], params[1])
+    }
+
+    fn fall_back_block(&mut self) -> Block {
+        *self
+            .fall_back
+            .get_or_insert_with(|| self.builder.create_block())
+    }
+
+    fn constant(&mut self, value: T) -> Value {
+        T::constant(&mut self.builder, value)
+    }
+
+    fn flag(&mut self, flag: bool) -> Value {
+        self.builder.ins().iconst(types::I8, i64::from(flag))
+    }
+
+    /// Negate a flag.
+    fn not(&mut self, flag: Value) -> Value {
+        self.builder.ins().bxor_imm(flag, 1)
+    }
+
+    /// Turn a flag into `1` or `0`.
+    fn number(&mut self, flag: Value) -> Value {
+        let (one, zero) = (self.constant(T::one()), self.constant(T::zero()));
+        self.builder.ins().select(flag, one, zero)
+    }
+}
+
+/// Get the memory offset of an index.
+fn offset(bytes: usize) -> i32 {
+    i32::try_from(bytes).unwrap_or_else(|_| unreachable!("formulas have few components"))
+}
@@ -0,0 +1,276 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Conversion of expressions to and from JSON.
+//!
+//! A formula is a JSON object `{"version": 1, "expr": node}`, where each node
+//! is an object with a `"type"` and the fields of that type:
+//!
+//! | `type`      | Fields                                          | Formula         |
+//! |-------------|-------------------------------------------------|-----------------|
+//! | `value`     | `value`: number, `null`, `"NaN"`, `"Infinity"` or `"-Infinity"` | `1.5`   |
+//! | `component` | `id`: number                                    | `#3`            |
+//! | `wildcard`  |                                                 | `#*`            |
+//! | `named`     | `name`: string                                  | `$pv`, `#"id"`  |
+//! | `neg`       | `operand`: node                                 | `-x`            |
+//! | `not`       | `operand`: node                                 | `NOT x`         |
+//! | `op`        | `op`: `+`, `-`, `*`, `/`, `%`, `^`, `==`, `!=`, `<`, `<=`, `>`, `>=`, `AND` or `OR`; `lhs`, `rhs`: nodes | `a + b` |
+//! | `custom_op` | `symbol`: string; `lhs`, `rhs`: nodes           | `a ~- b`        |
+//! | `function`  | `name`: upper-case function name; `args`: nodes | `MIN(a, b)`     |
+//! | `custom`    | `name`: string; `args`: nodes                   | `avg(a, b)`     |
+//! | `let`       | `name`: string; `value`, `body`: nodes          | `LET x = a IN b`|
+//! | `variable`  | `name`: string                                  | `x`             |
+//! | `reference` | `name`: string                                  | `@grid_power`   |
+//! | `tou`       | `window`: string                                | `TOU("peak")`   |
+//! | `time`      | `function`: `NOW`, `HOUR` or `DAYOFWEEK`        | `NOW()`         |
+//! | `temporal`  | `name`: upper-case function name; `args`: nodes | `ROLLING_AVG(a, 30)` |
+//! | `select`    | `function`: function name; `args`, `branches`: nodes | (derivatives) |
+//!
+//! For example, `MAX(#0, 0) * 2` is
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "expr": {
+//!     "type": "op",
+//!     "op": "*",
+//!     "lhs": {
+//!       "type": "function",
+//!       "name": "MAX",
+//!       "args": [{"type": "component", "id": 0}, {"type": "value", "value": 0}]
+//!     },
+//!     "rhs": {"type": "value", "value": 2}
+//!   }
+//! }
+//! ```
+
+use serde_json::{json, Value};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{Expr, Function, Op, TemporalFunction, TimeFunction},
+    value::FormulaValue,
+};
+
+/// The version of the JSON schema written by [`Expr::to_json`].
+pub const JSON_VERSION: u64 = 1;
+
+impl<T: FormulaValue> Expr<T> {
+    /// Convert the expression to JSON, as described in the [`json`](self)
+    /// module.
+    pub fn to_json(&self) -> Value
+    where
+        T: Float,
+    {
+        json!({ "version": JSON_VERSION, "expr": self.to_json_node() })
+    }
+
+    /// Convert JSON written by [`Expr::to_json`] to an expression.
+    ///
+    /// The expression is only checked for its structure, so it should be
+    /// passed to [`FormulaEngine::try_from_expr`](crate::FormulaEngine::try_from_expr)
+    /// to be validated.
+    pub fn from_json(json: &Value) -> Result<Self, FormulaError> {
+        match json.get("version").and_then(Value::as_u64) {
+            Some(JSON_VERSION) => {}
+            Some(version) => {
+                return Err(FormulaError(format!(
+                    "Unsupported formula JSON version: {}",
+                    version
+                )))
+            }
+            None => return Err(FormulaError("Missing formula JSON version".to_string())),
+        }
+        Expr::from_json_node(field(json, "expr")?)
+    }
+
+    fn to_json_node(&self) -> Value
+    where
+        T: Float,
+    {
+        let nodes = |exprs: &[Expr<T>]| exprs.iter().map(Expr::to_json_node).collect::<Vec<_>>();
+        match self {
+            Expr::Value(value) => json!({ "type": "value", "value": value_to_json(*value) }),
+            Expr::Component(id) => json!({ "type": "component", "id": id }),
+            Expr::Wildcard => json!({ "type": "wildcard" }),
+            Expr::Named(name) => json!({ "type": "named", "name": name }),
+            Expr::UnaryMinus(operand) => {
+                json!({ "type": "neg", "operand": operand.to_json_node() })
+            }
+            Expr::Not(operand) => json!({ "type": "not", "operand": operand.to_json_node() }),
+            Expr::Op { lhs, op, rhs } => json!({
+                "type": "op",
+                "op": op.symbol(),
+                "lhs": lhs.to_json_node(),
+                "rhs": rhs.to_json_node(),
+            }),
+            Expr::CustomOp { symbol, lhs, rhs } => json!({
+                "type": "custom_op",
+                "symbol": symbol,
+                "lhs": lhs.to_json_node(),
+                "rhs": rhs.to_json_node(),
+            }),
+            Expr::Function { function, args } => json!({
+                "type": "function",
+                "name": function.name(),
+                "args": nodes(args),
+            }),
+            Expr::Custom { name, args } => {
+                json!({ "type": "custom", "name": name, "args": nodes(args) })
+            }
+            Expr::Temporal { function, args } => json!({
+                "type": "temporal",
+                "name": function.name(),
+                "args": nodes(args),
+            }),
+            Expr::Let { name, value, body } => json!({
+                "type": "let",
+                "name": name,
+                "value": value.to_json_node(),
+                "body": body.to_json_node(),
+            }),
+            Expr::Variable(name) => json!({ "type": "variable", "name": name }),
+            Expr::Reference(name) => json!({ "type": "reference", "name": name }),
+            Expr::TimeOfUse(window) => json!({ "type": "tou", "window": window }),
+            Expr::Time(function) => json!({ "type": "time", "function": function.name() }),
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => json!({
+                "type": "select",
+                "function": function.name(),
+                "args": nodes(args),
+                "branches": nodes(branches),
+            }),
+        }
+    }
+
+    fn from_json_node(json: &Value) -> Result<Self, FormulaError> {
+        let node = |name| -> Result<Box<Expr<T>>, FormulaError> {
+            Ok(Box::new(Expr::from_json_node(field(json, name)?)?))
+        };
+        let nodes = |name| -> Result<Vec<Expr<T>>, FormulaError> {
+            field(json, name)?
+                .as_array()
+                .ok_or_else(|| invalid(json, &format!("\"{}\" must be an array", name)))?
+                .iter()
+                .map(Expr::from_json_node)
+                .collect()
+        };
+        let string = |name| -> Result<String, FormulaError> {
+            field(json, name)?
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| invalid(json, &format!("\"{}\" must be a string", name)))
+        };
+        let function = |name: String| {
+            Function::from_name(&name)
+                .ok_or_else(|| FormulaError(format!("Unknown function: {}", name)))
+        };
+
+        Ok(match field(json, "type")?.as_str().unwrap_or_default() {
+            "value" => Expr::Value(value_from_json(field(json, "value")?)?),
+            "component" => Expr::Component(
+                field(json, "id")?
+                    .as_u64()
+                    .ok_or_else(|| invalid(json, "\"id\" must be a component ID"))?,
+            ),
+            "wildcard" => Expr::Wildcard,
+            "named" => Expr::Named(string("name")?),
+            "neg" => Expr::UnaryMinus(node("operand")?),
+            "not" => Expr::Not(node("operand")?),
+            "op" => {
+                let symbol = string("op")?;
+                Expr::Op {
+                    lhs: node("lhs")?,
+                    op: Op::from_symbol(&symbol)
+                        .ok_or_else(|| FormulaError(format!("Unknown operator: {}", symbol)))?,
+                    rhs: node("rhs")?,
+                }
+            }
+            "custom_op" => Expr::CustomOp {
+                symbol: string("symbol")?,
+                lhs: node("lhs")?,
+                rhs: node("rhs")?,
+            },
+            "function" => Expr::Function {
+                function: function(string("name")?)?,
+                args: nodes("args")?,
+            },
+            "custom" => Expr::Custom {
+                name: string("name")?,
+                args: nodes("args")?,
+            },
+            "temporal" => {
+                let name = string("name")?;
+                Expr::Temporal {
+                    function: TemporalFunction::from_name(&name)
+                        .ok_or_else(|| FormulaError(format!("Unknown function: {}", name)))?,
+                    args: nodes("args")?,
+                }
+            }
+            "let" => Expr::Let {
+                name: string("name")?,
+                value: node("value")?,
+                body: node("body")?,
+            },
+            "variable" => Expr::Variable(string("name")?),
+            "reference" => Expr::Reference(string("name")?),
+            "tou" => Expr::TimeOfUse(string("window")?),
+            "time" => {
+                let name = string("function")?;
+                Expr::Time(
+                    TimeFunction::from_name(&name)
+                        .ok_or_else(|| FormulaError(format!("Unknown function: {}", name)))?,
+                )
+            }
+            "select" => Expr::Select {
+                function: function(string("function")?)?,
+                args: nodes("args")?,
+                branches: nodes("branches")?,
+            },
+            _ => return Err(invalid(json, "unknown \"type\"")),
+        })
+    }
+}
+
+fn value_to_json<T: FormulaValue + Float>(value: Option<T>) -> Value {
+    match value.and_then(|value| value.to_f64()) {
+        None => Value::Null,
+        Some(value) if value.is_nan() => json!("NaN"),
+        Some(value) if value == f64::INFINITY => json!("Infinity"),
+        Some(value) if value == f64::NEG_INFINITY => json!("-Infinity"),
+        Some(value) => json!(value),
+    }
+}
+
+fn value_from_json<T: FormulaValue>(json: &Value) -> Result<Option<T>, FormulaError> {
+    let value = match json {
+        Value::Null => return Ok(None),
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => match string.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            _ => None,
+        },
+        _ => None,
+    };
+    value
+        .and_then(T::from_f64)
+        .map(Some)
+        .ok_or_else(|| invalid(json, "expected a number"))
+}
+
+/// Get a field of a JSON node.
+fn field<'a>(json: &'a Value, name: &str) -> Result<&'a Value, FormulaError> {
+    json.get(name)
+        .ok_or_else(|| invalid(json, &format!("missing \"{}\"", name)))
+}
+
+fn invalid(json: &Value, reason: &str) -> FormulaError {
+    FormulaError(format!("Invalid formula JSON {}: {}", json, reason))
+}
@@ -11,12 +11,13 @@ A library to create formulas over streamed data
 A [`FormulaEngine`] instance can be created from a [`String`] formula with the [`try_new`][`FormulaEngine::try_new`] method.
 Such a formula can contain component placeholders, which are represented by `#`
 followed by a number.
-To calculate the formula, you need to provide an iterator of Option values,
+To calculate the formula, you need to provide the Option values of its
+placeholders by component ID, e.g. as a `HashMap` or an iterator of pairs,
 where
 - `None` represents a missing value
 - `Some(value)` represents a value.
 
-The iterator must contain as many values as the formula has placeholders.
+Values must be given for all of the formula's placeholders.
 The result of the calculation is an Option value.
 
 ```rust
@@ -31,15 +32,102 @@ fn main() -> Result<(), FormulaError> {
     Ok(())
 }
 ```
+
+Formulas can also be built in code from [`Expr`]'s constructors and
+operators, and turned into an engine with
+[`try_from_expr`][`FormulaEngine::try_from_expr`].
+
+The [`Formula32`] and [`Formula64`] aliases cover the common value types, and
+the [`prelude`] module re-exports the commonly used types and traits.
 */
 
+// Lets the code expanded by `formula!` refer to this crate in its own tests.
+#[cfg(feature = "macros")]
+extern crate self as frequenz_microgrid_formula_engine;
+
+mod batch;
+mod bind;
+mod builder;
+mod categories;
+#[cfg(feature = "tokio")]
+mod channels;
+#[cfg(feature = "config")]
+pub mod config;
+mod display;
+mod energy;
 mod error;
 mod expression;
 mod formula_engine;
+mod formula_registry;
+mod functions;
+mod incremental;
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(feature = "json")]
+pub mod json;
+mod memo;
+#[cfg(feature = "prometheus")]
+mod metrics;
+#[cfg(feature = "monte-carlo")]
+mod monte_carlo;
+mod options;
+#[cfg(feature = "rayon")]
+mod parallel;
 mod parser;
+pub mod prelude;
+#[cfg(feature = "proto")]
+pub mod proto;
+mod provenance;
+mod python;
+mod quality;
+mod remap;
+mod replay;
+mod resample;
+#[cfg(feature = "simd")]
+mod simd;
+mod simplify;
+mod sql;
+#[cfg(feature = "async")]
+mod stream;
+mod streaming;
+mod syntax;
+mod temporal;
+mod value;
+mod visit;
+mod vm;
 
+pub use display::PrettyOptions;
+pub use energy::{
+    AccumulatorSnapshot, EnergyAccumulator, EnergyInterval, IntervalCallback, ResetBoundary,
+};
 pub use error::FormulaError;
-pub use formula_engine::FormulaEngine;
+pub use expression::{Expr, Function, Op, TemporalFunction, TimeFunction, ValueProvider};
+pub use formula_engine::{Formula32, Formula64, FormulaEngine};
+pub use formula_registry::FormulaRegistry;
+#[cfg(feature = "macros")]
+pub use frequenz_microgrid_formula_engine_macros::formula;
+pub use functions::{CustomFunction, CustomOperator, FunctionRegistry};
+pub use incremental::IncrementalEngine;
+#[cfg(feature = "jit")]
+pub use jit::{JitFormula, JitValue};
+#[cfg(feature = "prometheus")]
+pub use metrics::PrometheusExporter;
+#[cfg(feature = "monte-carlo")]
+pub use monte_carlo::{InputDistribution, MonteCarloStats};
+pub use options::{
+    Clock, DivisionByZero, EngineOptions, RoundingMode, SystemClock, TouWindow, Weekday,
+};
+pub use provenance::CoalesceChoice;
+pub use quality::{Qualities, Quality};
+pub use replay::{Recorder, Recording};
+pub use resample::{Aggregation, Resampler};
+#[cfg(feature = "simd")]
+pub use simd::SimdValue;
+pub use streaming::{ChangeCallback, InputCallback, StreamingFormulaEngine, StreamingInput};
+pub use syntax::{Associativity, Precedence};
+pub use temporal::{StateSnapshot, TemporalSnapshot};
+pub use value::{FormulaValue, Sample};
+pub use visit::{walk_expr, Visitor};
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,129 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::sync::{Mutex, PoisonError};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{Expr, Inputs},
+    value::{same_value, FormulaValue},
+};
+
+/// The values of the placeholders a calculation used.
+type Key<T> = Vec<Option<Option<T>>>;
+
+/// The last result of a formula, with the values of the placeholders it
+/// was calculated from.
+#[derive(Debug)]
+pub(crate) struct ResultCache<T> {
+    components: Vec<u64>,
+    names: Vec<String>,
+    references: Vec<String>,
+    wildcard: bool,
+    last: Mutex<Option<(Key<T>, Option<T>)>>,
+}
+
+/// Clones start without a result.
+impl<T> Clone for ResultCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            components: self.components.clone(),
+            names: self.names.clone(),
+            references: self.references.clone(),
+            wildcard: self.wildcard,
+            last: Mutex::new(None),
+        }
+    }
+}
+
+impl<T: FormulaValue> ResultCache<T> {
+    /// Create a cache for the result of an expression, unless it can change
+    /// while its placeholders don't, like with the clock.
+    pub(crate) fn new(expr: &Expr<T>) -> Option<Self> {
+        let volatile = |expr: &Expr<T>| {
+            matches!(
+                expr,
+                Expr::Time(_) | Expr::TimeOfUse(_) | Expr::Custom { .. } | Expr::CustomOp { .. }
+            )
+        };
+        if expr.any(&volatile) {
+            return None;
+        }
+        let mut components: Vec<_> = expr.components().into_iter().collect();
+        components.sort();
+        let mut names: Vec<_> = expr.names().into_iter().collect();
+        names.sort();
+        let mut references: Vec<_> = expr.references().into_iter().collect();
+        references.sort();
+        Some(Self {
+            components,
+            names,
+            references,
+            wildcard: expr.any(&|expr| matches!(expr, Expr::Wildcard)),
+            last: Mutex::new(None),
+        })
+    }
+
+    /// Get the last result if the placeholders have the same values as in
+    /// the last calculation, or calculate and keep the result otherwise.
+    pub(crate) fn get_or_calculate(
+        &self,
+        values: &impl Inputs<T>,
+        calculate: impl FnOnce() -> Result<Option<T>, FormulaError>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        let key = self.key(values);
+        if let (Some(key), Some((last_key, result))) = (&key, &*self.lock()) {
+            let same = key.len() == last_key.len()
+                && key.iter().zip(last_key).all(|(a, b)| match (a, b) {
+                    (Some(a), Some(b)) => same_value(*a, *b),
+                    (a, b) => a.is_none() && b.is_none(),
+                });
+            if same {
+                return Ok(*result);
+            }
+        }
+        // The lock isn't held while calculating, so that other threads can
+        // use the formula meanwhile.
+        let result = calculate()?;
+        if let Some(key) = key {
+            *self.lock() = Some((key, result));
+        }
+        Ok(result)
+    }
+
+    /// Get the values of the placeholders, or `None` if the values for `#*`
+    /// can't be listed.
+    fn key(&self, values: &impl Inputs<T>) -> Option<Key<T>> {
+        let mut key: Key<T> = self
+            .components
+            .iter()
+            .map(|id| values.component(*id))
+            .chain(self.names.iter().map(|name| values.named(name)))
+            .chain(self.references.iter().map(|name| values.reference(name)))
+            .collect();
+        if self.wildcard {
+            key.extend(values.all()?.into_iter().map(Some));
+        }
+        Some(key)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<(Key<T>, Option<T>)>> {
+        self.last.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Expr<T> {
+    /// Whether the expression or any of its operands satisfies `predicate`.
+    fn any(&self, predicate: &impl Fn(&Expr<T>) -> bool) -> bool {
+        predicate(self)
+            || self
+                .children()
+                .into_iter()
+                .any(|child| child.any(predicate))
+    }
+}
@@ -0,0 +1,221 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use num_traits::Float;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    GaugeVec, Opts,
+};
+
+use crate::{
+    error::FormulaError,
+    options::Clock,
+    streaming::{StreamingFormulaEngine, StreamingInput},
+    value::FormulaValue,
+};
+
+/// The number of latest results the rate of `None` results is calculated
+/// over, unless set with [`PrometheusExporter::with_window`].
+const DEFAULT_WINDOW: usize = 100;
+
+/// Prometheus gauges of the results of named streaming formulas, to be
+/// registered with a [`prometheus::Registry`].
+///
+/// The gauges are labeled with the name of the formula:
+/// - `formula_result`: the latest result, NaN if it is `None` or an error.
+/// - `formula_staleness_seconds`: the time since the latest value of a
+///   component, by the clock of the engine's options when the metrics are
+///   gathered.
+/// - `formula_none_rate`: the share of the latest results after values of
+///   components that are `None` or errors.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::{FormulaEngine, PrometheusExporter};
+///
+/// let registry = prometheus::Registry::new();
+/// let exporter = PrometheusExporter::new();
+/// registry.register(Box::new(exporter.clone())).unwrap();
+///
+/// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+/// let mut streaming = fe.streaming();
+/// exporter.watch("grid_power", &mut streaming);
+/// streaming.update(0, Some(1.0)).ok();
+/// streaming.update(1, Some(2.0)).ok();
+///
+/// let metrics = prometheus::TextEncoder::new()
+///     .encode_to_string(&registry.gather())
+///     .unwrap();
+/// assert!(metrics.contains("formula_result{formula=\"grid_power\"} 3"));
+/// assert!(metrics.contains("formula_none_rate{formula=\"grid_power\"} 0.5"));
+/// ```
+#[derive(Clone)]
+pub struct PrometheusExporter {
+    result: GaugeVec,
+    staleness: GaugeVec,
+    none_rate: GaugeVec,
+    window: usize,
+    formulas: Arc<Mutex<HashMap<String, Watched>>>,
+}
+
+/// The state of a formula watched by a [`PrometheusExporter`].
+struct Watched {
+    /// Identifies the engine watched, whose callback outlives unwatching it.
+    token: Arc<()>,
+    clock: Arc<dyn Clock>,
+    /// When the latest value of a component was received.
+    updated: Option<SystemTime>,
+    /// Whether each of the latest results was `None` or an error.
+    nones: VecDeque<bool>,
+}
+
+impl std::fmt::Debug for PrometheusExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self
+            .formulas
+            .lock()
+            .map(|formulas| formulas.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        f.debug_struct("PrometheusExporter")
+            .field("window", &self.window)
+            .field("formulas", &names)
+            .finish()
+    }
+}
+
+impl Default for PrometheusExporter {
+    fn default() -> Self {
+        let gauge = |name: &str, help: &str| {
+            GaugeVec::new(Opts::new(name, help), &["formula"]).expect("static options are valid")
+        };
+        Self {
+            result: gauge("formula_result", "The latest result of the formula."),
+            staleness: gauge(
+                "formula_staleness_seconds",
+                "The time since the latest value of a component of the formula.",
+            ),
+            none_rate: gauge(
+                "formula_none_rate",
+                "The share of the latest results of the formula that are missing.",
+            ),
+            window: DEFAULT_WINDOW,
+            formulas: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl PrometheusExporter {
+    /// Create an exporter without any formulas.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of latest results the rate of `None` results is
+    /// calculated over, at least one.
+    pub fn with_window(mut self, count: usize) -> Self {
+        self.window = count.max(1);
+        self
+    }
+
+    /// Publish the results of a streaming formula under a name, replacing
+    /// the formula published under it before.
+    pub fn watch<T: FormulaValue + Float>(
+        &self,
+        name: &str,
+        streaming: &mut StreamingFormulaEngine<T>,
+    ) {
+        let token = Arc::new(());
+        let watched = Watched {
+            token: token.clone(),
+            clock: streaming.engine().options.clock(),
+            updated: None,
+            nones: VecDeque::new(),
+        };
+        self.unwatch(name);
+        if let Ok(mut formulas) = self.formulas.lock() {
+            formulas.insert(name.to_string(), watched);
+        }
+        let exporter = self.clone();
+        let name = name.to_string();
+        streaming.on_input(move |input, result| exporter.record(&name, &token, input, result));
+    }
+
+    /// Stop publishing the results of the formula watched under a name, and
+    /// remove its gauges.
+    pub fn unwatch(&self, name: &str) {
+        let removed = self
+            .formulas
+            .lock()
+            .is_ok_and(|mut formulas| formulas.remove(name).is_some());
+        if removed {
+            for gauge in [&self.result, &self.staleness, &self.none_rate] {
+                gauge.remove_label_values(&[name]).ok();
+            }
+        }
+    }
+
+    /// Update the gauges of a formula after an input.
+    fn record<T: FormulaValue + Float>(
+        &self,
+        name: &str,
+        token: &Arc<()>,
+        input: &StreamingInput<T>,
+        result: &Result<Option<T>, FormulaError>,
+    ) {
+        let Ok(mut formulas) = self.formulas.lock() else {
+            return;
+        };
+        // The formula may have been unwatched, or replaced by another one.
+        let Some(watched) = formulas
+            .get_mut(name)
+            .filter(|watched| Arc::ptr_eq(&watched.token, token))
+        else {
+            return;
+        };
+        let value = result.as_ref().ok().copied().flatten();
+        let gauge = value.and_then(|value| value.to_f64()).unwrap_or(f64::NAN);
+        self.result.with_label_values(&[name]).set(gauge);
+        if let StreamingInput::Update(_, sample) = input {
+            watched.updated = watched.updated.max(Some(sample.timestamp));
+            watched.nones.push_back(value.is_none());
+            while watched.nones.len() > self.window {
+                watched.nones.pop_front();
+            }
+            let nones = watched.nones.iter().filter(|none| **none).count();
+            let rate = nones as f64 / watched.nones.len() as f64;
+            self.none_rate.with_label_values(&[name]).set(rate);
+        }
+    }
+}
+
+impl Collector for PrometheusExporter {
+    fn desc(&self) -> Vec<&Desc> {
+        [&self.result, &self.staleness, &self.none_rate]
+            .into_iter()
+            .flat_map(Collector::desc)
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        if let Ok(formulas) = self.formulas.lock() {
+            for (name, watched) in formulas.iter() {
+                if let Some(updated) = watched.updated {
+                    let staleness = watched.clock.now().duration_since(updated);
+                    self.staleness
+                        .with_label_values(&[name])
+                        .set(staleness.unwrap_or_default().as_secs_f64());
+                }
+            }
+        }
+        [&self.result, &self.staleness, &self.none_rate]
+            .into_iter()
+            .flat_map(Collector::collect)
+            .collect()
+    }
+}
@@ -0,0 +1,155 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::collections::HashMap;
+
+use num_traits::Float;
+use rand::Rng;
+use rand_distr::{uniform::SampleUniform, Normal, StandardNormal, Uniform};
+
+use crate::{error::FormulaError, formula_engine::FormulaEngine, value::FormulaValue};
+
+/// The distribution a component's value is sampled from.
+#[derive(Debug, Clone)]
+pub enum InputDistribution<T> {
+    /// A normal distribution, e.g. a meter reading with a known accuracy.
+    Normal { mean: T, std_dev: T },
+    /// A uniform distribution over the closed interval `[low, high]`.
+    Uniform { low: T, high: T },
+    /// A fixed value, which can also be a missing value.
+    Constant(Option<T>),
+}
+
+/// A sampler for a single component's value.
+enum Sampler<T: Float + SampleUniform>
+where
+    StandardNormal: rand_distr::Distribution<T>,
+{
+    Normal(Normal<T>),
+    Uniform(Uniform<T>),
+    Constant(Option<T>),
+}
+
+impl<T: Float + SampleUniform> Sampler<T>
+where
+    StandardNormal: rand_distr::Distribution<T>,
+{
+    fn try_new(distribution: &InputDistribution<T>) -> Result<Self, FormulaError> {
+        Ok(match *distribution {
+            InputDistribution::Normal { mean, std_dev } => Sampler::Normal(
+                Normal::new(mean, std_dev).map_err(|e| FormulaError(format!("{}", e)))?,
+            ),
+            InputDistribution::Uniform { low, high } => {
+                if low.is_nan() || high.is_nan() || low > high {
+                    return Err(FormulaError(
+                        "Uniform distribution requires low <= high".to_string(),
+                    ));
+                }
+                Sampler::Uniform(Uniform::new_inclusive(low, high))
+            }
+            InputDistribution::Constant(value) => Sampler::Constant(value),
+        })
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> Option<T> {
+        match self {
+            Sampler::Normal(normal) => Some(rng.sample(normal)),
+            Sampler::Uniform(uniform) => Some(rng.sample(uniform)),
+            Sampler::Constant(value) => *value,
+        }
+    }
+}
+
+/// Statistics over the results of a Monte Carlo simulation.
+#[derive(Debug, Clone)]
+pub struct MonteCarloStats<T> {
+    /// The number of evaluations that returned `None`.
+    pub none_count: usize,
+    /// The results of the evaluations that returned a value, sorted in
+    /// ascending order.
+    results: Vec<T>,
+}
+
+impl<T: Float> MonteCarloStats<T> {
+    fn new(mut results: Vec<T>, none_count: usize) -> Self {
+        results.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Self {
+            none_count,
+            results,
+        }
+    }
+
+    /// Get the sorted results of the evaluations that returned a value.
+    pub fn results(&self) -> &[T] {
+        &self.results
+    }
+
+    /// Get the mean of the results, or `None` if there are no results.
+    pub fn mean(&self) -> Option<T> {
+        let count = T::from(self.results.len())?;
+        (!self.results.is_empty())
+            .then(|| self.results.iter().fold(T::zero(), |acc, &x| acc + x) / count)
+    }
+
+    /// Get the sample standard deviation of the results, or `None` if there
+    /// are fewer than two results.
+    pub fn std_dev(&self) -> Option<T> {
+        let mean = self.mean()?;
+        let count = T::from(self.results.len().checked_sub(1)?)?;
+        (count > T::zero()).then(|| {
+            (self
+                .results
+                .iter()
+                .fold(T::zero(), |acc, &x| acc + (x - mean) * (x - mean))
+                / count)
+                .sqrt()
+        })
+    }
+
+    /// Get the given percentile (from 0 to 100) of the results, linearly
+    /// interpolating between the closest ranks.
+    pub fn percentile(&self, percentile: T) -> Option<T> {
+        let last = self.results.len().checked_sub(1)?;
+        let rank = percentile.max(T::zero()).min(T::from(100)?) / T::from(100)? * T::from(last)?;
+        let lower = rank.floor();
+        let i = lower.to_usize()?;
+        let j = rank.ceil().to_usize()?;
+        Some(self.results[i] + (self.results[j] - self.results[i]) * (rank - lower))
+    }
+}
+
+impl<T> FormulaEngine<T>
+where
+    T: FormulaValue + SampleUniform + Float,
+    StandardNormal: rand_distr::Distribution<T>,
+{
+    /// Evaluate the formula for the given number of samples, drawing each
+    /// component's value from its distribution, and return statistics over
+    /// the results.
+    pub fn monte_carlo<R: Rng>(
+        &self,
+        distributions: &HashMap<u64, InputDistribution<T>>,
+        samples: usize,
+        rng: &mut R,
+    ) -> Result<MonteCarloStats<T>, FormulaError> {
+        let samplers = distributions
+            .iter()
+            .map(|(&id, distribution)| Ok((id, Sampler::try_new(distribution)?)))
+            .collect::<Result<Vec<_>, FormulaError>>()?;
+
+        let mut results = Vec::with_capacity(samples);
+        let mut none_count = 0;
+        for _ in 0..samples {
+            let values: HashMap<_, _> = samplers
+                .iter()
+                .map(|(id, sampler)| (*id, sampler.sample(rng)))
+                .collect();
+            match self.calculate(values)? {
+                Some(result) => results.push(result),
+                None => none_count += 1,
+            }
+        }
+
+        Ok(MonteCarloStats::new(results, none_count))
+    }
+}
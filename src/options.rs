@@ -0,0 +1,434 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use num_traits::Float;
+
+use crate::error::FormulaError;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A source of the current time for time-dependent functions.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock, used by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<F: Fn() -> SystemTime + Send + Sync> Clock for F {
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
+
+/// How `ROUND` and `QUANTIZE` round values.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to the nearest value, with halfway values away from zero, e.g.
+    /// `2.5` to `3` and `-2.5` to `-3`.
+    #[default]
+    HalfUp,
+    /// Round to the nearest value, with halfway values to the even neighbour,
+    /// e.g. `2.5` to `2` and `3.5` to `4`.
+    HalfEven,
+    /// Round towards zero, e.g. `2.7` to `2` and `-2.7` to `-2`.
+    TowardZero,
+}
+
+impl RoundingMode {
+    /// Round a value to an integer.
+    pub(crate) fn round<T: Float>(self, x: T) -> T {
+        match self {
+            RoundingMode::HalfUp => x.round(),
+            RoundingMode::HalfEven => {
+                let two = T::one() + T::one();
+                if (x - x.trunc()).abs() == T::one() / two {
+                    (x / two).round() * two
+                } else {
+                    x.round()
+                }
+            }
+            RoundingMode::TowardZero => x.trunc(),
+        }
+    }
+}
+
+/// What dividing by zero, with `/` or `%`, evaluates to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DivisionByZero {
+    /// Evaluate to infinity with the sign of the dividend, or to NaN for
+    /// `0 / 0` and `x % 0`, as for floating point numbers.
+    #[default]
+    Infinity,
+    /// Evaluate to `None`.
+    None,
+    /// Fail with an error naming the division.
+    Error,
+}
+
+/// A day of the week.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ];
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A daily time-of-use window, e.g. a tariff's peak hours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouWindow {
+    start: u32,
+    end: u32,
+    weekdays: u8,
+}
+
+impl TouWindow {
+    /// Create a window from `start` (inclusive) to `end` (exclusive), given as
+    /// `(hour, minute)`, applying to every day of the week.
+    ///
+    /// A window whose end is before its start wraps around midnight.
+    pub fn try_new(start: (u32, u32), end: (u32, u32)) -> Result<Self, FormulaError> {
+        Ok(Self {
+            start: Self::seconds_of_day(start)?,
+            end: Self::seconds_of_day(end)?,
+            weekdays: Weekday::ALL.iter().fold(0, |acc, day| acc | day.mask()),
+        })
+    }
+
+    /// Restrict the window to the given days of the week.
+    ///
+    /// For windows wrapping around midnight, the day is the one the window
+    /// starts on.
+    pub fn on_weekdays(self, weekdays: &[Weekday]) -> Self {
+        Self {
+            weekdays: weekdays.iter().fold(0, |acc, day| acc | day.mask()),
+            ..self
+        }
+    }
+
+    fn seconds_of_day((hour, minute): (u32, u32)) -> Result<u32, FormulaError> {
+        if hour > 24 || minute > 59 || (hour == 24 && minute > 0) {
+            return Err(FormulaError(format!(
+                "Invalid time of day: {:02}:{:02}",
+                hour, minute
+            )));
+        }
+        Ok((hour * 60 + minute) * 60)
+    }
+
+    fn contains(&self, time: &LocalTime) -> bool {
+        if self.start <= self.end {
+            self.weekdays & time.weekday.mask() != 0
+                && (self.start..self.end).contains(&time.seconds_of_day)
+        } else if time.seconds_of_day >= self.start {
+            self.weekdays & time.weekday.mask() != 0
+        } else {
+            self.weekdays & time.previous_weekday().mask() != 0 && time.seconds_of_day < self.end
+        }
+    }
+}
+
+/// A point in time as seen on a wall clock.
+pub(crate) struct LocalTime {
+    pub(crate) seconds_of_day: u32,
+    pub(crate) weekday: Weekday,
+}
+
+impl LocalTime {
+    fn utc(time: SystemTime) -> Self {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+        };
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        Self {
+            seconds_of_day: seconds.rem_euclid(SECONDS_PER_DAY) as u32,
+            // 1970-01-01 was a Thursday.
+            weekday: Weekday::ALL[(days + 3).rem_euclid(7) as usize],
+        }
+    }
+
+    #[cfg(feature = "chrono-tz")]
+    fn in_timezone(time: SystemTime, timezone: &chrono_tz::Tz) -> Self {
+        use chrono::{Datelike, Timelike};
+
+        let time = chrono::DateTime::<chrono::Utc>::from(time).with_timezone(timezone);
+        Self {
+            seconds_of_day: time.num_seconds_from_midnight(),
+            weekday: Weekday::ALL[time.weekday().num_days_from_monday() as usize],
+        }
+    }
+
+    fn previous_weekday(&self) -> Weekday {
+        Weekday::ALL[(self.weekday as usize + 6) % 7]
+    }
+}
+
+/// Options controlling how a [`FormulaEngine`][crate::FormulaEngine]
+/// evaluates formulas.
+#[derive(Clone)]
+pub struct EngineOptions {
+    clock: Arc<dyn Clock>,
+    tou_windows: HashMap<String, Vec<TouWindow>>,
+    rounding_mode: RoundingMode,
+    division_by_zero: DivisionByZero,
+    none_as_zero: bool,
+    strict: bool,
+    memoize: bool,
+    warm_up: bool,
+    max_history_len: Option<usize>,
+    max_history_age: Option<Duration>,
+    #[cfg(feature = "chrono-tz")]
+    timezone: Option<chrono_tz::Tz>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            tou_windows: HashMap::new(),
+            rounding_mode: RoundingMode::default(),
+            division_by_zero: DivisionByZero::default(),
+            none_as_zero: false,
+            strict: false,
+            memoize: false,
+            warm_up: true,
+            max_history_len: None,
+            max_history_age: None,
+            #[cfg(feature = "chrono-tz")]
+            timezone: None,
+        }
+    }
+}
+
+impl Debug for EngineOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_struct("EngineOptions");
+        f.field("tou_windows", &self.tou_windows);
+        f.field("rounding_mode", &self.rounding_mode);
+        f.field("division_by_zero", &self.division_by_zero);
+        f.field("none_as_zero", &self.none_as_zero);
+        f.field("strict", &self.strict);
+        f.field("memoize", &self.memoize);
+        f.field("warm_up", &self.warm_up);
+        f.field("max_history_len", &self.max_history_len);
+        f.field("max_history_age", &self.max_history_age);
+        #[cfg(feature = "chrono-tz")]
+        f.field("timezone", &self.timezone);
+        f.finish_non_exhaustive()
+    }
+}
+
+impl EngineOptions {
+    /// Set the time source for time-dependent functions.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Add a time-of-use window that `TOU("name")` evaluates to `1` in.
+    ///
+    /// Adding several windows with the same name makes `TOU` match any of
+    /// them.
+    pub fn with_tou_window(mut self, name: impl Into<String>, window: TouWindow) -> Self {
+        self.tou_windows
+            .entry(name.into())
+            .or_default()
+            .push(window);
+        self
+    }
+
+    /// Set how `ROUND` and `QUANTIZE` round values. Defaults to
+    /// [`RoundingMode::HalfUp`].
+    pub fn with_rounding_mode(mut self, rounding_mode: RoundingMode) -> Self {
+        self.rounding_mode = rounding_mode;
+        self
+    }
+
+    /// Set what dividing by zero evaluates to. Defaults to
+    /// [`DivisionByZero::Infinity`].
+    pub fn with_division_by_zero(mut self, division_by_zero: DivisionByZero) -> Self {
+        self.division_by_zero = division_by_zero;
+        self
+    }
+
+    /// Set whether placeholders whose values are `None` are evaluated as `0`,
+    /// e.g. to sum the values of whichever components are reporting.
+    /// Defaults to `false`.
+    ///
+    /// Placeholders without a value are still an error.
+    pub fn with_none_as_zero(mut self, none_as_zero: bool) -> Self {
+        self.none_as_zero = none_as_zero;
+        self
+    }
+
+    /// Set whether calculating a formula is an error unless all its
+    /// placeholders, including those of branches that aren't taken, have
+    /// values that aren't `None`. Defaults to `false`.
+    ///
+    /// The error lists all placeholders without values, e.g.
+    /// `Missing values for #1, #3, $pv`. If `None` values are evaluated as
+    /// `0`, only placeholders without values are errors.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Set whether calculating a formula with the same placeholder values as
+    /// the previous calculation returns the previous result without
+    /// calculating it again, e.g. for telemetry that repeats samples.
+    /// Defaults to `false`.
+    ///
+    /// Formulas using the clock or custom functions are always calculated,
+    /// as are those whose previous calculation failed.
+    pub fn with_memoize(mut self, memoize: bool) -> Self {
+        self.memoize = memoize;
+        self
+    }
+
+    /// Set whether temporal functions over a window, e.g. `ROLLING_AVG` and
+    /// `TW_AVG`, evaluate to `None` until they have taken samples for a
+    /// whole window. Defaults to `true`; with `false` they use the samples so
+    /// far.
+    pub fn with_warm_up(mut self, warm_up: bool) -> Self {
+        self.warm_up = warm_up;
+        self
+    }
+
+    /// Set the maximum number of samples each temporal function keeps, e.g.
+    /// to bound the memory of a rolling average over a long window of
+    /// frequent samples. Defaults to no limit.
+    ///
+    /// Functions over a window then only use its latest samples. `INTEGRATE`
+    /// and `RAMP_LIMIT` keep only the samples they need regardless.
+    pub fn with_max_history_len(mut self, max_len: usize) -> Self {
+        self.max_history_len = Some(max_len);
+        self
+    }
+
+    /// Set the maximum age of the samples each temporal function keeps,
+    /// relative to its latest sample. Defaults to no limit.
+    ///
+    /// Functions over a window then only use its latest samples. `INTEGRATE`
+    /// and `RAMP_LIMIT` keep only the samples they need regardless.
+    pub fn with_max_history_age(mut self, max_age: Duration) -> Self {
+        self.max_history_age = Some(max_age);
+        self
+    }
+
+    /// Set the timezone time-of-use windows and the `HOUR()` and
+    /// `DAYOFWEEK()` functions are evaluated in. Defaults to UTC.
+    #[cfg(feature = "chrono-tz")]
+    pub fn with_timezone(mut self, timezone: chrono_tz::Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    pub(crate) fn has_tou_window(&self, name: &str) -> bool {
+        self.tou_windows.contains_key(name)
+    }
+
+    /// Check whether the current time is in the given time-of-use window.
+    pub(crate) fn in_tou_window(&self, name: &str) -> bool {
+        let now = self.local_time();
+        self.tou_windows
+            .get(name)
+            .is_some_and(|windows| windows.iter().any(|window| window.contains(&now)))
+    }
+
+    pub(crate) fn rounding_mode(&self) -> RoundingMode {
+        self.rounding_mode
+    }
+
+    pub(crate) fn division_by_zero(&self) -> DivisionByZero {
+        self.division_by_zero
+    }
+
+    pub(crate) fn none_as_zero(&self) -> bool {
+        self.none_as_zero
+    }
+
+    pub(crate) fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub(crate) fn memoize(&self) -> bool {
+        self.memoize
+    }
+
+    pub(crate) fn warm_up(&self) -> bool {
+        self.warm_up
+    }
+
+    pub(crate) fn max_history_len(&self) -> Option<usize> {
+        self.max_history_len
+    }
+
+    pub(crate) fn max_history_age(&self) -> Option<Duration> {
+        self.max_history_age
+    }
+
+    /// Get the value a placeholder is evaluated as, given its value.
+    pub(crate) fn placeholder_value<T: Float>(&self, value: Option<T>) -> Option<T> {
+        match value {
+            None if self.none_as_zero => Some(T::zero()),
+            value => value,
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    #[cfg(feature = "chrono-tz")]
+    pub(crate) fn timezone(&self) -> Option<&chrono_tz::Tz> {
+        self.timezone.as_ref()
+    }
+
+    pub(crate) fn local_time(&self) -> LocalTime {
+        #[cfg(feature = "chrono-tz")]
+        if let Some(timezone) = &self.timezone {
+            return LocalTime::in_timezone(self.now(), timezone);
+        }
+        LocalTime::utc(self.now())
+    }
+}
@@ -0,0 +1,49 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Parallel evaluation of batches of rows with rayon.
+
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use num_traits::Float;
+
+use crate::{error::FormulaError, formula_engine::FormulaEngine, value::FormulaValue};
+
+/// The number of chunks of rows per thread, so that threads that finish
+/// early can take over chunks of those that don't.
+const CHUNKS_PER_THREAD: usize = 4;
+
+impl<T: FormulaValue + Float + Send + Sync> FormulaEngine<T> {
+    /// Calculate the results of the formula for several rows of component
+    /// values, like [`FormulaEngine::calculate_batch`], but with the rows
+    /// split into chunks calculated on rayon's thread pool.
+    ///
+    /// The results are in the order of the rows.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 * 2").unwrap();
+    /// let rows: Vec<_> = (0..1000)
+    ///     .map(|i| HashMap::from([(0, Some(i as f64))]))
+    ///     .collect();
+    /// let results = fe.calculate_batch_par(&rows);
+    /// assert_eq!(results[999].as_ref().unwrap(), &Some(1998.0));
+    /// ```
+    pub fn calculate_batch_par(
+        &self,
+        rows: &[HashMap<u64, Option<T>>],
+    ) -> Vec<Result<Option<T>, FormulaError>> {
+        let chunks = rayon::current_num_threads() * CHUNKS_PER_THREAD;
+        let chunk_size = rows.len().div_ceil(chunks).max(1);
+        rows.par_chunks(chunk_size)
+            .map(|chunk| self.calculate_batch(chunk))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
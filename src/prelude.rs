@@ -0,0 +1,16 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Re-exports of the commonly used types and traits.
+//!
+//! ```rust
+//! use frequenz_microgrid_formula_engine::prelude::*;
+//!
+//! let fe = Formula64::try_new("#0 * 2").unwrap();
+//! ```
+
+pub use crate::{
+    Associativity, Clock, DivisionByZero, EngineOptions, Expr, Formula32, Formula64, FormulaEngine,
+    FormulaError, FormulaRegistry, FormulaValue, FunctionRegistry, Precedence, PrettyOptions,
+    RoundingMode, Sample, TouWindow, ValueProvider, Weekday,
+};
@@ -0,0 +1,401 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Protobuf messages for formulas, as defined in `proto/formula.proto`, with
+//! conversions from and to [`Expr`](crate::Expr).
+//!
+//! ```rust
+//! use frequenz_microgrid_formula_engine::{proto, Expr, FormulaEngine};
+//! use prost::Message;
+//!
+//! let fe = FormulaEngine::<f64>::try_new("MAX(#0, 0) * 2").unwrap();
+//! let bytes = proto::Formula::from(fe.expr()).encode_to_vec();
+//!
+//! let formula = proto::Formula::decode(bytes.as_slice()).unwrap();
+//! assert_eq!(&Expr::<f64>::try_from(formula).unwrap(), fe.expr());
+//! ```
+
+use num_traits::Float;
+
+use crate::{error::FormulaError, expression, value::FormulaValue};
+
+/// A formula.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Formula {
+    #[prost(message, optional, tag = "1")]
+    pub expr: Option<Expr>,
+}
+
+/// A node of the expression tree.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Expr {
+    #[prost(
+        oneof = "expr::Kind",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17"
+    )]
+    pub kind: Option<expr::Kind>,
+}
+
+/// Nested types of [`Expr`].
+pub mod expr {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        Value(super::Value),
+        #[prost(uint64, tag = "2")]
+        Component(u64),
+        #[prost(message, tag = "3")]
+        Wildcard(super::Wildcard),
+        #[prost(string, tag = "4")]
+        Named(String),
+        #[prost(message, tag = "5")]
+        Neg(Box<super::Expr>),
+        #[prost(message, tag = "6")]
+        Not(Box<super::Expr>),
+        #[prost(message, tag = "7")]
+        Op(Box<super::BinaryOp>),
+        #[prost(message, tag = "8")]
+        CustomOp(Box<super::CustomOp>),
+        #[prost(message, tag = "9")]
+        Function(super::FunctionCall),
+        #[prost(message, tag = "10")]
+        Custom(super::FunctionCall),
+        #[prost(message, tag = "11")]
+        LetIn(Box<super::Let>),
+        #[prost(string, tag = "12")]
+        Variable(String),
+        #[prost(string, tag = "13")]
+        Tou(String),
+        #[prost(enumeration = "super::TimeFunction", tag = "14")]
+        Time(i32),
+        #[prost(message, tag = "15")]
+        Select(super::Select),
+        #[prost(string, tag = "16")]
+        Reference(String),
+        #[prost(message, tag = "17")]
+        Temporal(super::FunctionCall),
+    }
+}
+
+/// A value, missing if `value` isn't set.
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Value {
+    #[prost(double, optional, tag = "1")]
+    pub value: Option<f64>,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Wildcard {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Operator {
+    Unspecified = 0,
+    Add = 1,
+    Sub = 2,
+    Mul = 3,
+    Div = 4,
+    Mod = 5,
+    Pow = 6,
+    Eq = 7,
+    Ne = 8,
+    Lt = 9,
+    Le = 10,
+    Gt = 11,
+    Ge = 12,
+    And = 13,
+    Or = 14,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BinaryOp {
+    #[prost(enumeration = "Operator", tag = "1")]
+    pub op: i32,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub lhs: Option<Box<Expr>>,
+    #[prost(message, optional, boxed, tag = "3")]
+    pub rhs: Option<Box<Expr>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CustomOp {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub lhs: Option<Box<Expr>>,
+    #[prost(message, optional, boxed, tag = "3")]
+    pub rhs: Option<Box<Expr>>,
+}
+
+/// A function call. Built-in functions are named in upper case, e.g. `MIN`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FunctionCall {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: Vec<Expr>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Let {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub value: Option<Box<Expr>>,
+    #[prost(message, optional, boxed, tag = "3")]
+    pub body: Option<Box<Expr>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum TimeFunction {
+    Unspecified = 0,
+    Now = 1,
+    Hour = 2,
+    Dayofweek = 3,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Select {
+    /// The name of the selecting function.
+    #[prost(string, tag = "1")]
+    pub function: String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: Vec<Expr>,
+    #[prost(message, repeated, tag = "3")]
+    pub branches: Vec<Expr>,
+}
+
+impl<T: FormulaValue + Float> From<&expression::Expr<T>> for Formula {
+    fn from(expr: &expression::Expr<T>) -> Self {
+        Formula {
+            expr: Some(expr.into()),
+        }
+    }
+}
+
+impl<T: FormulaValue> TryFrom<Formula> for expression::Expr<T> {
+    type Error = FormulaError;
+
+    fn try_from(formula: Formula) -> Result<Self, Self::Error> {
+        formula.expr.map(Box::new).try_into()
+    }
+}
+
+impl<T: FormulaValue + Float> From<&expression::Expr<T>> for Expr {
+    fn from(expr: &expression::Expr<T>) -> Self {
+        use expr::Kind;
+        use expression::Expr as E;
+
+        let node = |expr: &E<T>| Some(Box::new(Expr::from(expr)));
+        let nodes = |exprs: &[E<T>]| exprs.iter().map(Expr::from).collect();
+        let kind = match expr {
+            E::Value(value) => Kind::Value(Value {
+                value: value.and_then(|value| value.to_f64()),
+            }),
+            E::Component(id) => Kind::Component(*id),
+            E::Wildcard => Kind::Wildcard(Wildcard {}),
+            E::Named(name) => Kind::Named(name.clone()),
+            E::UnaryMinus(operand) => Kind::Neg(Box::new(operand.as_ref().into())),
+            E::Not(operand) => Kind::Not(Box::new(operand.as_ref().into())),
+            E::Op { lhs, op, rhs } => Kind::Op(Box::new(BinaryOp {
+                op: Operator::from(op) as i32,
+                lhs: node(lhs),
+                rhs: node(rhs),
+            })),
+            E::CustomOp { symbol, lhs, rhs } => Kind::CustomOp(Box::new(CustomOp {
+                symbol: symbol.clone(),
+                lhs: node(lhs),
+                rhs: node(rhs),
+            })),
+            E::Function { function, args } => Kind::Function(FunctionCall {
+                name: function.name().to_string(),
+                args: nodes(args),
+            }),
+            E::Custom { name, args } => Kind::Custom(FunctionCall {
+                name: name.clone(),
+                args: nodes(args),
+            }),
+            E::Temporal { function, args } => Kind::Temporal(FunctionCall {
+                name: function.name().to_string(),
+                args: nodes(args),
+            }),
+            E::Let { name, value, body } => Kind::LetIn(Box::new(Let {
+                name: name.clone(),
+                value: node(value),
+                body: node(body),
+            })),
+            E::Variable(name) => Kind::Variable(name.clone()),
+            E::Reference(name) => Kind::Reference(name.clone()),
+            E::TimeOfUse(window) => Kind::Tou(window.clone()),
+            E::Time(function) => Kind::Time(TimeFunction::from(function) as i32),
+            E::Select {
+                function,
+                args,
+                branches,
+            } => Kind::Select(Select {
+                function: function.name().to_string(),
+                args: nodes(args),
+                branches: nodes(branches),
+            }),
+        };
+        Expr { kind: Some(kind) }
+    }
+}
+
+impl<T: FormulaValue> TryFrom<Option<Box<Expr>>> for expression::Expr<T> {
+    type Error = FormulaError;
+
+    fn try_from(expr: Option<Box<Expr>>) -> Result<Self, Self::Error> {
+        match expr {
+            Some(expr) => (*expr).try_into(),
+            None => Err(FormulaError(
+                "Missing expression in formula message".to_string(),
+            )),
+        }
+    }
+}
+
+impl<T: FormulaValue> TryFrom<Expr> for expression::Expr<T> {
+    type Error = FormulaError;
+
+    fn try_from(expr: Expr) -> Result<Self, Self::Error> {
+        use expr::Kind;
+        use expression::{Expr as E, Function, TemporalFunction};
+
+        let node = |expr: Option<Box<Expr>>| E::<T>::try_from(expr).map(Box::new);
+        let nodes = |exprs: Vec<Expr>| -> Result<Vec<E<T>>, FormulaError> {
+            exprs.into_iter().map(E::try_from).collect()
+        };
+        let function = |name: String| {
+            Function::from_name(&name)
+                .ok_or_else(|| FormulaError(format!("Unknown function: {}", name)))
+        };
+        Ok(match expr.kind {
+            None => {
+                return Err(FormulaError(
+                    "Missing expression in formula message".to_string(),
+                ))
+            }
+            Some(Kind::Value(Value { value })) => E::Value(value.and_then(T::from_f64)),
+            Some(Kind::Component(id)) => E::Component(id),
+            Some(Kind::Wildcard(_)) => E::Wildcard,
+            Some(Kind::Named(name)) => E::Named(name),
+            Some(Kind::Neg(operand)) => E::UnaryMinus(node(Some(operand))?),
+            Some(Kind::Not(operand)) => E::Not(node(Some(operand))?),
+            Some(Kind::Op(op)) => E::Op {
+                op: Operator::try_from(op.op)
+                    .ok()
+                    .and_then(Operator::to_op)
+                    .ok_or_else(|| FormulaError(format!("Unknown operator: {}", op.op)))?,
+                lhs: node(op.lhs)?,
+                rhs: node(op.rhs)?,
+            },
+            Some(Kind::CustomOp(op)) => E::CustomOp {
+                symbol: op.symbol,
+                lhs: node(op.lhs)?,
+                rhs: node(op.rhs)?,
+            },
+            Some(Kind::Function(call)) => E::Function {
+                function: function(call.name)?,
+                args: nodes(call.args)?,
+            },
+            Some(Kind::Custom(call)) => E::Custom {
+                name: call.name,
+                args: nodes(call.args)?,
+            },
+            Some(Kind::Temporal(call)) => E::Temporal {
+                function: TemporalFunction::from_name(&call.name)
+                    .ok_or_else(|| FormulaError(format!("Unknown function: {}", call.name)))?,
+                args: nodes(call.args)?,
+            },
+            Some(Kind::LetIn(let_in)) => E::Let {
+                name: let_in.name,
+                value: node(let_in.value)?,
+                body: node(let_in.body)?,
+            },
+            Some(Kind::Variable(name)) => E::Variable(name),
+            Some(Kind::Reference(name)) => E::Reference(name),
+            Some(Kind::Tou(window)) => E::TimeOfUse(window),
+            Some(Kind::Time(function)) => E::Time(
+                TimeFunction::try_from(function)
+                    .ok()
+                    .and_then(TimeFunction::to_time_function)
+                    .ok_or_else(|| FormulaError(format!("Unknown time function: {}", function)))?,
+            ),
+            Some(Kind::Select(select)) => E::Select {
+                function: function(select.function)?,
+                args: nodes(select.args)?,
+                branches: nodes(select.branches)?,
+            },
+        })
+    }
+}
+
+impl From<&expression::Op> for Operator {
+    fn from(op: &expression::Op) -> Self {
+        use expression::Op;
+
+        match op {
+            Op::Add => Operator::Add,
+            Op::Sub => Operator::Sub,
+            Op::Mul => Operator::Mul,
+            Op::Div => Operator::Div,
+            Op::Mod => Operator::Mod,
+            Op::Pow => Operator::Pow,
+            Op::Eq => Operator::Eq,
+            Op::Ne => Operator::Ne,
+            Op::Lt => Operator::Lt,
+            Op::Le => Operator::Le,
+            Op::Gt => Operator::Gt,
+            Op::Ge => Operator::Ge,
+            Op::And => Operator::And,
+            Op::Or => Operator::Or,
+        }
+    }
+}
+
+impl Operator {
+    fn to_op(self) -> Option<expression::Op> {
+        use expression::Op;
+
+        Some(match self {
+            Operator::Unspecified => return None,
+            Operator::Add => Op::Add,
+            Operator::Sub => Op::Sub,
+            Operator::Mul => Op::Mul,
+            Operator::Div => Op::Div,
+            Operator::Mod => Op::Mod,
+            Operator::Pow => Op::Pow,
+            Operator::Eq => Op::Eq,
+            Operator::Ne => Op::Ne,
+            Operator::Lt => Op::Lt,
+            Operator::Le => Op::Le,
+            Operator::Gt => Op::Gt,
+            Operator::Ge => Op::Ge,
+            Operator::And => Op::And,
+            Operator::Or => Op::Or,
+        })
+    }
+}
+
+impl From<&expression::TimeFunction> for TimeFunction {
+    fn from(function: &expression::TimeFunction) -> Self {
+        match function {
+            expression::TimeFunction::Now => TimeFunction::Now,
+            expression::TimeFunction::Hour => TimeFunction::Hour,
+            expression::TimeFunction::DayOfWeek => TimeFunction::Dayofweek,
+        }
+    }
+}
+
+impl TimeFunction {
+    fn to_time_function(self) -> Option<expression::TimeFunction> {
+        Some(match self {
+            TimeFunction::Unspecified => return None,
+            TimeFunction::Now => expression::TimeFunction::Now,
+            TimeFunction::Hour => expression::TimeFunction::Hour,
+            TimeFunction::Dayofweek => expression::TimeFunction::DayOfWeek,
+        })
+    }
+}
@@ -0,0 +1,113 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{Bound, Expr, Function, Inputs, Provided, ValueProvider},
+    formula_engine::FormulaEngine,
+    functions::FunctionRegistry,
+    options::EngineOptions,
+    value::FormulaValue,
+};
+
+/// The argument a `COALESCE` of a formula fell back to in a calculation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CoalesceChoice {
+    /// The `COALESCE` call, as the formula displays it.
+    pub coalesce: String,
+    /// The index of the first argument that isn't `None`, counting the
+    /// values of `#*` as arguments, or `None` if all of them are.
+    pub used: Option<usize>,
+}
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Calculate the result of the formula like [`calculate`](Self::calculate),
+    /// together with the argument each `COALESCE` the result depends on fell
+    /// back to, in the order of the formula.
+    ///
+    /// `COALESCE` calls in branches that aren't taken, or in arguments of
+    /// other `COALESCE` calls that aren't used, are left out.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::{CoalesceChoice, FormulaEngine};
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, #1, #2 * 2)").unwrap();
+    /// let values = HashMap::from([(0, None), (1, None), (2, Some(2.0))]);
+    /// let (result, choices) = fe.calculate_with_provenance(&values).unwrap();
+    /// assert_eq!(result, Some(4.0));
+    /// assert_eq!(
+    ///     choices,
+    ///     vec![CoalesceChoice {
+    ///         coalesce: "COALESCE(#0, #1, #2 * 2)".to_string(),
+    ///         used: Some(2),
+    ///     }]
+    /// );
+    /// ```
+    pub fn calculate_with_provenance(
+        &self,
+        values: impl ValueProvider<T>,
+    ) -> Result<(Option<T>, Vec<CoalesceChoice>), FormulaError> {
+        let values = Provided(values);
+        let result = self.calculate_inputs(&values)?;
+        let mut choices = Vec::new();
+        self.expr()
+            .coalesce_choices(&values, &self.options, &self.functions, &mut choices)?;
+        Ok((result, choices))
+    }
+}
+
+impl<T: FormulaValue + Float> Expr<T> {
+    /// Add the choices of the `COALESCE` calls the result of the expression
+    /// depends on.
+    fn coalesce_choices(
+        &self,
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+        choices: &mut Vec<CoalesceChoice>,
+    ) -> Result<(), FormulaError> {
+        let used: Vec<_> = match self {
+            Expr::Let { name, value, body } => {
+                value.coalesce_choices(values, options, functions, choices)?;
+                let bound = Bound {
+                    inputs: values,
+                    name,
+                    value: value.calculate(values, options, functions)?,
+                };
+                return body.coalesce_choices(&bound, options, functions, choices);
+            }
+            Expr::Function { function, args } if function.is_selecting() => {
+                if *function == Function::Coalesce {
+                    choices.push(CoalesceChoice {
+                        coalesce: self.to_string(),
+                        used: Expr::select(function, args, values, options, functions)?,
+                    });
+                }
+                if args.iter().any(|arg| matches!(arg, Expr::Wildcard)) {
+                    args.iter().collect()
+                } else {
+                    Expr::used_args(function, args, values, options, functions)?
+                        .into_iter()
+                        .map(|i| &args[i])
+                        .collect()
+                }
+            }
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => match Expr::select(function, args, values, options, functions)? {
+                Some(i) => vec![&args[i], &branches[i]],
+                None => args.iter().collect(),
+            },
+            expr => expr.children(),
+        };
+        for expr in used {
+            expr.coalesce_choices(values, options, functions, choices)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,636 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Translation of formulas to Python source, to check the engine's results
+//! in notebooks.
+
+use std::collections::HashSet;
+
+use num_traits::Float;
+
+use crate::{
+    display::select_case,
+    error::FormulaError,
+    expression::{Expr, Function, Op},
+    formula_engine::FormulaEngine,
+    options::{DivisionByZero, RoundingMode},
+    value::FormulaValue,
+};
+
+/// The helper functions the translated formulas call, with the helpers they
+/// call in turn. They implement the engine's handling of `None`, and return
+/// infinities and NaN where Python would raise an exception.
+///
+/// `_rint`, which rounds with the engine's rounding mode, is given by
+/// [`rint`].
+const HELPERS: &[(&str, &[&str], &str)] = &[
+    (
+        "_negate",
+        &[],
+        "def _negate(x):
+    return None if x is None else -x",
+    ),
+    (
+        "_not",
+        &[],
+        "def _not(x):
+    return None if x is None else float(x == 0)",
+    ),
+    (
+        "_add",
+        &[],
+        "def _add(a, b):
+    return None if a is None or b is None else a + b",
+    ),
+    (
+        "_sub",
+        &[],
+        "def _sub(a, b):
+    return None if a is None or b is None else a - b",
+    ),
+    (
+        "_mul",
+        &[],
+        "def _mul(a, b):
+    return None if a is None or b is None else a * b",
+    ),
+    (
+        "_div",
+        &[],
+        "def _div(a, b):
+    if a is None or b is None:
+        return None
+    if b == 0:
+        if a == 0 or math.isnan(a):
+            return math.nan
+        return math.copysign(math.inf, a) * math.copysign(1.0, b)
+    return a / b",
+    ),
+    (
+        "_mod",
+        &[],
+        "def _mod(a, b):
+    if a is None or b is None:
+        return None
+    if b == 0 or math.isinf(a):
+        return math.nan
+    return math.fmod(a, b)",
+    ),
+    (
+        "_pow",
+        &[],
+        "def _pow(a, b):
+    if a is None or b is None:
+        return None
+    odd = math.isfinite(b) and b % 2 == 1
+    try:
+        return math.pow(a, b)
+    except ValueError:
+        if a != 0:
+            return math.nan
+        return math.copysign(math.inf, a) if odd else math.inf
+    except OverflowError:
+        return -math.inf if a < 0 and odd else math.inf",
+    ),
+    (
+        "_eq",
+        &[],
+        "def _eq(a, b):
+    return None if a is None or b is None else float(a == b)",
+    ),
+    (
+        "_ne",
+        &[],
+        "def _ne(a, b):
+    return None if a is None or b is None else float(a != b)",
+    ),
+    (
+        "_lt",
+        &[],
+        "def _lt(a, b):
+    return None if a is None or b is None else float(a < b)",
+    ),
+    (
+        "_le",
+        &[],
+        "def _le(a, b):
+    return None if a is None or b is None else float(a <= b)",
+    ),
+    (
+        "_gt",
+        &[],
+        "def _gt(a, b):
+    return None if a is None or b is None else float(a > b)",
+    ),
+    (
+        "_ge",
+        &[],
+        "def _ge(a, b):
+    return None if a is None or b is None else float(a >= b)",
+    ),
+    (
+        "_and",
+        &[],
+        "def _and(a, b):
+    if a == 0 or b == 0:
+        return 0.0
+    return None if a is None or b is None else 1.0",
+    ),
+    (
+        "_or",
+        &[],
+        "def _or(a, b):
+    if (a is not None and a != 0) or (b is not None and b != 0):
+        return 1.0
+    return None if a is None or b is None else 0.0",
+    ),
+    (
+        "_if",
+        &[],
+        "def _if(condition, then, otherwise):
+    if condition is None:
+        return None
+    return then() if condition != 0 else otherwise()",
+    ),
+    (
+        "_coalesce",
+        &[],
+        "def _coalesce(*args):
+    return next((arg for arg in args if arg is not None), None)",
+    ),
+    (
+        "_min",
+        &[],
+        "def _min(*args):
+    result = None
+    for arg in args:
+        if arg is not None and (result is None or not result < arg):
+            result = arg
+    return result",
+    ),
+    (
+        "_max",
+        &[],
+        "def _max(*args):
+    result = None
+    for arg in args:
+        if arg is not None and (result is None or not result > arg):
+            result = arg
+    return result",
+    ),
+    (
+        "_min_strict",
+        &["_min"],
+        "def _min_strict(*args):
+    return None if None in args else _min(*args)",
+    ),
+    (
+        "_max_strict",
+        &["_max"],
+        "def _max_strict(*args):
+    return None if None in args else _max(*args)",
+    ),
+    (
+        "_pos",
+        &[],
+        "def _pos(x):
+    return None if x is None else x if x > 0 else 0.0",
+    ),
+    (
+        "_neg",
+        &[],
+        "def _neg(x):
+    return None if x is None else x if x < 0 else 0.0",
+    ),
+    (
+        "_hypot",
+        &[],
+        "def _hypot(*args):
+    return None if None in args else math.hypot(*args)",
+    ),
+    (
+        "_sin",
+        &[],
+        "def _sin(x):
+    return None if x is None else math.nan if math.isinf(x) else math.sin(x)",
+    ),
+    (
+        "_cos",
+        &[],
+        "def _cos(x):
+    return None if x is None else math.nan if math.isinf(x) else math.cos(x)",
+    ),
+    (
+        "_tan",
+        &[],
+        "def _tan(x):
+    return None if x is None else math.nan if math.isinf(x) else math.tan(x)",
+    ),
+    (
+        "_atan2",
+        &[],
+        "def _atan2(y, x):
+    return None if y is None or x is None else math.atan2(y, x)",
+    ),
+    (
+        "_lerp",
+        &[],
+        "def _lerp(a, b, t):
+    return None if None in (a, b, t) else a + (b - a) * t",
+    ),
+    (
+        "_poly",
+        &[],
+        "def _poly(x, *coefficients):
+    if x is None or None in coefficients:
+        return None
+    result = 0.0
+    for c in reversed(coefficients):
+        result = result * x + c
+    return result",
+    ),
+    (
+        "_curve",
+        &[],
+        "def _curve(x, *coordinates):
+    if x is None or None in coordinates:
+        return None
+    points = list(zip(coordinates[::2], coordinates[1::2]))
+    if any(x0 > x1 for (x0, _), (x1, _) in zip(points, points[1:])):
+        return None
+    if x <= points[0][0]:
+        return points[0][1]
+    if x >= points[-1][0]:
+        return points[-1][1]
+    for (x0, y0), (x1, y1) in zip(points, points[1:]):
+        if x <= x1:
+            return y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+    return None",
+    ),
+    (
+        "_round",
+        &["_rint"],
+        "def _round(x):
+    return None if x is None else _rint(x)",
+    ),
+    (
+        "_round_digits",
+        &["_rint", "_half_up", "_pow"],
+        "def _round_digits(x, digits):
+    if x is None or digits is None:
+        return None
+    scale = _pow(10.0, _half_up(digits))
+    return _rint(x * scale) / scale",
+    ),
+    (
+        "_quantize",
+        &["_rint"],
+        "def _quantize(x, step):
+    if x is None or step is None or not step > 0:
+        return None
+    return _rint(x / step) * step",
+    ),
+    (
+        "_half_up",
+        &[],
+        "def _half_up(x):
+    if not math.isfinite(x):
+        return x
+    integer = float(math.floor(abs(x)))
+    if abs(x) - integer >= 0.5:
+        integer += 1
+    return math.copysign(integer, x)",
+    ),
+    (
+        "_floor",
+        &[],
+        "def _floor(x):
+    return x if x is None or not math.isfinite(x) else float(math.floor(x))",
+    ),
+    (
+        "_ceil",
+        &[],
+        "def _ceil(x):
+    return x if x is None or not math.isfinite(x) else float(math.ceil(x))",
+    ),
+    (
+        "_sum",
+        &[],
+        "def _sum(*args):
+    result = None
+    for arg in args:
+        if arg is not None:
+            result = arg if result is None else result + arg
+    return result",
+    ),
+    (
+        "_product",
+        &[],
+        "def _product(*args):
+    if None in args:
+        return None
+    result = 1.0
+    for arg in args:
+        result *= arg
+    return result",
+    ),
+    (
+        "_nullif",
+        &[],
+        "def _nullif(a, b):
+    return None if a is not None and b is not None and a == b else a",
+    ),
+    (
+        "_is_none",
+        &[],
+        "def _is_none(x):
+    return float(x is None)",
+    ),
+    (
+        "_is_some",
+        &[],
+        "def _is_some(x):
+    return float(x is not None)",
+    ),
+    (
+        "_count_some",
+        &[],
+        "def _count_some(*args):
+    return float(sum(arg is not None for arg in args))",
+    ),
+];
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Translate the formula to the source of a Python function `formula`,
+    /// which takes a `dict` of the values of the formula's placeholders, like
+    /// [`FormulaEngine::calculate`] and [`FormulaEngine::calculate_named`],
+    /// and returns the result of the formula as a `float`, or `None`.
+    ///
+    /// The function only needs the `math` module. Formulas with time
+    /// functions, time-of-use windows or custom functions and operators can't
+    /// be translated, as these depend on the engine. If the engine evaluates
+    /// `None` values as `0`, the function replaces them by `0.0` first. If it
+    /// fails on division by zero, the function raises `ZeroDivisionError`.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + MAX(#1, 0)").unwrap();
+    /// assert_eq!(
+    ///     fe.to_python().unwrap().lines().last(),
+    ///     Some("    return _add(values[0], _max(values[1], 0.0))")
+    /// );
+    /// ```
+    pub fn to_python(&self) -> Result<String, FormulaError> {
+        let rounding_mode = self.options.rounding_mode();
+        let division_by_zero = self.options.division_by_zero();
+        let mut helpers = HashSet::new();
+        let body = match division_by_zero {
+            DivisionByZero::None => self.expr().nullif_divisors().python(&mut helpers)?,
+            _ => self.expr().python(&mut helpers)?,
+        };
+        if helpers.contains("_rint") && rounding_mode == RoundingMode::HalfUp {
+            helpers.insert("_half_up");
+        }
+        let mut source = String::from("import math\n\n\n");
+        for (name, _, helper) in HELPERS {
+            if helpers.contains(name) {
+                let helper = match division_by_zero {
+                    DivisionByZero::Error => division_error(name).unwrap_or(helper),
+                    _ => helper,
+                };
+                source.push_str(&format!("{}\n\n\n", helper));
+            }
+            if *name == "_round" && helpers.contains("_rint") {
+                source.push_str(&format!("{}\n\n\n", rint(rounding_mode)));
+            }
+        }
+        source.push_str("def formula(values):\n");
+        if self.options.none_as_zero() {
+            source.push_str(
+                "    values = {key: 0.0 if value is None else value for key, value in values.items()}\n",
+            );
+        }
+        source.push_str(&format!("    return {}", body));
+        Ok(source)
+    }
+}
+
+impl<T: FormulaValue + Float> Expr<T> {
+    /// Translate the expression to a Python expression, collecting the
+    /// helper functions it calls.
+    fn python(&self, helpers: &mut HashSet<&'static str>) -> Result<String, FormulaError> {
+        let all = |args: &[Expr<T>], helpers: &mut HashSet<_>| {
+            args.iter()
+                .map(|arg| arg.python(helpers))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match self {
+            Expr::Value(value) => value_python(*value),
+            Expr::Component(id) => format!("values[{}]", id),
+            Expr::Named(name) => format!("values[{}]", string_python(name)),
+            Expr::Wildcard => "*(values[key] for key in sorted(values))".to_string(),
+            Expr::UnaryMinus(operand) => {
+                let operand = operand.python(helpers)?;
+                call(helpers, "_negate", &[operand])
+            }
+            Expr::Not(operand) => {
+                let operand = operand.python(helpers)?;
+                call(helpers, "_not", &[operand])
+            }
+            Expr::Op { lhs, op, rhs } => {
+                let args = [lhs.python(helpers)?, rhs.python(helpers)?];
+                let helper = match op {
+                    Op::Add => "_add",
+                    Op::Sub => "_sub",
+                    Op::Mul => "_mul",
+                    Op::Div => "_div",
+                    Op::Mod => "_mod",
+                    Op::Pow => "_pow",
+                    Op::Eq => "_eq",
+                    Op::Ne => "_ne",
+                    Op::Lt => "_lt",
+                    Op::Le => "_le",
+                    Op::Gt => "_gt",
+                    Op::Ge => "_ge",
+                    Op::And => "_and",
+                    Op::Or => "_or",
+                };
+                call(helpers, helper, &args)
+            }
+            // IF and CASE only evaluate the selected branch.
+            Expr::Function {
+                function: Function::If | Function::Case,
+                args,
+            } => {
+                let args = all(args, helpers)?;
+                let (branches, default) = match args.split_last() {
+                    Some((default, branches)) if args.len() % 2 == 1 => (branches, default.clone()),
+                    _ => (&args[..], "None".to_string()),
+                };
+                branches.chunks(2).rev().fold(default, |otherwise, branch| {
+                    call(
+                        helpers,
+                        "_if",
+                        &[
+                            branch[0].clone(),
+                            format!("lambda: {}", branch[1]),
+                            format!("lambda: {}", otherwise),
+                        ],
+                    )
+                })
+            }
+            Expr::Function { function, args } => {
+                let mut args = all(args, helpers)?;
+                let helper = match function {
+                    Function::Coalesce => "_coalesce",
+                    Function::Min => "_min",
+                    Function::Max => "_max",
+                    Function::MinStrict => "_min_strict",
+                    Function::MaxStrict => "_max_strict",
+                    Function::Pos => "_pos",
+                    Function::Neg => "_neg",
+                    Function::Hypot => "_hypot",
+                    Function::Sin => "_sin",
+                    Function::Cos => "_cos",
+                    Function::Tan => "_tan",
+                    Function::Atan2 => "_atan2",
+                    Function::Lerp => "_lerp",
+                    Function::Curve => "_curve",
+                    Function::Poly => "_poly",
+                    Function::Kw | Function::Kwh => {
+                        args.push("1000.0".to_string());
+                        "_mul"
+                    }
+                    Function::Mw => {
+                        args.push("1000000.0".to_string());
+                        "_mul"
+                    }
+                    Function::Pow => "_pow",
+                    Function::Round if args.len() == 2 => "_round_digits",
+                    Function::Round => "_round",
+                    Function::Quantize => "_quantize",
+                    Function::Floor => "_floor",
+                    Function::Ceil => "_ceil",
+                    Function::Sum => "_sum",
+                    Function::Product => "_product",
+                    Function::NullIf => "_nullif",
+                    Function::IsNone => "_is_none",
+                    Function::IsSome => "_is_some",
+                    Function::CountSome => "_count_some",
+                    Function::If | Function::Case => unreachable!("IF and CASE are handled above"),
+                };
+                call(helpers, helper, &args)
+            }
+            // The value is bound to the parameter of a lambda, so that it is
+            // only calculated once.
+            Expr::Let { name, value, body } => format!(
+                "(lambda {}: {})({})",
+                variable_python(name),
+                body.python(helpers)?,
+                value.python(helpers)?
+            ),
+            Expr::Variable(name) => variable_python(name),
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => select_case(function, args, branches).python(helpers)?,
+            Expr::CustomOp { symbol, .. } => {
+                return Err(unsupported(&format!("Custom operator {}", symbol)))
+            }
+            Expr::Custom { name, .. } => {
+                return Err(unsupported(&format!("Custom function {}", name)))
+            }
+            Expr::Reference(name) => return Err(unsupported(&format!("@{}", name))),
+            Expr::TimeOfUse(name) => return Err(unsupported(&format!("TOU(\"{}\")", name))),
+            Expr::Time(function) => return Err(unsupported(&format!("{}()", function.name()))),
+            Expr::Temporal { function, .. } => return Err(unsupported(function.name())),
+        })
+    }
+}
+
+/// Call a helper function, collecting it and the helpers it calls.
+fn call(helpers: &mut HashSet<&'static str>, helper: &'static str, args: &[String]) -> String {
+    fn collect(helpers: &mut HashSet<&'static str>, helper: &'static str) {
+        if helpers.insert(helper) {
+            let dependencies = HELPERS
+                .iter()
+                .find(|(name, ..)| *name == helper)
+                .map_or(&[][..], |(_, dependencies, _)| dependencies);
+            for dependency in dependencies {
+                collect(helpers, dependency);
+            }
+        }
+    }
+    collect(helpers, helper);
+    format!("{}({})", helper, args.join(", "))
+}
+
+/// The helper that rounds a value to an integer with the given rounding mode.
+fn rint(rounding_mode: RoundingMode) -> &'static str {
+    match rounding_mode {
+        RoundingMode::HalfUp => {
+            "def _rint(x):
+    return _half_up(x)"
+        }
+        // Python's `round` rounds halfway values to even.
+        RoundingMode::HalfEven => {
+            "def _rint(x):
+    return float(round(x)) if math.isfinite(x) else x"
+        }
+        RoundingMode::TowardZero => {
+            "def _rint(x):
+    return float(math.trunc(x)) if math.isfinite(x) else x"
+        }
+    }
+}
+
+/// Get the source of `_div` or `_mod` raising `ZeroDivisionError`, like the
+/// engine fails, on division by zero.
+fn division_error(name: &str) -> Option<&'static str> {
+    match name {
+        "_div" => Some(
+            "def _div(a, b):
+    if b == 0:
+        raise ZeroDivisionError(\"division by zero\")
+    return None if a is None or b is None else a / b",
+        ),
+        "_mod" => Some(
+            "def _mod(a, b):
+    if b == 0:
+        raise ZeroDivisionError(\"modulo by zero\")
+    if a is None or b is None:
+        return None
+    if math.isinf(a):
+        return math.nan
+    return math.fmod(a, b)",
+        ),
+        _ => None,
+    }
+}
+
+fn value_python<T: FormulaValue + Float>(value: Option<T>) -> String {
+    match value.and_then(|value| value.to_f64()) {
+        None => "None".to_string(),
+        Some(value) if value.is_nan() => "math.nan".to_string(),
+        Some(value) if value == f64::INFINITY => "math.inf".to_string(),
+        Some(value) if value == f64::NEG_INFINITY => "-math.inf".to_string(),
+        // The `Debug` format of a float is a valid Python float literal.
+        Some(value) => format!("{:?}", value),
+    }
+}
+
+fn string_python(string: &str) -> String {
+    format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Prefix `LET` variables, so that they can't clash with Python's keywords
+/// or the names in the translated source.
+fn variable_python(name: &str) -> String {
+    format!("var_{}", name)
+}
+
+fn unsupported(what: &str) -> FormulaError {
+    FormulaError(format!("{} can't be translated to Python", what))
+}
@@ -0,0 +1,327 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{collections::HashMap, ops::Neg};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    expression::{from_bool, Bound, Expr, Function, Inputs, Provided, ValueProvider},
+    formula_engine::FormulaEngine,
+    functions::FunctionRegistry,
+    options::EngineOptions,
+    value::FormulaValue,
+};
+
+/// How trustworthy a value is, ordered from best to worst.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Quality {
+    /// A valid measurement.
+    #[default]
+    Good,
+    /// A value that may be wrong, e.g. an estimate or a measurement out of
+    /// its usual range.
+    Suspect,
+    /// A value that is known to be wrong.
+    Bad,
+}
+
+/// The qualities of the placeholders a formula is calculated with, keyed
+/// like their values: by component ID for `#id` placeholders, e.g. as a
+/// `HashMap<u64, Quality>`, and by name for `$name` and `#"id"`
+/// placeholders, e.g. as a `HashMap<String, Quality>`.
+///
+/// Placeholders without a quality are [`Quality::Good`].
+pub trait Qualities {
+    /// Get the quality of the `#id` placeholder, if given.
+    fn component(&self, id: u64) -> Option<Quality>;
+
+    /// Get the quality of the `$name` or `#"id"` placeholder, if given.
+    fn named(&self, name: &str) -> Option<Quality>;
+
+    /// Get the worst quality of all given placeholders, for `#*`.
+    fn worst(&self) -> Quality;
+}
+
+impl Qualities for HashMap<u64, Quality> {
+    fn component(&self, id: u64) -> Option<Quality> {
+        self.get(&id).copied()
+    }
+
+    fn named(&self, _name: &str) -> Option<Quality> {
+        None
+    }
+
+    fn worst(&self) -> Quality {
+        self.values().copied().max().unwrap_or_default()
+    }
+}
+
+impl Qualities for HashMap<String, Quality> {
+    fn component(&self, _id: u64) -> Option<Quality> {
+        None
+    }
+
+    fn named(&self, name: &str) -> Option<Quality> {
+        self.get(name).copied()
+    }
+
+    fn worst(&self) -> Quality {
+        self.values().copied().max().unwrap_or_default()
+    }
+}
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Calculate the result of the formula like [`calculate`](Self::calculate),
+    /// together with the worst quality of the component values it was
+    /// calculated from.
+    ///
+    /// Components without a quality are [`Quality::Good`]. Only the values
+    /// the result depends on count, e.g. the argument `COALESCE` falls back
+    /// to and the conditions and branch of an `IF` that is taken, so that a
+    /// bad value that isn't used doesn't make the result bad. Functions
+    /// comparing their arguments, like `MIN`, `MAX` and `NULLIF`, depend on
+    /// all of them.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::{FormulaEngine, Quality};
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, #1 + #2)").unwrap();
+    /// let qualities = HashMap::from([(0, Quality::Bad), (2, Quality::Suspect)]);
+    /// let values = HashMap::from([(0, None), (1, Some(1.0)), (2, Some(2.0))]);
+    /// assert_eq!(
+    ///     fe.calculate_with_quality(&values, &qualities).unwrap(),
+    ///     (Some(3.0), Quality::Suspect)
+    /// );
+    /// ```
+    pub fn calculate_with_quality(
+        &self,
+        values: impl ValueProvider<T>,
+        qualities: &impl Qualities,
+    ) -> Result<(Option<T>, Quality), FormulaError> {
+        self.calculate_inputs_with_quality(&Provided(values), qualities)
+    }
+
+    /// Calculate the result of the formula like
+    /// [`calculate_named`](Self::calculate_named), together with the worst
+    /// quality of the values it was calculated from, like
+    /// [`calculate_with_quality`](Self::calculate_with_quality).
+    pub fn calculate_named_with_quality(
+        &self,
+        values: HashMap<String, Option<T>>,
+        qualities: &impl Qualities,
+    ) -> Result<(Option<T>, Quality), FormulaError> {
+        self.calculate_inputs_with_quality(&values, qualities)
+    }
+
+    fn calculate_inputs_with_quality(
+        &self,
+        values: &impl Inputs<T>,
+        qualities: &impl Qualities,
+    ) -> Result<(Option<T>, Quality), FormulaError> {
+        if self.options.strict() {
+            self.check_values(values)?;
+        }
+        self.expr().calculate_with_quality(
+            values,
+            &self.options,
+            &self.functions,
+            qualities,
+            &mut Vec::new(),
+        )
+    }
+}
+
+impl<T: FormulaValue + Float> Expr<T> {
+    /// Calculate the result of the expression like [`Expr::calculate`],
+    /// together with the worst quality of the values it depends on, with the
+    /// qualities of the `LET` variables in scope.
+    fn calculate_with_quality(
+        &self,
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+        qualities: &dyn Qualities,
+        scope: &mut Vec<(String, Quality)>,
+    ) -> Result<(Option<T>, Quality), FormulaError> {
+        let calculate = |expr: &Expr<T>, scope: &mut Vec<(String, Quality)>| {
+            expr.calculate_with_quality(values, options, functions, qualities, scope)
+        };
+        let worst = |args: &[(Option<T>, Quality)]| {
+            args.iter()
+                .map(|(_, quality)| *quality)
+                .max()
+                .unwrap_or_default()
+        };
+        Ok(match self {
+            Expr::UnaryMinus(expr) => {
+                let (value, quality) = calculate(expr, scope)?;
+                (value.map(Neg::neg), quality)
+            }
+            Expr::Not(expr) => {
+                let (value, quality) = calculate(expr, scope)?;
+                (value.map(|x| from_bool(x == T::zero())), quality)
+            }
+            Expr::Op { lhs, op, rhs } => {
+                let (lhs, lhs_quality) = calculate(lhs, scope)?;
+                let (rhs, rhs_quality) = calculate(rhs, scope)?;
+                (
+                    self.apply_op(op, lhs, rhs, options)?,
+                    lhs_quality.max(rhs_quality),
+                )
+            }
+            Expr::CustomOp { symbol, lhs, rhs } => {
+                let (lhs, lhs_quality) = calculate(lhs, scope)?;
+                let (rhs, rhs_quality) = calculate(rhs, scope)?;
+                match functions.operator(symbol) {
+                    Some(op) => ((op.function)(lhs, rhs), lhs_quality.max(rhs_quality)),
+                    None => return Err(FormulaError(format!("Unknown operator: {}", symbol))),
+                }
+            }
+            // IF and CASE only depend on the conditions evaluated to select
+            // a branch, and that branch.
+            Expr::Function {
+                function: Function::If | Function::Case,
+                args,
+            } => {
+                Expr::branch_with_quality(args, args, values, options, functions, qualities, scope)?
+            }
+            Expr::Function { function, args } => {
+                let args =
+                    Expr::args_with_quality(args, values, options, functions, qualities, scope)?;
+                let results: Vec<_> = args.iter().map(|(value, _)| *value).collect();
+                let quality = match (function, function.select(&results)) {
+                    // COALESCE only depends on the argument it falls back to.
+                    (Function::Coalesce, Some(i)) => args[i].1,
+                    _ => worst(&args),
+                };
+                (function.apply(&results, options), quality)
+            }
+            Expr::Custom { name, args } => {
+                let args =
+                    Expr::args_with_quality(args, values, options, functions, qualities, scope)?;
+                let results: Vec<_> = args.iter().map(|(value, _)| *value).collect();
+                match functions.get(name) {
+                    Some(function) => (function(&results), worst(&args)),
+                    None => return Err(FormulaError(format!("Unknown function: {}", name))),
+                }
+            }
+            Expr::Component(id) => (
+                self.calculate(values, options, functions)?,
+                qualities.component(*id).unwrap_or_default(),
+            ),
+            Expr::Named(name) => (
+                self.calculate(values, options, functions)?,
+                qualities.named(name).unwrap_or_default(),
+            ),
+            Expr::Variable(name) => (
+                self.calculate(values, options, functions)?,
+                scope
+                    .iter()
+                    .rev()
+                    .find(|(bound, _)| bound == name)
+                    .map(|(_, quality)| *quality)
+                    .unwrap_or_default(),
+            ),
+            Expr::Let { name, value, body } => {
+                let (value, quality) = calculate(value, scope)?;
+                let bound = Bound {
+                    inputs: values,
+                    name,
+                    value,
+                };
+                scope.push((name.clone(), quality));
+                let body =
+                    body.calculate_with_quality(&bound, options, functions, qualities, scope);
+                scope.pop();
+                body?
+            }
+            Expr::Select {
+                function: Function::If | Function::Case,
+                args,
+                branches,
+            } => Expr::branch_with_quality(
+                args, branches, values, options, functions, qualities, scope,
+            )?,
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => {
+                let args =
+                    Expr::args_with_quality(args, values, options, functions, qualities, scope)?;
+                let results: Vec<_> = args.iter().map(|(value, _)| *value).collect();
+                match function.select(&results) {
+                    Some(i) => {
+                        let (value, quality) = calculate(&branches[i], scope)?;
+                        let selected = match function {
+                            Function::Coalesce => args[i].1,
+                            _ => worst(&args),
+                        };
+                        (value, selected.max(quality))
+                    }
+                    None => (None, worst(&args)),
+                }
+            }
+            // Values, references and functions of the time are good.
+            expr => (expr.calculate(values, options, functions)?, Quality::Good),
+        })
+    }
+
+    /// Calculate the branch of an IF or CASE selected by its arguments, with
+    /// the worst quality of the conditions evaluated to select it and the
+    /// branch.
+    fn branch_with_quality(
+        args: &[Expr<T>],
+        branches: &[Expr<T>],
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+        qualities: &dyn Qualities,
+        scope: &mut Vec<(String, Quality)>,
+    ) -> Result<(Option<T>, Quality), FormulaError> {
+        let mut conditions = Quality::Good;
+        let selected = Function::select_branch(args.len(), |i| {
+            let (value, quality) =
+                args[i].calculate_with_quality(values, options, functions, qualities, scope)?;
+            conditions = conditions.max(quality);
+            Ok::<_, FormulaError>(value)
+        })?;
+        Ok(match selected {
+            Some(i) => {
+                let (value, quality) = branches[i]
+                    .calculate_with_quality(values, options, functions, qualities, scope)?;
+                (value, conditions.max(quality))
+            }
+            None => (None, conditions),
+        })
+    }
+
+    /// Calculate the arguments of a function with their qualities, expanding
+    /// `#*` to all given values with the worst quality given.
+    fn args_with_quality(
+        args: &[Expr<T>],
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+        qualities: &dyn Qualities,
+        scope: &mut Vec<(String, Quality)>,
+    ) -> Result<Vec<(Option<T>, Quality)>, FormulaError> {
+        let mut results = Vec::with_capacity(args.len());
+        for arg in args {
+            match arg {
+                Expr::Wildcard => results.extend(
+                    Expr::wildcard_values(values, options)?
+                        .into_iter()
+                        .map(|value| (value, qualities.worst())),
+                ),
+                arg => results.push(
+                    arg.calculate_with_quality(values, options, functions, qualities, scope)?,
+                ),
+            }
+        }
+        Ok(results)
+    }
+}
@@ -0,0 +1,39 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{collections::HashMap, convert::Infallible};
+
+use crate::{expression::Expr, formula_engine::FormulaEngine, value::FormulaValue};
+
+impl<T: FormulaValue> FormulaEngine<T> {
+    /// Create a new FormulaEngine with the component IDs replaced by the IDs
+    /// they are mapped to, e.g. when a meter is replaced.
+    ///
+    /// Components that aren't mapped keep their IDs. All IDs are replaced at
+    /// once, so two components can swap theirs.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#1 + #2 - #3").unwrap();
+    /// let remapped = fe.remap(&HashMap::from([(2, 7), (3, 1), (1, 3)]));
+    /// assert_eq!(remapped.to_string(), "#3 + #7 - #1");
+    /// ```
+    pub fn remap(&self, ids: &HashMap<u64, u64>) -> FormulaEngine<T> {
+        self.with_expr(self.expr().remap(ids))
+    }
+}
+
+impl<T: FormulaValue> Expr<T> {
+    /// Replace the component IDs in the expression by the IDs they are
+    /// mapped to.
+    fn remap(&self, ids: &HashMap<u64, u64>) -> Expr<T> {
+        match self {
+            Expr::Component(id) => Expr::Component(*ids.get(id).unwrap_or(id)),
+            expr => expr
+                .try_map_children(|child| Ok::<_, Infallible>(child.remap(ids)))
+                .unwrap_or_else(|never| match never {}),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    streaming::{StreamingFormulaEngine, StreamingInput},
+    temporal::StateSnapshot,
+    value::FormulaValue,
+};
+
+/// The inputs of a [`StreamingFormulaEngine`] recorded by a [`Recorder`],
+/// with the state of the engine when the recording started, to reproduce
+/// its results offline by [`replay`](Self::replay).
+///
+/// With the `serde` feature, recordings can be serialized, e.g. to a file.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Recording<T> {
+    /// The formula of the engine.
+    pub formula: String,
+    /// The values of the components when the recording started, sorted by
+    /// component ID.
+    pub values: Vec<(u64, Option<T>)>,
+    /// When the values that can become too old were updated, when the
+    /// recording started.
+    pub updated: Vec<(u64, SystemTime)>,
+    /// The state of the temporal functions when the recording started.
+    pub state: StateSnapshot<T>,
+    /// The inputs of the engine, in order.
+    pub inputs: Vec<StreamingInput<T>>,
+}
+
+/// Records the inputs of a [`StreamingFormulaEngine`], from
+/// [`StreamingFormulaEngine::record`], e.g. to debug results seen in the
+/// field on another machine.
+///
+/// Inputs are recorded until the recorder is dropped.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::FormulaEngine;
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let fe = FormulaEngine::<f64>::try_new("INTEGRATE(#0) + #1").unwrap();
+/// let mut streaming = fe.streaming();
+/// let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+/// streaming.update_at(1, Some(1.0), at(0)).ok();
+///
+/// let recorder = streaming.record();
+/// let results = vec![
+///     streaming.update_at(0, Some(1000.0), at(0)),
+///     streaming.update_at(0, Some(2000.0), at(3600)),
+/// ];
+///
+/// let recording = recorder.recording();
+/// let replayed = recording.replay(&mut fe.streaming()).unwrap();
+/// assert_eq!(format!("{:?}", replayed), format!("{:?}", results));
+/// ```
+#[derive(Debug)]
+pub struct Recorder<T> {
+    recording: Arc<Mutex<Recording<T>>>,
+}
+
+impl<T: FormulaValue + Float + Send + 'static> StreamingFormulaEngine<T> {
+    /// Start recording the inputs of the engine, from its current values
+    /// and state.
+    pub fn record(&mut self) -> Recorder<T> {
+        let mut values: Vec<_> = self.values().iter().map(|(id, v)| (*id, *v)).collect();
+        values.sort_by_key(|(id, _)| *id);
+        let recording = Arc::new(Mutex::new(Recording {
+            formula: self.engine().to_string(),
+            values,
+            updated: self.updated(),
+            state: self.state_snapshot(),
+            inputs: Vec::new(),
+        }));
+        let inputs = Arc::downgrade(&recording);
+        self.on_input(move |input, _| {
+            if let Some(recording) = inputs.upgrade() {
+                let mut recording = recording.lock().unwrap_or_else(PoisonError::into_inner);
+                recording.inputs.push(*input);
+            }
+        });
+        Recorder { recording }
+    }
+}
+
+impl<T: Clone> Recorder<T> {
+    /// Get the inputs recorded so far.
+    pub fn recording(&self) -> Recording<T> {
+        self.recording
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl<T: FormulaValue + Float> Recording<T> {
+    /// Feed the recorded inputs to a streaming engine of the same formula,
+    /// starting from the state of the recorded engine, and get the result
+    /// after each input.
+    ///
+    /// The engine needs the maximum ages of the recorded engine to
+    /// reproduce its results.
+    pub fn replay(
+        &self,
+        streaming: &mut StreamingFormulaEngine<T>,
+    ) -> Result<Vec<Result<Option<T>, FormulaError>>, FormulaError> {
+        let formula = streaming.engine().to_string();
+        if formula != self.formula {
+            return Err(FormulaError(format!(
+                "The recording is of {}, not {}",
+                self.formula, formula
+            )));
+        }
+        streaming.continue_with(&self.values, &self.updated, &self.state)?;
+        Ok(self
+            .inputs
+            .iter()
+            .map(|input| match input {
+                StreamingInput::Update(id, sample) => streaming.update_sample(*id, *sample),
+                StreamingInput::Expire(now) => streaming.expire(*now),
+            })
+            .collect())
+    }
+}
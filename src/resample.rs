@@ -0,0 +1,180 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError, formula_engine::FormulaEngine, streaming::StreamingFormulaEngine,
+    value::FormulaValue,
+};
+
+/// How the samples of a component within a resampling period are combined.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// The last sample, even if it is `None`.
+    #[default]
+    Last,
+    /// The mean of the samples other than `None`.
+    Mean,
+    /// The minimum of the samples other than `None`.
+    Min,
+    /// The maximum of the samples other than `None`.
+    Max,
+}
+
+impl Aggregation {
+    fn apply<T: FormulaValue + Float>(self, samples: &[Option<T>]) -> Option<T> {
+        let values = samples.iter().flatten().copied();
+        match self {
+            Aggregation::Last => samples.last().copied().flatten(),
+            Aggregation::Mean => {
+                let (sum, count) =
+                    values.fold((T::zero(), 0), |(sum, count), x| (sum + x, count + 1));
+                (count > 0).then(|| sum / T::from_usize(count).unwrap_or_else(T::nan))
+            }
+            Aggregation::Min => values.reduce(T::min),
+            Aggregation::Max => values.reduce(T::max),
+        }
+    }
+}
+
+/// A formula calculated on time-aligned snapshots of component values that
+/// arrive at different rates.
+///
+/// Samples are collected in periods aligned to the Unix epoch, and combined
+/// per component when a sample of a later period arrives. The formula is
+/// then calculated with the combined values, keeping the previous value of
+/// components without samples in the period. Temporal functions take one
+/// sample per period, at its start.
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::{Aggregation, FormulaEngine};
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+/// let mut resampler = fe
+///     .resampler(Duration::from_secs(5))
+///     .unwrap()
+///     .with_component_aggregation(0, Aggregation::Mean);
+/// let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+///
+/// assert!(resampler.push(0, at(100), Some(1.0)).is_none());
+/// assert!(resampler.push(0, at(101), Some(3.0)).is_none());
+/// assert!(resampler.push(1, at(103), Some(10.0)).is_none());
+/// let (period, result) = resampler.push(0, at(105), Some(5.0)).unwrap();
+/// assert_eq!(period, at(100));
+/// assert_eq!(result.unwrap(), Some(12.0));
+/// ```
+#[derive(Debug)]
+pub struct Resampler<T> {
+    streaming: StreamingFormulaEngine<T>,
+    period: Duration,
+    aggregation: Aggregation,
+    aggregations: HashMap<u64, Aggregation>,
+    /// The start of the current period.
+    start: Option<SystemTime>,
+    samples: HashMap<u64, Vec<Option<T>>>,
+}
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Create a [`Resampler`] calculating the formula once per `period`,
+    /// taking the last sample of each component by default.
+    pub fn resampler(&self, period: Duration) -> Result<Resampler<T>, FormulaError> {
+        if period.is_zero() {
+            return Err(FormulaError(
+                "The resampling period must be longer than zero".to_string(),
+            ));
+        }
+        Ok(Resampler {
+            streaming: self.streaming(),
+            period,
+            aggregation: Aggregation::default(),
+            aggregations: HashMap::new(),
+            start: None,
+            samples: HashMap::new(),
+        })
+    }
+}
+
+impl<T: FormulaValue + Float> Resampler<T> {
+    /// Set how the samples of components are combined, unless set for the
+    /// component.
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    /// Set how the samples of a component are combined.
+    pub fn with_component_aggregation(mut self, id: u64, aggregation: Aggregation) -> Self {
+        self.aggregations.insert(id, aggregation);
+        self
+    }
+
+    /// Get the engine of the formula.
+    pub fn engine(&self) -> &FormulaEngine<T> {
+        self.streaming.engine()
+    }
+
+    /// Get the combined values of the components in the last completed
+    /// period.
+    pub fn values(&self) -> &HashMap<u64, Option<T>> {
+        self.streaming.values()
+    }
+
+    /// Add a sample of a component, and get the start of the previous
+    /// period and the result for it if the sample is from a later period.
+    ///
+    /// Samples from before the current period are ignored.
+    pub fn push(
+        &mut self,
+        id: u64,
+        timestamp: SystemTime,
+        value: Option<T>,
+    ) -> Option<(SystemTime, Result<Option<T>, FormulaError>)> {
+        let start = self.period_start(timestamp);
+        let completed = match self.start {
+            Some(current) if start < current => return None,
+            Some(current) if start > current => self.flush(),
+            _ => None,
+        };
+        self.start = Some(start);
+        self.samples.entry(id).or_default().push(value);
+        completed
+    }
+
+    /// Complete the current period, and get its start and result, if it has
+    /// any samples.
+    pub fn flush(&mut self) -> Option<(SystemTime, Result<Option<T>, FormulaError>)> {
+        let start = self.start.take()?;
+        let mut result = None;
+        for (id, samples) in self.samples.drain() {
+            let aggregation = self
+                .aggregations
+                .get(&id)
+                .copied()
+                .unwrap_or(self.aggregation);
+            result = Some(
+                self.streaming
+                    .update_at(id, aggregation.apply(&samples), start),
+            );
+        }
+        result.map(|result| (start, result))
+    }
+
+    /// Get the start of the period a timestamp is in.
+    fn period_start(&self, timestamp: SystemTime) -> SystemTime {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let periods = since_epoch.as_nanos() / self.period.as_nanos();
+        let nanos = periods * self.period.as_nanos();
+        UNIX_EPOCH
+            + Duration::new(
+                (nanos / 1_000_000_000) as u64,
+                (nanos % 1_000_000_000) as u32,
+            )
+    }
+}
@@ -0,0 +1,265 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! A SIMD evaluation path for columns of `f32` and `f64` values.
+
+use std::{
+    collections::HashMap,
+    ops::{Add, BitAnd, BitOr, Div, Mul, Neg, Sub},
+};
+
+use wide::{f32x8, f64x4, CmpEq, CmpGe, CmpGt, CmpLe, CmpLt};
+
+use num_traits::Float;
+
+use crate::{
+    batch::sorted_columns,
+    error::FormulaError,
+    expression::{Expr, Function, Op},
+    formula_engine::FormulaEngine,
+    options::{DivisionByZero, EngineOptions},
+    value::FormulaValue,
+};
+
+/// The value types with a SIMD evaluation path, i.e. `f32` and `f64`.
+pub trait SimdValue: FormulaValue + Float {
+    /// A vector of values, one per lane.
+    #[doc(hidden)]
+    type Lanes: Copy
+        + Add<Output = Self::Lanes>
+        + Sub<Output = Self::Lanes>
+        + Mul<Output = Self::Lanes>
+        + Div<Output = Self::Lanes>
+        + Neg<Output = Self::Lanes>
+        + BitAnd<Output = Self::Lanes>
+        + BitOr<Output = Self::Lanes>;
+
+    /// The number of lanes.
+    #[doc(hidden)]
+    const LANES: usize;
+
+    /// Load `LANES` values.
+    #[doc(hidden)]
+    fn load(values: &[Self]) -> Self::Lanes;
+
+    /// Store the lanes into `LANES` values.
+    #[doc(hidden)]
+    fn store(lanes: Self::Lanes, values: &mut [Self]);
+
+    #[doc(hidden)]
+    fn splat(value: Self) -> Self::Lanes;
+
+    /// Pick the lanes of `t` where `mask` is set, and those of `f` elsewhere.
+    #[doc(hidden)]
+    fn blend(mask: Self::Lanes, t: Self::Lanes, f: Self::Lanes) -> Self::Lanes;
+
+    /// Compare the lanes with a comparison operator other than `!=`, giving
+    /// a mask with all bits of a lane set where the comparison holds.
+    #[doc(hidden)]
+    fn compare(op: &Op, lhs: Self::Lanes, rhs: Self::Lanes) -> Self::Lanes;
+}
+
+/// The largest number of lanes of a [`SimdValue`].
+const MAX_LANES: usize = 8;
+
+macro_rules! impl_simd_value {
+    ($t:ty, $lanes:ty, $n:expr) => {
+        impl SimdValue for $t {
+            type Lanes = $lanes;
+
+            const LANES: usize = $n;
+
+            fn load(values: &[Self]) -> Self::Lanes {
+                let mut array = [0.0; $n];
+                array.copy_from_slice(values);
+                <$lanes>::new(array)
+            }
+
+            fn store(lanes: Self::Lanes, values: &mut [Self]) {
+                values.copy_from_slice(&lanes.to_array());
+            }
+
+            fn splat(value: Self) -> Self::Lanes {
+                <$lanes>::splat(value)
+            }
+
+            fn blend(mask: Self::Lanes, t: Self::Lanes, f: Self::Lanes) -> Self::Lanes {
+                mask.blend(t, f)
+            }
+
+            fn compare(op: &Op, lhs: Self::Lanes, rhs: Self::Lanes) -> Self::Lanes {
+                match op {
+                    Op::Eq => lhs.cmp_eq(rhs),
+                    Op::Lt => lhs.cmp_lt(rhs),
+                    Op::Le => lhs.cmp_le(rhs),
+                    Op::Gt => lhs.cmp_gt(rhs),
+                    Op::Ge => lhs.cmp_ge(rhs),
+                    op => unreachable!("{} isn't compared with SIMD", op.symbol()),
+                }
+            }
+        }
+    };
+}
+
+impl_simd_value!(f64, f64x4, 4);
+impl_simd_value!(f32, f32x8, 8);
+
+impl<T: SimdValue> FormulaEngine<T> {
+    /// Calculate the result column of the formula from columns of component
+    /// values of equal length, like [`FormulaEngine::calculate_columns`],
+    /// but with SIMD instructions for several rows at once.
+    ///
+    /// Formulas of `+`, `-`, `*`, `/`, comparisons other than `!=` and
+    /// COALESCE over components and values are calculated with SIMD,
+    /// tracking which values are missing in a separate mask. Other formulas,
+    /// and formulas evaluated in strict mode or without infinite results of
+    /// division by zero, are calculated like with `calculate_columns`.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use std::collections::HashMap;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+    /// let columns = HashMap::from([
+    ///     (0, &[Some(1.0), None][..]),
+    ///     (1, &[Some(2.0), Some(3.0)][..]),
+    /// ]);
+    /// assert_eq!(fe.calculate_columns_simd(&columns).unwrap(), [Some(3.0), None]);
+    /// ```
+    pub fn calculate_columns_simd(
+        &self,
+        columns: &HashMap<u64, &[Option<T>]>,
+    ) -> Result<Vec<Option<T>>, FormulaError> {
+        let (sorted, len) = sorted_columns(columns)?;
+        if self.options.strict() || !self.expr().is_simd(&sorted, &self.options) {
+            return self.calculate_columns(columns);
+        }
+
+        let mut results = Vec::with_capacity(len);
+        let mut values = vec![T::zero(); T::LANES];
+        let mut present = vec![T::zero(); T::LANES];
+        for start in (0..len).step_by(T::LANES) {
+            let chunk = Chunk {
+                columns: &sorted,
+                start,
+                len: T::LANES.min(len - start),
+                none_as_zero: self.options.none_as_zero(),
+            };
+            let (lanes, mask) = self.expr().calculate_simd(&chunk);
+            T::store(lanes, &mut values);
+            T::store(mask, &mut present);
+            results.extend(
+                values
+                    .iter()
+                    .zip(&present)
+                    // Lanes of the mask with all bits set are NaN.
+                    .map(|(value, present)| present.is_nan().then_some(*value))
+                    .take(chunk.len),
+            );
+        }
+        Ok(results)
+    }
+}
+
+/// The rows of columns of component values calculated at once.
+struct Chunk<'a, T> {
+    columns: &'a [(u64, &'a [Option<T>])],
+    start: usize,
+    len: usize,
+    none_as_zero: bool,
+}
+
+impl<T: SimdValue> Chunk<'_, T> {
+    /// Load the values of a component, with the mask of those that aren't
+    /// missing.
+    fn load(&self, id: u64) -> (T::Lanes, T::Lanes) {
+        let mut values = [T::zero(); MAX_LANES];
+        let mut present = [T::zero(); MAX_LANES];
+        if let Ok(i) = self.columns.binary_search_by_key(&id, |(id, _)| *id) {
+            let column = &self.columns[i].1[self.start..self.start + self.len];
+            for (lane, value) in column.iter().enumerate() {
+                match value {
+                    Some(value) => (values[lane], present[lane]) = (*value, T::one()),
+                    None if self.none_as_zero => present[lane] = T::one(),
+                    None => {}
+                }
+            }
+        }
+        (
+            T::load(&values[..T::LANES]),
+            mask::<T>(T::load(&present[..T::LANES])),
+        )
+    }
+}
+
+impl<T: SimdValue> Expr<T> {
+    /// Whether the expression can be calculated with SIMD from the given
+    /// columns.
+    fn is_simd(&self, columns: &[(u64, &[Option<T>])], options: &EngineOptions) -> bool {
+        let is_simd = |expr: &Expr<T>| expr.is_simd(columns, options);
+        match self {
+            Expr::Value(_) => true,
+            Expr::Component(id) => columns.binary_search_by_key(id, |(id, _)| *id).is_ok(),
+            Expr::UnaryMinus(operand) => is_simd(operand),
+            Expr::Op { lhs, op, rhs } => {
+                let supported = match op {
+                    Op::Add | Op::Sub | Op::Mul => true,
+                    Op::Div => options.division_by_zero() == DivisionByZero::Infinity,
+                    Op::Eq | Op::Lt | Op::Le | Op::Gt | Op::Ge => true,
+                    _ => false,
+                };
+                supported && is_simd(lhs) && is_simd(rhs)
+            }
+            Expr::Function {
+                function: Function::Coalesce,
+                args,
+            } => !args.is_empty() && args.iter().all(is_simd),
+            _ => false,
+        }
+    }
+
+    /// Calculate the expression for a chunk of rows, with the mask of the
+    /// results that aren't `None`.
+    fn calculate_simd(&self, chunk: &Chunk<T>) -> (T::Lanes, T::Lanes) {
+        match self {
+            Expr::Value(Some(value)) => (T::splat(*value), mask::<T>(T::splat(T::one()))),
+            Expr::Value(None) => (T::splat(T::zero()), mask::<T>(T::splat(T::zero()))),
+            Expr::Component(id) => chunk.load(*id),
+            Expr::UnaryMinus(operand) => {
+                let (values, present) = operand.calculate_simd(chunk);
+                (-values, present)
+            }
+            Expr::Op { lhs, op, rhs } => {
+                let (lhs, lhs_present) = lhs.calculate_simd(chunk);
+                let (rhs, rhs_present) = rhs.calculate_simd(chunk);
+                let values = match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div => lhs / rhs,
+                    op => T::blend(
+                        T::compare(op, lhs, rhs),
+                        T::splat(T::one()),
+                        T::splat(T::zero()),
+                    ),
+                };
+                (values, lhs_present & rhs_present)
+            }
+            // The first argument that isn't `None` wins.
+            Expr::Function { args, .. } => args
+                .iter()
+                .rev()
+                .map(|arg| arg.calculate_simd(chunk))
+                .reduce(|(values, present), (arg, arg_present)| {
+                    (T::blend(arg_present, arg, values), arg_present | present)
+                })
+                .unwrap_or_else(|| unreachable!("COALESCE has arguments")),
+            expr => unreachable!("{} isn't calculated with SIMD", expr),
+        }
+    }
+}
+
+/// Turn lanes of `1` and `0` into a mask.
+fn mask<T: SimdValue>(lanes: T::Lanes) -> T::Lanes {
+    T::compare(&Op::Eq, lanes, T::splat(T::one()))
+}
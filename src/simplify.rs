@@ -0,0 +1,82 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::convert::Infallible;
+
+use crate::{
+    expression::{Expr, Function, Op},
+    formula_engine::FormulaEngine,
+    value::FormulaValue,
+};
+
+impl<T: FormulaValue> FormulaEngine<T> {
+    /// Create a new FormulaEngine with the formula simplified by rewrites
+    /// that keep its results, e.g. to clean up generated formulas.
+    ///
+    /// Adding or subtracting `0`, multiplying or dividing by `1` and double
+    /// negations are removed, COALESCE of a single argument is replaced by
+    /// the argument, and COALESCE calls nested in COALESCE are merged into
+    /// it. Results only differ in that `-0` plus `0` stays `-0`.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("COALESCE(#0 * 1, COALESCE(--#1 + 0, 0))").unwrap();
+    /// assert_eq!(fe.simplify().to_string(), "COALESCE(#0, #1, 0)");
+    /// ```
+    pub fn simplify(&self) -> FormulaEngine<T> {
+        self.with_expr(self.expr().simplify())
+    }
+}
+
+impl<T: FormulaValue> Expr<T> {
+    /// Simplify the expression bottom-up.
+    fn simplify(&self) -> Expr<T> {
+        let expr = self
+            .try_map_children(|child| Ok::<_, Infallible>(child.simplify()))
+            .unwrap_or_else(|never| match never {});
+        match expr {
+            Expr::UnaryMinus(operand) => match *operand {
+                Expr::UnaryMinus(operand) => *operand,
+                operand => Expr::UnaryMinus(Box::new(operand)),
+            },
+            Expr::Op { lhs, op, rhs } => match op {
+                Op::Add | Op::Sub if rhs.is_constant(T::zero()) => *lhs,
+                Op::Add if lhs.is_constant(T::zero()) => *rhs,
+                Op::Mul | Op::Div if rhs.is_constant(T::one()) => *lhs,
+                Op::Mul if lhs.is_constant(T::one()) => *rhs,
+                op => Expr::Op { lhs, op, rhs },
+            },
+            Expr::Function {
+                function: Function::Coalesce,
+                args,
+            } => {
+                let mut args: Vec<_> = args
+                    .into_iter()
+                    .flat_map(|arg| match arg {
+                        Expr::Function {
+                            function: Function::Coalesce,
+                            args,
+                        } => args,
+                        arg => vec![arg],
+                    })
+                    .collect();
+                // A single argument is the result, unless it is `#*`, which
+                // can stand for several.
+                match args.as_slice() {
+                    [arg] if !matches!(arg, Expr::Wildcard) => args.remove(0),
+                    _ => Expr::Function {
+                        function: Function::Coalesce,
+                        args,
+                    },
+                }
+            }
+            expr => expr,
+        }
+    }
+
+    /// Whether the expression is the given value.
+    fn is_constant(&self, value: T) -> bool {
+        matches!(self, Expr::Value(Some(x)) if *x == value)
+    }
+}
@@ -0,0 +1,468 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Translation of formulas to PostgreSQL expressions, to push them down into
+//! queries of historical data.
+
+use std::{borrow::Cow, convert::Infallible};
+
+use num_traits::Float;
+
+use crate::{
+    display::select_case,
+    error::FormulaError,
+    expression::{Expr, Function, Op},
+    formula_engine::FormulaEngine,
+    options::{DivisionByZero, RoundingMode},
+    syntax::Precedence,
+    value::FormulaValue,
+};
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Translate the formula to a PostgreSQL expression of `double precision`.
+    ///
+    /// Placeholders become quoted column names: `#3` the column `"3"`, and
+    /// `$pv` and `#"pv"` the column `"pv"`. Missing values are `NULL`, and
+    /// conditions are `1` or `0`, as in the engine. `ROUND` and `QUANTIZE`
+    /// use the engine's rounding mode. If the engine evaluates `None` values
+    /// as `0`, so does the SQL expression.
+    ///
+    /// Formulas with `#*`, time functions, time-of-use windows or custom
+    /// functions and operators can't be translated, as these depend on the
+    /// engine. Division by zero is an error in SQL, unless the engine evaluates
+    /// it to `None`.
+    ///
+    /// Subexpressions used more than once, like `LET` values and the
+    /// arguments of `MIN_STRICT` or `CURVE`, are bound once as the columns
+    /// of a subquery, so that the SQL grows with the formula, not
+    /// exponentially with its nesting.
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, 0) + MAX(#1, #2)").unwrap();
+    /// assert_eq!(
+    ///     fe.to_sql().unwrap(),
+    ///     "(COALESCE(\"0\", 0::float8) + GREATEST(\"1\", \"2\"))"
+    /// );
+    /// ```
+    pub fn to_sql(&self) -> Result<String, FormulaError> {
+        let mut expr = Cow::Borrowed(self.expr());
+        if self.options.none_as_zero() {
+            expr = Cow::Owned(expr.coalesce_placeholders());
+        }
+        if self.options.division_by_zero() == DivisionByZero::None {
+            expr = Cow::Owned(expr.nullif_divisors());
+        }
+        expr.sql(self.options.rounding_mode())
+    }
+}
+
+impl<T: FormulaValue + Float> Expr<T> {
+    /// Replace the placeholders in the expression by `COALESCE(x, 0)`.
+    fn coalesce_placeholders(&self) -> Expr<T> {
+        match self {
+            Expr::Component(_) | Expr::Named(_) => Expr::Function {
+                function: Function::Coalesce,
+                args: vec![self.clone(), Expr::Value(Some(T::zero()))],
+            },
+            expr => expr
+                .try_map_children(|child| Ok::<_, Infallible>(child.coalesce_placeholders()))
+                .unwrap_or_else(|never| match never {}),
+        }
+    }
+
+    /// Translate the expression to a numeric SQL expression.
+    fn sql(&self, rounding_mode: RoundingMode) -> Result<String, FormulaError> {
+        let sql = |expr: &Expr<T>| expr.sql(rounding_mode);
+        let all = |args: &[Expr<T>]| args.iter().map(sql).collect::<Result<Vec<_>, _>>();
+        if self.is_sql_condition() {
+            return Ok(format!(
+                "{}::int::float8",
+                self.sql_condition(rounding_mode)?
+            ));
+        }
+        Ok(match self {
+            Expr::Value(value) => value_sql(*value),
+            Expr::Component(id) => column(&id.to_string()),
+            Expr::Named(name) => column(name),
+            Expr::UnaryMinus(operand) => format!("(-{})", sql(operand)?),
+            Expr::Not(_) => unreachable!("NOT is translated as a condition"),
+            Expr::Op { lhs, op, rhs } => {
+                let (lhs, rhs) = (sql(lhs)?, sql(rhs)?);
+                match op {
+                    Op::Pow => format!("POWER({}, {})", lhs, rhs),
+                    // Like `%` in Rust, the remainder has the sign of the
+                    // dividend.
+                    Op::Mod => bind(&[lhs, rhs], |args| {
+                        format!(
+                            "({} - {} * TRUNC({} / {}))",
+                            args[0], args[1], args[0], args[1]
+                        )
+                    }),
+                    op => format!("({} {} {})", lhs, op.symbol(), rhs),
+                }
+            }
+            Expr::Function { function, args } => {
+                function_sql(function, &all(args)?, rounding_mode, || {
+                    args.iter()
+                        .map(|arg| arg.sql_condition(rounding_mode))
+                        .collect()
+                })?
+            }
+            Expr::Let { name, value, body } => let_sql(
+                name,
+                value,
+                body,
+                |body| body.sql(rounding_mode),
+                rounding_mode,
+            )?,
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => sql(&select_case(function, args, branches))?,
+            Expr::Wildcard => return Err(unsupported("#*")),
+            Expr::CustomOp { symbol, .. } => {
+                return Err(unsupported(&format!("Custom operator {}", symbol)))
+            }
+            Expr::Custom { name, .. } => {
+                return Err(unsupported(&format!("Custom function {}", name)))
+            }
+            Expr::Variable(name) => {
+                return Err(FormulaError(format!("Unknown variable: {}", name)))
+            }
+            Expr::Reference(name) => return Err(unsupported(&format!("@{}", name))),
+            Expr::TimeOfUse(name) => return Err(unsupported(&format!("TOU(\"{}\")", name))),
+            Expr::Time(function) => return Err(unsupported(&format!("{}()", function.name()))),
+            Expr::Temporal { function, .. } => return Err(unsupported(function.name())),
+        })
+    }
+
+    /// Translate the expression to a parenthesized SQL condition, which is
+    /// `NULL` where the expression is `None`.
+    fn sql_condition(&self, rounding_mode: RoundingMode) -> Result<String, FormulaError> {
+        let condition = |expr: &Expr<T>| expr.sql_condition(rounding_mode);
+        Ok(match self {
+            Expr::Not(operand) => format!("(NOT {})", condition(operand)?),
+            Expr::Op {
+                lhs,
+                op: op @ (Op::Or | Op::And),
+                rhs,
+            } => format!("({} {} {})", condition(lhs)?, op.symbol(), condition(rhs)?),
+            Expr::Op { lhs, op, rhs } if op.precedence().0 == Precedence::Comparison => {
+                let symbol = match op {
+                    Op::Eq => "=",
+                    Op::Ne => "<>",
+                    op => op.symbol(),
+                };
+                let (lhs, rhs) = (lhs.sql(rounding_mode)?, rhs.sql(rounding_mode)?);
+                format!("({} {} {})", lhs, symbol, rhs)
+            }
+            Expr::Function {
+                function: function @ (Function::IsNone | Function::IsSome),
+                args,
+            } => {
+                let test = match function {
+                    Function::IsNone => "IS NULL",
+                    _ => "IS NOT NULL",
+                };
+                format!("({} {})", args[0].sql(rounding_mode)?, test)
+            }
+            Expr::Let { name, value, body } => let_sql(
+                name,
+                value,
+                body,
+                |body| body.sql_condition(rounding_mode),
+                rounding_mode,
+            )?,
+            expr => format!("({} <> 0)", expr.sql(rounding_mode)?),
+        })
+    }
+
+    /// Whether the expression is translated as a condition in the first
+    /// place.
+    fn is_sql_condition(&self) -> bool {
+        match self {
+            Expr::Not(_)
+            | Expr::Function {
+                function: Function::IsNone | Function::IsSome,
+                ..
+            } => true,
+            Expr::Op { op, .. } => matches!(
+                op.precedence().0,
+                Precedence::Or | Precedence::And | Precedence::Comparison
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Translate a call of a built-in function, given its translated arguments
+/// and a way to translate them as conditions.
+fn function_sql(
+    function: &Function,
+    args: &[String],
+    rounding_mode: RoundingMode,
+    conditions: impl FnOnce() -> Result<Vec<String>, FormulaError>,
+) -> Result<String, FormulaError> {
+    let list = args.join(", ");
+    // `NULL` if any of the arguments is.
+    let strict = |args: &[String], sql: String| {
+        let tests = args
+            .iter()
+            .map(|arg| format!("{} IS NULL", arg))
+            .collect::<Vec<_>>();
+        format!(
+            "CASE WHEN {} THEN NULL ELSE {} END",
+            tests.join(" OR "),
+            sql
+        )
+    };
+    let join = |separator: &str, args: &[String]| format!("({})", args.join(separator));
+    Ok(match function {
+        Function::Coalesce => format!("COALESCE({})", list),
+        // LEAST and GREATEST ignore `NULL`s, like MIN and MAX.
+        Function::Min => format!("LEAST({})", list),
+        Function::Max => format!("GREATEST({})", list),
+        Function::MinStrict => bind(args, |args| {
+            strict(args, format!("LEAST({})", args.join(", ")))
+        }),
+        Function::MaxStrict => bind(args, |args| {
+            strict(args, format!("GREATEST({})", args.join(", ")))
+        }),
+        Function::Pos => bind(args, |args| {
+            strict(args, format!("GREATEST({}, 0::float8)", args[0]))
+        }),
+        Function::Neg => bind(args, |args| {
+            strict(args, format!("LEAST({}, 0::float8)", args[0]))
+        }),
+        Function::Hypot => format!(
+            "SQRT({})",
+            args.iter()
+                .map(|arg| format!("POWER({}, 2)", arg))
+                .collect::<Vec<_>>()
+                .join(" + ")
+        ),
+        Function::Sin => format!("SIN({})", args[0]),
+        Function::Cos => format!("COS({})", args[0]),
+        Function::Tan => format!("TAN({})", args[0]),
+        Function::Atan2 => format!("ATAN2({})", list),
+        Function::Lerp => bind(args, |args| {
+            format!("({} + ({} - {}) * {})", args[0], args[1], args[0], args[2])
+        }),
+        Function::Kw | Function::Kwh => format!("({} * 1000::float8)", args[0]),
+        Function::Mw => format!("({} * 1000000::float8)", args[0]),
+        Function::Pow => format!("POWER({})", list),
+        // Horner's scheme: c0 + x * (c1 + x * (c2 + ...))
+        Function::Poly => bind(args, |args| {
+            args[1..]
+                .iter()
+                .rev()
+                .cloned()
+                .reduce(|acc, c| format!("({} + {} * {})", c, args[0], acc))
+                .unwrap_or_default()
+        }),
+        Function::Curve => bind(args, |args| {
+            let x = &args[0];
+            let points = args[1..].chunks(2).collect::<Vec<_>>();
+            let sorted = points
+                .windows(2)
+                .map(|pair| format!("{} <= {}", pair[0][0], pair[1][0]))
+                .collect::<Vec<_>>();
+            let mut case = String::from("CASE");
+            if !sorted.is_empty() {
+                case.push_str(&format!(" WHEN NOT ({}) THEN NULL", sorted.join(" AND ")));
+            }
+            case.push_str(&format!(
+                " WHEN {} <= {} THEN {}",
+                x, points[0][0], points[0][1]
+            ));
+            for pair in points.windows(2) {
+                let ((x0, y0), (x1, y1)) = ((&pair[0][0], &pair[0][1]), (&pair[1][0], &pair[1][1]));
+                case.push_str(&format!(
+                    " WHEN {} <= {} THEN ({} + ({} - {}) * ({} - {}) / ({} - {}))",
+                    x, x1, y0, y1, y0, x, x0, x1, x0
+                ));
+            }
+            let last = points[points.len() - 1][1].clone();
+            strict(args, format!("{} ELSE {} END", case, last))
+        }),
+        // A `None` condition makes the result `None`, where SQL would go on
+        // with the next condition, so each condition gets its own `CASE`,
+        // which matches neither `true` nor `false` for `NULL`.
+        Function::If | Function::Case => {
+            let conditions = conditions()?;
+            let pairs = args.len() / 2;
+            let mut otherwise = args.get(pairs * 2).cloned();
+            for i in (0..pairs).rev() {
+                let case = format!(
+                    "CASE {} WHEN true THEN {}",
+                    conditions[i * 2],
+                    args[i * 2 + 1]
+                );
+                otherwise = Some(match otherwise {
+                    Some(otherwise) => format!("{} WHEN false THEN {} END", case, otherwise),
+                    None => format!("{} END", case),
+                });
+            }
+            otherwise.unwrap_or_default()
+        }
+        Function::Round => match args {
+            [x] => round_sql(x, rounding_mode),
+            [_, _] => bind(args, |args| {
+                let scale = format!(
+                    "POWER(10::float8, {})",
+                    round_sql(&args[1], RoundingMode::HalfUp)
+                );
+                format!(
+                    "({} / {})",
+                    round_sql(&format!("({} * {})", args[0], scale), rounding_mode),
+                    scale
+                )
+            }),
+            _ => unreachable!("ROUND expects 1 to 2 arguments"),
+        },
+        Function::Quantize => bind(args, |args| {
+            format!(
+                "CASE WHEN {} > 0 THEN {} * {} END",
+                args[1],
+                round_sql(&format!("({} / {})", args[0], args[1]), rounding_mode),
+                args[1]
+            )
+        }),
+        Function::Floor => format!("FLOOR({})", args[0]),
+        Function::Ceil => format!("CEIL({})", args[0]),
+        // The sum of the values that aren't `NULL`, or `NULL` if all are.
+        Function::Sum if args.len() == 1 => args[0].clone(),
+        Function::Sum => bind(args, |args| {
+            format!(
+                "CASE WHEN COALESCE({}) IS NULL THEN NULL ELSE {} END",
+                args.join(", "),
+                join(
+                    " + ",
+                    &args
+                        .iter()
+                        .map(|arg| format!("COALESCE({}, 0)", arg))
+                        .collect::<Vec<_>>()
+                )
+            )
+        }),
+        Function::Product => join(" * ", args),
+        Function::NullIf => format!("NULLIF({})", list),
+        Function::IsNone | Function::IsSome => {
+            unreachable!("IS_NONE and IS_SOME are translated as conditions")
+        }
+        Function::CountSome => format!(
+            "{}::float8",
+            join(
+                " + ",
+                &args
+                    .iter()
+                    .map(|arg| format!("({} IS NOT NULL)::int", arg))
+                    .collect::<Vec<_>>()
+            )
+        ),
+    })
+}
+
+/// Translate `LET name = value IN body`, with a way to translate the body.
+///
+/// Columns and constants are inlined, and other values bound once, as a
+/// column named like the variable, unless that is a column of the body.
+fn let_sql<T: FormulaValue + Float>(
+    name: &str,
+    value: &Expr<T>,
+    body: &Expr<T>,
+    translate: impl FnOnce(&Expr<T>) -> Result<String, FormulaError>,
+    rounding_mode: RoundingMode,
+) -> Result<String, FormulaError> {
+    let sql = value.sql(rounding_mode)?;
+    if is_simple(&sql) {
+        return translate(&body.inline(name, value));
+    }
+    let names = body.names();
+    let mut alias = name.to_string();
+    while names.contains(&alias) {
+        alias.push('_');
+    }
+    let body = translate(&body.inline(name, &Expr::Named(alias.clone())))?;
+    Ok(format!(
+        "(SELECT {} FROM (SELECT {} AS {}) AS bound)",
+        body,
+        sql,
+        column(&alias)
+    ))
+}
+
+/// Translate SQL that uses its arguments more than once.
+///
+/// Unless the arguments are all columns or constants, those that aren't
+/// constants are bound once as the columns of a subquery, so that nesting
+/// doesn't repeat them exponentially. The SQL then only refers to these
+/// columns, which can't be shadowed by the columns of the arguments.
+fn bind(args: &[String], sql: impl FnOnce(&[String]) -> String) -> String {
+    if args.iter().all(|arg| is_simple(arg)) {
+        return sql(args);
+    }
+    let mut columns = Vec::new();
+    let args = args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            if is_constant(arg) {
+                return arg.clone();
+            }
+            let name = format!("a{}", i);
+            columns.push(format!("{} AS {}", arg, name));
+            name
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "(SELECT {} FROM (SELECT {}) AS bound)",
+        sql(&args),
+        columns.join(", ")
+    )
+}
+
+/// Whether translated SQL is a column or a constant.
+fn is_simple(sql: &str) -> bool {
+    is_constant(sql) || (sql.starts_with('"') && sql.ends_with('"'))
+}
+
+/// Whether translated SQL is a constant, like `NULL` or `1::float8`.
+fn is_constant(sql: &str) -> bool {
+    !sql.starts_with('"') && !sql.contains('(')
+}
+
+/// Round a value to an integer with the given rounding mode.
+fn round_sql(x: &str, rounding_mode: RoundingMode) -> String {
+    match rounding_mode {
+        RoundingMode::HalfUp => bind(&[x.to_string()], |x| {
+            format!("(SIGN({}) * FLOOR(ABS({}) + 0.5))", x[0], x[0])
+        }),
+        // PostgreSQL rounds `double precision` halfway values to even.
+        RoundingMode::HalfEven => format!("ROUND({})", x),
+        RoundingMode::TowardZero => format!("TRUNC({})", x),
+    }
+}
+
+fn value_sql<T: FormulaValue + Float>(value: Option<T>) -> String {
+    match value.and_then(|value| value.to_f64()) {
+        None => "NULL".to_string(),
+        Some(value) if value.is_nan() => "'NaN'::float8".to_string(),
+        Some(value) if value == f64::INFINITY => "'Infinity'::float8".to_string(),
+        Some(value) if value == f64::NEG_INFINITY => "'-Infinity'::float8".to_string(),
+        Some(value) if value.is_sign_negative() => format!("(-{}::float8)", -value),
+        Some(value) => format!("{}::float8", value),
+    }
+}
+
+/// Quote a column name.
+fn column(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn unsupported(what: &str) -> FormulaError {
+    FormulaError(format!("{} can't be translated to SQL", what))
+}
@@ -0,0 +1,58 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use futures_util::{stream::select_all, Stream, StreamExt};
+
+use num_traits::Float;
+
+use crate::{error::FormulaError, formula_engine::FormulaEngine, value::FormulaValue};
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Calculate the formula over a stream of component values, yielding
+    /// the result for the latest values after each of them.
+    ///
+    /// The latest value of each component is kept by a
+    /// [`StreamingFormulaEngine`](crate::StreamingFormulaEngine), so results
+    /// are errors until all components had a value, like with
+    /// [`FormulaEngine::calculate`].
+    ///
+    /// ```rust
+    /// use frequenz_microgrid_formula_engine::FormulaEngine;
+    /// use futures_util::{stream, StreamExt};
+    ///
+    /// let fe = FormulaEngine::<f64>::try_new("#0 + #1").unwrap();
+    /// let updates = stream::iter([(0, Some(1.0)), (1, Some(2.0)), (0, None)]);
+    /// let results = futures_executor::block_on(fe.stream(updates).collect::<Vec<_>>());
+    /// assert!(results[0].is_err());
+    /// assert_eq!(results[1].as_ref().unwrap(), &Some(3.0));
+    /// assert_eq!(results[2].as_ref().unwrap(), &None);
+    /// ```
+    pub fn stream<S>(&self, updates: S) -> impl Stream<Item = Result<Option<T>, FormulaError>>
+    where
+        S: Stream<Item = (u64, Option<T>)>,
+    {
+        let mut streaming = self.streaming();
+        updates.map(move |(id, value)| streaming.update(id, value))
+    }
+
+    /// Calculate the formula over a stream of values for each component,
+    /// yielding the result for the latest values after each value of any of
+    /// the streams.
+    ///
+    /// The values of different streams are taken in the order they are
+    /// ready, like with [`FormulaEngine::stream`].
+    pub fn stream_components<S>(
+        &self,
+        streams: impl IntoIterator<Item = (u64, S)>,
+    ) -> impl Stream<Item = Result<Option<T>, FormulaError>>
+    where
+        S: Stream<Item = Option<T>>,
+    {
+        let updates = select_all(
+            streams
+                .into_iter()
+                .map(|(id, stream)| Box::pin(stream.map(move |value| (id, value)))),
+        );
+        self.stream(updates)
+    }
+}
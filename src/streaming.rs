@@ -0,0 +1,310 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use num_traits::Float;
+
+use crate::{
+    error::FormulaError,
+    formula_engine::FormulaEngine,
+    incremental::{same_result, IncrementalEngine},
+    temporal::StateSnapshot,
+    value::{FormulaValue, Sample},
+};
+
+/// A callback for changes of the result of a [`StreamingFormulaEngine`].
+pub type ChangeCallback<T> = dyn FnMut(&Result<Option<T>, FormulaError>) + Send;
+
+/// A callback for the inputs of a [`StreamingFormulaEngine`], with the
+/// result of the formula after each of them.
+pub type InputCallback<T> = dyn FnMut(&StreamingInput<T>, &Result<Option<T>, FormulaError>) + Send;
+
+/// An input of a [`StreamingFormulaEngine`], passed to the callbacks
+/// registered with [`on_input`](StreamingFormulaEngine::on_input).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StreamingInput<T> {
+    /// A value of a component, from
+    /// [`update_at`](StreamingFormulaEngine::update_at) and the methods
+    /// calling it.
+    Update(u64, Sample<T>),
+    /// Expiring the values that are too old at a time, from
+    /// [`expire`](StreamingFormulaEngine::expire).
+    Expire(SystemTime),
+}
+
+/// A formula calculated as the values of its components arrive, keeping the
+/// latest value of each component.
+///
+/// Only the parts of the formula depending on an updated component are
+/// recalculated, like with an [`IncrementalEngine`]. Callbacks registered
+/// with [`on_change`](Self::on_change) are called whenever the result
+/// changes.
+///
+/// Values older than a maximum age, e.g. of a meter that stopped reporting,
+/// can be treated as `None`, see [`with_max_age`](Self::with_max_age).
+///
+/// ```rust
+/// use frequenz_microgrid_formula_engine::FormulaEngine;
+/// use std::sync::{Arc, Mutex};
+///
+/// let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, 0) + COALESCE(#1, 0)").unwrap();
+/// let mut streaming = fe.streaming();
+/// let results = Arc::new(Mutex::new(Vec::new()));
+/// let changes = results.clone();
+/// streaming.on_change(move |result| {
+///     if let Ok(value) = result {
+///         changes.lock().unwrap().push(*value);
+///     }
+/// });
+///
+/// // Components without values are errors, like with `FormulaEngine::calculate`.
+/// assert!(streaming.update(0, Some(1.0)).is_err());
+/// streaming.update(1, Some(0.0));
+/// streaming.update(1, Some(2.0));
+/// assert_eq!(streaming.current().unwrap(), Some(3.0));
+/// assert_eq!(*results.lock().unwrap(), vec![Some(1.0), Some(3.0)]);
+/// ```
+pub struct StreamingFormulaEngine<T> {
+    incremental: IncrementalEngine<T>,
+    current: Result<Option<T>, FormulaError>,
+    callbacks: Vec<Box<ChangeCallback<T>>>,
+    input_callbacks: Vec<Box<InputCallback<T>>>,
+    max_age: Option<Duration>,
+    max_ages: HashMap<u64, Duration>,
+    /// When the values that can become too old were updated.
+    updated: HashMap<u64, SystemTime>,
+}
+
+impl<T: Debug> Debug for StreamingFormulaEngine<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamingFormulaEngine")
+            .field("incremental", &self.incremental)
+            .field("current", &self.current)
+            .field("callbacks", &self.callbacks.len())
+            .field("input_callbacks", &self.input_callbacks.len())
+            .field("max_age", &self.max_age)
+            .field("max_ages", &self.max_ages)
+            .field("updated", &self.updated)
+            .finish()
+    }
+}
+
+impl<T: FormulaValue + Float> FormulaEngine<T> {
+    /// Create a [`StreamingFormulaEngine`] for the formula, without any
+    /// component values yet.
+    pub fn streaming(&self) -> StreamingFormulaEngine<T> {
+        let incremental = self.incremental();
+        StreamingFormulaEngine {
+            current: incremental.result(),
+            incremental,
+            callbacks: Vec::new(),
+            input_callbacks: Vec::new(),
+            max_age: None,
+            max_ages: HashMap::new(),
+            updated: HashMap::new(),
+        }
+    }
+}
+
+impl<T: FormulaValue + Float> StreamingFormulaEngine<T> {
+    /// Treat values older than `max_age` as `None`, unless a maximum age is
+    /// set for the component.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Treat values of a component older than `max_age` as `None`.
+    pub fn with_component_max_age(mut self, id: u64, max_age: Duration) -> Self {
+        self.max_ages.insert(id, max_age);
+        self
+    }
+
+    /// Get the engine of the formula.
+    pub fn engine(&self) -> &FormulaEngine<T> {
+        self.incremental.engine()
+    }
+
+    /// Get the latest values of the components.
+    pub fn values(&self) -> &HashMap<u64, Option<T>> {
+        self.incremental.values()
+    }
+
+    /// Get the result of the formula for the latest values.
+    pub fn current(&self) -> Result<Option<T>, FormulaError> {
+        match &self.current {
+            Ok(value) => Ok(*value),
+            Err(err) => Err(FormulaError(err.0.clone())),
+        }
+    }
+
+    /// Forget the samples of the temporal functions, as if they were just
+    /// created. Their results change with the next update.
+    pub fn reset(&mut self) {
+        self.incremental.reset();
+    }
+
+    /// Get a snapshot of the samples of the temporal functions, to continue
+    /// with by [`restore`](Self::restore).
+    pub fn state_snapshot(&self) -> StateSnapshot<T> {
+        self.incremental.state_snapshot()
+    }
+
+    /// Continue with the samples of the temporal functions of a snapshot,
+    /// e.g. one taken before restarting the process. Their results change
+    /// with the next update.
+    pub fn restore(&mut self, snapshot: &StateSnapshot<T>) -> Result<(), FormulaError> {
+        self.incremental.restore(snapshot)
+    }
+
+    /// Get the samples of a component kept by the temporal functions of its
+    /// placeholder, oldest first.
+    pub fn history(&self, id: u64) -> Vec<(SystemTime, T)> {
+        self.incremental.history(id)
+    }
+
+    /// Add samples of a component taken before to the temporal functions of
+    /// its placeholder, e.g. stored ones to warm up a rolling average after
+    /// restarting. Their results change with the next update.
+    pub fn seed_history(&mut self, id: u64, samples: &[(SystemTime, T)]) {
+        self.incremental.seed_history(id, samples);
+    }
+
+    /// Register a callback to call with the result of the formula whenever
+    /// it changes.
+    pub fn on_change(
+        &mut self,
+        callback: impl FnMut(&Result<Option<T>, FormulaError>) + Send + 'static,
+    ) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Register a callback to call with every input of the engine and the
+    /// result of the formula after it, whether it changed or not.
+    pub fn on_input(
+        &mut self,
+        callback: impl FnMut(&StreamingInput<T>, &Result<Option<T>, FormulaError>) + Send + 'static,
+    ) {
+        self.input_callbacks.push(Box::new(callback));
+    }
+
+    /// Set the value of a component, and get the new result of the formula.
+    ///
+    /// The callbacks are called before returning if the result changed.
+    /// The value is timestamped with the clock of the engine's options.
+    pub fn update(&mut self, id: u64, value: Option<T>) -> Result<Option<T>, FormulaError> {
+        let now = self.engine().options.now();
+        self.update_at(id, value, now)
+    }
+
+    /// Set the value of a component received at `timestamp`, and get the
+    /// new result of the formula.
+    ///
+    /// Values that are too old at `timestamp` become `None` first, and
+    /// temporal functions take their samples at `timestamp`.
+    pub fn update_at(
+        &mut self,
+        id: u64,
+        value: Option<T>,
+        timestamp: SystemTime,
+    ) -> Result<Option<T>, FormulaError> {
+        self.expire_values(timestamp);
+        if self.max_age(id).is_some() && value.is_some() {
+            self.updated.insert(id, timestamp);
+        } else {
+            self.updated.remove(&id);
+        }
+        self.set(id, value, timestamp);
+        self.input(StreamingInput::Update(id, Sample::new(value, timestamp)))
+    }
+
+    /// Set the value of a component from a sample, and get the new result of
+    /// the formula, like [`update_at`](Self::update_at) at the time the
+    /// value was measured.
+    pub fn update_sample(&mut self, id: u64, sample: Sample<T>) -> Result<Option<T>, FormulaError> {
+        self.update_at(id, sample.value, sample.timestamp)
+    }
+
+    /// Set the values that are too old at `now` to `None`, and get the new
+    /// result of the formula.
+    ///
+    /// Streams without updates for a while need this to be called
+    /// periodically, for their results to stop using stale values.
+    pub fn expire(&mut self, now: SystemTime) -> Result<Option<T>, FormulaError> {
+        self.expire_values(now);
+        self.input(StreamingInput::Expire(now))
+    }
+
+    /// Set the values that are too old at `now` to `None`.
+    fn expire_values(&mut self, now: SystemTime) {
+        let mut expired: Vec<_> = self
+            .updated
+            .iter()
+            .filter(|(id, updated)| {
+                let age = now.duration_since(**updated).unwrap_or_default();
+                self.max_age(**id).is_some_and(|max_age| age > max_age)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort();
+        for id in expired {
+            self.updated.remove(&id);
+            self.set(id, None, now);
+        }
+    }
+
+    /// Call the input callbacks with an input, and get the result after it.
+    fn input(&mut self, input: StreamingInput<T>) -> Result<Option<T>, FormulaError> {
+        for callback in &mut self.input_callbacks {
+            callback(&input, &self.current);
+        }
+        self.current()
+    }
+
+    /// Get when the values that can become too old were updated, sorted by
+    /// component ID.
+    pub(crate) fn updated(&self) -> Vec<(u64, SystemTime)> {
+        let mut updated: Vec<_> = self.updated.iter().map(|(id, at)| (*id, *at)).collect();
+        updated.sort();
+        updated
+    }
+
+    /// Continue with the values of components, when the values that can
+    /// become too old were updated and the samples of the temporal
+    /// functions, without calling the input callbacks.
+    pub(crate) fn continue_with(
+        &mut self,
+        values: &[(u64, Option<T>)],
+        updated: &[(u64, SystemTime)],
+        state: &StateSnapshot<T>,
+    ) -> Result<(), FormulaError> {
+        for (id, value) in values {
+            self.set(*id, *value, UNIX_EPOCH);
+        }
+        self.updated = updated.iter().copied().collect();
+        self.restore(state)
+    }
+
+    /// Get the maximum age of the values of a component.
+    fn max_age(&self, id: u64) -> Option<Duration> {
+        self.max_ages.get(&id).copied().or(self.max_age)
+    }
+
+    /// Set the value of a component, calling the callbacks if the result
+    /// changes.
+    fn set(&mut self, id: u64, value: Option<T>, timestamp: SystemTime) {
+        let result = self.incremental.update_at(id, value, timestamp);
+        if !same_result(&result, &self.current) {
+            self.current = result;
+            for callback in &mut self.callbacks {
+                callback(&self.current);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! The rules of the formula syntax beyond the grammar, shared with the
+//! `formula!` macro, which includes this file, so that formulas are parsed
+//! the same way at compile time.
+//!
+//! This file must not depend on the rest of the crate.
+
+/// The most placeholders a `#first..#last` range can expand to, so that a
+/// range like `#0..#4000000000` is an error instead of exhausting memory.
+pub(crate) const MAX_RANGE_LEN: u64 = 10_000;
+
+/// How tightly an infix operator binds its operands, from loosest to
+/// tightest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Precedence {
+    /// Like `OR`.
+    Or,
+    /// Like `AND`.
+    And,
+    /// Like `==` and `<`.
+    Comparison,
+    /// Like `+` and `-`.
+    Additive,
+    /// Like `*`, `/` and `%`.
+    Multiplicative,
+    /// Like `^`.
+    Power,
+}
+
+/// How a chain of infix operators of the same precedence is grouped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Associativity {
+    /// `a - b - c` is `(a - b) - c`.
+    Left,
+    /// `a ^ b ^ c` is `a ^ (b ^ c)`.
+    Right,
+}
+
+/// The binding power of the operand of `NOT`, which binds more tightly than
+/// `AND` but more loosely than comparisons.
+pub(crate) const NOT_BINDING_POWER: u8 = 6;
+
+/// The binding power of the operand of unary minus, which binds more tightly
+/// than `*` but more loosely than `^`.
+pub(crate) const UNARY_MINUS_BINDING_POWER: u8 = 14;
+
+impl Precedence {
+    /// Get the binding powers of the left and right operands of an infix
+    /// operator, for Pratt parsing.
+    pub(crate) fn binding_powers(self, associativity: Associativity) -> (u8, u8) {
+        let left = match self {
+            Precedence::Or => 2,
+            Precedence::And => 4,
+            Precedence::Comparison => 8,
+            Precedence::Additive => 10,
+            Precedence::Multiplicative => 12,
+            Precedence::Power => 16,
+        };
+        match associativity {
+            Associativity::Left => (left, left + 1),
+            Associativity::Right => (left, left),
+        }
+    }
+}
+
+/// Get the minimum and, if limited, maximum number of arguments of a
+/// built-in or temporal function, by its upper-case name.
+fn arity(name: &str) -> Option<(usize, Option<usize>)> {
+    Some(match name {
+        "COALESCE" | "MIN" | "MAX" | "MIN_STRICT" | "MAX_STRICT" | "CASE" => (2, None),
+        "SUM" | "PRODUCT" | "COUNT_SOME" => (1, None),
+        "POW" | "NULLIF" | "ATAN2" | "QUANTIZE" => (2, Some(2)),
+        "IF" | "LERP" => (3, Some(3)),
+        "HYPOT" => (2, Some(3)),
+        "CURVE" => (3, None),
+        "POLY" => (2, None),
+        "ROUND" => (1, Some(2)),
+        "POS" | "NEG" | "KW" | "MW" | "KWH" | "SIN" | "COS" | "TAN" | "FLOOR" | "CEIL"
+        | "IS_NONE" | "IS_SOME" => (1, Some(1)),
+        "ROLLING_AVG" | "ROLLING_MIN" | "ROLLING_MAX" | "DELTA_WRAP" | "RAMP_LIMIT" | "TW_AVG" => {
+            (2, Some(2))
+        }
+        "DERIV" | "DELTA" => (1, Some(1)),
+        "INTEGRATE" | "LAG" => (1, Some(2)),
+        _ => return None,
+    })
+}
+
+/// Check that a built-in or temporal function is called with a valid number
+/// of arguments.
+///
+/// A `#*` placeholder can stand for any number of arguments.
+pub(crate) fn check_arity(name: &str, count: usize, wildcard: bool) -> Result<(), String> {
+    let Some(arity) = arity(name) else {
+        return Ok(());
+    };
+    let (expected, limit) = match arity {
+        (min, Some(max)) if min == max && count != min => (format!("{}", min), min),
+        (min, Some(max)) if count < min || count > max => (format!("{} to {}", min, max), max),
+        (min, None) if count < min && !wildcard => (format!("at least {}", min), min),
+        (_, None) if name == "CURVE" && count.is_multiple_of(2) => {
+            return Err("CURVE expects x followed by pairs of point coordinates".to_string())
+        }
+        _ => return Ok(()),
+    };
+    Err(format!(
+        "{} expects {} argument{}, got {}",
+        name,
+        expected,
+        if limit == 1 { "" } else { "s" },
+        count
+    ))
+}
+
+/// Get the contents of a string literal, where `\"` and `\\` stand for a
+/// quote and a backslash.
+pub(crate) fn unescape(literal: &str) -> String {
+    let mut unescaped = String::with_capacity(literal.len());
+    let mut chars = literal.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(next @ ('"' | '\\'))) => {
+                unescaped.push(*next);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
@@ -0,0 +1,345 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use num_traits::Float;
+
+use crate::{expression::TemporalFunction, options::EngineOptions, value::FormulaValue};
+
+/// The state of the temporal functions of a formula, from
+/// [`IncrementalEngine::state_snapshot`](crate::IncrementalEngine::state_snapshot),
+/// e.g. to continue where a process stopped after restarting it.
+///
+/// With the `serde` feature, snapshots can be serialized, e.g. to a file.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateSnapshot<T> {
+    /// The state of each call of a temporal function, in the order of the
+    /// formula.
+    pub functions: Vec<TemporalSnapshot<T>>,
+}
+
+/// The state of a call of a temporal function.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemporalSnapshot<T> {
+    /// The function the state is of.
+    pub function: TemporalFunction,
+    /// When the function took its first sample.
+    pub since: Option<SystemTime>,
+    /// The samples of the first argument with values, oldest first.
+    pub samples: Vec<(SystemTime, T)>,
+    /// The integral up to the first sample, for `INTEGRATE`.
+    pub total: T,
+    /// The last results, oldest first, for `RAMP_LIMIT`.
+    pub outputs: Vec<(SystemTime, T)>,
+}
+
+/// The samples a temporal function keeps between calculations.
+#[derive(Debug, Clone)]
+pub(crate) struct TemporalState<T> {
+    /// When the first sample was taken, for warming up.
+    since: Option<SystemTime>,
+    /// The samples of the first argument with values, oldest first.
+    samples: VecDeque<(SystemTime, T)>,
+    /// The integral up to the first sample, for `INTEGRATE`.
+    total: T,
+    /// The last results, for `RAMP_LIMIT`.
+    outputs: VecDeque<(SystemTime, T)>,
+}
+
+impl<T: FormulaValue> Default for TemporalState<T> {
+    fn default() -> Self {
+        Self {
+            since: None,
+            samples: VecDeque::new(),
+            total: T::zero(),
+            outputs: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: FormulaValue + Float> TemporalState<T> {
+    /// Get a snapshot of the state of a call of `function`.
+    pub(crate) fn snapshot(&self, function: &TemporalFunction) -> TemporalSnapshot<T> {
+        TemporalSnapshot {
+            function: function.clone(),
+            since: self.since,
+            samples: self.samples.iter().copied().collect(),
+            total: self.total,
+            outputs: self.outputs.iter().copied().collect(),
+        }
+    }
+
+    /// Create the state of a snapshot.
+    pub(crate) fn restore(snapshot: &TemporalSnapshot<T>) -> Self {
+        Self {
+            since: snapshot.since,
+            samples: snapshot.samples.iter().copied().collect(),
+            total: snapshot.total,
+            outputs: snapshot.outputs.iter().copied().collect(),
+        }
+    }
+
+    /// Get the samples of the first argument with values, oldest first.
+    pub(crate) fn samples(&self) -> impl Iterator<Item = &(SystemTime, T)> {
+        self.samples.iter()
+    }
+
+    /// Add samples of the first argument taken before, e.g. stored ones to
+    /// warm up a function over a window after restarting.
+    pub(crate) fn seed(
+        &mut self,
+        function: &TemporalFunction,
+        samples: &[(SystemTime, T)],
+        options: &EngineOptions,
+    ) {
+        for (timestamp, value) in samples {
+            self.since = Some(self.since.map_or(*timestamp, |since| since.min(*timestamp)));
+            self.record(*timestamp, Some(*value));
+        }
+        self.limit(function, options);
+    }
+
+    /// Take a sample of the arguments of a function at `timestamp`, and get
+    /// the result of the function.
+    ///
+    /// With warming up in the options, functions over a window are `None`
+    /// until the first sample is a window old.
+    pub(crate) fn sample(
+        &mut self,
+        function: &TemporalFunction,
+        timestamp: SystemTime,
+        args: &[Option<T>],
+        options: &EngineOptions,
+    ) -> Option<T> {
+        self.since = Some(self.since.map_or(timestamp, |since| since.min(timestamp)));
+        self.record(timestamp, args[0]);
+        self.limit(function, options);
+        let warm_up = options.warm_up();
+        match function {
+            TemporalFunction::RollingAvg
+            | TemporalFunction::RollingMin
+            | TemporalFunction::RollingMax => {
+                let window = seconds(args[1])?;
+                self.forget(timestamp, window);
+                if warm_up && !self.warmed_up(timestamp, window) {
+                    return None;
+                }
+                let values = self.samples.iter().map(|(_, value)| *value);
+                match function {
+                    TemporalFunction::RollingMin => values.reduce(T::min),
+                    TemporalFunction::RollingMax => values.reduce(T::max),
+                    _ => {
+                        let count = T::from_usize(self.samples.len())?;
+                        values
+                            .reduce(|sum, value| sum + value)
+                            .map(|sum| sum / count)
+                    }
+                }
+            }
+            TemporalFunction::TwAvg => {
+                args[0]?;
+                let window = seconds(args[1])?;
+                if warm_up && !self.warmed_up(timestamp, window) {
+                    return None;
+                }
+                let start = timestamp.checked_sub(window)?;
+                // The last sample before the window holds at its start.
+                while self.samples.get(1).is_some_and(|(time, _)| *time <= start) {
+                    self.samples.pop_front();
+                }
+                let mut sum = T::zero();
+                let mut total = T::zero();
+                for (i, (time, value)) in self.samples.iter().enumerate() {
+                    let end = self.samples.get(i + 1).map_or(timestamp, |(end, _)| *end);
+                    let held = end.duration_since((*time).max(start)).unwrap_or_default();
+                    let held = T::from_f64(held.as_secs_f64())?;
+                    sum = sum + *value * held;
+                    total = total + held;
+                }
+                if total > T::zero() {
+                    Some(sum / total)
+                } else {
+                    args[0]
+                }
+            }
+            TemporalFunction::Deriv => {
+                args[0]?;
+                self.keep_last(2);
+                let [(start, from), (end, to)] = [self.samples.front()?, self.samples.back()?];
+                let seconds = T::from_f64(end.duration_since(*start).ok()?.as_secs_f64())?;
+                (seconds > T::zero()).then(|| (*to - *from) / seconds)
+            }
+            TemporalFunction::Delta | TemporalFunction::DeltaWrap => {
+                args[0]?;
+                self.keep_last(2);
+                let [(_, from), (_, to)] = [self.samples.front()?, self.samples.back()?];
+                if self.samples.len() < 2 {
+                    return None;
+                }
+                match args.get(1) {
+                    // A counter that went down has wrapped around.
+                    Some(max) if to < from => Some(*to - *from + (*max)?),
+                    _ => Some(*to - *from),
+                }
+            }
+            TemporalFunction::Lag => {
+                let count = match args.get(1) {
+                    Some(count) => count.as_ref()?.to_usize()?,
+                    None => 1,
+                };
+                self.keep_last(count.saturating_add(1));
+                let last = self.samples.len().checked_sub(count + 1)?;
+                self.samples.get(last).map(|(_, value)| *value)
+            }
+            TemporalFunction::RampLimit => {
+                self.keep_last(0);
+                // A result at the same time is replaced, so it is limited by
+                // the result before it.
+                while self
+                    .outputs
+                    .back()
+                    .is_some_and(|(time, _)| *time >= timestamp)
+                {
+                    self.outputs.pop_back();
+                }
+                let (value, rate) = (args[0]?, args[1]?);
+                if rate < T::zero() {
+                    return None;
+                }
+                let limited = match self.outputs.back() {
+                    Some((time, previous)) => {
+                        let seconds = timestamp.duration_since(*time).ok()?.as_secs_f64();
+                        let step = rate * T::from_f64(seconds)?;
+                        value.max(*previous - step).min(*previous + step)
+                    }
+                    None => value,
+                };
+                self.outputs.push_back((timestamp, limited));
+                if self.outputs.len() > 2 {
+                    self.outputs.pop_front();
+                }
+                Some(limited)
+            }
+            TemporalFunction::Integrate => {
+                if args
+                    .get(1)
+                    .copied()
+                    .flatten()
+                    .is_some_and(|reset| reset != T::zero())
+                {
+                    self.keep_last(args[0].map_or(0, |_| 1));
+                    self.total = T::zero();
+                }
+                // Samples at the same time replace each other, so the area
+                // up to the last sample is only added once it is followed.
+                while self.samples.len() > 2 {
+                    if let Some(area) = self.area(0) {
+                        self.total = self.total + area;
+                    }
+                    self.samples.pop_front();
+                }
+                match self.samples.len() {
+                    0 => None,
+                    1 => Some(self.total),
+                    _ => Some(self.total + self.area(0)?),
+                }
+            }
+        }
+    }
+
+    /// Add a sample, in order of the timestamps. Samples at the same time
+    /// replace each other, so that updating several components at once
+    /// takes a single sample.
+    fn record(&mut self, timestamp: SystemTime, value: Option<T>) {
+        let i = self.samples.partition_point(|(time, _)| *time < timestamp);
+        if self
+            .samples
+            .get(i)
+            .is_some_and(|(time, _)| *time == timestamp)
+        {
+            self.samples.remove(i);
+        }
+        if let Some(value) = value {
+            self.samples.insert(i, (timestamp, value));
+        }
+    }
+
+    /// Forget the samples beyond the maximum number and age of the options,
+    /// except for functions that keep only the samples they need.
+    fn limit(&mut self, function: &TemporalFunction, options: &EngineOptions) {
+        if matches!(
+            function,
+            TemporalFunction::Integrate | TemporalFunction::RampLimit
+        ) {
+            return;
+        }
+        if let Some(max_len) = options.max_history_len() {
+            self.keep_last(max_len);
+        }
+        if let (Some(max_age), Some((latest, _))) =
+            (options.max_history_age(), self.samples.back().copied())
+        {
+            while let Some((time, _)) = self.samples.front() {
+                match latest.duration_since(*time) {
+                    Ok(age) if age > max_age => self.samples.pop_front(),
+                    _ => break,
+                };
+            }
+        }
+    }
+
+    /// Get the area under the line from the sample at `i` to the next one,
+    /// in units of the samples times hours.
+    fn area(&self, i: usize) -> Option<T> {
+        trapezoid(*self.samples.get(i)?, *self.samples.get(i + 1)?)
+    }
+
+    /// Check whether the first sample is at least `window` older than
+    /// `timestamp`.
+    fn warmed_up(&self, timestamp: SystemTime, window: Duration) -> bool {
+        self.since.is_some_and(|since| {
+            timestamp
+                .duration_since(since)
+                .is_ok_and(|age| age >= window)
+        })
+    }
+
+    /// Forget all but the last `count` samples.
+    fn keep_last(&mut self, count: usize) {
+        while self.samples.len() > count {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Forget the samples that are at least `window` older than `timestamp`.
+    fn forget(&mut self, timestamp: SystemTime, window: Duration) {
+        while let Some((time, _)) = self.samples.front() {
+            match timestamp.duration_since(*time) {
+                Ok(age) if age >= window => self.samples.pop_front(),
+                _ => break,
+            };
+        }
+    }
+}
+
+/// Get a duration from a number of seconds, if it isn't negative.
+fn seconds<T: FormulaValue + Float>(value: Option<T>) -> Option<Duration> {
+    Duration::try_from_secs_f64(value?.to_f64()?).ok()
+}
+
+/// Get the area under the line from one sample to a later one, in units of
+/// the samples times hours.
+pub(crate) fn trapezoid<T: FormulaValue + Float>(
+    (start, from): (SystemTime, T),
+    (end, to): (SystemTime, T),
+) -> Option<T> {
+    let hours = T::from_f64(end.duration_since(start).ok()?.as_secs_f64() / 3600.0)?;
+    let two = T::one() + T::one();
+    Some((from + to) / two * hours)
+}
@@ -3,7 +3,7 @@
 
 use rand::Rng;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Add, Sub},
     vec,
 };
@@ -90,53 +90,103 @@ impl Sub for OptionW<f32> {
 #[test]
 fn test_parse_addition() {
     let fe = FormulaEngine::<f32>::try_new("1 + 1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. + 1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. + 1.
+    );
 }
 
 #[test]
 fn test_parse_multiplication() {
     let fe = FormulaEngine::<f32>::try_new("0.9 * 1.1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 0.9 * 1.1);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        0.9 * 1.1
+    );
 }
 
 #[test]
 fn test_parse_subtraction() {
     let fe = FormulaEngine::<f32>::try_new("1 - 1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. - 1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. - 1.
+    );
 }
 
 #[test]
 fn test_parse_division() {
     let fe = FormulaEngine::<f32>::try_new("1 / 1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. / 1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. / 1.
+    );
 }
 
 #[test]
 fn test_parse_addition_whitespace() {
     let fe = FormulaEngine::<f32>::try_new("1+1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. + 1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. + 1.
+    );
     let fe = FormulaEngine::<f32>::try_new("1+ 1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. + 1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. + 1.
+    );
     let fe = FormulaEngine::<f32>::try_new("1 +1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. + 1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. + 1.
+    );
 }
 
 #[test]
 fn test_combination() {
     let fe = FormulaEngine::<f32>::try_new("1 + 1 * 2").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 1. + 1. * 2.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        1. + 1. * 2.
+    );
 }
 
 #[test]
 fn test_combination_mul_add() {
     let fe = FormulaEngine::<f32>::try_new("2 * 1 + 2").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), 2. * 1. + 2.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        2. * 1. + 2.
+    );
 }
 
 #[test]
 fn test_negative_value() {
     let fe = FormulaEngine::<f32>::try_new("-1").unwrap();
-    assert_eq!(fe.calculate(HashMap::new()).unwrap().unwrap(), -1.);
+    assert_eq!(
+        fe.calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+            .unwrap(),
+        -1.
+    );
 }
 
 #[test]
@@ -266,7 +316,7 @@ fn test_components_getter_function_function() {
     assert_eq!(fe.components(), &vec![0, 1, 2].into_iter().collect());
 }
 
-fn test_large_microgrid_formula(components: HashMap<usize, Option<f32>>) {
+fn test_large_microgrid_formula(components: HashMap<u64, Option<f32>>) {
     let formula_result = FormulaEngine::try_new(concat!(
         "MIN(0.0, COALESCE(#4 + #3, #2, COALESCE(#4, 0.0) + COALESCE(#3, 0.0))) + ",
         "MIN(0.0, COALESCE(#6, #5, 0.0)) + ",
@@ -279,28 +329,27 @@ fn test_large_microgrid_formula(components: HashMap<usize, Option<f32>>) {
     let expected_result = min(
         OptionW(Some(0.0)),
         coalesce(vec![
-            OptionW(components.get(&4).unwrap().clone())
-                + OptionW(components.get(&3).unwrap().clone()),
-            OptionW(components.get(&2).unwrap().clone()),
+            OptionW(*components.get(&4).unwrap()) + OptionW(*components.get(&3).unwrap()),
+            OptionW(*components.get(&2).unwrap()),
             coalesce(vec![
-                OptionW(components.get(&4).unwrap().clone()),
+                OptionW(*components.get(&4).unwrap()),
                 OptionW(Some(0.0)),
             ]) + coalesce(vec![
-                OptionW(components.get(&3).unwrap().clone()),
+                OptionW(*components.get(&3).unwrap()),
                 OptionW(Some(0.0)),
             ]),
         ]),
     ) + min(
         OptionW(Some(0.0)),
         coalesce(vec![
-            OptionW(components.get(&6).unwrap().clone()),
-            OptionW(components.get(&5).unwrap().clone()),
+            OptionW(*components.get(&6).unwrap()),
+            OptionW(*components.get(&5).unwrap()),
             OptionW(Some(0.0)),
         ]),
     ) + min(
         OptionW(Some(0.0)),
         coalesce(vec![
-            OptionW(components.get(&7).unwrap().clone()),
+            OptionW(*components.get(&7).unwrap()),
             OptionW(Some(0.0)),
         ]),
     );
@@ -325,7 +374,7 @@ fn test_large_microgrid_formula_fuzz() {
     }
 }
 
-fn test_large_microgrid_formula_2(components: HashMap<usize, Option<f32>>) {
+fn test_large_microgrid_formula_2(components: HashMap<u64, Option<f32>>) {
     let formula_result = FormulaEngine::try_new(concat!(
         "MAX(0.0, #1 - COALESCE(#2, #3, 0.0) - ",
         "COALESCE(#5, COALESCE(#7, 0.0) + COALESCE(#6, 0.0))) + ",
@@ -337,35 +386,34 @@ fn test_large_microgrid_formula_2(components: HashMap<usize, Option<f32>>) {
 
     let expected_result = max(
         OptionW(Some(0.0)),
-        OptionW(components.get(&1).unwrap().clone())
+        OptionW(*components.get(&1).unwrap())
             - coalesce(vec![
-                OptionW(components.get(&2).unwrap().clone()),
-                OptionW(components.get(&3).unwrap().clone()),
+                OptionW(*components.get(&2).unwrap()),
+                OptionW(*components.get(&3).unwrap()),
                 OptionW(Some(0.0)),
             ])
             - coalesce(vec![
-                OptionW(components.get(&5).unwrap().clone()),
+                OptionW(*components.get(&5).unwrap()),
                 coalesce(vec![
-                    OptionW(components.get(&7).unwrap().clone()),
+                    OptionW(*components.get(&7).unwrap()),
                     OptionW(Some(0.0)),
                 ]) + coalesce(vec![
-                    OptionW(components.get(&6).unwrap().clone()),
+                    OptionW(*components.get(&6).unwrap()),
                     OptionW(Some(0.0)),
                 ]),
             ]),
     ) + coalesce(vec![
         max(
             OptionW(Some(0.0)),
-            OptionW(components.get(&2).unwrap().clone())
-                - OptionW(components.get(&3).unwrap().clone()),
+            OptionW(*components.get(&2).unwrap()) - OptionW(*components.get(&3).unwrap()),
         ),
         OptionW(Some(0.0)),
     ]) + coalesce(vec![
         max(
             OptionW(Some(0.0)),
-            OptionW(components.get(&5).unwrap().clone())
-                - OptionW(components.get(&6).unwrap().clone())
-                - OptionW(components.get(&7).unwrap().clone()),
+            OptionW(*components.get(&5).unwrap())
+                - OptionW(*components.get(&6).unwrap())
+                - OptionW(*components.get(&7).unwrap()),
         ),
         OptionW(Some(0.0)),
     ]);
@@ -389,3 +437,4133 @@ fn test_large_microgrid_formula_2_fuzz() {
         test_large_microgrid_formula_2(components);
     }
 }
+
+#[test]
+fn test_derivative_arithmetic() {
+    let fe = FormulaEngine::<f32>::try_new("#0 * #0 + 3 * #1 - #0 / #1").unwrap();
+    let values = HashMap::from([(0, Some(2.)), (1, Some(4.))]);
+    assert_eq!(
+        fe.derivative(0).unwrap().calculate(values.clone()).unwrap(),
+        Some(2. * 2. - 1. / 4.)
+    );
+    assert_eq!(
+        fe.derivative(1).unwrap().calculate(values).unwrap(),
+        Some(3. + 2. / (4. * 4.))
+    );
+}
+
+#[test]
+fn test_derivative_constant() {
+    let fe = FormulaEngine::<f32>::try_new("#0 * 2").unwrap();
+    let derivative = fe.derivative(1).unwrap();
+    assert!(derivative.components().is_empty());
+    assert_eq!(
+        derivative
+            .calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap(),
+        Some(0.)
+    );
+}
+
+#[test]
+fn test_derivative_piecewise() {
+    let fe = FormulaEngine::<f32>::try_new("MIN(2 * #0, #1, 5)").unwrap();
+    let derivative = fe.derivative(0).unwrap();
+    assert_eq!(
+        derivative
+            .calculate(HashMap::from([(0, Some(1.)), (1, Some(3.))]))
+            .unwrap(),
+        Some(2.)
+    );
+    assert_eq!(
+        derivative
+            .calculate(HashMap::from([(0, Some(2.)), (1, Some(3.))]))
+            .unwrap(),
+        Some(0.)
+    );
+
+    let fe = FormulaEngine::<f32>::try_new("COALESCE(#0, -#1)").unwrap();
+    assert_eq!(
+        fe.derivative(1)
+            .unwrap()
+            .calculate(HashMap::from([(0, None), (1, Some(3.))]))
+            .unwrap(),
+        Some(-1.)
+    );
+}
+
+#[cfg(feature = "monte-carlo")]
+#[test]
+fn test_monte_carlo() {
+    use crate::InputDistribution;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let fe = FormulaEngine::<f64>::try_new("#0 + COALESCE(#1, #2)").unwrap();
+    let distributions = HashMap::from([
+        (
+            0,
+            InputDistribution::Normal {
+                mean: 10.,
+                std_dev: 1.,
+            },
+        ),
+        (1, InputDistribution::Constant(None)),
+        (2, InputDistribution::Uniform { low: 4., high: 6. }),
+    ]);
+    let stats = fe
+        .monte_carlo(&distributions, 10_000, &mut StdRng::seed_from_u64(0))
+        .unwrap();
+
+    assert_eq!(stats.none_count, 0);
+    assert_eq!(stats.results().len(), 10_000);
+    assert!((stats.mean().unwrap() - 15.).abs() < 0.1);
+    assert!((stats.percentile(50.).unwrap() - 15.).abs() < 0.1);
+    assert!(stats.percentile(0.).unwrap() < stats.percentile(100.).unwrap());
+    assert!((stats.std_dev().unwrap() - (1f64 + 1. / 3.).sqrt()).abs() < 0.1);
+}
+
+#[cfg(feature = "monte-carlo")]
+#[test]
+fn test_monte_carlo_invalid_distribution() {
+    use crate::InputDistribution;
+
+    let fe = FormulaEngine::<f64>::try_new("#0").unwrap();
+    let distributions = HashMap::from([(0, InputDistribution::Uniform { low: 1., high: 0. })]);
+    assert!(fe
+        .monte_carlo(&distributions, 10, &mut rand::thread_rng())
+        .is_err());
+}
+
+/// 2024-01-01T00:00:00Z, a Monday.
+const MONDAY: u64 = 1_704_067_200;
+
+fn clock_at(seconds: u64) -> impl crate::Clock {
+    move || std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds)
+}
+
+#[test]
+fn test_time_of_use() {
+    use crate::{EngineOptions, TouWindow, Weekday};
+
+    let peak = TouWindow::try_new((17, 0), (20, 0))
+        .unwrap()
+        .on_weekdays(&[Weekday::Monday, Weekday::Tuesday]);
+    let options = |seconds| {
+        EngineOptions::default()
+            .with_clock(clock_at(seconds))
+            .with_tou_window("peak", peak)
+    };
+    let values = HashMap::from([(0, Some(2.))]);
+
+    let fe = FormulaEngine::<f32>::try_new_with_options(
+        "TOU(\"peak\") * #0 * 0.5 + #0",
+        options(MONDAY + 18 * 3600),
+    )
+    .unwrap();
+    assert_eq!(fe.calculate(values.clone()).unwrap(), Some(3.));
+
+    let fe = FormulaEngine::<f32>::try_new_with_options(
+        "TOU(\"peak\") * #0 * 0.5 + #0",
+        options(MONDAY + 20 * 3600),
+    )
+    .unwrap();
+    assert_eq!(fe.calculate(values.clone()).unwrap(), Some(2.));
+
+    let fe = FormulaEngine::<f32>::try_new_with_options(
+        "TOU(\"peak\") * #0 * 0.5 + #0",
+        options(MONDAY + (5 * 24 + 18) * 3600),
+    )
+    .unwrap();
+    assert_eq!(fe.calculate(values).unwrap(), Some(2.));
+}
+
+#[test]
+fn test_time_of_use_across_midnight() {
+    use crate::{EngineOptions, TouWindow, Weekday};
+
+    let night = TouWindow::try_new((22, 0), (6, 0))
+        .unwrap()
+        .on_weekdays(&[Weekday::Friday]);
+    let tou_at = |seconds| {
+        FormulaEngine::<f32>::try_new_with_options(
+            "TOU(\"night\")",
+            EngineOptions::default()
+                .with_clock(clock_at(seconds))
+                .with_tou_window("night", night),
+        )
+        .unwrap()
+        .calculate(HashMap::<u64, Option<_>>::new())
+        .unwrap()
+    };
+
+    assert_eq!(tou_at(MONDAY + (4 * 24 + 23) * 3600), Some(1.));
+    assert_eq!(tou_at(MONDAY + (5 * 24 + 3) * 3600), Some(1.));
+    assert_eq!(tou_at(MONDAY + (6 * 24 + 3) * 3600), Some(0.));
+}
+
+#[test]
+fn test_time_of_use_unknown_window() {
+    assert!(FormulaEngine::<f32>::try_new("TOU(\"peak\")").is_err());
+    assert!(crate::TouWindow::try_new((25, 0), (6, 0)).is_err());
+}
+
+#[test]
+fn test_time_functions() {
+    use crate::EngineOptions;
+
+    let calculate_at = |formula, seconds| {
+        FormulaEngine::<f64>::try_new_with_options(
+            formula,
+            EngineOptions::default().with_clock(clock_at(seconds)),
+        )
+        .unwrap()
+        .calculate(HashMap::<u64, Option<_>>::new())
+        .unwrap()
+    };
+
+    let saturday_evening = MONDAY + (5 * 24 + 18) * 3600 + 59 * 60;
+    assert_eq!(calculate_at("HOUR()", saturday_evening), Some(18.));
+    assert_eq!(calculate_at("DAYOFWEEK()", saturday_evening), Some(6.));
+    assert_eq!(calculate_at("DAYOFWEEK()", MONDAY), Some(1.));
+    assert_eq!(
+        calculate_at("NOW() - 60", saturday_evening),
+        Some(saturday_evening as f64 - 60.)
+    );
+}
+
+#[cfg(feature = "chrono-tz")]
+#[test]
+fn test_timezone() {
+    use crate::{EngineOptions, TouWindow};
+
+    let early = TouWindow::try_new((5, 0), (6, 0)).unwrap();
+    // 20:30 UTC on a Sunday, which is 21:30 in Berlin and 05:30 on Monday in Tokyo.
+    let options = EngineOptions::default()
+        .with_clock(clock_at(MONDAY - 7 * 1800))
+        .with_tou_window("early", early);
+
+    let calculate = |formula, options| {
+        FormulaEngine::<f32>::try_new_with_options(formula, options)
+            .unwrap()
+            .calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap()
+    };
+    assert_eq!(calculate("HOUR()", options.clone()), Some(20.));
+    assert_eq!(calculate("TOU(\"early\")", options.clone()), Some(0.));
+
+    let options = options.with_timezone(chrono_tz::Europe::Berlin);
+    assert_eq!(calculate("HOUR()", options.clone()), Some(21.));
+    assert_eq!(calculate("DAYOFWEEK()", options.clone()), Some(7.));
+
+    let options = options.with_timezone(chrono_tz::Asia::Tokyo);
+    assert_eq!(calculate("HOUR()", options.clone()), Some(5.));
+    assert_eq!(calculate("DAYOFWEEK()", options.clone()), Some(1.));
+    assert_eq!(calculate("TOU(\"early\")", options), Some(1.));
+}
+
+#[test]
+fn test_pow() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(3.)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("2 ^ 3"), Some(8.));
+    assert_eq!(calculate("2 ^ 3 ^ 2"), Some(512.));
+    assert_eq!(calculate("-2 ^ 2"), Some(-4.));
+    assert_eq!(calculate("2 ^ -1"), Some(0.5));
+    assert_eq!(calculate("2 * #0 ^ 2"), Some(18.));
+    assert_eq!(calculate("POW(#0, 2) + 1"), Some(10.));
+    assert_eq!(calculate("#1 ^ 2"), None);
+    assert_eq!(calculate("POW(2, #1)"), None);
+}
+
+#[test]
+fn test_non_float_values() {
+    // Only calculating formulas requires floating point values.
+    let fe = FormulaEngine::<i64>::try_new("#0 ^ 2 + POW(#1, 3) - 4").unwrap();
+    assert_eq!(fe.components(), &HashSet::from([0, 1]));
+    assert_eq!(fe.to_string(), "#0 ^ 2 + POW(#1, 3) - 4");
+    assert_eq!(fe.derivative(1).unwrap().to_string(), "3 * #1 ^ 2");
+}
+
+#[test]
+fn test_formula_aliases() {
+    use crate::{EngineOptions, Expr, Formula32, Formula64};
+
+    // The aliases have the constructors of FormulaEngine.
+    let fe = Formula32::try_new("#0 * 2").unwrap();
+    assert_eq!(fe.calculate_slice(&[Some(1.5)]).unwrap(), Some(3.0));
+    let fe = Formula64::try_new_with_options("#0 / 0", EngineOptions::default()).unwrap();
+    assert_eq!(
+        fe.calculate_slice(&[Some(1.0)]).unwrap(),
+        Some(f64::INFINITY)
+    );
+    let fe = Formula64::try_from_expr(
+        Expr::component(0) + Expr::value(1.0),
+        EngineOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(fe, Formula64::try_new("#0 + 1").unwrap());
+}
+
+#[test]
+fn test_derivative_pow() {
+    let fe = FormulaEngine::<f32>::try_new("#0 ^ 3 + POW(#1, #0)").unwrap();
+    let values = HashMap::from([(0, Some(2.)), (1, Some(4.))]);
+    assert_eq!(
+        fe.derivative(1).unwrap().calculate(values).unwrap(),
+        Some(2. * 4.)
+    );
+    assert!(fe.derivative(0).is_err());
+}
+
+#[test]
+fn test_modulo() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(370.)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("#0 % 360"), Some(10.));
+    assert_eq!(calculate("1 + 7 % 4 * 2"), Some(7.));
+    assert_eq!(calculate("7.5 % 2"), Some(1.5));
+    assert_eq!(calculate("-7 % 4"), Some(-3.));
+    assert_eq!(calculate("#1 % 2"), None);
+}
+
+#[test]
+fn test_comparison() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(3.)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("(#0 > 0) * #0"), Some(3.));
+    assert_eq!(calculate("(#0 < 0) * #0"), Some(0.));
+    assert_eq!(calculate("#0 >= 3"), Some(1.));
+    assert_eq!(calculate("#0 <= 2"), Some(0.));
+    assert_eq!(calculate("#0 == 1 + 2"), Some(1.));
+    assert_eq!(calculate("#0 != 3"), Some(0.));
+    assert_eq!(calculate("#1 > 0"), None);
+}
+
+#[test]
+fn test_boolean_operators() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(3.)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("#0 > 0 AND #0 < 5"), Some(1.));
+    assert_eq!(calculate("#0 > 0 && #0 < 2"), Some(0.));
+    assert_eq!(calculate("#0 < 0 OR #0 == 3"), Some(1.));
+    assert_eq!(calculate("#0 < 0 || #0 > 3"), Some(0.));
+    assert_eq!(calculate("NOT #0 > 5"), Some(1.));
+    assert_eq!(calculate("!(#0 - 3)"), Some(1.));
+    assert_eq!(calculate("1 OR 1 AND 0"), Some(1.));
+    assert_eq!(calculate("--#0"), Some(3.));
+
+    assert_eq!(calculate("#1 > 0 AND 0"), Some(0.));
+    assert_eq!(calculate("#1 > 0 AND 1"), None);
+    assert_eq!(calculate("#1 OR 1"), Some(1.));
+    assert_eq!(calculate("#1 OR 0"), None);
+    assert_eq!(calculate("NOT #1"), None);
+
+    // Identifiers starting with a keyword aren't operators.
+    assert_eq!(calculate("LET NOTE = 5 IN NOTE"), Some(5.));
+    assert_eq!(
+        calculate("LET ORDER = 2 IN LET ANDY = #0 IN ORDER * ANDY"),
+        Some(6.)
+    );
+    assert_eq!(calculate("LET NOT_X = 1 IN NOT NOT_X"), Some(0.));
+    assert!(FormulaEngine::<f32>::try_new("#0 ANDY #0").is_err());
+}
+
+#[test]
+fn test_if() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(3.)), (1, None)]))
+    };
+    assert_eq!(calculate("IF(#0 > 0, #0, 0)").unwrap(), Some(3.));
+    assert_eq!(calculate("IF(#0 < 0, #0, -#0)").unwrap(), Some(-3.));
+    assert_eq!(calculate("IF(#1, 1, 2)").unwrap(), None);
+    assert_eq!(calculate("IF(#0 > 0, 1, #1)").unwrap(), Some(1.));
+    // The untaken branch isn't evaluated, so it can't fail.
+    assert_eq!(calculate("IF(#0 > 0, 1, #2)").unwrap(), Some(1.));
+    assert!(calculate("IF(#0 < 0, 1, #2)").is_err());
+}
+
+#[test]
+fn test_derivative_if() {
+    let fe = FormulaEngine::<f32>::try_new("IF(#0 > 0, #0 * #0, -#0)").unwrap();
+    let derivative = fe.derivative(0).unwrap();
+    assert_eq!(
+        derivative
+            .calculate(HashMap::from([(0, Some(3.))]))
+            .unwrap(),
+        Some(6.)
+    );
+    assert_eq!(
+        derivative
+            .calculate(HashMap::from([(0, Some(-3.))]))
+            .unwrap(),
+        Some(-1.)
+    );
+}
+
+#[test]
+fn test_case() {
+    let calculate = |formula, value| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, value)]))
+    };
+    let tariff = "CASE(#0 < 10, 1, #0 < 20, 2, 3)";
+    assert_eq!(calculate(tariff, Some(5.)).unwrap(), Some(1.));
+    assert_eq!(calculate(tariff, Some(15.)).unwrap(), Some(2.));
+    assert_eq!(calculate(tariff, Some(25.)).unwrap(), Some(3.));
+    assert_eq!(calculate(tariff, None).unwrap(), None);
+
+    // Without a default, no matching condition evaluates to None.
+    assert_eq!(calculate("CASE(#0 < 10, 1)", Some(15.)).unwrap(), None);
+    // Branches and conditions after the selected branch aren't evaluated.
+    assert_eq!(
+        calculate("CASE(#0 < 10, 1, #1, #2, #3)", Some(5.)).unwrap(),
+        Some(1.)
+    );
+    assert!(calculate("CASE(#0 < 10, 1, #1, #2, #3)", Some(15.)).is_err());
+}
+
+#[test]
+fn test_rounding() {
+    let calculate = |formula| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1234.5678)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("ROUND(#0)"), Some(1235.));
+    assert_eq!(calculate("ROUND(#0, 2)"), Some(1234.57));
+    assert_eq!(calculate("ROUND(#0, -2)"), Some(1200.));
+    assert_eq!(calculate("ROUND(-2.5)"), Some(-3.));
+    assert_eq!(calculate("FLOOR(#0)"), Some(1234.));
+    assert_eq!(calculate("FLOOR(-#0)"), Some(-1235.));
+    assert_eq!(calculate("CEIL(#0)"), Some(1235.));
+    assert_eq!(calculate("ROUND(#1)"), None);
+    assert_eq!(calculate("ROUND(#0, #1)"), None);
+}
+
+#[test]
+fn test_sum() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([
+                (0, Some(1.)),
+                (1, None),
+                (2, Some(2.)),
+                (3, None),
+            ]))
+            .unwrap()
+    };
+    assert_eq!(calculate("SUM(#0, #1, #2)"), Some(3.));
+    assert_eq!(calculate("SUM(#0)"), Some(1.));
+    assert_eq!(calculate("SUM(#1, #3)"), None);
+    assert_eq!(calculate("SUM(#0, #1 + #2, 4)"), Some(5.));
+}
+
+#[test]
+fn test_product() {
+    let values = HashMap::from([(0, Some(0.98)), (1, Some(0.99)), (2, None)]);
+    let calculate = |formula| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .calculate(values.clone())
+            .unwrap()
+    };
+    assert_eq!(
+        calculate("PRODUCT(#0, #1, 1000)"),
+        Some(0.98 * 0.99 * 1000.)
+    );
+    assert_eq!(calculate("PRODUCT(#0)"), Some(0.98));
+    assert_eq!(calculate("PRODUCT(#0, #1, #2)"), None);
+
+    let fe = FormulaEngine::<f64>::try_new("PRODUCT(#0, #1, #0)").unwrap();
+    assert_eq!(
+        fe.derivative(0).unwrap().calculate(values).unwrap(),
+        Some(2. * 0.98 * 0.99)
+    );
+}
+
+#[test]
+fn test_nullif() {
+    let calculate = |formula, value| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, value), (1, Some(5.)), (2, None)]))
+            .unwrap()
+    };
+    let formula = "COALESCE(NULLIF(#0, -9999), #1)";
+    assert_eq!(calculate(formula, Some(-9999.)), Some(5.));
+    assert_eq!(calculate(formula, Some(3.)), Some(3.));
+    assert_eq!(calculate(formula, None), Some(5.));
+    assert_eq!(calculate("NULLIF(#0, #2)", Some(3.)), Some(3.));
+}
+
+#[test]
+fn test_availability_predicates() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("IS_NONE(#0)"), Some(0.));
+    assert_eq!(calculate("IS_NONE(#1)"), Some(1.));
+    assert_eq!(calculate("IS_SOME(#0 + #1)"), Some(0.));
+    assert_eq!(calculate("IS_SOME(#0)"), Some(1.));
+    assert_eq!(calculate("IF(IS_SOME(#1), #1, #0 * 0.5)"), Some(1.));
+}
+
+#[test]
+fn test_count_some() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.)), (1, None), (2, Some(4.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate("COUNT_SOME(#1)"), Some(0.));
+    assert_eq!(calculate("COUNT_SOME(#0, #1, #2)"), Some(2.));
+    assert_eq!(
+        calculate("SUM(#0, #1, #2) / COUNT_SOME(#0, #1, #2)"),
+        Some(3.)
+    );
+}
+
+#[test]
+fn test_constants() {
+    let calculate = |formula| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate("PI"), Some(std::f64::consts::PI));
+    assert_eq!(calculate("E * #0"), Some(std::f64::consts::E * 2.));
+    assert_eq!(
+        calculate("SQRT2 ^ 2"),
+        Some(std::f64::consts::SQRT_2.powi(2))
+    );
+    assert_eq!(calculate("#0 * SQRT3"), Some(2. * 3f64.sqrt()));
+    assert_eq!(
+        FormulaEngine::<f32>::try_new("SQRT3")
+            .unwrap()
+            .calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap(),
+        Some(3f32.sqrt())
+    );
+    assert!(FormulaEngine::<f64>::try_new("PIE").is_err());
+}
+
+#[test]
+fn test_digit_separators() {
+    let calculate = |formula| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate("1_000_000"), Some(1_000_000.));
+    assert_eq!(calculate("#0 * 1_000.000_5"), Some(2_000.001));
+    assert!(FormulaEngine::<f64>::try_new("1__000").is_err());
+    assert!(FormulaEngine::<f64>::try_new("1000_").is_err());
+    assert!(FormulaEngine::<f64>::try_new("_1000").is_err());
+}
+
+#[test]
+fn test_named_placeholders() {
+    let fe = FormulaEngine::<f32>::try_new("$grid_meter - COALESCE($pv_1, 0)").unwrap();
+    assert_eq!(
+        fe.names(),
+        &HashSet::from(["grid_meter".to_string(), "pv_1".to_string()])
+    );
+    assert!(fe.components().is_empty());
+
+    let calculate = |values: &[(&str, Option<f32>)]| {
+        fe.calculate_named(
+            values
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    };
+    assert_eq!(
+        calculate(&[("grid_meter", Some(10.)), ("pv_1", Some(4.))]).unwrap(),
+        Some(6.)
+    );
+    assert_eq!(
+        calculate(&[("grid_meter", Some(10.)), ("pv_1", None)]).unwrap(),
+        Some(10.)
+    );
+    assert!(calculate(&[("grid_meter", Some(10.))]).is_err());
+    assert!(fe.calculate(HashMap::from([(0, Some(1.))])).is_err());
+
+    assert!(FormulaEngine::<f32>::try_new("$1abc").is_err());
+}
+
+#[test]
+fn test_string_component_ids() {
+    let fe = FormulaEngine::<f32>::try_new(r##"#"mtr-7f3a" + #"mtr 01" * 2"##).unwrap();
+    assert_eq!(
+        fe.names(),
+        &HashSet::from(["mtr-7f3a".to_string(), "mtr 01".to_string()])
+    );
+    assert_eq!(
+        fe.calculate_named(HashMap::from([
+            ("mtr-7f3a".to_string(), Some(1.)),
+            ("mtr 01".to_string(), Some(3.)),
+        ]))
+        .unwrap(),
+        Some(7.)
+    );
+    let missing = HashMap::from([("mtr-7f3a".to_string(), Some(1.))]);
+    assert_eq!(
+        fe.calculate_named(missing).unwrap_err().to_string(),
+        "Missing value for #\"mtr 01\""
+    );
+    assert_eq!(
+        fe.incremental()
+            .update(0, Some(1.))
+            .unwrap_err()
+            .to_string(),
+        "Missing value for #\"mtr-7f3a\""
+    );
+
+    // Quotes and backslashes are escaped, so that IDs with them re-parse.
+    let formula = r##"#"say \"hi\"" + #"C:\\meter" + #"a\b""##;
+    let fe = FormulaEngine::<f32>::try_new(formula).unwrap();
+    assert_eq!(
+        fe.names(),
+        &HashSet::from([
+            "say \"hi\"".to_string(),
+            "C:\\meter".to_string(),
+            "a\\b".to_string(),
+        ])
+    );
+    assert_eq!(
+        fe.to_string(),
+        r##"#"say \"hi\"" + #"C:\\meter" + #"a\\b""##
+    );
+    assert_eq!(FormulaEngine::<f32>::try_new(&fe.to_string()).unwrap(), fe);
+    assert_eq!(
+        fe.calculate_named(HashMap::new()).unwrap_err().to_string(),
+        r##"Missing value for #"say \"hi\"""##
+    );
+    assert_eq!(
+        crate::Expr::<f32>::TimeOfUse("a \"peak\"".to_string()).to_string(),
+        r##"TOU("a \"peak\"")"##
+    );
+    assert!(FormulaEngine::<f32>::try_new(r##"#"a\""##).is_err());
+}
+
+#[test]
+fn test_component_ranges() {
+    let fe = FormulaEngine::<f32>::try_new("SUM(#1..#4)").unwrap();
+    assert_eq!(fe.components(), &HashSet::from([1, 2, 3, 4]));
+
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([
+                (0, Some(1.)),
+                (1, None),
+                (2, Some(2.)),
+                (3, Some(3.)),
+            ]))
+            .unwrap()
+    };
+    assert_eq!(calculate("SUM(#0..#3)"), Some(6.));
+    assert_eq!(calculate("SUM(#0, #2 .. #3, 10)"), Some(16.));
+    assert_eq!(calculate("COALESCE(#1..#3, 0)"), Some(2.));
+    assert_eq!(calculate("COALESCE(#3..#1)"), Some(3.));
+    assert_eq!(calculate("MAX(#0..#0, #3)"), Some(3.));
+    assert_eq!(calculate("COUNT_SOME(#0..#3)"), Some(3.));
+    assert!(FormulaEngine::<f32>::try_new("#0..#3").is_err());
+
+    assert_eq!(
+        FormulaEngine::<f32>::try_new("SUM(#0..#4000000000)")
+            .map(|_| ())
+            .unwrap_err()
+            .to_string(),
+        "Placeholder range #0..#4000000000 has more than 10000 placeholders"
+    );
+    assert!(FormulaEngine::<f32>::try_new("SUM(#10000..#1)").is_ok());
+    assert!(FormulaEngine::<f32>::try_new("SUM(#10000..#0)").is_err());
+}
+
+#[test]
+fn test_wildcard() {
+    let fe = FormulaEngine::<f32>::try_new("SUM(#*) - #0").unwrap();
+    assert_eq!(fe.components(), &HashSet::from([0]));
+    assert_eq!(
+        fe.calculate(HashMap::from([(0, Some(1.)), (3, Some(2.)), (7, None)]))
+            .unwrap(),
+        Some(2.)
+    );
+
+    let calculate = |formula, values| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(values)
+            .unwrap()
+    };
+    let values = HashMap::from([(4, Some(4.)), (2, None), (3, Some(3.))]);
+    assert_eq!(calculate("COALESCE(#*, 0)", values.clone()), Some(3.));
+    assert_eq!(calculate("MAX(#*)", values.clone()), Some(4.));
+    assert_eq!(calculate("COUNT_SOME(#*)", values.clone()), Some(2.));
+    assert_eq!(calculate("COALESCE(#*, 0)", HashMap::new()), Some(0.));
+
+    assert!(FormulaEngine::<f32>::try_new("#* + 1").is_err());
+    assert!(FormulaEngine::<f32>::try_new("SUM(#*)")
+        .unwrap()
+        .derivative(0)
+        .is_err());
+}
+
+#[test]
+fn test_comments() {
+    let formula = "
+        // Grid meter
+        #0
+        /* minus the PV inverters */ - #1 / #2 // scaled
+    ";
+    let fe = FormulaEngine::<f32>::try_new(formula).unwrap();
+    assert_eq!(
+        fe.calculate(HashMap::from([
+            (0, Some(10.)),
+            (1, Some(4.)),
+            (2, Some(2.))
+        ]))
+        .unwrap(),
+        Some(8.)
+    );
+    assert!(FormulaEngine::<f32>::try_new("#0 /* unterminated").is_err());
+}
+
+#[test]
+fn test_case_insensitive_functions() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, None), (1, Some(2.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate("coalesce(#0, #1)"), Some(2.));
+    assert_eq!(calculate("Min(#1, 5) + max(#1, 5)"), Some(7.));
+    assert_eq!(calculate("If(#1 > 1, Sum(#0, #1), 0)"), Some(2.));
+}
+
+#[test]
+fn test_let() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, None), (1, Some(2.)), (2, Some(3.))]))
+            .unwrap()
+    };
+    assert_eq!(
+        calculate("LET pv = COALESCE(#0, #1, 0) IN pv * pv + pv"),
+        Some(6.)
+    );
+    assert_eq!(
+        calculate("1 + LET x = #1 IN LET y = x * #2 IN y - x"),
+        Some(5.)
+    );
+    assert_eq!(calculate("let x = #1 in LET x = x + 1 IN x"), Some(3.));
+    assert_eq!(
+        calculate("LET letter = #2 IN letter * E"),
+        Some(3. * std::f32::consts::E)
+    );
+
+    assert!(FormulaEngine::<f32>::try_new("LET x = 1 IN y").is_err());
+    assert!(FormulaEngine::<f32>::try_new("(LET x = 1 IN x) + x").is_err());
+    assert!(FormulaEngine::<f32>::try_new("LET in = 1 IN in").is_err());
+
+    let fe = FormulaEngine::<f32>::try_new("LET x = #1 * #2 IN x * x").unwrap();
+    assert_eq!(fe.components(), &HashSet::from([1, 2]));
+    assert_eq!(
+        fe.derivative(1)
+            .unwrap()
+            .calculate(HashMap::from([(1, Some(2.)), (2, Some(3.))]))
+            .unwrap(),
+        Some(36.)
+    );
+}
+
+#[test]
+fn test_arity() {
+    let error = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .map(|_| ())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(error("MIN()"), "MIN expects at least 2 arguments, got 0");
+    assert_eq!(
+        error("COALESCE(#0)"),
+        "COALESCE expects at least 2 arguments, got 1"
+    );
+    assert_eq!(error("POW(#0, 1, 2)"), "POW expects 2 arguments, got 3");
+    assert_eq!(error("FLOOR(#0, 1)"), "FLOOR expects 1 argument, got 2");
+    assert_eq!(error("ROUND()"), "ROUND expects 1 to 2 arguments, got 0");
+    assert_eq!(error("1 + IF(#0, 1)"), "IF expects 3 arguments, got 2");
+    assert_eq!(error("SUM()"), "SUM expects at least 1 argument, got 0");
+
+    assert!(FormulaEngine::<f32>::try_new("COALESCE(#*)").is_ok());
+    assert!(FormulaEngine::<f32>::try_new("COALESCE(#0..#1)").is_ok());
+}
+
+#[test]
+fn test_strict_min_max() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.)), (1, None), (2, Some(3.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate("MIN_STRICT(#0, #2)"), Some(1.));
+    assert_eq!(calculate("MAX_STRICT(#0, #2)"), Some(3.));
+    assert_eq!(calculate("MIN_STRICT(#0, #1, #2)"), None);
+    assert_eq!(calculate("MAX_STRICT(#0, #1, #2)"), None);
+    assert_eq!(calculate("MAX(#0, #1, #2)"), Some(3.));
+
+    let fe = FormulaEngine::<f32>::try_new("MAX_STRICT(#0, 2 * #1)").unwrap();
+    let derivative = |values| fe.derivative(1).unwrap().calculate(values).unwrap();
+    assert_eq!(
+        derivative(HashMap::from([(0, Some(1.)), (1, Some(3.))])),
+        Some(2.)
+    );
+    assert_eq!(derivative(HashMap::from([(0, None), (1, Some(3.))])), None);
+}
+
+#[test]
+fn test_pos_neg() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(3.)), (1, Some(-2.)), (2, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("POS(#0)"), Some(3.));
+    assert_eq!(calculate("POS(#1)"), Some(0.));
+    assert_eq!(calculate("NEG(#0)"), Some(0.));
+    assert_eq!(calculate("NEG(#1)"), Some(-2.));
+    assert_eq!(calculate("POS(#2)"), None);
+    assert_eq!(calculate("NEG(#2)"), None);
+
+    let fe = FormulaEngine::<f32>::try_new("POS(2 * #0)").unwrap();
+    let derivative = |value| {
+        fe.derivative(0)
+            .unwrap()
+            .calculate(HashMap::from([(0, value)]))
+            .unwrap()
+    };
+    assert_eq!(derivative(Some(1.)), Some(2.));
+    assert_eq!(derivative(Some(-1.)), Some(0.));
+    assert_eq!(derivative(None), None);
+}
+
+#[test]
+fn test_hypot() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([
+                (0, Some(3.)),
+                (1, Some(4.)),
+                (2, Some(12.)),
+                (3, None),
+            ]))
+            .unwrap()
+    };
+    assert_eq!(calculate("HYPOT(#0, #1)"), Some(5.));
+    assert_eq!(calculate("HYPOT(#0, #1, #2)"), Some(13.));
+    assert_eq!(calculate("HYPOT(#0, #3)"), None);
+    assert!(FormulaEngine::<f32>::try_new("HYPOT(#0)").is_err());
+
+    let fe = FormulaEngine::<f32>::try_new("HYPOT(#0, 2 * #1)").unwrap();
+    assert_eq!(
+        fe.derivative(1)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(6.)), (1, Some(4.))]))
+            .unwrap(),
+        Some(1.6)
+    );
+}
+
+#[test]
+fn test_trigonometric_functions() {
+    let calculate = |formula| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(0.5)), (1, Some(2.)), (2, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("SIN(#0)"), Some(0.5f64.sin()));
+    assert_eq!(calculate("COS(#0)"), Some(0.5f64.cos()));
+    assert_eq!(calculate("TAN(#0)"), Some(0.5f64.tan()));
+    assert_eq!(calculate("ATAN2(#0, #1)"), Some(0.5f64.atan2(2.)));
+    assert_eq!(calculate("COS(PI)"), Some(-1.));
+    assert_eq!(calculate("SIN(#2)"), None);
+    assert_eq!(calculate("ATAN2(#0, #2)"), None);
+
+    let derivative = |formula, component| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .derivative(component)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(0.5)), (1, Some(2.))]))
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(derivative("SIN(2 * #0)", 0), 2. * 1f64.cos());
+    assert_eq!(derivative("COS(#0)", 0), -(0.5f64.sin()));
+    assert_eq!(derivative("TAN(#0)", 0), 1. / (0.5f64.cos() * 0.5f64.cos()));
+    assert_eq!(derivative("ATAN2(#0, #1)", 0), 2. / 4.25);
+    assert_eq!(derivative("ATAN2(#0, #1)", 1), -0.5 / 4.25);
+}
+
+#[test]
+fn test_lerp() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([
+                (0, Some(10.)),
+                (1, Some(20.)),
+                (2, Some(0.25)),
+                (3, None),
+            ]))
+            .unwrap()
+    };
+    assert_eq!(calculate("LERP(#0, #1, #2)"), Some(12.5));
+    assert_eq!(calculate("LERP(#0, #1, 0)"), Some(10.));
+    assert_eq!(calculate("LERP(#0, #1, 1)"), Some(20.));
+    assert_eq!(calculate("LERP(#3, #1, #2)"), None);
+    assert_eq!(calculate("LERP(#0, #3, #2)"), None);
+    assert_eq!(calculate("LERP(#0, #1, #3)"), None);
+
+    let fe = FormulaEngine::<f32>::try_new("LERP(#0, #1, #2)").unwrap();
+    let values = HashMap::from([(0, Some(10.)), (1, Some(20.)), (2, Some(0.25))]);
+    let derivative = |component| {
+        fe.derivative(component)
+            .unwrap()
+            .calculate(values.clone())
+            .unwrap()
+    };
+    assert_eq!(derivative(0), Some(0.75));
+    assert_eq!(derivative(1), Some(0.25));
+    assert_eq!(derivative(2), Some(10.));
+}
+
+#[test]
+fn test_curve() {
+    let calculate = |x: Option<f32>| {
+        FormulaEngine::<f32>::try_new("CURVE(#0, 0, 0.5, 1000, 0.75, 3000, 1)")
+            .unwrap()
+            .calculate(HashMap::from([(0, x)]))
+            .unwrap()
+    };
+    assert_eq!(calculate(Some(-5.)), Some(0.5));
+    assert_eq!(calculate(Some(500.)), Some(0.625));
+    assert_eq!(calculate(Some(1000.)), Some(0.75));
+    assert_eq!(calculate(Some(2000.)), Some(0.875));
+    assert_eq!(calculate(Some(5000.)), Some(1.));
+    assert_eq!(calculate(None), None);
+
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.)), (1, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("CURVE(#0, 0, 7)"), Some(7.));
+    assert_eq!(calculate("CURVE(#0, 0, 1, #1, 2)"), None);
+    assert_eq!(calculate("CURVE(#0, 2, 1, 0, 2)"), None);
+
+    assert_eq!(
+        FormulaEngine::<f32>::try_new("CURVE(#0, 0, 1, 2)")
+            .unwrap_err()
+            .to_string(),
+        "CURVE expects x followed by pairs of point coordinates"
+    );
+    assert!(FormulaEngine::<f32>::try_new("CURVE(#0, 0, 1)")
+        .unwrap()
+        .derivative(0)
+        .is_err());
+}
+
+#[test]
+fn test_poly() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.)), (1, Some(3.)), (2, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("POLY(#0, 5)"), Some(5.));
+    assert_eq!(calculate("POLY(#0, 1, 2, 3)"), Some(17.));
+    assert_eq!(calculate("POLY(#0, 1, #1, 0, 1)"), Some(15.));
+    assert_eq!(calculate("POLY(#2, 1, 2)"), None);
+    assert_eq!(calculate("POLY(#0, 1, #2)"), None);
+
+    let fe = FormulaEngine::<f32>::try_new("POLY(#0, 1, #1, 3)").unwrap();
+    let derivative = |component| {
+        fe.derivative(component)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.)), (1, Some(4.))]))
+            .unwrap()
+    };
+    assert_eq!(derivative(0), Some(16.));
+    assert_eq!(derivative(1), Some(2.));
+}
+
+#[test]
+fn test_unit_scaling() {
+    let calculate = |formula| {
+        FormulaEngine::<f64>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.5)), (1, Some(200.)), (2, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("KW(#0) + #1"), Some(1700.));
+    assert_eq!(calculate("MW(#0)"), Some(1_500_000.));
+    assert_eq!(calculate("KWH(#0)"), Some(1500.));
+    assert_eq!(calculate("kw(#2)"), None);
+
+    let fe = FormulaEngine::<f64>::try_new("MW(2 * #0)").unwrap();
+    assert_eq!(
+        fe.derivative(0)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.))]))
+            .unwrap(),
+        Some(2e6)
+    );
+}
+
+#[test]
+fn test_quantize() {
+    let calculate = |formula| {
+        FormulaEngine::<f32>::try_new(formula)
+            .unwrap()
+            .calculate(HashMap::from([
+                (0, Some(1234.)),
+                (1, Some(-1260.)),
+                (2, None),
+            ]))
+            .unwrap()
+    };
+    assert_eq!(calculate("QUANTIZE(#0, 100)"), Some(1200.));
+    assert_eq!(calculate("QUANTIZE(#1, 100)"), Some(-1300.));
+    assert_eq!(calculate("QUANTIZE(#0, 0.5)"), Some(1234.));
+    assert_eq!(calculate("QUANTIZE(#0, 0)"), None);
+    assert_eq!(calculate("QUANTIZE(#0, -100)"), None);
+    assert_eq!(calculate("QUANTIZE(#2, 100)"), None);
+
+    let fe = FormulaEngine::<f32>::try_new("QUANTIZE(#0, 100 * #1)").unwrap();
+    let derivative = |component| {
+        fe.derivative(component)
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1234.)), (1, Some(1.))]))
+            .unwrap()
+    };
+    assert_eq!(derivative(0), Some(0.));
+    assert_eq!(derivative(1), Some(1200.));
+}
+
+#[test]
+fn test_rounding_mode() {
+    use crate::{
+        EngineOptions,
+        RoundingMode::{HalfEven, HalfUp, TowardZero},
+    };
+
+    let calculate = |formula, rounding_mode| {
+        FormulaEngine::<f64>::try_new_with_options(
+            formula,
+            EngineOptions::default().with_rounding_mode(rounding_mode),
+        )
+        .unwrap()
+        .calculate(HashMap::from([
+            (0, Some(2.5)),
+            (1, Some(-2.5)),
+            (2, Some(3.5)),
+        ]))
+        .unwrap()
+    };
+    assert_eq!(calculate("ROUND(#0)", HalfUp), Some(3.));
+    assert_eq!(calculate("ROUND(#1)", HalfUp), Some(-3.));
+    assert_eq!(calculate("ROUND(#0)", HalfEven), Some(2.));
+    assert_eq!(calculate("ROUND(#1)", HalfEven), Some(-2.));
+    assert_eq!(calculate("ROUND(#2)", HalfEven), Some(4.));
+    assert_eq!(calculate("ROUND(2.6)", HalfEven), Some(3.));
+    assert_eq!(calculate("ROUND(#2)", TowardZero), Some(3.));
+    assert_eq!(calculate("ROUND(#1)", TowardZero), Some(-2.));
+    assert_eq!(calculate("ROUND(0.125, 2)", HalfEven), Some(0.12));
+    assert_eq!(calculate("QUANTIZE(250, 100)", HalfUp), Some(300.));
+    assert_eq!(calculate("QUANTIZE(250, 100)", HalfEven), Some(200.));
+    assert_eq!(calculate("QUANTIZE(-290, 100)", TowardZero), Some(-200.));
+}
+
+#[test]
+fn test_custom_functions() {
+    use crate::{EngineOptions, FunctionRegistry};
+
+    let functions = FunctionRegistry::new()
+        .register("AVG", |values: &[Option<f32>]| {
+            let values: Vec<f32> = values.iter().flatten().copied().collect();
+            (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+        })
+        .register("double", |values: &[Option<f32>]| {
+            Some(values.first()?.as_ref()? * 2.)
+        })
+        .register("NOTCH", |values: &[Option<f32>]| values.first().copied()?);
+    let calculate = |formula| {
+        FormulaEngine::try_new_with_functions(formula, EngineOptions::default(), functions.clone())
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.)), (1, None), (2, Some(5.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate("AVG(#0, #1, #2)"), Some(3.));
+    assert_eq!(calculate("AVG(#*) + double(#2)"), Some(13.));
+    assert_eq!(calculate("AVG(#0..#1)"), Some(1.));
+    assert_eq!(calculate("double(#1)"), None);
+    assert_eq!(calculate("MIN(AVG(#0, #2), 2)"), Some(2.));
+    assert_eq!(calculate("NOTCH(#2) OR 0"), Some(1.));
+
+    let error = |formula| {
+        FormulaEngine::try_new_with_functions(formula, EngineOptions::default(), functions.clone())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(error("avg(#0)"), "Unknown function: avg");
+    assert!(FormulaEngine::<f32>::try_new("AVG(#0)").is_err());
+    assert!(FormulaEngine::try_new_with_functions(
+        "double (#0)",
+        EngineOptions::default(),
+        functions.clone()
+    )
+    .is_err());
+
+    let error = |name: &str| {
+        let functions = FunctionRegistry::new().register(name, |_: &[Option<f32>]| Some(0.));
+        FormulaEngine::try_new_with_functions("1", EngineOptions::default(), functions)
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("max"),
+        "Function name max is taken by a built-in function or keyword"
+    );
+    assert_eq!(
+        error("Rolling_Avg"),
+        "Function name Rolling_Avg is taken by a built-in function or keyword"
+    );
+    assert_eq!(
+        error("NOT"),
+        "Function name NOT is taken by a built-in function or keyword"
+    );
+    assert_eq!(error("my func"), "Invalid function name: my func");
+    assert_eq!(error("2x"), "Invalid function name: 2x");
+    assert_eq!(error(""), "Invalid function name: ");
+}
+
+#[test]
+fn test_custom_operators() {
+    use crate::{Associativity, EngineOptions, FunctionRegistry, Precedence};
+
+    let functions = FunctionRegistry::new()
+        .register_operator(
+            "~-",
+            Precedence::Additive,
+            Associativity::Left,
+            |a: Option<f32>, b: Option<f32>| Some((a? - b?).max(0.)),
+        )
+        .register_operator("⊕", Precedence::Power, Associativity::Right, |a, b| {
+            Some(a? * 10. + b?)
+        });
+    let calculate = |formula| {
+        FormulaEngine::try_new_with_functions(formula, EngineOptions::default(), functions.clone())
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(2.)), (1, Some(5.)), (2, None)]))
+            .unwrap()
+    };
+    assert_eq!(calculate("#0 ~- #1"), Some(0.));
+    assert_eq!(calculate("#1 ~- #0"), Some(3.));
+    assert_eq!(calculate("#1 ~- #0 * 2"), Some(1.));
+    assert_eq!(calculate("10 ~- 3 + 2"), Some(9.));
+    assert_eq!(calculate("3 ~- 10 + 2"), Some(2.));
+    assert_eq!(calculate("#0 ~- #2"), None);
+    assert_eq!(calculate("1 ⊕ 2 ⊕ 3"), Some(33.));
+    assert_eq!(calculate("-1 ⊕ 2"), Some(-12.));
+
+    let error = |formula| {
+        FormulaEngine::try_new_with_functions(formula, EngineOptions::default(), functions.clone())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(error("#0 ~~ #1"), "Unknown operator: ~~");
+    assert!(FormulaEngine::<f32>::try_new("#0 ~- #1").is_err());
+
+    let invalid = FunctionRegistry::new().register_operator(
+        "-~",
+        Precedence::Additive,
+        Associativity::Left,
+        |a: Option<f32>, _| a,
+    );
+    assert_eq!(
+        FormulaEngine::try_new_with_functions("#0", EngineOptions::default(), invalid)
+            .unwrap_err()
+            .to_string(),
+        "Invalid operator symbol: -~"
+    );
+}
+
+#[test]
+fn test_builder() {
+    use crate::{EngineOptions, Expr};
+
+    let calculate = |expr: Expr<f32>| {
+        FormulaEngine::try_from_expr(expr, EngineOptions::default())
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.)), (1, None), (2, Some(5.))]))
+            .unwrap()
+    };
+    assert_eq!(
+        calculate(Expr::component(2) + Expr::value(2.) * Expr::component(0)),
+        Some(7.)
+    );
+    assert_eq!(
+        calculate(Expr::max([Expr::component(0), Expr::component(2)]) % Expr::value(3.)),
+        Some(2.)
+    );
+    assert_eq!(
+        calculate(Expr::coalesce([Expr::component(1), -Expr::component(0)])),
+        Some(-1.)
+    );
+    assert_eq!(
+        calculate(Expr::if_else(
+            Expr::component(2).gt(Expr::value(4.)),
+            Expr::value(2.).pow(Expr::value(3.)),
+            Expr::none(),
+        )),
+        Some(8.)
+    );
+    assert_eq!(calculate(!Expr::component(0).and(Expr::component(1))), None);
+    assert_eq!(calculate(Expr::component(1) - Expr::component(0)), None);
+
+    let fe = FormulaEngine::try_from_expr(
+        Expr::sum([Expr::component(3), Expr::named("pv")]) / Expr::value(2.),
+        EngineOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(fe.components(), &HashSet::from([3]));
+    assert_eq!(fe.names(), &HashSet::from(["pv".to_string()]));
+
+    assert!(
+        FormulaEngine::try_from_expr(Expr::min([Expr::value(1.)]), EngineOptions::default())
+            .is_err()
+    );
+}
+
+#[cfg(feature = "macros")]
+#[test]
+fn test_formula_macro() {
+    use crate::{
+        formula,
+        parser::{FormulaParser, Rule},
+        EngineOptions, Expr, FunctionRegistry,
+    };
+    use pest::Parser;
+
+    let calculate = |expr: Expr<f64>| {
+        FormulaEngine::try_from_expr(expr, EngineOptions::default())
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.)), (1, None), (2, Some(5.))]))
+            .unwrap()
+    };
+    assert_eq!(calculate(formula!("MAX(#0, #2) + 3")), Some(8.));
+    assert_eq!(calculate(formula!("-2 ^ 2 * 3 - 1_000 % 7")), Some(-18.));
+    assert_eq!(calculate(formula!("COALESCE(#1, #2..#0) * 2")), Some(10.));
+    assert_eq!(calculate(formula!("LET x = #2 - 1 IN x * x")), Some(16.));
+    assert_eq!(
+        calculate(formula!("if(#0 > 0 AND NOT #1 == 1, PI, E)")),
+        None
+    );
+    assert_eq!(calculate(formula!("IF(#0 > 0, SUM(#*), 0)")), Some(6.));
+    assert_eq!(calculate(formula!("MAX(#*)")), Some(5.));
+    assert_eq!(calculate(formula!("#0 * 1.5m + 250ms")), Some(90.25));
+
+    let temporal: [(&str, Expr<f64>); 10] = [
+        ("ROLLING_AVG(#0, 5m)", formula!("ROLLING_AVG(#0, 5m)")),
+        ("ROLLING_MIN(#0, 30s)", formula!("ROLLING_MIN(#0, 30s)")),
+        ("ROLLING_MAX(#0, 1h)", formula!("ROLLING_MAX(#0, 1h)")),
+        ("DERIV(#0)", formula!("DERIV(#0)")),
+        (
+            "INTEGRATE(#0, HOUR() == 0)",
+            formula!("INTEGRATE(#0, HOUR() == 0)"),
+        ),
+        ("LAG(#0, 2)", formula!("LAG(#0, 2)")),
+        ("DELTA(#0)", formula!("DELTA(#0)")),
+        ("DELTA_WRAP(#0, 65536)", formula!("DELTA_WRAP(#0, 65536)")),
+        ("RAMP_LIMIT(#0, 100)", formula!("RAMP_LIMIT(#0, 100)")),
+        ("TW_AVG(#0, 15m)", formula!("TW_AVG(#0, 15m)")),
+    ];
+    for (formula, expanded) in temporal {
+        let pairs = FormulaParser::parse(Rule::formula, formula).unwrap();
+        let parsed: Expr<f64> = Expr::parse(pairs, &FunctionRegistry::default()).unwrap();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", expanded));
+    }
+
+    let formula = "LET y = -#0 ^ 2 IN COALESCE(#1, #2..#0, y) * $pv / #\"m-1\" <= @pv || !y";
+    let pairs = FormulaParser::parse(Rule::formula, formula).unwrap();
+    let parsed: Expr<f64> = Expr::parse(pairs, &FunctionRegistry::default()).unwrap();
+    let expanded: Expr<f64> =
+        formula!("LET y = -#0 ^ 2 IN COALESCE(#1, #2..#0, y) * $pv / #\"m-1\" <= @pv || !y");
+    assert_eq!(format!("{:?}", parsed), format!("{:?}", expanded));
+}
+
+#[test]
+fn test_display() {
+    use crate::{EngineOptions, Expr};
+
+    let display = |formula| FormulaEngine::<f64>::try_new(formula).unwrap().to_string();
+    assert_eq!(display("#0+#1*2"), "#0 + #1 * 2");
+    assert_eq!(display("(#0 + #1) * 2"), "(#0 + #1) * 2");
+    assert_eq!(display("#0 - (#1 - #2) - #3"), "#0 - (#1 - #2) - #3");
+    assert_eq!(
+        display("(2 ^ 3) ^ 2 + 2 ^ (3 ^ 2)"),
+        "(2 ^ 3) ^ 2 + 2 ^ 3 ^ 2"
+    );
+    assert_eq!(display("-(#0 ^ 2) + (-#0) ^ 2"), "-#0 ^ 2 + (-#0) ^ 2");
+    assert_eq!(
+        display("!(#0 < 1 && #1) || #2"),
+        "NOT (#0 < 1 AND #1) OR #2"
+    );
+    assert_eq!(display("#0 + (NOT #1) == 0"), "#0 + (NOT #1) == 0");
+    assert_eq!(display("min(#0..#2, #*) // comment"), "MIN(#0, #1, #2, #*)");
+    assert_eq!(
+        display("let x = $pv in x + #\"m 1\" + (LET y = 2 IN y)"),
+        "LET x = $pv IN x + #\"m 1\" + (LET y = 2 IN y)"
+    );
+    assert_eq!(
+        display("IF(HOUR() < 6, 1_000.5, PI)"),
+        "IF(HOUR() < 6, 1000.5, 3.141592653589793)"
+    );
+
+    let display = |expr: Expr<f64>| expr.to_string();
+    assert_eq!(display(Expr::value(-2.).pow(Expr::value(2.))), "(-2) ^ 2");
+    assert_eq!(display(Expr::component(0) * Expr::value(-2.)), "#0 * -2");
+    assert_eq!(
+        display(Expr::coalesce([Expr::none(), Expr::value(f64::INFINITY)])),
+        "COALESCE(NULLIF(0, 0), (1 / 0))"
+    );
+    assert_eq!(
+        display(Expr::value(f64::NEG_INFINITY) + Expr::value(f64::NAN)),
+        "(-1 / 0) + (0 / 0)"
+    );
+
+    // Rendered formulas parse to the same expression, and derivatives with
+    // their piecewise selections render to equivalent formulas.
+    let formulas = [
+        "-#0 ^ -#1 ^ 2 % 3 / (#2 * #3) - -(#1 - #0)",
+        "NOT NOT #0 == (#1 >= 2) AND (#2 OR #3) != 1",
+        "COALESCE(#0 * #1, MIN(#1, 2 * #2), MAX_STRICT(#3, #0 ^ 2)) + SUM(#*)",
+        "CASE(#0 > #1, #0 * #0, #1 > 0, NULLIF(#1 * 3, 6), -#2)",
+    ];
+    let values = [
+        HashMap::from([(0, Some(2.)), (1, Some(3.)), (2, Some(-1.)), (3, Some(0.5))]),
+        HashMap::from([(0, None), (1, Some(2.)), (2, Some(4.)), (3, None)]),
+        HashMap::from([(0, Some(4.)), (1, Some(1.)), (2, None), (3, Some(1.))]),
+    ];
+    for formula in formulas {
+        let fe = FormulaEngine::<f64>::try_new(formula).unwrap();
+        let reparsed = FormulaEngine::<f64>::try_new(&fe.to_string()).unwrap();
+        assert_eq!(
+            format!("{:?}", fe.expr()),
+            format!("{:?}", reparsed.expr()),
+            "{}",
+            fe
+        );
+        for component in 0..4 {
+            let Ok(derivative) = fe.derivative(component) else {
+                continue;
+            };
+            let reparsed = FormulaEngine::try_new_with_options(
+                &derivative.to_string(),
+                EngineOptions::default(),
+            )
+            .unwrap();
+            for values in &values {
+                let expected = derivative.calculate(values.clone()).unwrap();
+                let actual = reparsed.calculate(values.clone()).unwrap();
+                assert!(
+                    expected == actual
+                        || expected
+                            .zip(actual)
+                            .is_some_and(|(a, b)| a.is_nan() && b.is_nan()),
+                    "{}: {:?} != {:?}",
+                    derivative,
+                    expected,
+                    actual
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_pretty() {
+    use crate::PrettyOptions;
+
+    let fe = FormulaEngine::<f64>::try_new(
+        "COALESCE(MIN(#10 + #11, #12 * 2), MAX(#20, #21 - #22, 0), $fallback) * -1 + 5",
+    )
+    .unwrap();
+    assert_eq!(
+        fe.pretty(&PrettyOptions::new().with_max_width(40)),
+        "COALESCE(
+    MIN(#10 + #11, #12 * 2),
+    MAX(#20, #21 - #22, 0),
+    $fallback
+) * -1 + 5"
+    );
+    assert_eq!(
+        fe.pretty(&PrettyOptions::new().with_max_width(20).with_indent(2)),
+        "COALESCE(
+  MIN(
+    #10 + #11,
+    #12 * 2
+  ),
+  MAX(
+    #20,
+    #21 - #22,
+    0
+  ),
+  $fallback
+) * -1 + 5"
+    );
+    assert_eq!(
+        fe.pretty(&PrettyOptions::new().with_spaces(false)),
+        "COALESCE(MIN(#10+#11,#12*2),MAX(#20,#21-#22,0),$fallback)*-1+5"
+    );
+    assert_eq!(fe.pretty(&PrettyOptions::new()), fe.to_string());
+
+    let fe = FormulaEngine::<f64>::try_new("LET x = #0 IN x AND NOT x - -1 > 2").unwrap();
+    let compact = fe.pretty(&PrettyOptions::new().with_spaces(false).with_max_width(5));
+    assert_eq!(compact, "LET x=#0 IN x AND NOT x--1>2");
+    let reparsed = FormulaEngine::<f64>::try_new(&compact).unwrap();
+    assert_eq!(format!("{:?}", fe.expr()), format!("{:?}", reparsed.expr()));
+}
+
+#[test]
+fn test_visitor() {
+    use crate::{walk_expr, Expr, Function, Visitor};
+
+    #[derive(Default)]
+    struct Placeholders(Vec<String>);
+
+    impl<T> Visitor<T> for Placeholders {
+        fn visit_expr(&mut self, expr: &Expr<T>) {
+            match expr {
+                Expr::Component(id) => self.0.push(format!("#{}", id)),
+                Expr::Named(name) => self.0.push(format!("${}", name)),
+                _ => walk_expr(self, expr),
+            }
+        }
+    }
+
+    let fe =
+        FormulaEngine::<f64>::try_new("LET x = #2 IN MAX(#0, x * $pv) - COALESCE(#1, 0)").unwrap();
+    let mut placeholders = Placeholders::default();
+    placeholders.visit_expr(fe.expr());
+    assert_eq!(placeholders.0, ["#2", "#0", "$pv", "#1"]);
+
+    // A cost estimate, counting function calls as twice as expensive as
+    // operators.
+    let cost = fe.expr().fold(&mut |expr, children: Vec<usize>| {
+        let own = match expr {
+            Expr::Function {
+                function: Function::Max | Function::Coalesce,
+                ..
+            } => 2,
+            Expr::Op { .. } => 1,
+            _ => 0,
+        };
+        own + children.iter().sum::<usize>()
+    });
+    assert_eq!(cost, 6);
+}
+
+#[test]
+fn test_equality() {
+    use crate::Expr;
+
+    let parse = |formula| FormulaEngine::<f64>::try_new(formula).unwrap();
+    assert_eq!(parse("#0 + 2 * #1"), parse("#0+(2*#1) // comment"));
+    assert_eq!(parse("MIN(#0..#2)"), parse("min(#0, #1, #2)"));
+    let fe = parse("IF(#0 > 0, #1, NOW())");
+    assert_eq!(fe.clone(), fe);
+    assert_ne!(parse("(#0 + 2) * #1"), parse("#0 + 2 * #1"));
+    assert_ne!(parse("#0 - #1"), parse("#1 - #0"));
+    assert_ne!(parse("MIN(#0, #1)"), parse("MAX(#0, #1)"));
+    assert_ne!(parse("$a"), parse("$b"));
+
+    let nan = Expr::value(f64::NAN) + Expr::component(0);
+    assert_eq!(nan, nan.clone());
+    assert_eq!(Expr::value(0.), Expr::value(-0.));
+    assert_ne!(Expr::value(1.), Expr::none());
+
+    let formulas = HashSet::from([
+        parse("#0 + #1"),
+        parse("#0+#1"),
+        parse("#1 + #0"),
+        parse("COALESCE(#0, 0)").derivative(0).unwrap(),
+    ]);
+    assert_eq!(formulas.len(), 3);
+    assert!(formulas.contains(&parse("(#0) + (#1)")));
+
+    let exprs = HashSet::from([Expr::value(0.), Expr::value(-0.), nan.clone(), nan]);
+    assert_eq!(exprs.len(), 2);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json() {
+    use crate::{
+        parser::{FormulaParser, Rule},
+        Associativity, Expr, FunctionRegistry, Precedence,
+    };
+    use pest::Parser;
+    use serde_json::json;
+
+    let fe = FormulaEngine::<f64>::try_new("MAX(#0, 0) * 2").unwrap();
+    assert_eq!(
+        fe.expr().to_json(),
+        json!({
+            "version": 1,
+            "expr": {
+                "type": "op",
+                "op": "*",
+                "lhs": {
+                    "type": "function",
+                    "name": "MAX",
+                    "args": [{"type": "component", "id": 0}, {"type": "value", "value": 0.0}]
+                },
+                "rhs": {"type": "value", "value": 2.0}
+            }
+        })
+    );
+
+    let formulas = [
+        "LET x = -#0 IN IF(NOT x > 1 OR $pv, x ^ 2 % 3, SUM(#*, #\"m-1\"))",
+        "COALESCE(#1, MIN(#2..#4)) - TOU(\"peak\") * HOUR() / 0",
+        "avg(#0, @pv) ~- 2",
+        "ROLLING_MAX(#0, 1m) - ROLLING_AVG(#1, 0.5)",
+    ];
+    for formula in formulas {
+        let pairs = FormulaParser::parse(Rule::formula, formula).unwrap();
+        let functions = FunctionRegistry::new().register_operator(
+            "~-",
+            Precedence::Additive,
+            Associativity::Left,
+            |a: Option<f64>, b| Some(a? - b?),
+        );
+        let expr = Expr::parse(pairs, &functions).unwrap();
+        let json = expr.to_json().to_string();
+        assert_eq!(
+            Expr::from_json(&serde_json::from_str(&json).unwrap()).unwrap(),
+            expr
+        );
+    }
+    let derivative = FormulaEngine::<f64>::try_new("MIN(#0 * #1, 1)")
+        .unwrap()
+        .derivative(0)
+        .unwrap();
+    assert_eq!(
+        Expr::from_json(&derivative.expr().to_json()).unwrap(),
+        *derivative.expr()
+    );
+    let special = Expr::coalesce([
+        Expr::none(),
+        Expr::value(f64::NAN),
+        Expr::value(f64::INFINITY),
+    ]);
+    assert_eq!(Expr::from_json(&special.to_json()).unwrap(), special);
+
+    let error = |json| Expr::<f64>::from_json(&json).unwrap_err().to_string();
+    assert_eq!(
+        error(json!({"version": 2, "expr": {"type": "wildcard"}})),
+        "Unsupported formula JSON version: 2"
+    );
+    assert_eq!(
+        error(json!({"version": 1, "expr": {"type": "function", "name": "AVG", "args": []}})),
+        "Unknown function: AVG"
+    );
+    assert_eq!(
+        error(json!({"version": 1, "expr": {"type": "neg"}})),
+        "Invalid formula JSON {\"type\":\"neg\"}: missing \"operand\""
+    );
+}
+
+#[cfg(feature = "proto")]
+#[test]
+fn test_proto() {
+    use crate::{
+        parser::{FormulaParser, Rule},
+        proto, Associativity, Expr, FunctionRegistry, Precedence,
+    };
+    use pest::Parser;
+    use prost::Message;
+
+    let round_trip = |expr: &Expr<f64>| {
+        let bytes = proto::Formula::from(expr).encode_to_vec();
+        Expr::<f64>::try_from(proto::Formula::decode(bytes.as_slice()).unwrap()).unwrap()
+    };
+
+    let formulas = [
+        "LET x = -#0 IN IF(NOT x > 1 OR $pv, x ^ 2 % 3, SUM(#*, #\"m-1\"))",
+        "COALESCE(#1, MIN(#2..#4)) - TOU(\"peak\") * DAYOFWEEK() / 0",
+        "avg(#0, @pv) ~- 2",
+        "ROLLING_MAX(#0, 1m) - ROLLING_AVG(#1, 0.5)",
+    ];
+    for formula in formulas {
+        let pairs = FormulaParser::parse(Rule::formula, formula).unwrap();
+        let functions = FunctionRegistry::new().register_operator(
+            "~-",
+            Precedence::Additive,
+            Associativity::Left,
+            |a: Option<f64>, b| Some(a? - b?),
+        );
+        let expr = Expr::parse(pairs, &functions).unwrap();
+        assert_eq!(round_trip(&expr), expr);
+    }
+    let derivative = FormulaEngine::<f64>::try_new("MIN(#0 * #1, 1)")
+        .unwrap()
+        .derivative(0)
+        .unwrap();
+    assert_eq!(round_trip(derivative.expr()), *derivative.expr());
+    let special = Expr::coalesce([
+        Expr::none(),
+        Expr::value(f64::NAN),
+        Expr::value(f64::NEG_INFINITY),
+    ]);
+    assert_eq!(round_trip(&special), special);
+
+    let error = |expr: proto::Expr| Expr::<f64>::try_from(expr).unwrap_err().to_string();
+    let call = |name: &str| {
+        proto::expr::Kind::Function(proto::FunctionCall {
+            name: name.to_string(),
+            args: Vec::new(),
+        })
+    };
+    assert_eq!(
+        error(proto::Expr {
+            kind: Some(call("AVG"))
+        }),
+        "Unknown function: AVG"
+    );
+    assert_eq!(
+        error(proto::Expr {
+            kind: Some(proto::expr::Kind::Op(Box::new(proto::BinaryOp {
+                op: proto::Operator::Unspecified as i32,
+                lhs: None,
+                rhs: None,
+            })))
+        }),
+        "Unknown operator: 0"
+    );
+    assert_eq!(
+        error(proto::Expr {
+            kind: Some(proto::expr::Kind::Neg(Box::default()))
+        }),
+        "Missing expression in formula message"
+    );
+}
+
+#[test]
+fn test_to_sql() {
+    use crate::{EngineOptions, FunctionRegistry, RoundingMode, TouWindow};
+
+    let sql = |formula| FormulaEngine::<f64>::try_new(formula).unwrap().to_sql();
+    assert_eq!(
+        sql("COALESCE(#0, $pv, 0) - MIN(#1, #\"m-1\") * 2").unwrap(),
+        "(COALESCE(\"0\", \"pv\", 0::float8) - (LEAST(\"1\", \"m-1\") * 2::float8))"
+    );
+    assert_eq!(
+        sql("-1.5 * #0 ^ 2").unwrap(),
+        "((-1.5::float8) * POWER(\"0\", 2::float8))"
+    );
+    assert_eq!(
+        sql("IF(#0 > 1 AND NOT IS_NONE(#1), #1, 0)").unwrap(),
+        "CASE ((\"0\" > 1::float8) AND (NOT (\"1\" IS NULL))) \
+         WHEN true THEN \"1\" WHEN false THEN 0::float8 END"
+    );
+    assert_eq!(
+        sql("CASE(#0 > 1, 1, #1 > 1, 2)").unwrap(),
+        "CASE (\"0\" > 1::float8) WHEN true THEN 1::float8 \
+         WHEN false THEN CASE (\"1\" > 1::float8) WHEN true THEN 2::float8 END END"
+    );
+
+    // Repeated subexpressions are bound once, as columns that the
+    // placeholders can't shadow.
+    assert_eq!(
+        sql("LET x = #0 * 2 IN x == x").unwrap(),
+        "(SELECT (\"x\" = \"x\")::int::float8 FROM (SELECT (\"0\" * 2::float8) AS \"x\") AS bound)"
+    );
+    assert_eq!(
+        sql("LET x = #0 * 2 IN x + $x").unwrap(),
+        "(SELECT (\"x_\" + \"x\") FROM (SELECT (\"0\" * 2::float8) AS \"x_\") AS bound)"
+    );
+    assert_eq!(sql("LET x = #0 IN x * x").unwrap(), "(\"0\" * \"0\")");
+    assert_eq!(
+        sql("MIN_STRICT(#0, $a0 + 1)").unwrap(),
+        "(SELECT CASE WHEN a0 IS NULL OR a1 IS NULL THEN NULL ELSE LEAST(a0, a1) END \
+         FROM (SELECT \"0\" AS a0, (\"a0\" + 1::float8) AS a1) AS bound)"
+    );
+    let mut nested = "#0".to_string();
+    for i in 1..=16 {
+        nested = format!("CURVE(MIN_STRICT({}, #{}) + 1, 0, 0, 1, 1)", nested, i);
+    }
+    assert!(sql(&nested).unwrap().len() < 10_000);
+    assert_eq!(
+        sql("SUM(#0, #1)").unwrap(),
+        "CASE WHEN COALESCE(\"0\", \"1\") IS NULL THEN NULL \
+         ELSE (COALESCE(\"0\", 0) + COALESCE(\"1\", 0)) END"
+    );
+    assert_eq!(
+        sql("COALESCE(0 / 0, 1 / 0)").unwrap(),
+        "COALESCE((0::float8 / 0::float8), (1::float8 / 0::float8))"
+    );
+
+    let options = EngineOptions::default().with_rounding_mode(RoundingMode::HalfEven);
+    let fe = FormulaEngine::<f64>::try_new_with_options("ROUND(#0)", options).unwrap();
+    assert_eq!(fe.to_sql().unwrap(), "ROUND(\"0\")");
+    assert_eq!(
+        sql("ROUND(#0)").unwrap(),
+        "(SIGN(\"0\") * FLOOR(ABS(\"0\") + 0.5))"
+    );
+
+    // Derivatives select their branches like the engine.
+    let derivative = FormulaEngine::<f64>::try_new("MAX(#0, #1)")
+        .unwrap()
+        .derivative(0)
+        .unwrap();
+    assert!(derivative.to_sql().unwrap().starts_with("CASE ("));
+
+    assert_eq!(
+        sql("SUM(#*)").unwrap_err().to_string(),
+        "#* can't be translated to SQL"
+    );
+    assert_eq!(
+        sql("HOUR() * #0").unwrap_err().to_string(),
+        "HOUR() can't be translated to SQL"
+    );
+    let options = EngineOptions::default()
+        .with_tou_window("peak", TouWindow::try_new((8, 0), (20, 0)).unwrap());
+    let fe = FormulaEngine::<f64>::try_new_with_options("TOU(\"peak\")", options).unwrap();
+    assert_eq!(
+        fe.to_sql().unwrap_err().to_string(),
+        "TOU(\"peak\") can't be translated to SQL"
+    );
+    let functions = FunctionRegistry::new().register("avg", |args| args[0]);
+    let fe = FormulaEngine::<f64>::try_new_with_functions(
+        "avg(#0)",
+        EngineOptions::default(),
+        functions,
+    )
+    .unwrap();
+    assert_eq!(
+        fe.to_sql().unwrap_err().to_string(),
+        "Custom function avg can't be translated to SQL"
+    );
+}
+
+#[test]
+fn test_to_python() {
+    use crate::{EngineOptions, FunctionRegistry, RoundingMode};
+
+    let python = |formula| FormulaEngine::<f64>::try_new(formula).unwrap().to_python();
+    assert_eq!(
+        python("-#0 * 2").unwrap(),
+        "import math
+
+
+def _negate(x):
+    return None if x is None else -x
+
+
+def _mul(a, b):
+    return None if a is None or b is None else a * b
+
+
+def formula(values):
+    return _mul(_negate(values[0]), 2.0)"
+    );
+
+    let body = |formula| python(formula).unwrap().lines().last().unwrap().to_string();
+    assert_eq!(
+        body("LET x = $pv IN CASE(x > 0, x, #\"m-1\", 0)"),
+        "    return (lambda var_x: _if(_gt(var_x, 0.0), lambda: var_x, \
+         lambda: _if(values[\"m-1\"], lambda: 0.0, lambda: None)))(values[\"pv\"])"
+    );
+    assert_eq!(
+        body("COALESCE(SUM(#*), 0 / 0, -1 / 0, 0.0000015)"),
+        "    return _coalesce(_sum(*(values[key] for key in sorted(values))), \
+         _div(0.0, 0.0), _div(_negate(1.0), 0.0), 1.5e-6)"
+    );
+
+    // Helpers are included with the helpers they call.
+    let source = python("MAX_STRICT(#0, #1)").unwrap();
+    assert!(source.contains("def _max(") && source.contains("def _max_strict("));
+    let options = EngineOptions::default().with_rounding_mode(RoundingMode::TowardZero);
+    let fe = FormulaEngine::<f64>::try_new_with_options("QUANTIZE(#0, 5)", options).unwrap();
+    assert!(fe.to_python().unwrap().contains("float(math.trunc(x))"));
+
+    assert_eq!(
+        python("NOW() - #0").unwrap_err().to_string(),
+        "NOW() can't be translated to Python"
+    );
+    let functions = FunctionRegistry::new().register("avg", |args| args[0]);
+    let fe = FormulaEngine::<f64>::try_new_with_functions(
+        "avg(#0)",
+        EngineOptions::default(),
+        functions,
+    )
+    .unwrap();
+    assert_eq!(
+        fe.to_python().unwrap_err().to_string(),
+        "Custom function avg can't be translated to Python"
+    );
+}
+
+#[test]
+fn test_categories() {
+    use crate::EngineOptions;
+
+    let categories = HashMap::from([
+        ("battery_power".to_string(), vec![1, 2]),
+        ("pv_power".to_string(), vec![3]),
+        ("chp_power".to_string(), vec![]),
+    ]);
+    let fe = |formula| {
+        FormulaEngine::<f64>::try_new_with_categories(
+            formula,
+            &categories,
+            EngineOptions::default(),
+        )
+    };
+
+    let grid = fe("#0 - battery_power - pv_power - chp_power").unwrap();
+    assert_eq!(grid.to_string(), "#0 - SUM(#1, #2) - #3 - 0");
+    assert_eq!(grid.components(), &HashSet::from([0, 1, 2, 3]));
+    let values = HashMap::from([(0, Some(10.0)), (1, Some(1.0)), (2, None), (3, Some(2.0))]);
+    assert_eq!(grid.calculate(values).unwrap(), Some(7.0));
+
+    // LET variables shadow categories.
+    assert_eq!(
+        fe("LET pv_power = MAX(pv_power, 0) IN pv_power * 2")
+            .unwrap()
+            .to_string(),
+        "LET pv_power = MAX(#3, 0) IN pv_power * 2"
+    );
+
+    assert_eq!(
+        fe("ev_power + pv_power").unwrap_err().to_string(),
+        "Unknown category: ev_power"
+    );
+}
+
+#[test]
+fn test_formula_registry() {
+    use crate::{Formula64, FormulaRegistry};
+    use std::time::Duration;
+
+    let mut formulas = FormulaRegistry::new();
+    assert!(formulas.is_empty());
+    formulas
+        .register("grid_power", Formula64::try_new("#0 + #1").unwrap())
+        .unwrap();
+    formulas
+        .register("pv_power", Formula64::try_new("MIN(#2, 0)").unwrap())
+        .unwrap();
+    assert_eq!(formulas.len(), 2);
+    assert!(formulas.contains("pv_power"));
+    let mut names: Vec<_> = formulas.names().collect();
+    names.sort();
+    assert_eq!(names, ["grid_power", "pv_power"]);
+    assert_eq!(
+        formulas
+            .calculate("pv_power", HashMap::from([(2, Some(-3.0))]))
+            .unwrap(),
+        Some(-3.0)
+    );
+    // Any value provider can be used, e.g. a slice or a closure.
+    let values = [None, Some(5.0), Some(-1.0)];
+    assert_eq!(
+        formulas.calculate("pv_power", &values[..]).unwrap(),
+        Some(-1.0)
+    );
+    assert_eq!(
+        formulas
+            .calculate("grid_power", |id: u64| Some(Some(id as f64)))
+            .unwrap(),
+        Some(1.0)
+    );
+
+    assert_eq!(
+        formulas
+            .register("grid_power", Formula64::try_new("#0").unwrap())
+            .unwrap_err()
+            .to_string(),
+        "Formula already registered: grid_power"
+    );
+    assert_eq!(
+        formulas
+            .register("grid power", Formula64::try_new("#0").unwrap())
+            .unwrap_err()
+            .to_string(),
+        "Invalid formula name: grid power"
+    );
+    assert_eq!(
+        formulas.get("ev_power").unwrap_err().to_string(),
+        "Unknown formula: ev_power"
+    );
+
+    formulas
+        .set_max_age("grid_power", Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(
+        formulas.max_age("grid_power"),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(
+        formulas
+            .set_max_age("ev_power", Duration::from_secs(10))
+            .unwrap_err()
+            .to_string(),
+        "Unknown formula: ev_power"
+    );
+
+    assert!(formulas.remove("grid_power").is_some());
+    assert!(formulas.remove("grid_power").is_none());
+    formulas
+        .register("grid_power", Formula64::try_new("#0").unwrap())
+        .unwrap();
+    assert_eq!(formulas.max_age("grid_power"), None);
+}
+
+#[test]
+fn test_formula_references() {
+    use crate::{Formula64, FormulaRegistry};
+
+    let mut formulas = FormulaRegistry::new();
+    // Formulas can reference formulas registered later.
+    formulas
+        .register("consumption", Formula64::try_new("@grid - @pv").unwrap())
+        .unwrap();
+    formulas
+        .register("grid", Formula64::try_new("#0 + #1").unwrap())
+        .unwrap();
+    formulas
+        .register("pv", Formula64::try_new("MIN(#2, 0)").unwrap())
+        .unwrap();
+    formulas
+        .register("share", Formula64::try_new("@pv / @consumption").unwrap())
+        .unwrap();
+    assert_eq!(
+        formulas.get("share").unwrap().references(),
+        &HashSet::from(["pv".to_string(), "consumption".to_string()])
+    );
+    assert_eq!(
+        formulas.get("share").unwrap().to_string(),
+        "@pv / @consumption"
+    );
+
+    let values = HashMap::from([(0, Some(4.0)), (1, Some(2.0)), (2, Some(-2.0))]);
+    assert_eq!(
+        formulas.calculate("consumption", values.clone()).unwrap(),
+        Some(8.0)
+    );
+    assert_eq!(
+        formulas.calculate_all(values).unwrap(),
+        HashMap::from([
+            ("consumption".to_string(), Some(8.0)),
+            ("grid".to_string(), Some(6.0)),
+            ("pv".to_string(), Some(-2.0)),
+            ("share".to_string(), Some(-0.25)),
+        ])
+    );
+
+    // Any value provider can be used, and formulas of named placeholders are
+    // calculated from named values.
+    let values = [Some(4.0), Some(2.0), Some(-2.0)];
+    assert_eq!(
+        formulas.calculate_all(&values[..]).unwrap()["share"],
+        Some(-0.25)
+    );
+    let mut named = FormulaRegistry::new();
+    named
+        .register("grid", Formula64::try_new("$meter + #\"pv-1\"").unwrap())
+        .unwrap();
+    named
+        .register("double", Formula64::try_new("@grid * 2").unwrap())
+        .unwrap();
+    let values = HashMap::from([
+        ("meter".to_string(), Some(3.0)),
+        ("pv-1".to_string(), Some(-1.0)),
+    ]);
+    assert_eq!(
+        named.calculate_named("double", values.clone()).unwrap(),
+        Some(4.0)
+    );
+    assert_eq!(
+        named.calculate_all_named(values).unwrap(),
+        HashMap::from([
+            ("grid".to_string(), Some(2.0)),
+            ("double".to_string(), Some(4.0)),
+        ])
+    );
+    assert_eq!(
+        named
+            .calculate_named("double", HashMap::new())
+            .unwrap_err()
+            .to_string(),
+        "Missing value for $meter"
+    );
+
+    assert_eq!(
+        formulas
+            .register("loop", Formula64::try_new("@loop + 1").unwrap())
+            .unwrap_err()
+            .to_string(),
+        "Circular formula reference: @loop -> @loop"
+    );
+    formulas
+        .register("a", Formula64::try_new("@b * 2").unwrap())
+        .unwrap();
+    assert_eq!(
+        formulas
+            .register("b", Formula64::try_new("@share + @a").unwrap())
+            .unwrap_err()
+            .to_string(),
+        "Circular formula reference: @b -> @a -> @b"
+    );
+    assert_eq!(
+        formulas
+            .calculate("a", HashMap::<u64, Option<_>>::new())
+            .unwrap_err()
+            .to_string(),
+        "Unknown formula: b"
+    );
+
+    // Formulas outside a registry can't be calculated with references.
+    assert_eq!(
+        Formula64::try_new("@grid")
+            .unwrap()
+            .calculate(HashMap::<u64, Option<_>>::new())
+            .unwrap_err()
+            .to_string(),
+        "Unknown formula: grid"
+    );
+    assert_eq!(
+        Formula64::try_new("@grid * #0")
+            .unwrap()
+            .derivative(0)
+            .unwrap_err()
+            .to_string(),
+        "Derivative of formula reference @grid is not supported"
+    );
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn test_formula_config() {
+    use crate::FormulaRegistry;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let toml = r##"
+        [formulas.grid_power]
+        formula = "#0 + #1"
+        none_as_zero = true
+        max_age = 10
+
+        [formulas.consumption]
+        formula = "@grid_power - #2"
+        strict = true
+    "##;
+    let yaml = r##"
+        formulas:
+          grid_power:
+            formula: "#0 + #1"
+            none_as_zero: true
+            max_age: 10
+          consumption:
+            formula: "@grid_power - #2"
+            strict: true
+    "##;
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    for formulas in [
+        FormulaRegistry::<f64>::from_toml(toml).unwrap(),
+        FormulaRegistry::<f64>::from_yaml(yaml).unwrap(),
+    ] {
+        assert_eq!(formulas.len(), 2);
+        let values = HashMap::from([(0, Some(4.0)), (1, None), (2, Some(1.0))]);
+        assert_eq!(
+            formulas.calculate("consumption", values).unwrap(),
+            Some(3.0)
+        );
+        let values = HashMap::from([(0, Some(4.0)), (1, Some(2.0)), (2, None)]);
+        assert_eq!(
+            formulas
+                .calculate("consumption", values)
+                .unwrap_err()
+                .to_string(),
+            "Missing values for #2"
+        );
+
+        assert_eq!(
+            formulas.max_age("grid_power"),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(formulas.max_age("consumption"), None);
+        let mut streaming = formulas.streaming("grid_power").unwrap();
+        streaming.update_at(0, Some(4.0), at(0)).ok();
+        assert_eq!(streaming.update_at(1, Some(2.0), at(5)).unwrap(), Some(6.0));
+        assert_eq!(streaming.expire(at(12)).unwrap(), Some(2.0));
+    }
+
+    let path = std::env::temp_dir().join("formula_engine_test_formula_config.toml");
+    std::fs::write(&path, toml).unwrap();
+    let formulas = FormulaRegistry::<f64>::load(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert!(formulas.contains("grid_power"));
+
+    for (toml, expected) in [
+        (
+            "[formulas.grid_power]\nformula = \"#0 +\"",
+            "Invalid formula grid_power: ",
+        ),
+        (
+            "[formulas.grid_power]\nformula = \"#0\"\nmax_age = -1",
+            "Invalid max_age of grid_power: -1",
+        ),
+        (
+            "[formulas.grid_power]\nformula = \"#0\"\nnone_policy = \"zero\"",
+            "Invalid formula file: ",
+        ),
+        (
+            "[formulas.a]\nformula = \"@b\"\n[formulas.b]\nformula = \"@a\"",
+            "Circular formula reference: @b -> @a -> @b",
+        ),
+    ] {
+        let err = FormulaRegistry::<f64>::from_toml(toml).unwrap_err();
+        assert!(err.to_string().starts_with(expected), "{}", err);
+    }
+
+    // Parameters are replaced by their values before parsing.
+    let toml = "[formulas.pv]\nformula = \"MIN(#${PV_METER}, 0) * ${SCALE}\"";
+    let params = HashMap::from([
+        ("PV_METER".to_string(), "7".to_string()),
+        ("SCALE".to_string(), "2".to_string()),
+    ]);
+    let formulas = FormulaRegistry::<f64>::from_toml_with_params(toml, &params).unwrap();
+    assert_eq!(formulas.get("pv").unwrap().to_string(), "MIN(#7, 0) * 2");
+    let yaml = "formulas:\n  pv:\n    formula: \"MIN(#${PV_METER}, 0) * ${SCALE}\"";
+    let formulas = FormulaRegistry::<f64>::from_yaml_with_params(yaml, &params).unwrap();
+    assert_eq!(formulas.get("pv").unwrap().to_string(), "MIN(#7, 0) * 2");
+    for (params, expected) in [
+        (
+            HashMap::new(),
+            "Invalid formula pv: Missing values for parameters ${PV_METER}, ${SCALE}",
+        ),
+        (
+            HashMap::from([("PV_METER".to_string(), "7".to_string())]),
+            "Invalid formula pv: Missing values for parameters ${SCALE}",
+        ),
+    ] {
+        assert_eq!(
+            FormulaRegistry::<f64>::from_toml_with_params(toml, &params)
+                .unwrap_err()
+                .to_string(),
+            expected
+        );
+    }
+    for (formula, expected) in [
+        ("#${PV_METER", "Unterminated parameter: ${PV_METER"),
+        ("#${PV METER}", "Invalid parameter name: PV METER"),
+    ] {
+        let toml = format!("[formulas.pv]\nformula = \"{}\"", formula);
+        assert_eq!(
+            FormulaRegistry::<f64>::from_toml_with_params(&toml, &params)
+                .unwrap_err()
+                .to_string(),
+            format!("Invalid formula pv: {}", expected)
+        );
+    }
+    // Without a map, parameters are taken from the environment.
+    std::env::set_var("FORMULA_ENGINE_TEST_PV_METER", "3");
+    let toml = "[formulas.pv]\nformula = \"#${FORMULA_ENGINE_TEST_PV_METER}\"";
+    let formulas = FormulaRegistry::<f64>::from_toml(toml).unwrap();
+    assert_eq!(formulas.get("pv").unwrap().to_string(), "#3");
+
+    assert_eq!(
+        FormulaRegistry::<f64>::load("formulas.json")
+            .unwrap_err()
+            .to_string()
+            .split(':')
+            .next(),
+        Some("Failed to read formulas.json")
+    );
+}
+
+#[test]
+fn test_bind() {
+    let fe =
+        FormulaEngine::<f64>::try_new("MIN(#0, KW(#1) * #2) + COALESCE(#3, #4) - SUM(#*)").unwrap();
+    let bound = fe.bind(HashMap::from([(1, 5.0), (2, 0.9), (3, 2.0)]));
+    assert_eq!(bound.to_string(), "MIN(#0, 4500) + 2 - SUM(#*)");
+    assert_eq!(bound.components(), &HashSet::from([0]));
+
+    let values = HashMap::from([(0, Some(5000.0)), (4, Some(1.0))]);
+    assert_eq!(bound.calculate(values).unwrap(), Some(-499.0));
+
+    // Only the parts that depend on constants alone are calculated.
+    let fe = FormulaEngine::<f64>::try_new("LET x = #0 + 1 IN IF(#1 > x, -#1, x / #2)").unwrap();
+    assert_eq!(
+        fe.bind(HashMap::from([(0, 1.0)])).to_string(),
+        "IF(#1 > 2, -#1, 2 / #2)"
+    );
+    assert_eq!(
+        fe.bind(HashMap::from([(0, 1.0), (1, 3.0), (2, 4.0)]))
+            .to_string(),
+        "-3"
+    );
+    assert_eq!(fe.bind(HashMap::new()), fe);
+}
+
+#[test]
+fn test_remap() {
+    let fe =
+        FormulaEngine::<f64>::try_new("LET x = #1 IN MAX(x, #2) + COALESCE(#3, #4, 0)").unwrap();
+    let remapped = fe.remap(&HashMap::from([(1, 2), (2, 1), (4, 5)]));
+    assert_eq!(
+        remapped.to_string(),
+        "LET x = #2 IN MAX(x, #1) + COALESCE(#3, #5, 0)"
+    );
+    assert_eq!(remapped.components(), &HashSet::from([1, 2, 3, 5]));
+
+    let values = HashMap::from([(1, Some(4.0)), (2, Some(-1.0)), (3, None), (5, Some(2.0))]);
+    assert_eq!(remapped.calculate(values).unwrap(), Some(6.0));
+    assert_eq!(fe.remap(&HashMap::new()), fe);
+}
+
+#[test]
+fn test_none_as_zero() {
+    use crate::EngineOptions;
+
+    let options = EngineOptions::default().with_none_as_zero(true);
+    let fe = FormulaEngine::<f64>::try_new_with_options("#0 + #1 - SUM(#*) / 2", options.clone())
+        .unwrap();
+    let values = HashMap::from([(0, Some(4.0)), (1, None)]);
+    assert_eq!(fe.calculate(values.clone()).unwrap(), Some(2.0));
+    assert_eq!(
+        FormulaEngine::<f64>::try_new("#0 + #1 - SUM(#*) / 2")
+            .unwrap()
+            .calculate(values)
+            .unwrap(),
+        None
+    );
+    assert!(fe.calculate(HashMap::from([(0, Some(4.0))])).is_err());
+
+    let fe =
+        FormulaEngine::<f64>::try_new_with_options("IS_NONE($pv) + $pv", options.clone()).unwrap();
+    assert_eq!(
+        fe.calculate_named(HashMap::from([("pv".to_string(), None)]))
+            .unwrap(),
+        Some(0.0)
+    );
+
+    let fe = FormulaEngine::<f64>::try_new_with_options("#0 + $pv", options).unwrap();
+    assert_eq!(
+        fe.to_sql().unwrap(),
+        "(COALESCE(\"0\", 0::float8) + COALESCE(\"pv\", 0::float8))"
+    );
+    assert_eq!(
+        fe.to_python().unwrap(),
+        "import math
+
+
+def _add(a, b):
+    return None if a is None or b is None else a + b
+
+
+def formula(values):
+    values = {key: 0.0 if value is None else value for key, value in values.items()}
+    return _add(values[0], values[\"pv\"])"
+    );
+}
+
+#[test]
+fn test_strict() {
+    use crate::EngineOptions;
+
+    let options = EngineOptions::default().with_strict(true);
+    let fe =
+        FormulaEngine::<f64>::try_new_with_options("IF(#0 > 0, #3, #1) + MAX(#*)", options.clone())
+            .unwrap();
+    assert_eq!(
+        fe.calculate(HashMap::from([
+            (0, Some(1.0)),
+            (3, Some(2.0)),
+            (1, Some(0.0))
+        ]))
+        .unwrap(),
+        Some(4.0)
+    );
+    // Placeholders of branches that aren't taken need values, too.
+    assert_eq!(
+        fe.calculate(HashMap::from([(0, Some(1.0)), (3, None)]))
+            .unwrap_err()
+            .to_string(),
+        "Missing values for #1, #3"
+    );
+    assert_eq!(
+        FormulaEngine::<f64>::try_new_with_options("SUM(#*)", options.clone())
+            .unwrap()
+            .calculate(HashMap::from([(0, Some(1.0)), (1, None)]))
+            .unwrap_err()
+            .to_string(),
+        "Missing values for #*"
+    );
+
+    let fe = FormulaEngine::<f64>::try_new_with_options("$pv + #\"m-1\" + $bat", options.clone())
+        .unwrap();
+    assert_eq!(
+        fe.calculate_named(HashMap::from([
+            ("pv".to_string(), None),
+            ("bat".to_string(), Some(1.0))
+        ]))
+        .unwrap_err()
+        .to_string(),
+        "Missing values for #\"m-1\", $pv"
+    );
+
+    let fe = FormulaEngine::<f64>::try_new_with_options("#0 + #1", options.with_none_as_zero(true))
+        .unwrap();
+    assert_eq!(
+        fe.calculate(HashMap::from([(0, None), (1, Some(1.0))]))
+            .unwrap(),
+        Some(1.0)
+    );
+    assert_eq!(
+        fe.calculate(HashMap::from([(0, None)]))
+            .unwrap_err()
+            .to_string(),
+        "Missing values for #1"
+    );
+}
+
+#[test]
+fn test_division_by_zero() {
+    use crate::{DivisionByZero, EngineOptions};
+
+    let engine = |division_by_zero| {
+        FormulaEngine::<f64>::try_new_with_options(
+            "COALESCE(#0 / (#1 - #2), #0 % #1, -1)",
+            EngineOptions::default().with_division_by_zero(division_by_zero),
+        )
+        .unwrap()
+    };
+    let values = HashMap::from([(0, Some(3.0)), (1, Some(0.0)), (2, Some(0.0))]);
+    assert_eq!(
+        engine(DivisionByZero::Infinity)
+            .calculate(values.clone())
+            .unwrap(),
+        Some(f64::INFINITY)
+    );
+    assert_eq!(
+        engine(DivisionByZero::None)
+            .calculate(values.clone())
+            .unwrap(),
+        Some(-1.0)
+    );
+    assert_eq!(
+        engine(DivisionByZero::Error)
+            .calculate(values.clone())
+            .unwrap_err()
+            .to_string(),
+        "Division by zero: #0 / (#1 - #2)"
+    );
+    let values = HashMap::from([(0, None), (1, Some(2.0)), (2, Some(1.0))]);
+    assert_eq!(
+        engine(DivisionByZero::Error).calculate(values).unwrap(),
+        Some(-1.0)
+    );
+
+    assert_eq!(
+        engine(DivisionByZero::None).to_sql().unwrap(),
+        "COALESCE((\"0\" / NULLIF((\"1\" - \"2\"), 0::float8)), \
+         (SELECT (a0 - a1 * TRUNC(a0 / a1)) \
+         FROM (SELECT \"0\" AS a0, NULLIF(\"1\", 0::float8) AS a1) AS bound), \
+         (-1::float8))"
+    );
+    assert_eq!(
+        engine(DivisionByZero::None)
+            .to_python()
+            .unwrap()
+            .lines()
+            .last(),
+        Some(
+            "    return _coalesce(_div(values[0], _nullif(_sub(values[1], values[2]), 0.0)), \
+             _mod(values[0], _nullif(values[1], 0.0)), _negate(1.0))"
+        )
+    );
+    assert!(engine(DivisionByZero::Error)
+        .to_python()
+        .unwrap()
+        .contains("        raise ZeroDivisionError(\"division by zero\")"));
+}
+
+#[test]
+fn test_calculate_slice() {
+    let fe = FormulaEngine::<f64>::try_new("#0 * #2 - SUM(#*)").unwrap();
+    assert_eq!(
+        fe.calculate_slice(&[Some(2.0), Some(1.0), Some(3.0)])
+            .unwrap(),
+        Some(0.0)
+    );
+    assert_eq!(fe.calculate_slice(&[Some(2.0), None, None]).unwrap(), None);
+    assert_eq!(
+        fe.calculate_slice(&[Some(2.0), None])
+            .unwrap_err()
+            .to_string(),
+        "Placeholder out of bounds"
+    );
+}
+
+#[test]
+fn test_value_provider() {
+    use crate::ValueProvider;
+
+    struct Telemetry(Vec<(u64, f64)>);
+
+    impl ValueProvider<f64> for Telemetry {
+        fn get(&self, id: u64) -> Option<Option<f64>> {
+            self.0
+                .iter()
+                .find(|(component, _)| *component == id)
+                .map(|(_, value)| Some(*value))
+        }
+    }
+
+    let fe = FormulaEngine::<f64>::try_new("#1 - #3").unwrap();
+    assert_eq!(
+        fe.calculate(Telemetry(vec![(3, 1.0), (1, 4.0)])).unwrap(),
+        Some(3.0)
+    );
+    assert_eq!(
+        fe.calculate(|id| (id < 3).then_some(Some(1.0)))
+            .unwrap_err()
+            .to_string(),
+        "Placeholder out of bounds"
+    );
+    let values: &[Option<f64>] = &[None, Some(2.0), None, Some(5.0)];
+    assert_eq!(fe.calculate(values).unwrap(), Some(-3.0));
+
+    let fe = FormulaEngine::<f64>::try_new("MAX(#*)").unwrap();
+    assert_eq!(fe.calculate(values).unwrap(), Some(5.0));
+    assert_eq!(
+        fe.calculate(|_| Some(Some(1.0))).unwrap_err().to_string(),
+        "The values for #* can't be listed"
+    );
+}
+
+#[test]
+fn test_borrowed_and_iterator_inputs() {
+    let values = HashMap::from([(0, Some(1.0)), (1, None), (2, Some(3.0))]);
+    let fe = FormulaEngine::<f64>::try_new("#0 + #2").unwrap();
+    assert_eq!(fe.calculate(&values).unwrap(), Some(4.0));
+    let fe = FormulaEngine::<f64>::try_new("COALESCE(#1, #2) - MIN(#*)").unwrap();
+    assert_eq!(fe.calculate(&values).unwrap(), Some(2.0));
+    assert_eq!(fe.calculate_iter(values.clone()).unwrap(), Some(2.0));
+
+    // The last value of a component is used.
+    assert_eq!(
+        fe.calculate_iter([(2, Some(3.0)), (0, Some(1.0)), (2, Some(5.0)), (1, None)])
+            .unwrap(),
+        Some(4.0)
+    );
+    assert_eq!(
+        fe.calculate_iter((0..3).map(|id| (id, Some(id as f64))))
+            .unwrap(),
+        Some(1.0)
+    );
+    assert!(fe.calculate_iter([(0, Some(1.0))]).is_err());
+}
+
+#[test]
+fn test_large_component_ids() {
+    let fe = FormulaEngine::<f64>::try_new("#5000000000 - #4294967296").unwrap();
+    assert_eq!(
+        fe.components(),
+        &HashSet::from([5_000_000_000, 4_294_967_296])
+    );
+    let values = HashMap::from([(5_000_000_000, Some(3.0)), (4_294_967_296, Some(1.0))]);
+    assert_eq!(fe.calculate(&values).unwrap(), Some(2.0));
+    assert_eq!(fe.to_string(), "#5000000000 - #4294967296");
+}
+
+#[test]
+fn test_calculate_batch() {
+    use crate::{DivisionByZero, EngineOptions};
+
+    let rows = [
+        HashMap::from([(0, Some(1.0)), (1, Some(2.0)), (2, Some(0.0))]),
+        HashMap::from([(0, Some(-1.0)), (1, None), (2, Some(4.0))]),
+        HashMap::from([(0, None), (1, Some(3.0)), (2, Some(1.0))]),
+        HashMap::from([(0, Some(0.0)), (1, Some(3.0))]),
+    ];
+    let options = EngineOptions::default().with_division_by_zero(DivisionByZero::Error);
+    let engine = |formula| FormulaEngine::<f64>::try_new_with_options(formula, options.clone());
+    for fe in [
+        engine("#0 + #1 * 2 - NOT #2").unwrap(),
+        engine("COALESCE(#1, #0, 0) + MAX(#*)").unwrap(),
+        engine("IF(#0 > 0, #1 / #2, #2)").unwrap(),
+        engine("CASE(#0 < 0, #1, #0 > 0, -#2, #0 == 0, #1 % #0)").unwrap(),
+        engine("LET x = #1 / #2 IN IF(IS_NONE(x), 0, x * x)").unwrap(),
+        // The derivative selects the derivative of the smaller argument.
+        engine("MIN(#0 * #0 / #2, #1)")
+            .unwrap()
+            .derivative(0)
+            .unwrap(),
+    ] {
+        let results = fe.calculate_batch(&rows);
+        assert_eq!(results.len(), rows.len());
+        for (row, result) in rows.iter().zip(results) {
+            assert_eq!(
+                format!("{:?}", result),
+                format!("{:?}", fe.calculate(row)),
+                "{} with {:?}",
+                fe,
+                row
+            );
+        }
+    }
+
+    let fe =
+        FormulaEngine::<f64>::try_new_with_options("#0 + #1", options.with_strict(true)).unwrap();
+    let results: Vec<_> = fe
+        .calculate_batch(&rows)
+        .into_iter()
+        .map(|result| result.map_err(|err| err.to_string()))
+        .collect();
+    assert_eq!(
+        results,
+        [
+            Ok(Some(3.0)),
+            Err("Missing values for #1".to_string()),
+            Err("Missing values for #0".to_string()),
+            Ok(Some(3.0)),
+        ]
+    );
+    assert!(fe.calculate_batch(&[]).is_empty());
+}
+
+#[test]
+fn test_calculate_columns() {
+    let fe = FormulaEngine::<f64>::try_new("IF(#3 > 0, #3 * #5, SUM(#*))").unwrap();
+    let columns = HashMap::from([
+        (3, &[Some(1.0), Some(0.0), None][..]),
+        (5, &[Some(2.0), Some(4.0), Some(6.0)][..]),
+    ]);
+    assert_eq!(
+        fe.calculate_columns(&columns).unwrap(),
+        [Some(2.0), Some(4.0), None]
+    );
+    assert_eq!(fe.calculate_columns(&HashMap::new()).unwrap(), []);
+    assert_eq!(
+        fe.calculate_columns(&HashMap::from([(3, &[Some(1.0)][..])]))
+            .unwrap_err()
+            .to_string(),
+        "Placeholder out of bounds"
+    );
+    assert_eq!(
+        fe.calculate_columns(&HashMap::from([
+            (3, &[Some(1.0)][..]),
+            (5, &[Some(1.0), None][..])
+        ]))
+        .unwrap_err()
+        .to_string(),
+        "All columns must have the same length"
+    );
+}
+
+#[cfg(feature = "simd")]
+#[test]
+fn test_calculate_columns_simd() {
+    use crate::{DivisionByZero, EngineOptions};
+
+    let values = [
+        Some(1.5),
+        None,
+        Some(0.0),
+        Some(-0.0),
+        Some(f64::NAN),
+        Some(f64::INFINITY),
+        Some(-2.0),
+        Some(3.0),
+    ];
+    // Pairs of all values, which isn't a multiple of the number of lanes.
+    let column_0: Vec<_> = values.iter().flat_map(|a| values.map(|_| *a)).collect();
+    let column_1: Vec<_> = values.iter().flat_map(|_| values).take(61).collect();
+    let columns = HashMap::from([(0, &column_0[..61]), (1, &column_1[..])]);
+    let columns_32: Vec<Vec<_>> = [&column_0[..61], &column_1]
+        .iter()
+        .map(|column| column.iter().map(|x| x.map(|x| x as f32)).collect())
+        .collect();
+    let columns_32 = HashMap::from([(0, &columns_32[0][..]), (1, &columns_32[1][..])]);
+
+    for (formula, options) in [
+        ("#0 + #1 * 2 - -#0", EngineOptions::default()),
+        ("#0 / #1 + #1 / 0", EngineOptions::default()),
+        (
+            "(#0 < #1) + (#0 <= #1) * 2 + (#0 == #1) * 4",
+            EngineOptions::default(),
+        ),
+        (
+            "(#0 > #1) - (#0 >= #1) + COALESCE(#1, #0, NULLIF(0, 0))",
+            EngineOptions::default(),
+        ),
+        ("#0 - #1", EngineOptions::default().with_none_as_zero(true)),
+        // Calculated without SIMD.
+        ("MAX(#0, #1) + #0 % #1", EngineOptions::default()),
+        (
+            "#0 / #1",
+            EngineOptions::default().with_division_by_zero(DivisionByZero::None),
+        ),
+    ] {
+        let fe = FormulaEngine::<f64>::try_new_with_options(formula, options.clone()).unwrap();
+        assert_eq!(
+            format!("{:?}", fe.calculate_columns_simd(&columns).unwrap()),
+            format!("{:?}", fe.calculate_columns(&columns).unwrap()),
+            "{}",
+            formula
+        );
+        let fe = FormulaEngine::<f32>::try_new_with_options(formula, options).unwrap();
+        assert_eq!(
+            format!("{:?}", fe.calculate_columns_simd(&columns_32).unwrap()),
+            format!("{:?}", fe.calculate_columns(&columns_32).unwrap()),
+            "{}",
+            formula
+        );
+    }
+
+    let fe = FormulaEngine::<f64>::try_new("#0 + #2").unwrap();
+    assert_eq!(
+        fe.calculate_columns_simd(&columns).unwrap_err().to_string(),
+        "Placeholder out of bounds"
+    );
+}
+
+#[test]
+fn test_engines_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FormulaEngine<f32>>();
+    assert_send_sync::<FormulaEngine<f64>>();
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_calculate_batch_par() {
+    use crate::{DivisionByZero, EngineOptions};
+
+    let rows: Vec<_> = (0..1001)
+        .map(|i| {
+            HashMap::from([
+                (0, (i % 7 != 0).then_some(i as f64)),
+                (1, Some((i % 5) as f64)),
+            ])
+        })
+        .collect();
+    let options = EngineOptions::default().with_division_by_zero(DivisionByZero::Error);
+    let fe =
+        FormulaEngine::<f64>::try_new_with_options("IF(#0 > 500, #0 / #1, -#0)", options).unwrap();
+    let results = fe.calculate_batch_par(&rows);
+    assert_eq!(
+        format!("{:?}", results),
+        format!("{:?}", fe.calculate_batch(&rows))
+    );
+    assert!(fe.calculate_batch_par(&[]).is_empty());
+}
+
+#[test]
+fn test_compiled_program() {
+    use crate::{Associativity, DivisionByZero, EngineOptions, FunctionRegistry, Precedence};
+
+    let rows = [
+        HashMap::from([(0, Some(1.0)), (1, Some(2.0)), (2, Some(0.0))]),
+        HashMap::from([(0, Some(-1.0)), (1, None), (2, Some(4.0))]),
+        HashMap::from([(0, None), (1, Some(3.0)), (2, Some(1.0))]),
+        HashMap::from([(0, Some(0.0)), (1, Some(3.0))]),
+    ];
+    let functions = FunctionRegistry::new()
+        .register("FIRST", |values: &[Option<f64>]| *values.first()?)
+        .register_operator(
+            "~-",
+            Precedence::Additive,
+            Associativity::Left,
+            |a: Option<f64>, b| Some((a? - b?).max(0.0)),
+        );
+    for division_by_zero in [
+        DivisionByZero::Infinity,
+        DivisionByZero::None,
+        DivisionByZero::Error,
+    ] {
+        let options = EngineOptions::default().with_division_by_zero(division_by_zero);
+        let engine = |formula| {
+            FormulaEngine::<f64>::try_new_with_functions(
+                formula,
+                options.clone(),
+                functions.clone(),
+            )
+            .unwrap()
+        };
+        for fe in [
+            engine("#0 + #1 * 2 - NOT #2 ^ 2 % 3"),
+            engine("#0 > 0 AND #1 < 3 OR #2 == 0"),
+            engine("COALESCE(#1, #0, 0) + MAX(#*) - FIRST(#2, #*, #0)"),
+            engine("SUM(#*) / COUNT_SOME(#*) ~- #1"),
+            engine("IF(#0 > 0, #1 / #2, #2)"),
+            engine("CASE(#0 < 0, #1, #0 > 0, -#2 % #2, #0 == 0, #1 % #0)"),
+            engine("CASE(#0 < 0, #1, #0 > 0, -#2)"),
+            engine("LET x = #1 / #2 IN IF(IS_NONE(x), 0, LET x = x * x IN x + 1)"),
+            engine("LET x = #0 IN LET y = x + #1 IN MIN(x, y, #2)"),
+            engine("#0 + #5"),
+            engine("MIN(#0 * #0 / #2, #1)").derivative(0).unwrap(),
+            engine("IF(#0 > 0, #0 * #1, #2)").derivative(0).unwrap(),
+        ] {
+            for row in &rows {
+                assert_eq!(
+                    format!("{:?}", fe.calculate(row)),
+                    format!("{:?}", fe.expr().calculate(row, &fe.options, &fe.functions)),
+                    "{} with {:?}",
+                    fe,
+                    row
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit() {
+    use crate::{DivisionByZero, EngineOptions};
+
+    let values = [
+        Some(1.5),
+        None,
+        Some(0.0),
+        Some(-0.0),
+        Some(f64::NAN),
+        Some(f64::INFINITY),
+        Some(-2.0),
+    ];
+    let rows: Vec<HashMap<u64, Option<f64>>> = values
+        .iter()
+        .flat_map(|a| values.map(|b| HashMap::from([(0, *a), (1, b), (2, Some(3.0))])))
+        .collect();
+    for options in [
+        EngineOptions::default(),
+        EngineOptions::default().with_division_by_zero(DivisionByZero::None),
+        EngineOptions::default().with_division_by_zero(DivisionByZero::Error),
+        EngineOptions::default().with_none_as_zero(true),
+        EngineOptions::default().with_strict(true),
+    ] {
+        for formula in [
+            "#0 + #1 * 2 - -#0 / #2",
+            "#0 / #1 + #2 / 0",
+            "(#0 < #1) + (#0 <= #1) * 2 + (#0 == #1) * 4 + (#0 != #1) * 8",
+            "(#0 > #1) - (#0 >= #1) + NOT #0",
+            "(#0 AND #1) + (#0 OR #1) * 2",
+            "COALESCE(#1, #0, NULLIF(0, 0)) + NULLIF(#0, #1)",
+            "MIN(#0, #1, #2) + MAX(#1, #0) * 10",
+            "MIN_STRICT(#0, #1) + MAX_STRICT(#2, #0)",
+            "SUM(#0, #1) + COUNT_SOME(#0, #1, #2) + IS_NONE(#0) - IS_SOME(#1)",
+            "IF(#0 > 0, #1 / #0, #2)",
+            "CASE(#0 < 0, #1, #1 > 0, -#2, #0 == 0, #2 / #1)",
+            "LET x = #0 - #1 IN IF(IS_NONE(x), 0, LET y = x * x IN y + x)",
+            // Calculated by the engine.
+            "#0 % #1 + #1 ^ 2",
+            // Calculated by the engine, as #5 isn't given.
+            "#0 + #5",
+        ] {
+            let fe = FormulaEngine::<f64>::try_new_with_options(formula, options.clone()).unwrap();
+            let jit = fe.jit().unwrap();
+            assert_eq!(jit.is_native(), !formula.contains('%'), "{}", formula);
+            for row in &rows {
+                assert_eq!(
+                    format!("{:?}", jit.calculate(row)),
+                    format!("{:?}", fe.calculate(row)),
+                    "{} with {:?}",
+                    formula,
+                    row
+                );
+            }
+
+            let fe = FormulaEngine::<f32>::try_new_with_options(formula, options.clone()).unwrap();
+            let jit = fe.jit().unwrap();
+            for row in &rows {
+                let row: HashMap<_, _> = row
+                    .iter()
+                    .map(|(id, value)| (*id, value.map(|x| x as f32)))
+                    .collect();
+                assert_eq!(
+                    format!("{:?}", jit.calculate(&row)),
+                    format!("{:?}", fe.calculate(&row)),
+                    "{} with {:?}",
+                    formula,
+                    row
+                );
+            }
+        }
+    }
+
+    // Formulas of many components gather their values on the heap.
+    let formula: Vec<_> = (0..40).map(|i| format!("#{}", i)).collect();
+    let fe = FormulaEngine::<f64>::try_new(&formula.join(" + ")).unwrap();
+    let values: HashMap<_, _> = (0..40).map(|i| (i, Some(i as f64))).collect();
+    assert_eq!(fe.jit().unwrap().calculate(&values).unwrap(), Some(780.0));
+}
+
+#[test]
+fn test_simplify() {
+    for (formula, simplified) in [
+        ("#0 + 0 - 0 + (0 + #1)", "#0 + #1"),
+        ("#0 * 1 / 1 * (1 * #1)", "#0 * #1"),
+        ("--#0 - ---#1", "#0 - -#1"),
+        ("COALESCE(#*) + 0", "COALESCE(#*)"),
+        (
+            "COALESCE(#0, COALESCE(#1, COALESCE(#2, #*)), 0)",
+            "COALESCE(#0, #1, #2, #*, 0)",
+        ),
+        (
+            "IF(#0 > 0, COALESCE(#1 * 1, 2), #2 + 0)",
+            "IF(#0 > 0, COALESCE(#1, 2), #2)",
+        ),
+        ("#0 - 0 * #1 + #2 ^ 1", "#0 - 0 * #1 + #2 ^ 1"),
+    ] {
+        let fe = FormulaEngine::<f64>::try_new(formula).unwrap();
+        let simplified_fe = fe.simplify();
+        assert_eq!(simplified_fe.to_string(), simplified, "{}", formula);
+        for values in [
+            HashMap::from([(0, Some(1.5)), (1, Some(-2.0)), (2, None)]),
+            HashMap::from([(0, None), (1, Some(f64::INFINITY)), (2, Some(3.0))]),
+        ] {
+            assert_eq!(
+                format!("{:?}", simplified_fe.calculate(&values)),
+                format!("{:?}", fe.calculate(&values)),
+                "{}",
+                formula
+            );
+        }
+    }
+}
+
+#[test]
+fn test_bind_dead_branches() {
+    for (formula, bound, components) in [
+        ("COALESCE(#0, #1, #2)", "COALESCE(#0, 0)", vec![0]),
+        ("COALESCE(#1, #0, #2)", "0", vec![]),
+        (
+            "COALESCE(#0, NULLIF(0, 0), #2)",
+            "COALESCE(#0, #2)",
+            vec![0, 2],
+        ),
+        ("COALESCE(#*, #1, #0)", "COALESCE(#*, 0)", vec![]),
+        ("IF(#1 == 0, #0, #2) + IF(#1, #2, #0)", "#0 + #0", vec![0]),
+        (
+            "CASE(#0 > 0, #2, #1 > 0, #3, #1 == 0, #4, #5)",
+            "CASE(#0 > 0, #2, #4)",
+            vec![0, 2, 4],
+        ),
+        ("CASE(#1 > 0, #2, #1 == 0, #3, #4)", "#3", vec![3]),
+        (
+            "CASE(#1 > 0, #2, #0 > 0, #3)",
+            "CASE(#0 > 0, #3)",
+            vec![0, 3],
+        ),
+        ("CASE(#1 > 0, #2, #1 < 0, #3)", "NULLIF(0, 0)", vec![]),
+        (
+            "LET x = #2 IN COALESCE(#1, x) + LET y = #3 IN y",
+            "0 + (LET y = #3 IN y)",
+            vec![3],
+        ),
+    ] {
+        let fe = FormulaEngine::<f64>::try_new(formula).unwrap();
+        let bound_fe = fe.bind(HashMap::from([(1, 0.0)]));
+        assert_eq!(bound_fe.to_string(), bound, "{}", formula);
+        assert_eq!(
+            bound_fe.components(),
+            &components.into_iter().collect::<HashSet<_>>(),
+            "{}",
+            formula
+        );
+
+        // The bound component has its bound value.
+        let values: HashMap<_, _> = (0..6)
+            .map(|id| (id, Some(if id == 1 { 0.0 } else { id as f64 - 2.0 })))
+            .collect();
+        assert_eq!(
+            bound_fe.calculate(&values).unwrap(),
+            fe.calculate(&values).unwrap(),
+            "{}",
+            formula
+        );
+    }
+}
+
+#[test]
+fn test_incremental() {
+    use crate::{DivisionByZero, EngineOptions};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let values = [
+        None,
+        Some(0.0),
+        Some(-0.0),
+        Some(2.0),
+        Some(-3.5),
+        Some(f64::NAN),
+    ];
+    for options in [
+        EngineOptions::default(),
+        EngineOptions::default().with_division_by_zero(DivisionByZero::Error),
+        EngineOptions::default().with_strict(true),
+    ] {
+        for formula in [
+            "#0 + #1 * 2 - NOT #2 / #0",
+            "COALESCE(#1, #0, 0) + MAX(#*) - SUM(#3, #*)",
+            "IF(#0 > 0, #1 / #2, #2)",
+            "CASE(#0 < 0, #1, #0 > 0, -#2, #0 == 0, #1 % #0)",
+            "LET x = #1 / #2 IN IF(IS_NONE(x), 0, LET y = x * x IN y + x)",
+            "#0 AND #1 OR #2",
+        ] {
+            let fe = FormulaEngine::<f64>::try_new_with_options(formula, options.clone()).unwrap();
+            let mut incremental = fe.incremental();
+            let mut expected = HashMap::new();
+            for _ in 0..200 {
+                let (id, value) = (rng.gen_range(0..4), values[rng.gen_range(0..values.len())]);
+                expected.insert(id, value);
+                assert_eq!(
+                    format!("{:?}", incremental.update(id, value)),
+                    format!("{:?}", fe.calculate(&expected)),
+                    "{} with {:?}",
+                    formula,
+                    expected
+                );
+            }
+            assert_eq!(
+                incremental.values().keys().collect::<HashSet<_>>(),
+                expected.keys().collect()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_memoize() {
+    use crate::{DivisionByZero, EngineOptions};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(11);
+    let values = [None, Some(0.0), Some(-0.0), Some(2.0), Some(f64::NAN)];
+    for options in [
+        EngineOptions::default(),
+        EngineOptions::default().with_division_by_zero(DivisionByZero::Error),
+        EngineOptions::default().with_strict(true),
+    ] {
+        for formula in [
+            "1 / #0 + #1",
+            "COALESCE(#1, #0, 0) + MAX(#*)",
+            "IF(#0, #1, #2)",
+        ] {
+            let fe = FormulaEngine::<f64>::try_new_with_options(formula, options.clone()).unwrap();
+            let memoized = FormulaEngine::<f64>::try_new_with_options(
+                formula,
+                options.clone().with_memoize(true),
+            )
+            .unwrap();
+            let mut current = HashMap::new();
+            for _ in 0..200 {
+                // Repeat the values of the previous calculation half of the time.
+                if rng.gen_bool(0.5) {
+                    let (id, value) = (rng.gen_range(0..4), values[rng.gen_range(0..values.len())]);
+                    current.insert(id, value);
+                }
+                assert_eq!(
+                    format!("{:?}", memoized.calculate(&current)),
+                    format!("{:?}", fe.calculate(&current)),
+                    "{} with {:?}",
+                    formula,
+                    current
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_streaming() {
+    use crate::{DivisionByZero, EngineOptions};
+    use std::sync::{Arc, Mutex};
+
+    let options = EngineOptions::default().with_division_by_zero(DivisionByZero::Error);
+    let fe = FormulaEngine::<f64>::try_new_with_options("#0 / #1", options).unwrap();
+    let mut streaming = fe.streaming();
+    assert!(streaming.current().is_err());
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let results = changes.clone();
+    streaming.on_change(move |result| results.lock().unwrap().push(format!("{:?}", result)));
+    let count = Arc::new(Mutex::new(0));
+    let calls = count.clone();
+    streaming.on_change(move |_| *calls.lock().unwrap() += 1);
+
+    assert!(streaming.update(0, Some(6.0)).is_err());
+    assert_eq!(streaming.update(1, Some(2.0)).unwrap(), Some(3.0));
+    assert_eq!(streaming.update(1, Some(2.0)).unwrap(), Some(3.0));
+    assert!(streaming.update(1, Some(0.0)).is_err());
+    assert!(streaming.update(0, Some(1.0)).is_err());
+    assert_eq!(streaming.update(1, Some(-0.5)).unwrap(), Some(-2.0));
+    assert_eq!(streaming.update(0, None).unwrap(), None);
+
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![
+            "Ok(Some(3.0))",
+            "Err(FormulaError(\"Division by zero: #0 / #1\"))",
+            "Ok(Some(-2.0))",
+            "Ok(None)",
+        ]
+    );
+    assert_eq!(*count.lock().unwrap(), 4);
+    assert_eq!(
+        streaming.values(),
+        &HashMap::from([(0, None), (1, Some(-0.5))])
+    );
+    assert_eq!(streaming.current().unwrap(), None);
+}
+
+#[test]
+fn test_streaming_inputs() {
+    use crate::{Sample, StreamingInput};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, 0) + #1").unwrap();
+    let mut streaming = fe
+        .streaming()
+        .with_component_max_age(0, Duration::from_secs(10));
+    let inputs = Arc::new(Mutex::new(Vec::new()));
+    let calls = inputs.clone();
+    streaming.on_input(move |input, result| {
+        calls
+            .lock()
+            .unwrap()
+            .push((*input, result.as_ref().ok().copied()))
+    });
+
+    streaming.update_at(0, Some(1.0), at(0)).ok();
+    streaming.update_at(1, Some(2.0), at(1)).ok();
+    streaming.update_at(1, Some(2.0), at(2)).ok();
+    streaming.expire(at(20)).ok();
+    assert_eq!(
+        *inputs.lock().unwrap(),
+        vec![
+            (
+                StreamingInput::Update(0, Sample::new(Some(1.0), at(0))),
+                None
+            ),
+            (
+                StreamingInput::Update(1, Sample::new(Some(2.0), at(1))),
+                Some(Some(3.0))
+            ),
+            (
+                StreamingInput::Update(1, Sample::new(Some(2.0), at(2))),
+                Some(Some(3.0))
+            ),
+            (StreamingInput::Expire(at(20)), Some(Some(2.0))),
+        ]
+    );
+}
+
+#[test]
+fn test_record_replay() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("INTEGRATE(COALESCE(#0, 0)) + #1").unwrap();
+    let streaming = || {
+        fe.streaming()
+            .with_component_max_age(0, Duration::from_secs(1800))
+    };
+    let mut live = streaming();
+    live.update_at(1, Some(1.0), at(0)).ok();
+    live.update_at(0, Some(1000.0), at(0)).ok();
+
+    // Recording starts with the current values and samples.
+    let recorder = live.record();
+    let results = vec![
+        live.update_at(0, Some(2000.0), at(1800)),
+        live.expire(at(4000)),
+        live.update_at(1, Some(2.0), at(5400)),
+        live.update_at(0, Some(3000.0), at(5400)),
+    ];
+    let recording = recorder.recording();
+    assert_eq!(recording.values, vec![(0, Some(1000.0)), (1, Some(1.0))]);
+    assert_eq!(recording.updated, vec![(0, at(0))]);
+    assert_eq!(recording.inputs.len(), 4);
+
+    let replayed = recording.replay(&mut streaming()).unwrap();
+    assert_eq!(format!("{:?}", replayed), format!("{:?}", results));
+    assert_eq!(
+        format!("{:?}", recording.replay(&mut streaming()).unwrap()),
+        format!("{:?}", results)
+    );
+    let other = FormulaEngine::<f64>::try_new("INTEGRATE(#0) + #1").unwrap();
+    assert_eq!(
+        recording
+            .replay(&mut other.streaming())
+            .unwrap_err()
+            .to_string(),
+        "The recording is of INTEGRATE(COALESCE(#0, 0)) + #1, not INTEGRATE(#0) + #1"
+    );
+
+    // Inputs are no longer recorded once the recorder is dropped.
+    drop(recorder);
+    live.update_at(0, Some(3000.0), at(7200)).ok();
+    assert_eq!(recording.inputs.len(), 4);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_recording_serde() {
+    use crate::Recording;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("ROLLING_AVG(#0, 1m)").unwrap();
+    let mut streaming = fe.streaming();
+    let recorder = streaming.record();
+    streaming.update_at(0, Some(1.0), at(0)).ok();
+    streaming.expire(at(10)).ok();
+
+    let json = serde_json::to_string(&recorder.recording()).unwrap();
+    let recording: Recording<f64> = serde_json::from_str(&json).unwrap();
+    assert_eq!(recording, recorder.recording());
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn test_prometheus_exporter() {
+    use crate::{EngineOptions, PrometheusExporter};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let registry = prometheus::Registry::new();
+    let exporter = PrometheusExporter::new().with_window(2);
+    registry.register(Box::new(exporter.clone())).unwrap();
+    let gauges = || {
+        let mut gauges: Vec<_> = registry
+            .gather()
+            .iter()
+            .flat_map(|family| {
+                family.get_metric().iter().map(|metric| {
+                    let formula = metric.get_label()[0].value().to_string();
+                    let value = metric.get_gauge().get_value();
+                    (family.name().to_string(), formula, value)
+                })
+            })
+            .collect();
+        gauges.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        gauges
+    };
+    let gauge = |name: &str, formula: &str, value| (name.to_string(), formula.to_string(), value);
+
+    let options = EngineOptions::default().with_clock(clock_at(100));
+    let fe = FormulaEngine::<f64>::try_new_with_options("#0", options).unwrap();
+    let mut grid = fe.streaming().with_max_age(Duration::from_secs(30));
+    let mut pv = fe.streaming();
+    exporter.watch("grid", &mut grid);
+    exporter.watch("pv", &mut pv);
+    assert_eq!(gauges(), vec![]);
+
+    grid.update_at(0, Some(5.0), at(80)).ok();
+    grid.update_at(0, None, at(90)).ok();
+    grid.update_at(0, Some(2.0), at(95)).ok();
+    pv.update_at(0, Some(-1.0), at(60)).ok();
+    assert_eq!(
+        gauges(),
+        vec![
+            gauge("formula_none_rate", "grid", 0.5),
+            gauge("formula_none_rate", "pv", 0.0),
+            gauge("formula_result", "grid", 2.0),
+            gauge("formula_result", "pv", -1.0),
+            gauge("formula_staleness_seconds", "grid", 5.0),
+            gauge("formula_staleness_seconds", "pv", 40.0),
+        ]
+    );
+
+    // Expired values change the result, but are not values of components.
+    grid.expire(at(130)).ok();
+    assert!(gauges().contains(&gauge("formula_none_rate", "grid", 0.5)));
+    assert!(gauges()[2].2.is_nan());
+
+    // Unwatched formulas are removed, also when watched again.
+    exporter.unwatch("pv");
+    pv.update_at(0, Some(1.0), at(100)).ok();
+    let mut other = fe.streaming();
+    exporter.watch("grid", &mut other);
+    grid.update_at(0, Some(1.0), at(100)).ok();
+    assert_eq!(gauges(), vec![]);
+    other.update_at(0, Some(3.0), at(100)).ok();
+    assert_eq!(
+        gauges(),
+        vec![
+            gauge("formula_none_rate", "grid", 0.0),
+            gauge("formula_result", "grid", 3.0),
+            gauge("formula_staleness_seconds", "grid", 0.0),
+        ]
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_stream() {
+    use futures_util::{stream, StreamExt};
+
+    let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, 0) - #1").unwrap();
+    let updates = stream::iter([(1, Some(2.0)), (0, Some(5.0)), (3, Some(1.0)), (0, None)]);
+    let results = futures_executor::block_on(fe.stream(updates).collect::<Vec<_>>());
+    assert_eq!(
+        format!("{:?}", results),
+        "[Err(FormulaError(\"Placeholder out of bounds\")), Ok(Some(3.0)), Ok(Some(3.0)), Ok(Some(-2.0))]"
+    );
+
+    let streams = [
+        (0, stream::iter(vec![Some(1.0), Some(4.0)])),
+        (1, stream::iter(vec![Some(1.0)])),
+    ];
+    let results = futures_executor::block_on(fe.stream_components(streams).collect::<Vec<_>>());
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[2].as_ref().unwrap(), &Some(3.0));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_publish() {
+    use tokio::sync::{broadcast, watch};
+
+    let fe = FormulaEngine::<f64>::try_new("#0 * 2").unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (meter, receiver) = watch::channel(Some(1.0));
+        let (sender, mut results) = broadcast::channel(8);
+        let task = tokio::spawn(fe.publish_broadcast([(0, receiver)], sender));
+        assert_eq!(results.recv().await.unwrap().unwrap(), Some(2.0));
+        meter.send(None).unwrap();
+        assert_eq!(results.recv().await.unwrap().unwrap(), None);
+        meter.send(Some(-3.0)).unwrap();
+        assert_eq!(results.recv().await.unwrap().unwrap(), Some(-6.0));
+        drop(meter);
+        task.await.unwrap();
+        assert!(results.recv().await.is_err());
+
+        let (meter, receiver) = watch::channel(None);
+        let (sender, mut results) = watch::channel(Ok(Some(0.0)));
+        let task = tokio::spawn(fe.publish_watch([(0, receiver)], sender));
+        results.changed().await.unwrap();
+        assert_eq!(results.borrow_and_update().as_ref().unwrap(), &None);
+        drop(results);
+        meter.send(Some(1.0)).unwrap();
+        task.await.unwrap();
+    });
+}
+
+#[test]
+fn test_resampler() {
+    use crate::Aggregation;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+    let fe = FormulaEngine::<f64>::try_new("#0 - #1 + COALESCE(#2, 0)").unwrap();
+    assert!(fe.resampler(Duration::ZERO).is_err());
+
+    let mut resampler = fe
+        .resampler(Duration::from_secs(1))
+        .unwrap()
+        .with_aggregation(Aggregation::Max)
+        .with_component_aggregation(1, Aggregation::Mean)
+        .with_component_aggregation(2, Aggregation::Last);
+    assert!(resampler.flush().is_none());
+    assert!(resampler.push(0, at(1_000), Some(1.0)).is_none());
+    assert!(resampler.push(0, at(1_500), Some(4.0)).is_none());
+    assert!(resampler.push(0, at(1_700), None).is_none());
+    assert!(resampler.push(1, at(1_200), Some(1.0)).is_none());
+    assert!(resampler.push(1, at(1_999), Some(2.0)).is_none());
+    assert!(resampler.push(2, at(1_100), Some(7.0)).is_none());
+    assert!(resampler.push(2, at(1_800), None).is_none());
+    let (start, result) = resampler.push(0, at(3_100), Some(2.0)).unwrap();
+    assert_eq!(start, at(1_000));
+    assert_eq!(result.unwrap(), Some(2.5));
+    assert_eq!(
+        resampler.values(),
+        &HashMap::from([(0, Some(4.0)), (1, Some(1.5)), (2, None)])
+    );
+
+    // Late samples are ignored, and components without samples keep their
+    // values.
+    assert!(resampler.push(1, at(2_500), Some(100.0)).is_none());
+    assert!(resampler.push(0, at(3_400), Some(-1.0)).is_none());
+    let (start, result) = resampler.flush().unwrap();
+    assert_eq!(start, at(3_000));
+    assert_eq!(result.unwrap(), Some(0.5));
+    assert!(resampler.flush().is_none());
+
+    let mut resampler = fe
+        .resampler(Duration::from_secs(1))
+        .unwrap()
+        .with_aggregation(Aggregation::Min);
+    resampler.push(0, at(0), Some(3.0));
+    resampler.push(0, at(10), Some(-3.0));
+    resampler.push(1, at(20), None);
+    resampler.push(1, at(30), None);
+    assert!(resampler.push(2, at(40), Some(1.0)).is_none());
+    assert_eq!(resampler.flush().unwrap().1.unwrap(), None);
+    assert_eq!(resampler.values()[&0], Some(-3.0));
+}
+
+#[test]
+fn test_streaming_max_age() {
+    use crate::EngineOptions;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("COALESCE(#0, 0) + COALESCE(#1, 0) + COALESCE(#2, 0)")
+        .unwrap();
+    let mut streaming = fe
+        .streaming()
+        .with_max_age(Duration::from_secs(10))
+        .with_component_max_age(1, Duration::from_secs(60));
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let results = changes.clone();
+    streaming.on_change(move |result| results.lock().unwrap().push(result.as_ref().ok().copied()));
+
+    streaming.update_at(0, Some(1.0), at(100)).ok();
+    streaming.update_at(1, Some(2.0), at(100)).ok();
+    assert_eq!(
+        streaming.update_at(2, Some(4.0), at(105)).unwrap(),
+        Some(7.0)
+    );
+    assert_eq!(streaming.expire(at(110)).unwrap(), Some(7.0));
+    assert_eq!(streaming.expire(at(111)).unwrap(), Some(6.0));
+    assert_eq!(streaming.values()[&0], None);
+    // #2 expires before its update, and #1 has a longer maximum age.
+    assert_eq!(
+        streaming.update_at(2, Some(8.0), at(150)).unwrap(),
+        Some(10.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(1.0), at(161)).unwrap(),
+        Some(1.0)
+    );
+    assert_eq!(streaming.expire(at(500)).unwrap(), Some(0.0));
+    assert_eq!(
+        *changes.lock().unwrap(),
+        [7.0, 6.0, 2.0, 10.0, 8.0, 0.0, 1.0, 0.0].map(|x| Some(Some(x)))
+    );
+
+    // Values are timestamped with the clock of the options.
+    let now = Arc::new(Mutex::new(at(0)));
+    let clock = now.clone();
+    let options = EngineOptions::default().with_clock(move || *clock.lock().unwrap());
+    let fe = FormulaEngine::<f64>::try_new_with_options("COALESCE(#0, -1)", options).unwrap();
+    let mut streaming = fe.streaming().with_max_age(Duration::from_secs(1));
+    assert_eq!(streaming.update(0, Some(5.0)).unwrap(), Some(5.0));
+    *now.lock().unwrap() = at(2);
+    assert_eq!(streaming.update(1, None).unwrap(), Some(-1.0));
+}
+
+#[test]
+fn test_rolling() {
+    use crate::EngineOptions;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    for (formula, expected) in [
+        ("ROLLING_AVG(#0, 30s)", "ROLLING_AVG(#0, 30)"),
+        ("rolling_min(#0, 1.5m)", "ROLLING_MIN(#0, 90)"),
+        ("ROLLING_MAX(#0 * 2, 250ms)", "ROLLING_MAX(#0 * 2, 0.25)"),
+        ("#0 * 2h", "#0 * 7200"),
+    ] {
+        let fe = FormulaEngine::<f64>::try_new(formula).unwrap();
+        assert_eq!(fe.to_string(), expected);
+        assert_eq!(FormulaEngine::<f64>::try_new(expected).unwrap(), fe);
+    }
+    assert_eq!(
+        FormulaEngine::<f64>::try_new("ROLLING_AVG(#0)")
+            .unwrap_err()
+            .to_string(),
+        "ROLLING_AVG expects 2 arguments, got 1"
+    );
+    assert!(FormulaEngine::<f64>::try_new("#0 + 5sec").is_err());
+
+    let fe = FormulaEngine::<f64>::try_new("ROLLING_AVG(#0, 30s) - #1").unwrap();
+    assert_eq!(
+        fe.calculate(HashMap::from([(0, Some(1.0)), (1, Some(1.0))]))
+            .unwrap_err()
+            .to_string(),
+        "ROLLING_AVG can only be calculated by stateful engines, e.g. FormulaEngine::streaming"
+    );
+
+    // Without warming up, functions over a window use the samples so far.
+    let fe = FormulaEngine::<f64>::try_new_with_options(
+        "ROLLING_AVG(#0, 30s) * 100 + ROLLING_MIN(#0, #1) * 10 + ROLLING_MAX(#0, 1m)",
+        EngineOptions::default().with_warm_up(false),
+    )
+    .unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(1, Some(20.0), at(0)).ok();
+    assert_eq!(
+        streaming.update_at(0, Some(3.0), at(0)).unwrap(),
+        Some(333.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(1.0), at(10)).unwrap(),
+        Some(213.0)
+    );
+    // Samples at the same time replace each other, and samples without
+    // values are left out.
+    assert_eq!(
+        streaming.update_at(0, Some(5.0), at(10)).unwrap(),
+        Some(435.0)
+    );
+    // The sample at 0 leaves the window of ROLLING_MIN, and then that of
+    // ROLLING_AVG.
+    assert_eq!(streaming.update_at(0, None, at(20)).unwrap(), Some(455.0));
+    assert_eq!(
+        streaming.update_at(0, Some(2.0), at(30)).unwrap(),
+        Some(375.0)
+    );
+    // Updates of #1 only take samples for ROLLING_MIN, whose window it is.
+    assert_eq!(streaming.update_at(1, None, at(45)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(1, Some(1.0), at(50)).unwrap(),
+        Some(375.0)
+    );
+    assert_eq!(streaming.update_at(1, Some(-5.0), at(55)).unwrap(), None);
+}
+
+#[test]
+fn test_deriv() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+    assert_eq!(
+        FormulaEngine::<f64>::try_new("DERIV(#0, 1)")
+            .unwrap_err()
+            .to_string(),
+        "DERIV expects 1 argument, got 2"
+    );
+
+    let fe = FormulaEngine::<f64>::try_new("DERIV(#0 + #1)").unwrap();
+    assert_eq!(fe.to_string(), "DERIV(#0 + #1)");
+    let mut streaming = fe.streaming();
+    streaming.update_at(1, Some(0.0), at(0)).ok();
+    assert_eq!(streaming.update_at(0, Some(10.0), at(0)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(14.0), at(2_000)).unwrap(),
+        Some(2.0)
+    );
+    assert_eq!(
+        streaming.update_at(1, Some(4.0), at(2_000)).unwrap(),
+        Some(4.0)
+    );
+    assert_eq!(
+        streaming.update_at(1, Some(4.0), at(2_500)).unwrap(),
+        Some(0.0)
+    );
+    assert_eq!(streaming.update_at(0, None, at(3_000)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(4.0), at(4_500)).unwrap(),
+        Some(-5.0)
+    );
+}
+
+#[test]
+fn test_integrate() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    assert!(FormulaEngine::<f64>::try_new("INTEGRATE(#0, #1, #2)").is_err());
+
+    let fe = FormulaEngine::<f64>::try_new("INTEGRATE(#0, #1)").unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(1, Some(0.0), at(0)).ok();
+    assert_eq!(
+        streaming.update_at(0, Some(1000.0), at(0)).unwrap(),
+        Some(0.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(3000.0), at(1800)).unwrap(),
+        Some(1000.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(2000.0), at(1800)).unwrap(),
+        Some(750.0)
+    );
+    // Samples without values are left out.
+    assert_eq!(streaming.update_at(0, None, at(3600)).unwrap(), Some(750.0));
+    assert_eq!(
+        streaming.update_at(0, Some(2000.0), at(5400)).unwrap(),
+        Some(2750.0)
+    );
+    assert_eq!(
+        streaming.update_at(1, Some(1.0), at(5400)).unwrap(),
+        Some(0.0)
+    );
+    assert_eq!(
+        streaming.update_at(1, Some(1.0), at(6000)).unwrap(),
+        Some(0.0)
+    );
+    assert_eq!(
+        streaming.update_at(1, Some(0.0), at(7800)).unwrap(),
+        Some(1000.0)
+    );
+
+    let fe = FormulaEngine::<f64>::try_new("INTEGRATE(#0)").unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, None, at(0)).unwrap(), None);
+    for secs in (0..=3600).step_by(60) {
+        streaming.update_at(0, Some(secs as f64), at(secs)).ok();
+    }
+    assert!((streaming.current().unwrap().unwrap() - 1800.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_energy_accumulator() {
+    use crate::{EnergyInterval, ResetBoundary};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let interval = |start, end, energy| EnergyInterval {
+        start: at(start),
+        end: at(end),
+        energy,
+    };
+    let fe = FormulaEngine::<f64>::try_new("#0").unwrap();
+    assert!(fe
+        .energy_accumulator(ResetBoundary::BillingPeriod(0))
+        .is_err());
+    assert!(fe
+        .energy_accumulator(ResetBoundary::BillingPeriod(32))
+        .is_err());
+
+    // A power rising from 0 W by 1000 W per hour, split at the hours.
+    let mut accumulator = fe.energy_accumulator(ResetBoundary::Hourly).unwrap();
+    let persisted = Arc::new(Mutex::new(Vec::new()));
+    let intervals = persisted.clone();
+    accumulator.on_interval(move |interval| intervals.lock().unwrap().push(*interval));
+    assert_eq!(accumulator.current(), None);
+    assert!(accumulator.update_at(0, Some(0.0), at(0)).is_empty());
+    assert_eq!(accumulator.current(), Some(interval(0, 3600, 0.0)));
+    // Results without values are bridged, results at the same time replace
+    // each other and earlier results are ignored.
+    assert!(accumulator.update_at(0, None, at(1800)).is_empty());
+    assert!(accumulator.update_at(0, Some(5000.0), at(14400)).is_empty());
+    assert!(accumulator.update_at(0, Some(4000.0), at(14400)).is_empty());
+    assert!(accumulator.update_at(0, Some(1.0), at(3600)).is_empty());
+    assert_eq!(accumulator.current(), Some(interval(0, 3600, 500.0)));
+    assert_eq!(
+        accumulator.update_at(0, Some(4000.0), at(18000)),
+        vec![
+            interval(0, 3600, 500.0),
+            interval(3600, 7200, 1500.0),
+            interval(7200, 10800, 2500.0),
+            interval(10800, 14400, 3500.0),
+        ]
+    );
+    assert_eq!(accumulator.current(), Some(interval(14400, 18000, 4000.0)));
+    assert_eq!(persisted.lock().unwrap().len(), 4);
+
+    // Continuing from a snapshot completes the same intervals.
+    let mut restored = fe.energy_accumulator(ResetBoundary::Hourly).unwrap();
+    restored.restore(&accumulator.state_snapshot()).unwrap();
+    assert_eq!(restored.current(), accumulator.current());
+    assert_eq!(
+        restored.update_at(0, Some(0.0), at(21600)),
+        vec![interval(14400, 18000, 4000.0)]
+    );
+    assert_eq!(
+        accumulator.update_at(0, Some(0.0), at(21600)),
+        vec![interval(14400, 18000, 4000.0)]
+    );
+    assert_eq!(restored.current(), accumulator.current());
+    let mut snapshot = accumulator.state_snapshot();
+    snapshot.samples.reverse();
+    assert!(restored.restore(&snapshot).is_err());
+
+    let day = 24 * 3600;
+    let mut accumulator = fe.energy_accumulator(ResetBoundary::Daily).unwrap();
+    accumulator.update_at(0, Some(1000.0), at(3 * day + 3600));
+    assert_eq!(accumulator.current(), Some(interval(3 * day, 4 * day, 0.0)));
+
+    // 2024-01-20, in the period from 2024-01-15.
+    let mut accumulator = fe
+        .energy_accumulator(ResetBoundary::BillingPeriod(15))
+        .unwrap();
+    accumulator.update_at(0, Some(1000.0), at(19742 * day));
+    assert_eq!(
+        accumulator.current(),
+        Some(interval(19737 * day, 19768 * day, 0.0))
+    );
+    // 2024-02-10, in the period from 2024-01-31 to the end of February.
+    let mut accumulator = fe
+        .energy_accumulator(ResetBoundary::BillingPeriod(31))
+        .unwrap();
+    accumulator.update_at(0, Some(1000.0), at(19763 * day));
+    assert_eq!(
+        accumulator.current(),
+        Some(interval(19753 * day, 19782 * day, 0.0))
+    );
+}
+
+#[cfg(feature = "chrono-tz")]
+#[test]
+fn test_energy_accumulator_timezone() {
+    use crate::{EngineOptions, ResetBoundary};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let current = |timezone, boundary, secs| {
+        let options = EngineOptions::default().with_timezone(timezone);
+        let fe = FormulaEngine::<f64>::try_new_with_options("#0", options).unwrap();
+        let mut accumulator = fe.energy_accumulator(boundary).unwrap();
+        accumulator.update_at(0, Some(1000.0), at(secs));
+        accumulator
+            .current()
+            .map(|interval| (interval.start, interval.end))
+    };
+
+    // Hours in India start at half past the hour in UTC.
+    assert_eq!(
+        current(chrono_tz::Asia::Kolkata, ResetBoundary::Hourly, 36100),
+        Some((at(34200), at(37800)))
+    );
+    // 2024-03-31 in Berlin is an hour short, from 23:00 UTC to 22:00 UTC.
+    let day = 24 * 3600;
+    assert_eq!(
+        current(chrono_tz::Europe::Berlin, ResetBoundary::Daily, 19813 * day),
+        Some((at(19812 * day + 23 * 3600), at(19813 * day + 22 * 3600)))
+    );
+}
+
+#[test]
+fn test_lag() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("#0 - LAG(#0)").unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(5.0), at(0)).unwrap(), None);
+    assert_eq!(streaming.update_at(0, Some(7.0), at(1)).unwrap(), Some(2.0));
+    assert_eq!(streaming.update_at(0, Some(6.0), at(1)).unwrap(), Some(1.0));
+    assert_eq!(streaming.update_at(0, Some(6.0), at(2)).unwrap(), Some(0.0));
+
+    let fe = FormulaEngine::<f64>::try_new("LAG(#0, 2) + LAG(#0, 0) * 10").unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(1.0), at(0)).unwrap(), None);
+    assert_eq!(streaming.update_at(0, Some(2.0), at(1)).unwrap(), None);
+    // Samples without values are left out.
+    assert_eq!(streaming.update_at(0, None, at(2)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(3.0), at(3)).unwrap(),
+        Some(31.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(4.0), at(4)).unwrap(),
+        Some(42.0)
+    );
+
+    let fe = FormulaEngine::<f64>::try_new("LAG(#0, -1)").unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(1.0), at(0)).unwrap(), None);
+}
+
+#[test]
+fn test_delta() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("DELTA(#0)").unwrap();
+    assert_eq!(fe.to_string(), "DELTA(#0)");
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(100.0), at(0)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(104.0), at(1)).unwrap(),
+        Some(4.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(105.0), at(1)).unwrap(),
+        Some(5.0)
+    );
+    assert_eq!(streaming.update_at(0, None, at(2)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(107.0), at(3)).unwrap(),
+        Some(2.0)
+    );
+
+    assert!(FormulaEngine::<f64>::try_new("DELTA(#0, #1)").is_err());
+}
+
+#[test]
+fn test_delta_wrap() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("DELTA_WRAP(#0, 65536)").unwrap();
+    assert_eq!(fe.to_string(), "DELTA_WRAP(#0, 65536)");
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(65530.0), at(0)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(65534.0), at(1)).unwrap(),
+        Some(4.0)
+    );
+    assert_eq!(streaming.update_at(0, Some(2.0), at(2)).unwrap(), Some(4.0));
+    assert_eq!(streaming.update_at(0, Some(2.0), at(3)).unwrap(), Some(0.0));
+
+    let fe = FormulaEngine::<f64>::try_new("DELTA_WRAP(#0, #1)").unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(0, Some(10.0), at(0)).unwrap_err();
+    assert_eq!(streaming.update_at(1, None, at(0)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(12.0), at(1)).unwrap(),
+        Some(2.0)
+    );
+    // Wrapping around needs the size of the counter.
+    assert_eq!(streaming.update_at(0, Some(1.0), at(2)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(1, Some(16.0), at(2)).unwrap(),
+        Some(5.0)
+    );
+
+    assert!(FormulaEngine::<f64>::try_new("DELTA_WRAP(#0)").is_err());
+}
+
+#[test]
+fn test_ramp_limit() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("RAMP_LIMIT(#0, 10)").unwrap();
+    assert_eq!(fe.to_string(), "RAMP_LIMIT(#0, 10)");
+    let mut streaming = fe.streaming();
+    assert_eq!(
+        streaming.update_at(0, Some(100.0), at(0)).unwrap(),
+        Some(100.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(200.0), at(2)).unwrap(),
+        Some(120.0)
+    );
+    // An update at the same time is limited by the result before it.
+    assert_eq!(
+        streaming.update_at(0, Some(50.0), at(2)).unwrap(),
+        Some(80.0)
+    );
+    assert_eq!(streaming.update_at(0, None, at(3)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(85.0), at(4)).unwrap(),
+        Some(85.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(0.0), at(5)).unwrap(),
+        Some(75.0)
+    );
+
+    assert!(FormulaEngine::<f64>::try_new("RAMP_LIMIT(#0)").is_err());
+}
+
+#[test]
+fn test_tw_avg() {
+    use crate::EngineOptions;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let options = EngineOptions::default().with_warm_up(false);
+    let fe = FormulaEngine::<f64>::try_new_with_options("TW_AVG(#0, 10s)", options).unwrap();
+    assert_eq!(fe.to_string(), "TW_AVG(#0, 10)");
+    let mut streaming = fe.streaming();
+    assert_eq!(
+        streaming.update_at(0, Some(10.0), at(100)).unwrap(),
+        Some(10.0)
+    );
+    // 10 held for 8 seconds and 20 for 2, unlike the average of the samples.
+    assert_eq!(
+        streaming.update_at(0, Some(20.0), at(108)).unwrap(),
+        Some(10.0)
+    );
+    assert_eq!(
+        streaming.update_at(0, Some(20.0), at(110)).unwrap(),
+        Some(12.0)
+    );
+    // The window starts in the middle of the first sample.
+    assert_eq!(
+        streaming.update_at(0, Some(0.0), at(114)).unwrap(),
+        Some(16.0)
+    );
+    assert_eq!(streaming.update_at(0, None, at(115)).unwrap(), None);
+
+    assert!(FormulaEngine::<f64>::try_new("TW_AVG(#0)").is_err());
+}
+
+#[test]
+fn test_temporal_samples() {
+    use crate::EngineOptions;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    // Updates of components the arguments don't depend on take no samples.
+    let fe = FormulaEngine::<f64>::try_new("DELTA(#0) + #1 * 0").unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(1, Some(1.0), at(0)).ok();
+    assert_eq!(streaming.update_at(0, Some(100.0), at(0)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(150.0), at(1)).unwrap(),
+        Some(50.0)
+    );
+    assert_eq!(
+        streaming.update_at(1, Some(2.0), at(2)).unwrap(),
+        Some(50.0)
+    );
+
+    let options = EngineOptions::default().with_warm_up(false);
+    let fe = FormulaEngine::<f64>::try_new_with_options("ROLLING_AVG(#0, 10s) + #1 * 0", options)
+        .unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(1, Some(1.0), at(0)).ok();
+    assert_eq!(streaming.update_at(0, Some(0.0), at(0)).unwrap(), Some(0.0));
+    for secs in 1..4 {
+        assert_eq!(
+            streaming.update_at(1, Some(1.0), at(secs)).unwrap(),
+            Some(0.0)
+        );
+    }
+    assert_eq!(
+        streaming.update_at(0, Some(10.0), at(4)).unwrap(),
+        Some(5.0)
+    );
+    assert_eq!(streaming.update_at(1, Some(2.0), at(5)).unwrap(), Some(5.0));
+
+    // Updates with the same value still take a sample.
+    assert_eq!(
+        streaming.update_at(0, Some(10.0), at(6)).unwrap(),
+        Some(20.0 / 3.0)
+    );
+
+    // Expired values take a sample too.
+    let mut streaming = fe
+        .streaming()
+        .with_component_max_age(0, Duration::from_secs(5));
+    streaming.update_at(1, Some(1.0), at(0)).ok();
+    streaming.update_at(0, Some(4.0), at(0)).ok();
+    assert_eq!(streaming.update_at(0, Some(8.0), at(4)).unwrap(), Some(6.0));
+    assert_eq!(streaming.expire(at(10)).unwrap(), Some(8.0));
+}
+
+#[test]
+fn test_temporal_state() {
+    use crate::{EngineOptions, TemporalFunction};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let formula = "ROLLING_AVG(#0, 10s) + DELTA(#0)";
+    let options = EngineOptions::default().with_warm_up(false);
+    let fe = FormulaEngine::<f64>::try_new_with_options(formula, options).unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(1.0), at(0)).unwrap(), None);
+    assert_eq!(streaming.update_at(0, Some(3.0), at(5)).unwrap(), Some(4.0));
+
+    // A snapshot continues in another engine, e.g. after a restart.
+    let snapshot = streaming.state_snapshot();
+    assert_eq!(snapshot.functions.len(), 2);
+    assert_eq!(snapshot.functions[0].function, TemporalFunction::RollingAvg);
+    assert_eq!(
+        snapshot.functions[0].samples,
+        vec![(at(0), 1.0), (at(5), 3.0)]
+    );
+    let mut restored = fe.streaming();
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(restored.state_snapshot(), snapshot);
+    assert_eq!(streaming.update_at(0, Some(5.0), at(6)).unwrap(), Some(5.0));
+    assert_eq!(restored.update_at(0, Some(5.0), at(6)).unwrap(), Some(5.0));
+    assert!(FormulaEngine::<f64>::try_new("DELTA(#0)")
+        .unwrap()
+        .streaming()
+        .restore(&snapshot)
+        .is_err());
+
+    streaming.reset();
+    assert_eq!(streaming.update_at(0, Some(6.0), at(7)).unwrap(), None);
+    assert_eq!(streaming.update_at(0, Some(8.0), at(8)).unwrap(), Some(9.0));
+
+    // By default, functions over a window wait for a whole window.
+    let fe = FormulaEngine::<f64>::try_new("ROLLING_MAX(#0, 10s)").unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(1.0), at(0)).unwrap(), None);
+    assert_eq!(streaming.update_at(0, Some(2.0), at(9)).unwrap(), None);
+    assert_eq!(
+        streaming.update_at(0, Some(0.0), at(10)).unwrap(),
+        Some(2.0)
+    );
+    streaming.reset();
+    assert_eq!(streaming.update_at(0, Some(0.0), at(11)).unwrap(), None);
+}
+
+#[test]
+fn test_history() {
+    use crate::EngineOptions;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let stored = [(at(0), 1.0), (at(4), 3.0), (at(8), 5.0)];
+
+    // Seeding warms up a rolling average with stored samples, and the
+    // history keeps at most the maximum number of samples.
+    let options = EngineOptions::default().with_max_history_len(3);
+    let fe = FormulaEngine::<f64>::try_new_with_options("ROLLING_AVG(#0, 10s)", options).unwrap();
+    let mut streaming = fe.streaming();
+    assert_eq!(streaming.update_at(0, Some(7.0), at(10)).unwrap(), None);
+    let mut streaming = fe.streaming();
+    streaming.seed_history(0, &stored);
+    assert_eq!(streaming.history(0), stored);
+    assert_eq!(streaming.history(1), vec![]);
+    assert_eq!(
+        streaming.update_at(0, Some(7.0), at(10)).unwrap(),
+        Some(5.0)
+    );
+    assert_eq!(
+        streaming.history(0),
+        vec![(at(4), 3.0), (at(8), 5.0), (at(10), 7.0)]
+    );
+
+    // The history keeps samples up to the maximum age before the latest.
+    let options = EngineOptions::default()
+        .with_warm_up(false)
+        .with_max_history_age(Duration::from_secs(5));
+    let fe = FormulaEngine::<f64>::try_new_with_options("ROLLING_MAX(#0, 1m)", options).unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(0, Some(9.0), at(0)).ok();
+    streaming.update_at(0, Some(1.0), at(4)).ok();
+    assert_eq!(streaming.update_at(0, Some(2.0), at(6)).unwrap(), Some(2.0));
+    assert_eq!(streaming.history(0), vec![(at(4), 1.0), (at(6), 2.0)]);
+
+    // Only functions of the placeholder itself keep its history.
+    let fe = FormulaEngine::<f64>::try_new("ROLLING_AVG(#0 * 2, 10s)").unwrap();
+    let mut incremental = fe.incremental();
+    incremental.seed_history(0, &stored);
+    assert_eq!(incremental.history(0), vec![]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_state_snapshot_serde() {
+    use crate::StateSnapshot;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("INTEGRATE(#0) + ROLLING_AVG(#0, 1m)").unwrap();
+    let mut streaming = fe.streaming();
+    streaming.update_at(0, Some(1000.0), at(0)).ok();
+    streaming.update_at(0, Some(2000.0), at(1800)).ok();
+
+    let json = serde_json::to_string(&streaming.state_snapshot()).unwrap();
+    let snapshot: StateSnapshot<f64> = serde_json::from_str(&json).unwrap();
+    assert_eq!(snapshot, streaming.state_snapshot());
+    let mut restored = fe.streaming();
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(
+        restored.update_at(0, Some(2000.0), at(3600)).unwrap(),
+        streaming.update_at(0, Some(2000.0), at(3600)).unwrap()
+    );
+}
+
+#[test]
+fn test_sample() {
+    use crate::Sample;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let fe = FormulaEngine::<f64>::try_new("#0 + SUM(#*)").unwrap();
+    let samples = HashMap::from([
+        (0, Sample::new(Some(1.0), at(0))),
+        (1, Sample::new(Some(2.0), at(1))),
+    ]);
+    assert_eq!(fe.calculate(&samples).unwrap(), Some(4.0));
+    assert_eq!(fe.calculate(samples).unwrap(), Some(4.0));
+
+    // Streaming engines use the timestamps of the samples, not when they
+    // arrive.
+    let fe = FormulaEngine::<f64>::try_new("INTEGRATE(#0)").unwrap();
+    let mut streaming = fe.streaming().with_max_age(Duration::from_secs(60));
+    assert_eq!(
+        streaming
+            .update_sample(0, Sample::new(Some(1.0), at(0)))
+            .unwrap(),
+        Some(0.0)
+    );
+    assert_eq!(
+        streaming
+            .update_sample(0, Sample::new(Some(1.0), at(3600)))
+            .unwrap(),
+        Some(1.0)
+    );
+    assert_eq!(streaming.expire(at(3700)).unwrap(), Some(1.0));
+    assert_eq!(streaming.values()[&0], None);
+}
+
+#[test]
+fn test_quality() {
+    use crate::Quality;
+
+    let qualities = HashMap::from([(0, Quality::Bad), (1, Quality::Suspect)]);
+    let values = HashMap::from([(0, Some(1.0)), (1, Some(2.0)), (2, Some(3.0)), (3, None)]);
+    for (formula, expected) in [
+        ("#2 * 2", (Some(6.0), Quality::Good)),
+        ("#1 + #2", (Some(5.0), Quality::Suspect)),
+        ("#0 + #1 + #2", (Some(6.0), Quality::Bad)),
+        // Only the argument COALESCE falls back to is used.
+        ("COALESCE(#2, #0)", (Some(3.0), Quality::Good)),
+        ("COALESCE(#3, #1)", (Some(2.0), Quality::Suspect)),
+        // All arguments are compared to select one.
+        ("MAX(#0, #1)", (Some(2.0), Quality::Bad)),
+        ("MIN(#2, #0 + 5)", (Some(3.0), Quality::Bad)),
+        ("NULLIF(#2, #1)", (Some(3.0), Quality::Suspect)),
+        ("MAX(#2, #*)", (Some(3.0), Quality::Bad)),
+        // The condition of IF is used, but not the branch that isn't taken.
+        ("IF(#1 > 0, #2, #0)", (Some(3.0), Quality::Suspect)),
+        ("IF(#2 > 0, #2, #0)", (Some(3.0), Quality::Good)),
+        ("LET x = #1 IN x + #2", (Some(5.0), Quality::Suspect)),
+        ("LET x = #0 IN COALESCE(#2, x)", (Some(3.0), Quality::Good)),
+    ] {
+        let fe = FormulaEngine::<f64>::try_new(formula).unwrap();
+        assert_eq!(
+            fe.calculate_with_quality(&values, &qualities).unwrap(),
+            expected,
+            "{}",
+            formula
+        );
+    }
+
+    let fe = FormulaEngine::<f64>::try_new("#0 + #4").unwrap();
+    assert!(fe.calculate_with_quality(&values, &qualities).is_err());
+
+    // Named placeholders have qualities by name.
+    let qualities = HashMap::from([("m-1".to_string(), Quality::Suspect)]);
+    let values = HashMap::from([
+        ("pv".to_string(), Some(1.0)),
+        ("m-1".to_string(), Some(2.0)),
+    ]);
+    let fe = FormulaEngine::<f64>::try_new("$pv * 2").unwrap();
+    assert_eq!(
+        fe.calculate_named_with_quality(values.clone(), &qualities)
+            .unwrap(),
+        (Some(2.0), Quality::Good)
+    );
+    let fe = FormulaEngine::<f64>::try_new("$pv + #\"m-1\"").unwrap();
+    assert_eq!(
+        fe.calculate_named_with_quality(values, &qualities).unwrap(),
+        (Some(3.0), Quality::Suspect)
+    );
+}
+
+#[test]
+fn test_provenance() {
+    use crate::{CoalesceChoice, DivisionByZero, EngineOptions};
+
+    let choice = |coalesce: &str, used| CoalesceChoice {
+        coalesce: coalesce.to_string(),
+        used,
+    };
+    let values = HashMap::from([(0, None), (1, Some(1.0)), (2, Some(2.0))]);
+    let fe = FormulaEngine::<f64>::try_new(
+        "COALESCE(#0, COALESCE(#0, #2), COALESCE(#1, 0)) + IF(#1 > 0, 0, COALESCE(#0, 1))",
+    )
+    .unwrap();
+    assert_eq!(
+        fe.calculate_with_provenance(&values).unwrap(),
+        (
+            Some(2.0),
+            vec![
+                choice("COALESCE(#0, COALESCE(#0, #2), COALESCE(#1, 0))", Some(1)),
+                choice("COALESCE(#0, #2)", Some(1)),
+            ]
+        )
+    );
+
+    let fe = FormulaEngine::<f64>::try_new("LET x = COALESCE(#0, #0) IN COALESCE(x, #*)").unwrap();
+    assert_eq!(
+        fe.calculate_with_provenance(&values).unwrap(),
+        (
+            Some(1.0),
+            vec![
+                choice("COALESCE(#0, #0)", None),
+                choice("COALESCE(x, #*)", Some(2)),
+            ]
+        )
+    );
+
+    // Branches after a `None` condition are not taken.
+    let values = HashMap::from([(0, Some(3.0)), (1, None)]);
+    let fe = FormulaEngine::<f64>::try_new("IF(#1, COALESCE(#0, 1), 2)").unwrap();
+    assert_eq!(
+        fe.calculate_with_provenance(&values).unwrap(),
+        (None, vec![])
+    );
+    let options = EngineOptions::default().with_division_by_zero(DivisionByZero::Error);
+    let fe = FormulaEngine::<f64>::try_new_with_options("IF(#1, COALESCE(#0 / 0, 1), 2)", options)
+        .unwrap();
+    assert_eq!(fe.calculate(&values).unwrap(), None);
+    assert_eq!(
+        fe.calculate_with_provenance(&values).unwrap(),
+        (None, vec![])
+    );
+}
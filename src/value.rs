@@ -0,0 +1,93 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+use std::{
+    fmt::Display,
+    ops::{Add, Div, Mul, Neg, Sub},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use num_traits::{Float, FromPrimitive, One, Zero};
+
+/// The numeric types formulas can be written over, e.g. `f32` and `f64`.
+///
+/// This trait is implemented for all types providing the required
+/// arithmetic, so it only needs to be named in generic code. Calculating
+/// formulas additionally requires `num_traits::Float`, for functions like
+/// `POW` and the handling of NaN.
+pub trait FormulaValue:
+    FromStr
+    + Display
+    + Copy
+    + Neg<Output = Self>
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + PartialOrd
+    + Zero
+    + One
+    + FromPrimitive
+{
+}
+
+impl<T> FormulaValue for T where
+    T: FromStr
+        + Display
+        + Copy
+        + Neg<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Div<Output = T>
+        + PartialOrd
+        + Zero
+        + One
+        + FromPrimitive
+{
+}
+
+/// A value of a component with the time it was measured at.
+///
+/// Maps of samples and references to them can be calculated like maps of
+/// values, and
+/// [`StreamingFormulaEngine::update_sample`](crate::StreamingFormulaEngine::update_sample)
+/// checks their age and samples temporal functions at their timestamps
+/// instead of when they arrive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sample<T> {
+    /// The value, or `None` if it is missing.
+    pub value: Option<T>,
+    /// When the value was measured.
+    pub timestamp: SystemTime,
+}
+
+impl<T> Sample<T> {
+    /// Create a sample of `value` measured at `timestamp`.
+    pub fn new(value: Option<T>, timestamp: SystemTime) -> Self {
+        Self { value, timestamp }
+    }
+}
+
+/// Whether a value is NaN, the only value that isn't ordered with itself,
+/// for values that don't implement `num_traits::Float`.
+pub(crate) fn is_nan<T: FormulaValue>(value: T) -> bool {
+    value.partial_cmp(&value).is_none()
+}
+
+/// Whether a value is infinite, for values that don't implement
+/// `num_traits::Float`: unlike finite values, infinities times zero are NaN.
+pub(crate) fn is_infinite<T: FormulaValue>(value: T) -> bool {
+    !is_nan(value) && is_nan(value * T::zero())
+}
+
+/// Whether two values are the same, telling apart `0` and `-0` and treating
+/// NaNs with the same bits as the same.
+pub(crate) fn same_value<T: FormulaValue + Float>(a: Option<T>, b: Option<T>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.integer_decode() == b.integer_decode(),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
@@ -0,0 +1,146 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Traversal of expressions, for analyses of formulas outside the engine.
+//!
+//! ```rust
+//! use frequenz_microgrid_formula_engine::{walk_expr, Expr, FormulaEngine, Function, Visitor};
+//!
+//! /// Counts the calls of each function.
+//! #[derive(Default)]
+//! struct Calls(Vec<Function>);
+//!
+//! impl<T> Visitor<T> for Calls {
+//!     fn visit_expr(&mut self, expr: &Expr<T>) {
+//!         if let Expr::Function { function, .. } = expr {
+//!             self.0.push(function.clone());
+//!         }
+//!         walk_expr(self, expr);
+//!     }
+//! }
+//!
+//! let fe = FormulaEngine::<f64>::try_new("MAX(#0, MIN(#1, 2))").unwrap();
+//! let mut calls = Calls::default();
+//! calls.visit_expr(fe.expr());
+//! assert_eq!(calls.0.len(), 2);
+//! ```
+
+use crate::expression::Expr;
+
+/// A visitor of the nodes of an expression.
+///
+/// The default implementation walks all nodes, so implementations only need
+/// to handle the nodes they are interested in, and call [`walk_expr`] to
+/// continue with the operands.
+pub trait Visitor<T> {
+    fn visit_expr(&mut self, expr: &Expr<T>) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visit the operands of an expression, in the order they appear in the
+/// formula.
+pub fn walk_expr<T, V: Visitor<T> + ?Sized>(visitor: &mut V, expr: &Expr<T>) {
+    for child in expr.children() {
+        visitor.visit_expr(child);
+    }
+}
+
+impl<T> Expr<T> {
+    /// Get the operands of the expression, in the order they appear in the
+    /// formula.
+    pub fn children(&self) -> Vec<&Expr<T>> {
+        match self {
+            Expr::Value(_)
+            | Expr::Component(_)
+            | Expr::Wildcard
+            | Expr::Named(_)
+            | Expr::Variable(_)
+            | Expr::Reference(_)
+            | Expr::TimeOfUse(_)
+            | Expr::Time(_) => Vec::new(),
+            Expr::UnaryMinus(operand) | Expr::Not(operand) => vec![operand],
+            Expr::Op { lhs, rhs, .. }
+            | Expr::CustomOp { lhs, rhs, .. }
+            | Expr::Let {
+                value: lhs,
+                body: rhs,
+                ..
+            } => vec![lhs, rhs],
+            Expr::Function { args, .. }
+            | Expr::Custom { args, .. }
+            | Expr::Temporal { args, .. } => args.iter().collect(),
+            Expr::Select { args, branches, .. } => args.iter().chain(branches).collect(),
+        }
+    }
+
+    /// Fold the expression bottom-up: `f` is called with each node and the
+    /// results for its operands, as given by [`Expr::children`].
+    pub fn fold<A>(&self, f: &mut impl FnMut(&Expr<T>, Vec<A>) -> A) -> A {
+        let children = self
+            .children()
+            .into_iter()
+            .map(|child| child.fold(f))
+            .collect();
+        f(self, children)
+    }
+}
+
+impl<T: Clone> Expr<T> {
+    /// Rebuild the expression with its operands replaced by the results of
+    /// `f` for them, for rewriting formulas.
+    pub(crate) fn try_map_children<'a, E>(
+        &'a self,
+        mut f: impl FnMut(&'a Expr<T>) -> Result<Expr<T>, E>,
+    ) -> Result<Expr<T>, E> {
+        let mut node = |expr: &'a Expr<T>| f(expr).map(Box::new);
+        Ok(match self {
+            Expr::UnaryMinus(operand) => Expr::UnaryMinus(node(operand)?),
+            Expr::Not(operand) => Expr::Not(node(operand)?),
+            Expr::Op { lhs, op, rhs } => Expr::Op {
+                lhs: node(lhs)?,
+                op: op.clone(),
+                rhs: node(rhs)?,
+            },
+            Expr::CustomOp { symbol, lhs, rhs } => Expr::CustomOp {
+                symbol: symbol.clone(),
+                lhs: node(lhs)?,
+                rhs: node(rhs)?,
+            },
+            Expr::Let { name, value, body } => Expr::Let {
+                name: name.clone(),
+                value: node(value)?,
+                body: node(body)?,
+            },
+            Expr::Function { function, args } => Expr::Function {
+                function: function.clone(),
+                args: args.iter().map(f).collect::<Result<_, _>>()?,
+            },
+            Expr::Custom { name, args } => Expr::Custom {
+                name: name.clone(),
+                args: args.iter().map(f).collect::<Result<_, _>>()?,
+            },
+            Expr::Temporal { function, args } => Expr::Temporal {
+                function: function.clone(),
+                args: args.iter().map(f).collect::<Result<_, _>>()?,
+            },
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => Expr::Select {
+                function: function.clone(),
+                args: args.iter().map(&mut f).collect::<Result<_, _>>()?,
+                branches: branches.iter().map(f).collect::<Result<_, _>>()?,
+            },
+            Expr::Value(_)
+            | Expr::Component(_)
+            | Expr::Wildcard
+            | Expr::Named(_)
+            | Expr::Variable(_)
+            | Expr::Reference(_)
+            | Expr::TimeOfUse(_)
+            | Expr::Time(_) => self.clone(),
+        })
+    }
+}
@@ -0,0 +1,447 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Formulas compiled to a flat sequence of instructions for a stack machine,
+//! so that they can be calculated without walking the expression tree.
+
+use std::ops::Neg;
+
+use num_traits::Float;
+
+use crate::{
+    display::named_placeholder,
+    error::FormulaError,
+    expression::{from_bool, Expr, Function, Inputs, Op, TimeFunction},
+    functions::FunctionRegistry,
+    options::{DivisionByZero, EngineOptions},
+    value::FormulaValue,
+};
+
+/// The arguments of a function call, on top of the stack.
+#[derive(Debug, Clone, Copy)]
+enum Args {
+    /// A fixed number of arguments.
+    Count(usize),
+    /// The arguments pushed since the last mark, for calls with `#*`.
+    Marked,
+}
+
+#[derive(Debug, Clone)]
+enum Instruction<T> {
+    Push(Option<T>),
+    Component(u64),
+    Named(String),
+    /// Push all given values, for `#*`.
+    Wildcard,
+    /// Mark where the arguments of a call with `#*` start.
+    Mark,
+    /// Pop the value of a `LET` variable into its slot.
+    Store(usize),
+    /// Push the value of a `LET` variable from its slot.
+    Load(usize),
+    /// Push the value of a variable that isn't bound by the formula itself.
+    Variable(String),
+    Reference(String),
+    TimeOfUse(String),
+    Time(TimeFunction),
+    Neg,
+    Not,
+    Op(Op),
+    /// A division or modulo whose zero divisors make the result `None`, or
+    /// fail with the given error.
+    Div {
+        op: Op,
+        error: Option<String>,
+    },
+    CustomOp(String),
+    Call(Function, Args),
+    Custom(String, Args),
+    /// Pop the condition of an IF or CASE, continuing with its branch if it
+    /// is true, and jumping to `next` if it is false, or to `end` with a
+    /// `None` result if it is `None`.
+    Branch {
+        next: usize,
+        end: usize,
+    },
+    /// Pop the arguments of a selecting function and jump to the branch of
+    /// the argument it selects, or to `end` with a `None` result.
+    Select {
+        function: Function,
+        args: Args,
+        branches: Vec<usize>,
+        end: usize,
+    },
+    Jump(usize),
+    Fail(String),
+}
+
+/// A formula compiled for a stack machine.
+#[derive(Debug, Clone)]
+pub(crate) struct Program<T> {
+    instructions: Vec<Instruction<T>>,
+    /// The number of `LET` variables, kept at the bottom of the stack.
+    slots: usize,
+    /// The largest number of values on the stack, not counting `#*`.
+    depth: usize,
+}
+
+impl<T: FormulaValue> Program<T> {
+    /// Compile a validated expression, to be calculated with the given
+    /// options.
+    pub(crate) fn compile(expr: &Expr<T>, options: &EngineOptions) -> Self {
+        let mut compiler = Compiler {
+            instructions: Vec::new(),
+            scope: Vec::new(),
+            slots: 0,
+            depth: 0,
+            max_depth: 0,
+            division_by_zero: options.division_by_zero(),
+        };
+        compiler.compile(expr);
+        Self {
+            instructions: compiler.instructions,
+            slots: compiler.slots,
+            depth: compiler.max_depth,
+        }
+    }
+
+    /// Calculate the result of the program, like [`Expr::calculate`] for
+    /// the expression it was compiled from.
+    pub(crate) fn run(
+        &self,
+        values: &impl Inputs<T>,
+        options: &EngineOptions,
+        functions: &FunctionRegistry<T>,
+    ) -> Result<Option<T>, FormulaError>
+    where
+        T: Float,
+    {
+        let mut stack = Vec::with_capacity(self.slots + self.depth);
+        stack.resize(self.slots, None);
+        let mut marks = Vec::new();
+        let mut pc = 0;
+        while let Some(instruction) = self.instructions.get(pc) {
+            pc += 1;
+            let value = match instruction {
+                Instruction::Push(value) => *value,
+                Instruction::Component(id) => options.placeholder_value(
+                    values
+                        .component(*id)
+                        .ok_or(FormulaError("Placeholder out of bounds".to_string()))?,
+                ),
+                Instruction::Named(name) => {
+                    options.placeholder_value(values.named(name).ok_or_else(|| {
+                        FormulaError(format!("Missing value for {}", named_placeholder(name)))
+                    })?)
+                }
+                Instruction::Wildcard => {
+                    stack.extend(Expr::wildcard_values(values, options)?);
+                    continue;
+                }
+                Instruction::Mark => {
+                    marks.push(stack.len());
+                    continue;
+                }
+                Instruction::Store(slot) => {
+                    stack[*slot] = pop(&mut stack);
+                    continue;
+                }
+                Instruction::Load(slot) => stack[*slot],
+                Instruction::Variable(name) => values
+                    .variable(name)
+                    .ok_or_else(|| FormulaError(format!("Unknown variable: {}", name)))?,
+                Instruction::Reference(name) => values
+                    .reference(name)
+                    .ok_or_else(|| FormulaError(format!("Unknown formula: {}", name)))?,
+                Instruction::TimeOfUse(name) => Some(from_bool(options.in_tou_window(name))),
+                Instruction::Time(function) => function.apply(options),
+                Instruction::Neg => pop(&mut stack).map(Neg::neg),
+                Instruction::Not => pop(&mut stack).map(|x| from_bool(x == T::zero())),
+                Instruction::Op(op) => {
+                    let rhs = pop(&mut stack);
+                    op.apply(pop(&mut stack), rhs)
+                }
+                Instruction::Div { op, error } => {
+                    let rhs = pop(&mut stack);
+                    let lhs = pop(&mut stack);
+                    match (rhs == Some(T::zero()), error) {
+                        (true, Some(error)) => return Err(FormulaError(error.clone())),
+                        (true, None) => None,
+                        (false, _) => op.apply(lhs, rhs),
+                    }
+                }
+                Instruction::CustomOp(symbol) => {
+                    let rhs = pop(&mut stack);
+                    let lhs = pop(&mut stack);
+                    match functions.operator(symbol) {
+                        Some(op) => (op.function)(lhs, rhs),
+                        None => return Err(FormulaError(format!("Unknown operator: {}", symbol))),
+                    }
+                }
+                Instruction::Call(function, args) => {
+                    let start = args.start(&stack, &mut marks);
+                    let result = function.apply(&stack[start..], options);
+                    stack.truncate(start);
+                    result
+                }
+                Instruction::Custom(name, args) => {
+                    let start = args.start(&stack, &mut marks);
+                    let result = match functions.get(name) {
+                        Some(function) => function(&stack[start..]),
+                        None => return Err(FormulaError(format!("Unknown function: {}", name))),
+                    };
+                    stack.truncate(start);
+                    result
+                }
+                Instruction::Branch { next, end } => match pop(&mut stack) {
+                    Some(c) if c != T::zero() => continue,
+                    Some(_) => {
+                        pc = *next;
+                        continue;
+                    }
+                    None => {
+                        pc = *end;
+                        None
+                    }
+                },
+                Instruction::Select {
+                    function,
+                    args,
+                    branches,
+                    end,
+                } => {
+                    let start = args.start(&stack, &mut marks);
+                    let selected = function.select(&stack[start..]);
+                    stack.truncate(start);
+                    match selected {
+                        Some(i) => {
+                            pc = branches[i];
+                            continue;
+                        }
+                        None => {
+                            pc = *end;
+                            None
+                        }
+                    }
+                }
+                Instruction::Jump(target) => {
+                    pc = *target;
+                    continue;
+                }
+                Instruction::Fail(error) => return Err(FormulaError(error.clone())),
+            };
+            stack.push(value);
+        }
+        Ok(pop(&mut stack))
+    }
+}
+
+impl Args {
+    /// Get the index of the first argument on the stack.
+    fn start<T>(self, stack: &[Option<T>], marks: &mut Vec<usize>) -> usize {
+        match self {
+            Args::Count(count) => stack.len() - count,
+            Args::Marked => marks
+                .pop()
+                .unwrap_or_else(|| unreachable!("calls with #* are marked")),
+        }
+    }
+}
+
+/// Pop the value on top of the stack.
+fn pop<T>(stack: &mut Vec<Option<T>>) -> Option<T> {
+    stack
+        .pop()
+        .unwrap_or_else(|| unreachable!("programs don't pop more than they push"))
+}
+
+struct Compiler<'a, T> {
+    instructions: Vec<Instruction<T>>,
+    /// The `LET` variables in scope, with their slots.
+    scope: Vec<(&'a str, usize)>,
+    slots: usize,
+    /// The number of values on the stack after the instructions so far.
+    depth: usize,
+    max_depth: usize,
+    division_by_zero: DivisionByZero,
+}
+
+impl<'a, T: FormulaValue> Compiler<'a, T> {
+    /// Compile the instructions pushing the value of an expression.
+    fn compile(&mut self, expr: &'a Expr<T>) {
+        let depth = self.depth;
+        match expr {
+            Expr::Value(value) => self.emit(Instruction::Push(*value)),
+            Expr::UnaryMinus(expr) => {
+                self.compile(expr);
+                self.emit(Instruction::Neg);
+            }
+            Expr::Not(expr) => {
+                self.compile(expr);
+                self.emit(Instruction::Not);
+            }
+            Expr::Op { lhs, op, rhs } => {
+                self.compile(lhs);
+                self.compile(rhs);
+                self.emit(match (op, self.division_by_zero) {
+                    (Op::Div | Op::Mod, DivisionByZero::None) => Instruction::Div {
+                        op: op.clone(),
+                        error: None,
+                    },
+                    (Op::Div | Op::Mod, DivisionByZero::Error) => Instruction::Div {
+                        op: op.clone(),
+                        error: Some(format!("Division by zero: {}", expr)),
+                    },
+                    (op, _) => Instruction::Op(op.clone()),
+                });
+            }
+            Expr::Function {
+                function: Function::If | Function::Case,
+                args,
+            } => self.compile_branches(args, args),
+            Expr::Function { function, args } => {
+                let args = self.compile_args(args);
+                self.emit(Instruction::Call(function.clone(), args));
+            }
+            Expr::CustomOp { symbol, lhs, rhs } => {
+                self.compile(lhs);
+                self.compile(rhs);
+                self.emit(Instruction::CustomOp(symbol.clone()));
+            }
+            Expr::Custom { name, args } => {
+                let args = self.compile_args(args);
+                self.emit(Instruction::Custom(name.clone(), args));
+            }
+            Expr::Component(id) => self.emit(Instruction::Component(*id)),
+            Expr::Wildcard => self.emit(Instruction::Fail(
+                "#* is only allowed in variadic functions".to_string(),
+            )),
+            Expr::Named(name) => self.emit(Instruction::Named(name.clone())),
+            Expr::Let { name, value, body } => {
+                self.compile(value);
+                let slot = self.slots;
+                self.slots += 1;
+                self.emit(Instruction::Store(slot));
+                self.scope.push((name, slot));
+                self.compile(body);
+                self.scope.pop();
+            }
+            Expr::Variable(name) => {
+                match self.scope.iter().rev().find(|(bound, _)| bound == name) {
+                    Some((_, slot)) => self.emit(Instruction::Load(*slot)),
+                    None => self.emit(Instruction::Variable(name.clone())),
+                }
+            }
+            Expr::Reference(name) => self.emit(Instruction::Reference(name.clone())),
+            Expr::TimeOfUse(name) => self.emit(Instruction::TimeOfUse(name.clone())),
+            Expr::Time(function) => self.emit(Instruction::Time(function.clone())),
+            Expr::Temporal { function, .. } => {
+                self.emit(Instruction::Fail(function.stateless_error().0))
+            }
+            Expr::Select {
+                function: Function::If | Function::Case,
+                args,
+                branches,
+            } => self.compile_branches(args, branches),
+            Expr::Select {
+                function,
+                args,
+                branches,
+            } => {
+                let args = self.compile_args(args);
+                let select = self.emit_placeholder();
+                self.depth = depth;
+                let mut targets = Vec::with_capacity(branches.len());
+                let mut jumps = Vec::with_capacity(branches.len());
+                for branch in branches {
+                    targets.push(self.instructions.len());
+                    self.depth = depth;
+                    self.compile(branch);
+                    jumps.push(self.emit_placeholder());
+                }
+                let end = self.instructions.len();
+                self.instructions[select] = Instruction::Select {
+                    function: function.clone(),
+                    args,
+                    branches: targets,
+                    end,
+                };
+                self.patch_jumps(jumps, end);
+            }
+        }
+        self.depth = depth + 1;
+        self.max_depth = self.max_depth.max(self.depth);
+    }
+
+    /// Compile the conditions of an IF or CASE, followed by the branch
+    /// they select, so that only the conditions needed and the selected
+    /// branch are calculated.
+    fn compile_branches(&mut self, conditions: &'a [Expr<T>], branches: &'a [Expr<T>]) {
+        let depth = self.depth;
+        let mut tests = Vec::new();
+        let mut jumps = Vec::new();
+        let mut i = 0;
+        while Function::is_condition(i, conditions.len()) {
+            self.compile(&conditions[i]);
+            let test = self.emit_placeholder();
+            self.depth = depth;
+            self.compile(&branches[i + 1]);
+            jumps.push(self.emit_placeholder());
+            self.depth = depth;
+            tests.push((test, self.instructions.len()));
+            i += 2;
+        }
+        if i < conditions.len() {
+            self.compile(&branches[i]);
+        } else {
+            self.emit(Instruction::Push(None));
+        }
+        let end = self.instructions.len();
+        for (test, next) in tests {
+            self.instructions[test] = Instruction::Branch { next, end };
+        }
+        self.patch_jumps(jumps, end);
+    }
+
+    /// Compile the arguments of a function, expanding `#*` to all given
+    /// values.
+    fn compile_args(&mut self, args: &'a [Expr<T>]) -> Args {
+        let marked = args.iter().any(|arg| matches!(arg, Expr::Wildcard));
+        if marked {
+            self.emit(Instruction::Mark);
+        }
+        for arg in args {
+            match arg {
+                Expr::Wildcard => {
+                    self.emit(Instruction::Wildcard);
+                    self.depth += 1;
+                    self.max_depth = self.max_depth.max(self.depth);
+                }
+                arg => self.compile(arg),
+            }
+        }
+        if marked {
+            Args::Marked
+        } else {
+            Args::Count(args.len())
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction<T>) {
+        self.instructions.push(instruction);
+    }
+
+    /// Emit a jump to be patched once its target is known, returning its
+    /// index.
+    fn emit_placeholder(&mut self) -> usize {
+        self.emit(Instruction::Jump(usize::MAX));
+        self.instructions.len() - 1
+    }
+
+    /// Point the given jumps at the target.
+    fn patch_jumps(&mut self, jumps: Vec<usize>, target: usize) {
+        for jump in jumps {
+            self.instructions[jump] = Instruction::Jump(target);
+        }
+    }
+}
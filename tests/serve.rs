@@ -0,0 +1,182 @@
+// License: MIT
+// Copyright © 2024 Frequenz Energy-as-a-Service GmbH
+
+//! Tests of the `serve` binary over HTTP.
+
+#![cfg(feature = "serve")]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+};
+
+use serde_json::{json, Value};
+use tungstenite::Message;
+
+/// A running `serve` binary, killed when dropped.
+struct Serve {
+    child: Child,
+    address: String,
+}
+
+impl Serve {
+    fn start() -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{}", port);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_serve"))
+            .arg(&address)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stderr.as_mut().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line.trim(), format!("Listening on {}", address));
+        Serve { child, address }
+    }
+
+    /// Send a request, and get the status and JSON body of the response.
+    fn request(&self, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.address,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+}
+
+impl Drop for Serve {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+#[test]
+fn test_serve() {
+    let serve = Serve::start();
+    assert_eq!(
+        serve.request("PUT", "/formulas/grid", r##"{"formula": "#0 + #1"}"##),
+        (200, json!({ "name": "grid" }))
+    );
+    assert_eq!(
+        serve.request("GET", "/formulas", ""),
+        (200, json!({ "grid": "#0 + #1" }))
+    );
+    assert_eq!(
+        serve.request(
+            "POST",
+            "/formulas/grid/calculate",
+            r#"{"0": 1.0, "1": 2.5}"#
+        ),
+        (200, json!({ "result": 3.5 }))
+    );
+    assert_eq!(
+        serve.request(
+            "POST",
+            "/formulas/grid/calculate",
+            r#"{"0": 1.0, "1": null}"#
+        ),
+        (200, json!({ "result": null }))
+    );
+    serve.request("PUT", "/formulas/ratio", r##"{"formula": "#0 / #1"}"##);
+    let ratio = |values| serve.request("POST", "/formulas/ratio/calculate", values);
+    assert_eq!(
+        ratio(r#"{"0": 1.0, "1": 0.0}"#),
+        (200, json!({ "result": "Infinity" }))
+    );
+    assert_eq!(
+        ratio(r#"{"0": -1.0, "1": 0.0}"#),
+        (200, json!({ "result": "-Infinity" }))
+    );
+    assert_eq!(
+        ratio(r#"{"0": 0.0, "1": 0.0}"#),
+        (200, json!({ "result": "NaN" }))
+    );
+    serve.request("DELETE", "/formulas/ratio", "");
+
+    let (status, error) = serve.request("POST", "/formulas/grid/calculate", r#"{"0": 1.0}"#);
+    assert_eq!(status, 400);
+    assert!(error["error"].is_string());
+    let (status, error) = serve.request("PUT", "/formulas/bad", r##"{"formula": "#0 +"}"##);
+    assert_eq!(status, 400);
+    assert!(error["error"].is_string());
+    assert_eq!(
+        serve.request("POST", "/formulas/pv/calculate", "{}"),
+        (404, json!({ "error": "Unknown formula: pv" }))
+    );
+
+    assert_eq!(
+        serve.request("DELETE", "/formulas/grid", ""),
+        (200, json!({ "name": "grid" }))
+    );
+    assert_eq!(
+        serve.request(
+            "POST",
+            "/formulas/grid/calculate",
+            r#"{"0": 1.0, "1": 2.5}"#
+        ),
+        (404, json!({ "error": "Unknown formula: grid" }))
+    );
+    assert_eq!(
+        serve.request("DELETE", "/formulas/grid", ""),
+        (404, json!({ "error": "Unknown formula: grid" }))
+    );
+    assert_eq!(serve.request("GET", "/formulas", ""), (200, json!({})));
+}
+
+#[test]
+fn test_serve_stream() {
+    let serve = Serve::start();
+    let formula = r##"{"formula": "COALESCE(#0, 0) + COALESCE(#1, 0)"}"##;
+    serve.request("PUT", "/formulas/grid", formula);
+    assert_eq!(
+        serve.request("POST", "/formulas/grid/values", r#"{"0": 1.0, "1": 2.0}"#),
+        (200, json!({ "result": 3.0 }))
+    );
+
+    let url = format!("ws://{}/formulas/grid/stream", serve.address);
+    let (mut socket, _) = tungstenite::connect(url).unwrap();
+    let mut next = || loop {
+        match socket.read().unwrap() {
+            Message::Text(text) => break serde_json::from_str::<Value>(&text).unwrap(),
+            Message::Ping(_) => continue,
+            message => panic!("Expected a text message, got {:?}", message),
+        }
+    };
+    assert_eq!(next(), json!({ "result": 3.0 }));
+
+    // Only changes of the result are sent.
+    serve.request("POST", "/formulas/grid/values", r#"{"1": 2.0}"#);
+    assert_eq!(
+        serve.request("POST", "/formulas/grid/values", r#"{"1": null}"#),
+        (200, json!({ "result": 1.0 }))
+    );
+    assert_eq!(next(), json!({ "result": 1.0 }));
+    serve.request("POST", "/formulas/grid/values", r#"{"0": 5.0}"#);
+    assert_eq!(next(), json!({ "result": 5.0 }));
+
+    // The stream ends with the formula.
+    serve.request("DELETE", "/formulas/grid", "");
+    assert!(matches!(socket.read(), Ok(Message::Close(_))));
+
+    let url = format!("ws://{}/formulas/pv/stream", serve.address);
+    assert!(tungstenite::connect(url).is_err());
+    assert_eq!(serve.request("GET", "/formulas/grid/stream", "").0, 404);
+}